@@ -1,7 +1,10 @@
-use crate::hashtree::leaf_hash;
+use crate::hashtree::{labeled_hash, leaf_hash};
 use crate::{Hash, HashTree};
-use candid::{Nat, Principal};
+use candid::ser::IDLBuilder;
+use candid::types::Type;
+use candid::{CandidType, Nat, Principal};
 use std::borrow::Cow;
+use std::ops::{Deref, DerefMut};
 use std::ptr::NonNull;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -173,6 +176,126 @@ where
     }
 }
 
+/// A [`Result`] is certified as the tree of its value under a label that tells which
+/// arm is present, that is `labeled("Ok", value)` or `labeled("Err", error)`, so the
+/// root hash is `labeled_hash(b"Ok", value.root_hash())` for a successful result.
+impl<T, E> AsHashTree for Result<T, E>
+where
+    T: AsHashTree,
+    E: AsHashTree,
+{
+    #[inline]
+    fn root_hash(&self) -> Hash {
+        match self {
+            Ok(value) => labeled_hash(b"Ok", &value.root_hash()),
+            Err(error) => labeled_hash(b"Err", &error.root_hash()),
+        }
+    }
+
+    #[inline]
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        match self {
+            Ok(value) => HashTree::Labeled(Cow::Borrowed(b"Ok"), Box::new(value.as_hash_tree())),
+            Err(error) => HashTree::Labeled(Cow::Borrowed(b"Err"), Box::new(error.as_hash_tree())),
+        }
+    }
+}
+
+/// A wrapper that certifies any [`CandidType`] as a leaf, the content of the leaf is the
+/// candid encoding of the value.
+///
+/// Since the hash depends on the encoded bytes, changing the candid type of `T` changes
+/// the hash of the leaf even if the value stays the same, and a client has to encode the
+/// value in the exact same way to reconstruct the hash.
+///
+/// # Panics
+///
+/// Computing the hash tree panics if the value can not be encoded as candid.
+///
+/// # Example
+///
+/// ```
+/// use candid::CandidType;
+/// use certified_vars::as_hash_tree::CandidLeaf;
+/// use certified_vars::{AsHashTree, Map};
+///
+/// #[derive(CandidType)]
+/// struct Profile {
+///     name: String,
+///     age: u8,
+/// }
+///
+/// let mut profiles = Map::<String, CandidLeaf<Profile>>::new();
+/// profiles.insert("alice".into(), CandidLeaf(Profile { name: "Alice".into(), age: 30 }));
+/// assert_eq!(profiles.get("alice").unwrap().age, 30);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct CandidLeaf<T: CandidType>(pub T);
+
+impl<T: CandidType> CandidLeaf<T> {
+    /// Returns the wrapped value.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+
+    /// Returns the candid encoding of the value, which is the content of the leaf.
+    pub fn encode(&self) -> Vec<u8> {
+        IDLBuilder::new()
+            .arg(&self.0)
+            .and_then(|builder| builder.serialize_to_vec())
+            .expect("Could not encode the value as candid.")
+    }
+}
+
+impl<T: CandidType> From<T> for CandidLeaf<T> {
+    #[inline]
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T: CandidType> Deref for CandidLeaf<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: CandidType> DerefMut for CandidLeaf<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: CandidType> CandidType for CandidLeaf<T> {
+    fn _ty() -> Type {
+        T::ty()
+    }
+
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: candid::types::Serializer,
+    {
+        self.0.idl_serialize(serializer)
+    }
+}
+
+impl<T: CandidType> AsHashTree for CandidLeaf<T> {
+    #[inline]
+    fn root_hash(&self) -> Hash {
+        leaf_hash(&self.encode())
+    }
+
+    #[inline]
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        HashTree::Leaf(Cow::Owned(self.encode()))
+    }
+}
+
 macro_rules! impl_fixed_size {
     ( $($size:expr),* ) => {
         $(
@@ -215,3 +338,30 @@ impl_fixed_size!(
     1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26,
     27, 28, 29, 30, 31, 32
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn result() {
+        let ok: Result<u32, u32> = Ok(7);
+        let err: Result<u32, u32> = Err(7);
+
+        assert_eq!(
+            ok.as_hash_tree(),
+            HashTree::Labeled(Cow::Borrowed(b"Ok"), Box::new(7u32.as_hash_tree()))
+        );
+        assert_eq!(
+            err.as_hash_tree(),
+            HashTree::Labeled(Cow::Borrowed(b"Err"), Box::new(7u32.as_hash_tree()))
+        );
+
+        assert_eq!(ok.root_hash(), ok.as_hash_tree().reconstruct());
+        assert_eq!(err.root_hash(), err.as_hash_tree().reconstruct());
+        assert_eq!(ok.root_hash(), labeled_hash(b"Ok", &7u32.root_hash()));
+
+        // The same value hashes differently in the two arms.
+        assert_ne!(ok.root_hash(), err.root_hash());
+    }
+}
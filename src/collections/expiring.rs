@@ -0,0 +1,153 @@
+use crate::hashtree::{fork, fork_hash, labeled, labeled_hash, leaf_hash};
+use crate::label::Label;
+use crate::{AsHashTree, Hash, HashTree, Map};
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::ops::{Deref, DerefMut};
+
+/// The label of the leaf containing the expiry timestamp of an [`Expiring`] value.
+const EXPIRES_AT_LABEL: &[u8] = b"expires_at";
+
+/// The label of the subtree containing the value of an [`Expiring`] value.
+const VALUE_LABEL: &[u8] = b"value";
+
+/// A value that expires at the given timestamp, the timestamp is certified along with the
+/// value so a client can tell whether the value it received is still valid.
+///
+/// A [`Map`] of expiring values can evict all of the expired entries at once using
+/// [`Map::remove_expired`].
+///
+/// # Example
+///
+/// ```
+/// use certified_vars::collections::expiring::Expiring;
+/// use certified_vars::Map;
+///
+/// let mut sessions = Map::<String, Expiring<String>>::new();
+/// sessions.insert("a".into(), Expiring::new("alice".into(), 100));
+/// sessions.insert("b".into(), Expiring::new("bob".into(), 200));
+///
+/// assert_eq!(sessions.remove_expired(150), 1);
+/// assert_eq!(sessions.get("b").map(|s| s.as_str()), Some("bob"));
+/// ```
+///
+/// # Hash tree
+///
+/// ```text
+/// ─┬╴"expires_at" ──╴Leaf(expires_at)
+///  └╴"value" ──╴V
+/// ```
+///
+/// Where the timestamp is encoded as a big endian `u64`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, CandidType)]
+pub struct Expiring<V> {
+    pub value: V,
+    pub expires_at: u64,
+}
+
+impl<V> Expiring<V> {
+    /// Wrap the value with the given expiry timestamp.
+    #[inline]
+    pub fn new(value: V, expires_at: u64) -> Self {
+        Self { value, expires_at }
+    }
+
+    /// Returns `true` if the value is expired at the given time, a value is expired from
+    /// its expiry timestamp onward.
+    #[inline]
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at <= now
+    }
+
+    /// Returns the wrapped value.
+    #[inline]
+    pub fn into_inner(self) -> V {
+        self.value
+    }
+}
+
+impl<V> Deref for Expiring<V> {
+    type Target = V;
+
+    #[inline]
+    fn deref(&self) -> &V {
+        &self.value
+    }
+}
+
+impl<V> DerefMut for Expiring<V> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut V {
+        &mut self.value
+    }
+}
+
+impl<V: AsHashTree> AsHashTree for Expiring<V> {
+    fn root_hash(&self) -> Hash {
+        fork_hash(
+            &labeled_hash(EXPIRES_AT_LABEL, &leaf_hash(&self.expires_at.to_be_bytes())),
+            &labeled_hash(VALUE_LABEL, &self.value.root_hash()),
+        )
+    }
+
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        fork(
+            labeled(
+                EXPIRES_AT_LABEL,
+                HashTree::Leaf(Cow::Owned(self.expires_at.to_be_bytes().to_vec())),
+            ),
+            labeled(VALUE_LABEL, self.value.as_hash_tree()),
+        )
+    }
+}
+
+impl<K: 'static + Label, V: 'static + AsHashTree> Map<K, Expiring<V>> {
+    /// Remove all of the entries that are expired at the given time, and return the number
+    /// of removed entries. The map is rehashed once regardless of the number of entries
+    /// that are removed.
+    ///
+    /// The map is ordered by its keys and not by the expiry of the values, so finding the
+    /// expired entries takes a walk over the entire map.
+    pub fn remove_expired(&mut self, now: u64) -> usize {
+        self.sync_index();
+        let removed = self.inner.retain(|_, v| !v.is_expired(now));
+        self.unindex(removed.iter().map(|(k, _)| k));
+        removed.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_expired() {
+        let mut map = Map::<u32, Expiring<u32>>::new();
+        for i in 0..100 {
+            map.insert(i, Expiring::new(i, (i % 10) as u64));
+        }
+
+        assert_eq!(map.remove_expired(0), 10);
+        assert_eq!(map.remove_expired(0), 0);
+        assert_eq!(map.remove_expired(4), 40);
+        assert_eq!(map.len(), 50);
+        assert!(map.iter().all(|(_, v)| v.expires_at > 4));
+        assert_eq!(map.root_hash(), map.as_hash_tree().reconstruct());
+
+        assert_eq!(map.remove_expired(u64::MAX), 50);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn as_hash_tree() {
+        let value = Expiring::new("session".to_string(), 10);
+        let tree = value.as_hash_tree();
+        assert_eq!(tree.reconstruct(), value.root_hash());
+        assert_eq!(tree.get_labels(), vec![b"expires_at" as &[u8], b"value"]);
+        assert_eq!(
+            tree.get_leaf_values(),
+            vec![&10u64.to_be_bytes() as &[u8], b"session"]
+        );
+    }
+}
@@ -1,12 +1,20 @@
+use crate::as_hash_tree::CandidLeaf;
 use crate::hashtree::HashTree::Pruned;
 use crate::hashtree::{fork_hash, labeled_hash, ForkInner};
-use crate::{AsHashTree, Hash, HashTree};
-use std::any::{Any, TypeId};
+use crate::label::Label;
+use crate::{AsHashTree, Hash, HashTree, Map, Seq};
+use candid::types::Type;
+use candid::{CandidType, Nat, Principal};
+use std::any::{type_name, Any, TypeId};
 use std::borrow::Cow;
+use std::cell::{Cell, OnceCell, Ref, RefCell, RefMut};
 use std::collections::{HashMap, HashSet};
-use std::fmt::Debug;
+use std::convert::Infallible;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::ops::{Deref, DerefMut};
 
 pub mod builder;
+pub mod view;
 
 type NodeId = u64;
 
@@ -16,7 +24,7 @@ pub struct Group {
     /// The root node of the group is a shadow of the shape of the group's tree.
     root: GroupNode,
     /// The data in this group.
-    data: HashMap<TypeId, Box<dyn GroupLeaf>>,
+    data: HashMap<TypeId, GroupSlot>,
     /// Map each typeId used in a Leaf node to all of its ancestors.
     dependencies: HashMap<TypeId, Vec<NodeId>>,
 }
@@ -39,6 +47,8 @@ struct GroupNode {
 
 #[derive(Debug)]
 enum GroupNodeInner {
+    /// The root of a group without any leaves.
+    Empty,
     Fork(Box<GroupNode>, Box<GroupNode>),
     Labeled(String, Box<GroupNode>),
     Leaf(TypeId),
@@ -59,6 +69,75 @@ impl Group {
         Ray::new(self)
     }
 
+    /// Returns the full tree of the group, except for the leaves with the given types that
+    /// are replaced with their hash. This is the opposite of building a witness using
+    /// [`Group::witness`], where everything is pruned unless it is requested.
+    pub fn witness_all_except(&self, skip: &[TypeId]) -> HashTree<'_> {
+        self.root.witness_all(self, skip)
+    }
+
+    /// Returns the path of every leaf in the group in the order of the hash tree, this is
+    /// the schema a client needs to build a [`view::GroupView`] of a witness.
+    pub fn describe(&self) -> Vec<Vec<String>> {
+        self.leaves().into_iter().map(|(path, _)| path).collect()
+    }
+
+    /// Returns the path, the type and the candid type of every leaf in the group in the
+    /// order of the hash tree, which is what a generator of a typed client needs.
+    ///
+    /// The kind and the candid type are only known for the leaves that are inserted using
+    /// [`GroupBuilder::insert_typed`], the other leaves have the kind
+    /// [`LeafKind::Unknown`] and no candid type.
+    ///
+    /// [`GroupBuilder::insert_typed`]: crate::GroupBuilder::insert_typed
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::collections::group::LeafKind;
+    /// use certified_vars::{GroupBuilder, Map, Seq};
+    ///
+    /// let group = GroupBuilder::new()
+    ///     .insert_typed(["balances"], Map::<String, u64>::new())
+    ///     .insert_typed(["meta", "name"], "XTC".to_string())
+    ///     .insert(["log"], Seq::<u64>::new())
+    ///     .build();
+    ///
+    /// let manifest = group.manifest();
+    /// assert_eq!(manifest[0].path, ["balances"]);
+    /// assert_eq!(manifest[0].kind, LeafKind::Map);
+    /// assert_eq!(manifest[1].path, ["log"]);
+    /// assert_eq!(manifest[1].kind, LeafKind::Unknown);
+    /// assert_eq!(manifest[2].path, ["meta", "name"]);
+    /// assert_eq!(manifest[2].candid_type, Some(candid::types::Type::Text));
+    /// ```
+    pub fn manifest(&self) -> Vec<LeafManifest> {
+        self.leaves()
+            .into_iter()
+            .map(|(path, tid)| {
+                let slot = &self.data[&tid];
+                let (kind, candid_type) = match &slot.manifest {
+                    Some((kind, ty)) => (*kind, Some(ty.clone())),
+                    None => (LeafKind::Unknown, None),
+                };
+
+                LeafManifest {
+                    path,
+                    type_name: slot.type_name,
+                    kind,
+                    candid_type,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the path and the type of every leaf in the order of the hash tree.
+    fn leaves(&self) -> Vec<(Vec<String>, TypeId)> {
+        let mut leaves = Vec::with_capacity(self.data.len());
+        self.root.describe(&mut Vec::new(), &mut leaves);
+        leaves
+    }
+
     /// Returns a mutable reference to the leaf node with the given type.
     ///
     /// # Panics
@@ -70,10 +149,117 @@ impl Group {
         self.data
             .get_mut(&tid)
             .expect("Group does not contain the type")
+            .get_mut()
             .downcast_mut()
             .unwrap()
     }
 
+    /// Returns a reference to the leaf node with the given type, or an error listing the
+    /// types that are available in this group. If the initializer of the lazy leaf has
+    /// panicked, [`GroupError::MissingLeafData`] is returned instead.
+    pub fn try_get<T: GroupLeaf>(&self) -> Result<&T, GroupError> {
+        let tid = TypeId::of::<T>();
+        match self.data.get(&tid) {
+            Some(slot) => Ok(slot.try_get()?.downcast_ref().unwrap()),
+            None => Err(GroupError::UnknownType {
+                requested: type_name::<T>(),
+                available: self.available_types(),
+            }),
+        }
+    }
+
+    /// Returns a mutable reference to the leaf node with the given type, or an error listing
+    /// the types that are available in this group. If the initializer of the lazy leaf has
+    /// panicked, [`GroupError::MissingLeafData`] is returned instead.
+    pub fn try_get_mut<T: GroupLeaf>(&mut self) -> Result<&mut T, GroupError> {
+        let tid = TypeId::of::<T>();
+        if !self.data.contains_key(&tid) {
+            return Err(GroupError::UnknownType {
+                requested: type_name::<T>(),
+                available: self.available_types(),
+            });
+        }
+
+        Ok(self
+            .data
+            .get_mut(&tid)
+            .unwrap()
+            .try_get_mut()?
+            .downcast_mut()
+            .unwrap())
+    }
+
+    /// Returns the leaf at the given path as an [`AsHashTree`] trait object, or [`None`] if
+    /// there is no leaf at the path. This is meant for the code that only needs to hash or
+    /// witness a leaf without knowing its type, a lazy leaf is initialized by this call.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::{AsHashTree, GroupBuilder, Map};
+    ///
+    /// let group = GroupBuilder::new()
+    ///     .insert(["balances"], Map::<String, u64>::new())
+    ///     .insert(["meta", "name"], "XTC".to_string())
+    ///     .build();
+    ///
+    /// let leaf = group.get_dyn_at(&["meta", "name"]).unwrap();
+    /// assert_eq!(leaf.root_hash(), "XTC".to_string().root_hash());
+    /// assert!(group.get_dyn_at(&["meta"]).is_none());
+    /// ```
+    pub fn get_dyn_at(&self, path: &[&str]) -> Option<&dyn AsHashTree> {
+        let tid = self.root.find(path)?;
+        Some(self.data.get(&tid)?.get().as_dyn_hash_tree())
+    }
+
+    /// Returns the names of the children of the directory at the given path in the order
+    /// of the hash tree, or [`None`] if there is not a directory at the path. The empty
+    /// path is the root of the group.
+    ///
+    /// The children of a directory are put in a balanced tree of forks, this is the flat
+    /// view of the directory that a client gets from [`HashTree::directory`] on the tree
+    /// of the directory in a witness of the group.
+    ///
+    /// ```
+    /// use certified_vars::{GroupBuilder, Map};
+    ///
+    /// let group = GroupBuilder::new()
+    ///     .insert(["balances"], Map::<String, u64>::new())
+    ///     .insert(["meta", "name"], "XTC".to_string())
+    ///     .insert(["meta", "decimals"], 8u8)
+    ///     .build();
+    ///
+    /// assert_eq!(group.directory(&[]).unwrap(), ["balances", "meta"]);
+    /// assert_eq!(group.directory(&["meta"]).unwrap(), ["decimals", "name"]);
+    /// assert!(group.directory(&["meta", "name"]).is_none());
+    /// ```
+    pub fn directory(&self, path: &[&str]) -> Option<Vec<&str>> {
+        self.root.directory(path)
+    }
+
+    /// Check that the data of every leaf is available, so that computing a witness of the
+    /// group does not panic. This is only not the case if the initializer of a lazy leaf
+    /// has panicked, which leaves the group without the data of the leaf.
+    pub fn validate(&self) -> Result<(), GroupError> {
+        match self.data.values().find(|slot| slot.is_missing()) {
+            Some(slot) => Err(GroupError::MissingLeafData {
+                type_name: slot.type_name,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the name of the type of every leaf in this group, sorted alphabetically.
+    pub fn available_types(&self) -> Vec<&'static str> {
+        let mut names = self
+            .data
+            .values()
+            .map(|slot| slot.type_name)
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+
     /// Returns a reference to the leaf node with the given type.
     ///
     /// # Panics
@@ -85,9 +271,149 @@ impl Group {
         self.data
             .get(&tid)
             .expect("Group does not contain the type")
+            .get()
             .downcast_ref()
             .unwrap()
     }
+
+    /// Returns `true` if the leaf with the given type is initialized, this is always the
+    /// case for the leaves that are not inserted using [`GroupBuilder::insert_lazy`].
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the group does not contain any leaf nodes with the given
+    /// type.
+    ///
+    /// [`GroupBuilder::insert_lazy`]: crate::GroupBuilder::insert_lazy
+    pub fn is_initialized<T: GroupLeaf>(&self) -> bool {
+        let tid = TypeId::of::<T>();
+        self.data
+            .get(&tid)
+            .expect("Group does not contain the type")
+            .is_initialized()
+    }
+}
+
+/// A function that builds a lazy leaf.
+type LeafInit = Box<dyn FnOnce() -> Box<dyn GroupLeaf>>;
+
+/// The storage of a leaf in a group, a leaf is either initialized or it is waiting for
+/// its first use.
+pub(crate) struct GroupSlot {
+    leaf: OnceCell<Box<dyn GroupLeaf>>,
+    /// The function that builds the leaf, only used for lazy leaves.
+    init: Cell<Option<LeafInit>>,
+    /// The root hash of a lazy leaf before it is initialized.
+    lazy_hash: Hash,
+    type_name: &'static str,
+    /// The kind and the candid type of the leaf, if it was inserted with
+    /// [`GroupBuilder::insert_typed`](builder::GroupBuilder::insert_typed).
+    manifest: Option<(LeafKind, Type)>,
+}
+
+impl GroupSlot {
+    /// Create a slot for a leaf that is already initialized.
+    pub(crate) fn new<T: GroupLeaf>(leaf: T) -> Self {
+        Self {
+            leaf: OnceCell::from(Box::new(leaf) as Box<dyn GroupLeaf>),
+            init: Cell::new(None),
+            lazy_hash: [0; 32],
+            type_name: type_name::<T>(),
+            manifest: None,
+        }
+    }
+
+    /// Create a slot for a leaf that is built on its first use, the given hash is used as
+    /// the root hash of the leaf until then.
+    pub(crate) fn lazy<T: GroupLeaf, F: FnOnce() -> T + 'static>(hash: Hash, init: F) -> Self {
+        let init = move || {
+            let leaf = init();
+            debug_assert_eq!(
+                leaf.root_hash(),
+                hash,
+                "The lazy leaf '{}' does not have the expected root hash.",
+                type_name::<T>()
+            );
+            Box::new(leaf) as Box<dyn GroupLeaf>
+        };
+
+        Self {
+            leaf: OnceCell::new(),
+            init: Cell::new(Some(Box::new(init))),
+            lazy_hash: hash,
+            type_name: type_name::<T>(),
+            manifest: None,
+        }
+    }
+
+    /// Record the kind and the candid type of the leaf for the manifest of the group.
+    #[inline]
+    pub(crate) fn with_manifest<T: ManifestLeaf>(mut self) -> Self {
+        self.manifest = Some((T::leaf_kind(), T::candid_type()));
+        self
+    }
+
+    /// Returns the leaf, and initializes it if it is not initialized yet.
+    ///
+    /// # Panics
+    ///
+    /// If the initializer of the leaf has panicked before.
+    #[inline]
+    fn get(&self) -> &dyn GroupLeaf {
+        self.try_get().unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    #[inline]
+    fn get_mut(&mut self) -> &mut dyn GroupLeaf {
+        self.try_get_mut()
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Returns the leaf and initializes it if it is not initialized yet, or an error if
+    /// the initializer of the leaf has panicked before.
+    fn try_get(&self) -> Result<&dyn GroupLeaf, GroupError> {
+        if let Some(leaf) = self.leaf.get() {
+            return Ok(leaf.as_ref());
+        }
+
+        let init = self.init.take().ok_or(GroupError::MissingLeafData {
+            type_name: self.type_name,
+        })?;
+        Ok(self.leaf.get_or_init(init).as_ref())
+    }
+
+    #[inline]
+    fn try_get_mut(&mut self) -> Result<&mut dyn GroupLeaf, GroupError> {
+        self.try_get()?;
+        Ok(self.leaf.get_mut().unwrap().as_mut())
+    }
+
+    #[inline]
+    fn is_initialized(&self) -> bool {
+        self.leaf.get().is_some()
+    }
+
+    /// Returns `true` if the leaf is not initialized and it can not be initialized anymore.
+    #[inline]
+    fn is_missing(&self) -> bool {
+        if self.is_initialized() {
+            return false;
+        }
+
+        let init = self.init.take();
+        let missing = init.is_none();
+        self.init.set(init);
+        missing
+    }
+
+    /// Returns the root hash of the leaf without initializing it.
+    #[inline]
+    fn root_hash(&self) -> Hash {
+        match self.leaf.get() {
+            Some(leaf) => leaf.root_hash(),
+            None => self.lazy_hash,
+        }
+    }
 }
 
 impl GroupNode {
@@ -100,6 +426,10 @@ impl GroupNode {
         path: &mut Vec<NodeId>,
     ) -> NodeId {
         match &mut self.data {
+            GroupNodeInner::Empty => {
+                self.id = id;
+                id + 1
+            }
             GroupNodeInner::Fork(left, right) => {
                 self.id = id;
                 path.push(self.id);
@@ -131,6 +461,7 @@ impl GroupNode {
         }
 
         match &self.data {
+            GroupNodeInner::Empty => HashTree::Empty,
             GroupNodeInner::Fork(left, right) => {
                 let l_tree = left.witness(ray);
                 let r_tree = right.witness(ray);
@@ -144,23 +475,94 @@ impl GroupNode {
         }
     }
 
-    fn witness_all<'a>(&'a self, group: &'a Group) -> HashTree<'a> {
+    /// Returns the full tree of this node, except for the leaves with the given types
+    /// which are pruned.
+    fn witness_all<'a>(&'a self, group: &'a Group, skip: &[TypeId]) -> HashTree<'a> {
         match &self.data {
+            GroupNodeInner::Empty => HashTree::Empty,
             GroupNodeInner::Fork(left, right) => {
-                let l_tree = left.witness_all(group);
-                let r_tree = right.witness_all(group);
+                let l_tree = left.witness_all(group, skip);
+                let r_tree = right.witness_all(group, skip);
                 HashTree::Fork(Box::new(ForkInner(l_tree, r_tree)))
             }
             GroupNodeInner::Labeled(label, n) => {
-                let tree = n.witness_all(group);
+                let tree = n.witness_all(group, skip);
                 HashTree::Labeled(Cow::Borrowed(label.as_bytes()), Box::new(tree))
             }
-            GroupNodeInner::Leaf(tid) => group.data.get(tid).unwrap().as_hash_tree(),
+            GroupNodeInner::Leaf(tid) if skip.contains(tid) => {
+                Pruned(group.data.get(tid).unwrap().root_hash())
+            }
+            GroupNodeInner::Leaf(tid) => group.data.get(tid).unwrap().get().as_hash_tree(),
+        }
+    }
+
+    /// Returns the type of the leaf at the given path under this node.
+    fn find(&self, path: &[&str]) -> Option<TypeId> {
+        match &self.data {
+            GroupNodeInner::Empty => None,
+            GroupNodeInner::Fork(left, right) => left.find(path).or_else(|| right.find(path)),
+            GroupNodeInner::Labeled(label, node) => match path.split_first() {
+                Some((name, rest)) if name == label => node.find(rest),
+                _ => None,
+            },
+            GroupNodeInner::Leaf(tid) if path.is_empty() => Some(*tid),
+            GroupNodeInner::Leaf(_) => None,
+        }
+    }
+
+    /// Returns the labels of the children of the directory at the given path under this
+    /// node.
+    fn directory(&self, path: &[&str]) -> Option<Vec<&str>> {
+        fn children<'a>(node: &'a GroupNode, labels: &mut Vec<&'a str>) -> Option<()> {
+            match &node.data {
+                GroupNodeInner::Empty => {}
+                GroupNodeInner::Fork(left, right) => {
+                    children(left, labels)?;
+                    children(right, labels)?;
+                }
+                GroupNodeInner::Labeled(label, _) => labels.push(label),
+                GroupNodeInner::Leaf(_) => return None,
+            }
+
+            Some(())
+        }
+
+        match (&self.data, path.split_first()) {
+            (_, None) => {
+                let mut labels = Vec::new();
+                children(self, &mut labels)?;
+                Some(labels)
+            }
+            (GroupNodeInner::Fork(left, right), Some(_)) => {
+                left.directory(path).or_else(|| right.directory(path))
+            }
+            (GroupNodeInner::Labeled(label, node), Some((name, rest))) if name == label => {
+                node.directory(rest)
+            }
+            _ => None,
+        }
+    }
+
+    /// Push the path of every leaf under this node to the given vector.
+    fn describe(&self, path: &mut Vec<String>, paths: &mut Vec<(Vec<String>, TypeId)>) {
+        match &self.data {
+            GroupNodeInner::Empty => {}
+            GroupNodeInner::Fork(left, right) => {
+                left.describe(path, paths);
+                right.describe(path, paths);
+            }
+            GroupNodeInner::Labeled(label, node) => {
+                path.push(label.clone());
+                node.describe(path, paths);
+                path.pop();
+            }
+            GroupNodeInner::Leaf(tid) => paths.push((path.clone(), *tid)),
         }
     }
 
     fn root_hash(&self, group: &Group) -> Hash {
         match &self.data {
+            GroupNodeInner::Empty => HashTree::Empty.reconstruct(),
             GroupNodeInner::Fork(left, right) => {
                 fork_hash(&left.root_hash(group), &right.root_hash(group))
             }
@@ -187,38 +589,327 @@ impl<'a> Ray<'a> {
         self.group.root.witness(&mut self)
     }
 
+    /// Include the full tree of the leaf with the type `T` in the witness.
     #[must_use]
-    pub fn full<T: GroupLeaf + 'static>(mut self) -> Self {
+    pub fn full<T: GroupLeaf>(mut self) -> Self {
         let tid = TypeId::of::<T>();
 
         for dep in self.group.dependencies.get(&tid).unwrap() {
             self.to_visit.insert(*dep);
         }
 
-        let tree = self.group.data.get(&tid).unwrap().as_hash_tree();
+        let tree = self.group.data.get(&tid).unwrap().get().as_hash_tree();
         self.leaves.insert(tid, tree);
 
         self
     }
 
+    /// Include the tree that `f` returns for the leaf with the type `T` in the witness, the
+    /// tree can borrow from the leaf or from anything else that outlives the group.
     #[must_use]
-    pub fn partial<T: GroupLeaf + 'static, F: FnOnce(&T) -> HashTree>(mut self, f: F) -> Self {
+    pub fn partial<T: GroupLeaf, F: FnOnce(&'a T) -> HashTree<'a>>(self, f: F) -> Self {
+        match self.try_partial::<T, _, Infallible>(|data| Ok(f(data))) {
+            Ok(ray) => ray,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Like [`Ray::partial`], but `f` can fail, in which case its error is returned and
+    /// the ray is dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::{GroupBuilder, Map};
+    ///
+    /// let mut balances = Map::<String, u64>::new();
+    /// balances.insert("alice".into(), 10);
+    /// let group = GroupBuilder::new().insert(["balances"], balances).build();
+    ///
+    /// let witness_of = |user: &'static str| {
+    ///     group.witness().try_partial(|balances: &Map<String, u64>| {
+    ///         match balances.get(user) {
+    ///             Some(_) => Ok(balances.witness(user)),
+    ///             None => Err(format!("Unknown user '{}'.", user)),
+    ///         }
+    ///     })
+    /// };
+    ///
+    /// assert!(witness_of("alice").is_ok());
+    /// assert_eq!(witness_of("bob").err().unwrap(), "Unknown user 'bob'.");
+    /// ```
+    pub fn try_partial<T, F, E>(mut self, f: F) -> Result<Self, E>
+    where
+        T: GroupLeaf,
+        F: FnOnce(&'a T) -> Result<HashTree<'a>, E>,
+    {
         let tid = TypeId::of::<T>();
 
         for dep in self.group.dependencies.get(&tid).unwrap() {
             self.to_visit.insert(*dep);
         }
 
-        let data = self.group.data.get(&tid).unwrap();
-        let tree = f(data.downcast_ref().unwrap());
+        let data = self.group.data.get(&tid).unwrap().get();
+        let tree = f(data.downcast_ref().unwrap())?;
         self.leaves.insert(tid, tree);
 
+        Ok(self)
+    }
+}
+
+impl Ray<'_> {
+    /// Patch a witness of the group that was built before the leaf with the type `T` was
+    /// changed, by replacing the subtree of the leaf with the given tree, which is usually
+    /// a new witness of the leaf. Only the path from the root to the leaf is visited, and
+    /// the rest of the previous witness is kept as is. This is only valid if no other leaf
+    /// has changed since the previous witness was built.
+    ///
+    /// If the leaf is pruned in the previous witness, the pruned nodes along its path are
+    /// expanded and their other children are pruned.
+    ///
+    /// The leaves that were added to this ray are ignored.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::{AsHashTree, GroupBuilder, Map, Seq};
+    ///
+    /// let mut group = GroupBuilder::new()
+    ///     .insert(["balances"], Map::<String, u64>::new())
+    ///     .insert(["log"], Seq::<u64>::new())
+    ///     .build();
+    ///
+    /// let witness = group.as_hash_tree().into_owned();
+    ///
+    /// group.get_mut::<Seq<u64>>().append(10);
+    /// let log = group.get::<Seq<u64>>().as_hash_tree();
+    /// let witness = group.witness().update_leaf::<Seq<u64>>(witness, log);
+    ///
+    /// assert_eq!(witness, group.as_hash_tree());
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If the group does not contain the type, or if the previous witness does not have the
+    /// shape of the group along the path of the leaf.
+    pub fn update_leaf<'t, T: GroupLeaf>(
+        self,
+        prev: HashTree<'t>,
+        subtree: HashTree<'t>,
+    ) -> HashTree<'t> {
+        fn go<'t>(
+            node: &GroupNode,
+            group: &Group,
+            path: &[NodeId],
+            prev: HashTree<'t>,
+            subtree: &mut Option<HashTree<'t>>,
+        ) -> HashTree<'t> {
+            if !path.contains(&node.id) {
+                return prev;
+            }
+
+            let pruned = |node: &GroupNode| Pruned(node.root_hash(group));
+
+            match (&node.data, prev) {
+                (GroupNodeInner::Leaf(_), _) => subtree.take().unwrap(),
+                (GroupNodeInner::Fork(left, right), HashTree::Fork(lr)) => {
+                    let ForkInner(l, r) = *lr;
+                    let l = go(left, group, path, l, subtree);
+                    let r = go(right, group, path, r, subtree);
+                    HashTree::Fork(Box::new(ForkInner(l, r)))
+                }
+                (GroupNodeInner::Fork(left, right), Pruned(_)) => {
+                    let l = go(left, group, path, pruned(left), subtree);
+                    let r = go(right, group, path, pruned(right), subtree);
+                    HashTree::Fork(Box::new(ForkInner(l, r)))
+                }
+                (GroupNodeInner::Labeled(label, n), HashTree::Labeled(l, tree))
+                    if l.as_ref() == label.as_bytes() =>
+                {
+                    HashTree::Labeled(l, Box::new(go(n, group, path, *tree, subtree)))
+                }
+                (GroupNodeInner::Labeled(label, n), Pruned(_)) => {
+                    let tree = go(n, group, path, pruned(n), subtree);
+                    HashTree::Labeled(Cow::Owned(label.as_bytes().to_vec()), Box::new(tree))
+                }
+                _ => panic!("The tree is not a witness of the group."),
+            }
+        }
+
+        let tid = TypeId::of::<T>();
+        let path = self
+            .group
+            .dependencies
+            .get(&tid)
+            .expect("Group does not contain the type");
+
+        go(&self.group.root, self.group, path, prev, &mut Some(subtree))
+    }
+}
+
+pub trait GroupLeaf: Any + AsHashTree {
+    /// Returns this leaf as an [`AsHashTree`] trait object.
+    fn as_dyn_hash_tree(&self) -> &dyn AsHashTree;
+}
+
+impl<T: Any + AsHashTree> GroupLeaf for T {
+    #[inline]
+    fn as_dyn_hash_tree(&self) -> &dyn AsHashTree {
         self
     }
 }
 
-pub trait GroupLeaf: Any + AsHashTree {}
-impl<T: Any + AsHashTree> GroupLeaf for T {}
+/// A leaf whose candid type is known, which makes it possible to describe the leaf in
+/// the [`Group::manifest`]. The default implementations describe a scalar leaf with the
+/// candid type of `Self`, so `impl ManifestLeaf for MyType {}` is enough for most types.
+///
+/// Like the other candid helpers the manifest is not behind a feature, see the
+/// [crate documentation](crate#candid).
+pub trait ManifestLeaf: GroupLeaf + CandidType {
+    /// Returns the kind of the leaf.
+    fn leaf_kind() -> LeafKind {
+        LeafKind::Scalar
+    }
+
+    /// Returns the candid type of the data of the leaf.
+    fn candid_type() -> Type {
+        Self::ty()
+    }
+}
+
+/// How a client can look up the data of a leaf, see [`Group::manifest`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LeafKind {
+    /// A [`Map`], the entries are under the label of their key.
+    Map,
+    /// A [`Seq`], see its documentation for the shape of its tree.
+    Seq,
+    /// A single value.
+    Scalar,
+    /// The leaf was not inserted using
+    /// [`GroupBuilder::insert_typed`](builder::GroupBuilder::insert_typed).
+    Unknown,
+}
+
+/// The description of a leaf of a group, see [`Group::manifest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeafManifest {
+    /// The labels from the root of the group to the leaf.
+    pub path: Vec<String>,
+    /// The name of the Rust type of the leaf.
+    pub type_name: &'static str,
+    /// How the data of the leaf is laid out in the hash tree.
+    pub kind: LeafKind,
+    /// The candid type of the data of the leaf, if it is known.
+    pub candid_type: Option<Type>,
+}
+
+impl<K, V> ManifestLeaf for Map<K, V>
+where
+    K: 'static + Label + CandidType,
+    V: 'static + AsHashTree + CandidType,
+{
+    fn leaf_kind() -> LeafKind {
+        LeafKind::Map
+    }
+}
+
+impl<T: 'static + AsHashTree + CandidType> ManifestLeaf for Seq<T> {
+    fn leaf_kind() -> LeafKind {
+        LeafKind::Seq
+    }
+}
+
+impl<T: 'static + CandidType> ManifestLeaf for CandidLeaf<T> {}
+
+macro_rules! impl_manifest_leaf {
+    ( $($name:ty),* ) => {
+        $(
+            impl ManifestLeaf for $name {}
+        )*
+    }
+}
+
+impl_manifest_leaf!(
+    bool,
+    String,
+    Principal,
+    Nat,
+    Vec<u8>,
+    u8,
+    i8,
+    u16,
+    i16,
+    u32,
+    i32,
+    u64,
+    i64,
+    u128,
+    i128,
+    usize,
+    isize,
+    f32,
+    f64
+);
+
+/// The error type for the fallible operations on a [`Group`] and its
+/// [`GroupBuilder`](builder::GroupBuilder).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupError {
+    /// The group does not contain a leaf with the requested type.
+    UnknownType {
+        requested: &'static str,
+        available: Vec<&'static str>,
+    },
+    /// The type is already used by another leaf, the leaves of a group are identified by
+    /// their type so each type can only be used once.
+    DuplicateType { type_name: &'static str },
+    /// The path is already used by another leaf or by a directory.
+    PathReused { path: Vec<String> },
+    /// The data of a leaf is not available, which happens when the initializer of a lazy
+    /// leaf has panicked, or when a group is built from a schema without the data of one
+    /// of its leaves.
+    MissingLeafData { type_name: &'static str },
+    /// The group does not have any leaves.
+    EmptyGroup,
+    /// The path goes through the leaf at the given path, and a leaf can not have children.
+    InsertIntoLeaf { path: Vec<String> },
+}
+
+impl Display for GroupError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            GroupError::UnknownType {
+                requested,
+                available,
+            } => write!(
+                f,
+                "Group does not contain the type '{}', available types are: [{}]",
+                requested,
+                available.join(", ")
+            ),
+            GroupError::DuplicateType { type_name } => {
+                write!(f, "Type '{}' is already used in the group.", type_name)
+            }
+            GroupError::PathReused { path } => {
+                write!(f, "Path '{}' is already used.", path.join("/"))
+            }
+            GroupError::MissingLeafData { type_name } => write!(
+                f,
+                "The data of the leaf '{}' is missing, its initializer has panicked.",
+                type_name
+            ),
+            GroupError::EmptyGroup => write!(f, "Group does not contain any leaves."),
+            GroupError::InsertIntoLeaf { path } => write!(
+                f,
+                "Can not insert to the leaf node at '{}'.",
+                path.join("/")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GroupError {}
 
 impl dyn GroupLeaf {
     pub fn is<T: GroupLeaf>(&self) -> bool {
@@ -250,15 +941,184 @@ impl AsHashTree for Group {
     }
 
     fn as_hash_tree(&self) -> HashTree<'_> {
-        self.root.witness_all(self)
+        self.root.witness_all(self, &[])
+    }
+}
+
+/// A [`Group`] with interior mutability, for a canister that keeps its group in a
+/// `thread_local!`. The group is borrowed through the [`SharedGroup::read`] and
+/// [`SharedGroup::write`] guards, so a write can not overlap with a read of the group, and
+/// a certified response is built from one read guard so the value and its witness always
+/// come from the same state.
+///
+/// # Example
+///
+/// ```
+/// use certified_vars::collections::group::SharedGroup;
+/// use certified_vars::{AsHashTree, GroupBuilder, Map};
+///
+/// type Balances = Map<String, u64>;
+///
+/// thread_local! {
+///     static GROUP: SharedGroup = SharedGroup::new(
+///         GroupBuilder::new().insert(["balances"], Balances::new()).build()
+///     );
+/// }
+///
+/// GROUP.with(|group| {
+///     group.write().get_mut::<Balances>().insert("alice".into(), 10);
+///
+///     let read = group.read();
+///     let response = group.certified_response(&read, |group, ray| {
+///         let balance = group.get::<Balances>().get("alice").copied();
+///         (balance, ray.partial::<Balances, _>(|b| b.witness("alice")))
+///     });
+///
+///     assert_eq!(response.value, Some(10));
+///     assert_eq!(response.witness.reconstruct(), read.root_hash());
+/// });
+/// ```
+pub struct SharedGroup {
+    group: RefCell<Group>,
+}
+
+/// A shared borrow of the group in a [`SharedGroup`].
+pub struct GroupReadGuard<'a> {
+    group: Ref<'a, Group>,
+    owner: &'a SharedGroup,
+}
+
+/// An exclusive borrow of the group in a [`SharedGroup`].
+pub struct GroupWriteGuard<'a> {
+    group: RefMut<'a, Group>,
+}
+
+/// A value along with the witness that certifies it, see
+/// [`SharedGroup::certified_response`].
+pub struct CertifiedResponse<'a, T> {
+    pub value: T,
+    pub witness: HashTree<'a>,
+}
+
+impl SharedGroup {
+    /// Wrap the given group.
+    #[inline]
+    pub fn new(group: Group) -> Self {
+        Self {
+            group: RefCell::new(group),
+        }
+    }
+
+    /// Borrow the group for reading.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the group is borrowed by a [`GroupWriteGuard`].
+    #[inline]
+    pub fn read(&self) -> GroupReadGuard<'_> {
+        self.try_read()
+            .expect("The group is already borrowed for writing.")
+    }
+
+    /// Borrow the group for reading, or return [`None`] if the group is borrowed by a
+    /// [`GroupWriteGuard`].
+    #[inline]
+    pub fn try_read(&self) -> Option<GroupReadGuard<'_>> {
+        let group = self.group.try_borrow().ok()?;
+        Some(GroupReadGuard { group, owner: self })
+    }
+
+    /// Borrow the group for writing.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the group is borrowed by any other guard.
+    #[inline]
+    pub fn write(&self) -> GroupWriteGuard<'_> {
+        self.try_write().expect("The group is already borrowed.")
+    }
+
+    /// Borrow the group for writing, or return [`None`] if the group is borrowed by any
+    /// other guard.
+    #[inline]
+    pub fn try_write(&self) -> Option<GroupWriteGuard<'_>> {
+        let group = self.group.try_borrow_mut().ok()?;
+        Some(GroupWriteGuard { group })
+    }
+
+    /// Build a certified response from the given read guard, `f` is called with the group
+    /// and an empty witness builder, and returns the value of the response along with the
+    /// builder of its witness.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if the guard does not belong to this group.
+    pub fn certified_response<'a, T, F>(
+        &self,
+        guard: &'a GroupReadGuard<'_>,
+        f: F,
+    ) -> CertifiedResponse<'a, T>
+    where
+        F: FnOnce(&'a Group, Ray<'a>) -> (T, Ray<'a>),
+    {
+        assert!(
+            std::ptr::eq(self, guard.owner),
+            "The guard does not belong to this group."
+        );
+
+        let group: &'a Group = guard;
+        let (value, ray) = f(group, group.witness());
+        CertifiedResponse {
+            value,
+            witness: ray.build(),
+        }
+    }
+
+    /// Unwrap the group.
+    #[inline]
+    pub fn into_inner(self) -> Group {
+        self.group.into_inner()
+    }
+}
+
+impl From<Group> for SharedGroup {
+    #[inline]
+    fn from(group: Group) -> Self {
+        Self::new(group)
+    }
+}
+
+impl Deref for GroupReadGuard<'_> {
+    type Target = Group;
+
+    #[inline]
+    fn deref(&self) -> &Group {
+        &self.group
+    }
+}
+
+impl Deref for GroupWriteGuard<'_> {
+    type Target = Group;
+
+    #[inline]
+    fn deref(&self) -> &Group {
+        &self.group
+    }
+}
+
+impl DerefMut for GroupWriteGuard<'_> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Group {
+        &mut self.group
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::builder::GroupBuilder;
+    use super::builder::{GroupBuilder, GroupData, GroupSchema};
     use super::*;
-    use crate::Map;
+    use crate::hashtree::{fork, labeled};
+    use crate::{Map, Seq};
     use candid::Principal;
 
     #[test]
@@ -303,8 +1163,10 @@ mod tests {
             dependencies: Default::default(),
         };
 
-        group.data.insert(TypeId::of::<String>(), Box::new("Cap"));
-        group.data.insert(TypeId::of::<S2S>(), Box::new(map));
+        group
+            .data
+            .insert(TypeId::of::<String>(), GroupSlot::new("Cap"));
+        group.data.insert(TypeId::of::<S2S>(), GroupSlot::new(map));
         group.init();
 
         let t1 = group.witness().build();
@@ -379,7 +1241,7 @@ mod tests {
         assert_eq!(t3.reconstruct(), t4.reconstruct());
         assert_eq!(t4.reconstruct(), t5.reconstruct());
 
-        assert_eq!(t1.get_labels(), vec![b"ledger" as &[u8], b"A"]);
+        assert_eq!(t1.get_labels(), vec![b"ledger" as &[u8], b"\x01A"]);
         assert_eq!(t2.get_labels(), vec![b"meta" as &[u8], b"name"]);
         assert_eq!(t3.get_labels(), vec![b"meta" as &[u8], b"owner"]);
         assert_eq!(t4.get_labels(), vec![b"meta" as &[u8], b"name", b"owner"]);
@@ -388,4 +1250,710 @@ mod tests {
             vec![b"canister" as &[u8], b"url", b"meta", b"name"]
         );
     }
+
+    #[test]
+    fn external() {
+        use super::builder::RawLeaf;
+
+        struct A;
+        struct B;
+
+        let external = || labeled(b"x", HashTree::Leaf(Cow::Owned(vec![1])));
+
+        let group = GroupBuilder::new()
+            .insert_external(["a"], RawLeaf::<A>::new(external().reconstruct(), external))
+            .insert_external(["b"], RawLeaf::<B>::pruned([1; 32]))
+            .insert(["c"], "C".to_string())
+            .build();
+
+        let expected = fork(
+            fork(labeled(b"a", external()), labeled(b"b", Pruned([1; 32]))),
+            labeled(b"c", "C".as_hash_tree()),
+        );
+        assert_eq!(group.root_hash(), expected.reconstruct());
+        assert_eq!(group.as_hash_tree(), expected);
+
+        let tree = group.witness().full::<RawLeaf<A>>().build();
+        assert_eq!(tree.reconstruct(), group.root_hash());
+        assert_eq!(tree.get_leaf_values(), vec![&[1]]);
+    }
+
+    #[test]
+    fn generic_leaf() {
+        struct Versioned<T> {
+            version: u32,
+            data: T,
+        }
+
+        impl<T: AsHashTree> AsHashTree for Versioned<T> {
+            fn as_hash_tree(&self) -> HashTree<'_> {
+                fork(
+                    labeled(b"data", self.data.as_hash_tree()),
+                    labeled(b"version", self.version.as_hash_tree()),
+                )
+            }
+        }
+
+        // Only the `GroupLeaf` bound is needed to build a witness for a generic leaf.
+        fn witness_of<T: GroupLeaf>(group: &Group) -> HashTree<'_> {
+            group.witness().full::<T>().build()
+        }
+
+        let mut map = Map::<String, u32>::new();
+        map.insert("x".into(), 1);
+
+        let group = GroupBuilder::new()
+            .insert(
+                ["map"],
+                Versioned {
+                    version: 1,
+                    data: map,
+                },
+            )
+            .insert(
+                ["name"],
+                Versioned {
+                    version: 2,
+                    data: "XTC".to_string(),
+                },
+            )
+            .build();
+
+        let tree = witness_of::<Versioned<String>>(&group);
+        assert_eq!(tree.reconstruct(), group.root_hash());
+        assert_eq!(
+            tree.get_labels(),
+            vec![b"name" as &[u8], b"data", b"version"]
+        );
+
+        // The tree of a partial leaf does not have to borrow from the leaf.
+        let key = "x".to_string();
+        let tree = group
+            .witness()
+            .partial(|leaf: &Versioned<Map<String, u32>>| {
+                fork(
+                    labeled(b"data", leaf.data.witness(&key)),
+                    Pruned(labeled_hash(b"version", &leaf.version.root_hash())),
+                )
+            })
+            .build();
+        assert_eq!(tree.reconstruct(), group.root_hash());
+        assert_eq!(tree.get_leaf_values(), vec![&1u32.to_be_bytes()]);
+    }
+
+    #[test]
+    fn update_leaf() {
+        type S2S = Map<String, String>;
+        type Log = crate::Seq<u32>;
+
+        let mut group = GroupBuilder::new()
+            .insert(["a", "map"], S2S::new())
+            .insert(["a", "log"], Log::new())
+            .insert(["name"], "XTC".to_string())
+            .build();
+
+        let full = group.as_hash_tree().into_owned();
+        let partial = group.witness().full::<String>().build().into_owned();
+
+        group.get_mut::<S2S>().insert("x".into(), "y".into());
+        let map = group.get::<S2S>();
+
+        // Patch a witness that contains the leaf.
+        let patched = group.witness().update_leaf::<S2S>(full, map.as_hash_tree());
+        assert_eq!(patched, group.as_hash_tree());
+
+        // Patch a witness in which the leaf is pruned.
+        let patched = group
+            .witness()
+            .update_leaf::<S2S>(partial, map.witness("x"));
+        assert_eq!(
+            patched,
+            group
+                .witness()
+                .full::<String>()
+                .partial(|m: &S2S| m.witness("x"))
+                .build()
+        );
+        assert_eq!(patched.reconstruct(), group.root_hash());
+    }
+
+    #[test]
+    fn empty() {
+        let group = GroupBuilder::new().build();
+
+        assert_eq!(group.root_hash(), HashTree::Empty.reconstruct());
+        assert_eq!(group.as_hash_tree(), HashTree::Empty);
+        assert_eq!(group.witness().build().reconstruct(), group.root_hash());
+        assert!(group.available_types().is_empty());
+    }
+
+    #[test]
+    fn single_leaf() {
+        let group = GroupBuilder::new()
+            .insert(["meta", "name"], "XTC".to_string())
+            .build();
+
+        let tree = group.witness().full::<String>().build();
+        assert_eq!(
+            tree,
+            labeled(
+                b"meta",
+                labeled(b"name", HashTree::Leaf(Cow::Borrowed(b"XTC")))
+            )
+        );
+        assert_eq!(tree.reconstruct(), group.root_hash());
+        assert_eq!(group.as_hash_tree(), tree);
+    }
+
+    #[test]
+    fn directory() {
+        use crate::hashtree::DirectoryEntry;
+
+        assert_eq!(
+            GroupBuilder::new().build().directory(&[]).unwrap(),
+            Vec::<&str>::new()
+        );
+
+        let group = GroupBuilder::new()
+            .insert(["a"], 1u32)
+            .insert(["b", "x"], 2u64)
+            .insert(["c"], "c".to_string())
+            .insert(["d"], Seq::<u32>::new())
+            .insert(["e"], Map::<String, u32>::new())
+            .build();
+
+        assert_eq!(group.directory(&[]).unwrap(), ["a", "b", "c", "d", "e"]);
+        assert_eq!(group.directory(&["b"]).unwrap(), ["x"]);
+        assert!(group.directory(&["a"]).is_none());
+        assert!(group.directory(&["b", "x"]).is_none());
+        assert!(group.directory(&["f"]).is_none());
+
+        // The tree of the group is the same directory.
+        let tree = group.as_hash_tree();
+        let entries = tree.directory().unwrap();
+        let labels = entries
+            .iter()
+            .map(|entry| match entry {
+                DirectoryEntry::Labeled(label, _) => std::str::from_utf8(label).unwrap(),
+                DirectoryEntry::Pruned(_) => panic!("Expected a labeled child."),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(labels, group.directory(&[]).unwrap());
+        match entries[0] {
+            DirectoryEntry::Labeled(_, a) => assert!(a.directory().is_none()),
+            _ => unreachable!(),
+        }
+
+        // The pruned parts of a witness are kept.
+        let witness = group.witness().full::<String>().build();
+        let entries = witness.directory().unwrap();
+        assert!(entries.contains(&DirectoryEntry::Labeled(
+            b"c",
+            &HashTree::Leaf(Cow::Borrowed(b"c"))
+        )));
+        assert!(entries
+            .iter()
+            .any(|e| matches!(e, DirectoryEntry::Pruned(_))));
+        assert_eq!(HashTree::Empty.directory().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn try_get() {
+        struct Name(String);
+
+        impl AsHashTree for Name {
+            fn as_hash_tree(&self) -> HashTree<'_> {
+                self.0.as_hash_tree()
+            }
+        }
+
+        let group = GroupBuilder::new()
+            .insert(["ledger"], Map::<Principal, u64>::new())
+            .insert(["name"], Name("XTC".to_string()))
+            .build();
+
+        assert_eq!(group.try_get::<Name>().unwrap().0, "XTC");
+        assert_eq!(
+            group.available_types(),
+            vec![type_name::<Name>(), type_name::<Map<Principal, u64>>()]
+        );
+
+        let error = group.try_get::<String>().err().unwrap();
+        assert_eq!(
+            error,
+            GroupError::UnknownType {
+                requested: type_name::<String>(),
+                available: group.available_types(),
+            }
+        );
+        assert!(error.to_string().contains(type_name::<Name>()));
+    }
+
+    #[test]
+    fn witness_all_except() {
+        struct Name(String);
+        struct Logo(Vec<u8>);
+
+        impl AsHashTree for Name {
+            fn as_hash_tree(&self) -> HashTree<'_> {
+                self.0.as_hash_tree()
+            }
+        }
+
+        impl AsHashTree for Logo {
+            fn as_hash_tree(&self) -> HashTree<'_> {
+                HashTree::Leaf(Cow::Borrowed(&self.0))
+            }
+        }
+
+        let mut ledger = Map::<String, u64>::new();
+        ledger.insert("A".into(), 100);
+
+        let group = GroupBuilder::new()
+            .insert(["ledger"], ledger)
+            .insert(["meta", "name"], Name("XTC".to_string()))
+            .insert(["meta", "logo"], Logo(vec![0; 1024]))
+            .build();
+
+        let tree = group.witness_all_except(&[]);
+        assert_eq!(tree, group.as_hash_tree());
+
+        let tree = group.witness_all_except(&[TypeId::of::<Logo>()]);
+        assert_eq!(tree.reconstruct(), group.root_hash());
+        assert_eq!(
+            tree.get_labels(),
+            vec![b"ledger" as &[u8], b"A", b"meta", b"logo", b"name"]
+        );
+        assert_eq!(
+            tree.get_leaf_values(),
+            vec![&100u64.to_be_bytes()[..], b"XTC"]
+        );
+
+        let tree = group.witness_all_except(&[TypeId::of::<Logo>(), TypeId::of::<Name>()]);
+        assert_eq!(tree.reconstruct(), group.root_hash());
+        assert_eq!(tree.get_leaf_values(), vec![&100u64.to_be_bytes()[..]]);
+    }
+
+    #[test]
+    fn shared() {
+        type Balances = Map<String, u64>;
+
+        let shared = SharedGroup::new(
+            GroupBuilder::new()
+                .insert(["balances"], Balances::new())
+                .insert(["name"], "XTC".to_string())
+                .build(),
+        );
+
+        {
+            let mut write = shared.write();
+            write.get_mut::<Balances>().insert("alice".into(), 10);
+            write.get_mut::<Balances>().insert("bob".into(), 20);
+            assert!(shared.try_read().is_none());
+            assert!(shared.try_write().is_none());
+        }
+
+        let read = shared.read();
+        let other = shared.read();
+        assert!(shared.try_write().is_none());
+
+        let response = shared.certified_response(&read, |group, ray| {
+            let balance = group.get::<Balances>().get("bob").copied();
+            (balance, ray.partial::<Balances, _>(|b| b.witness("bob")))
+        });
+        assert_eq!(response.value, Some(20));
+        assert_eq!(response.witness.reconstruct(), other.root_hash());
+        assert_eq!(
+            response.witness.get_labels(),
+            vec![b"balances" as &[u8], b"bob"]
+        );
+
+        drop(response);
+        drop(read);
+        drop(other);
+        shared.write().get_mut::<Balances>().remove("bob");
+        assert_eq!(shared.into_inner().get::<Balances>().len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "The guard does not belong to this group.")]
+    fn shared_foreign_guard() {
+        let a = SharedGroup::new(GroupBuilder::new().insert(["a"], 0u32).build());
+        let b = SharedGroup::new(GroupBuilder::new().insert(["a"], 0u32).build());
+        let read = b.read();
+        let _ = a.certified_response(&read, |_, ray| ((), ray));
+    }
+
+    #[test]
+    fn lazy_default() {
+        type Ledger = Map<Principal, u64>;
+
+        // An empty leaf has a known hash, so it can be inserted lazily using `Default`.
+        let mut group = GroupBuilder::new()
+            .insert(["name"], "XTC".to_string())
+            .insert_lazy(["ledger"], HashTree::Empty.reconstruct(), Ledger::default)
+            .insert_lazy(["log"], Seq::<u64>::new().root_hash(), Seq::<u64>::default)
+            .build();
+
+        let root_hash = group.root_hash();
+        assert!(!group.is_initialized::<Ledger>());
+        assert!(!group.is_initialized::<Seq<u64>>());
+
+        assert!(group.get::<Ledger>().is_empty());
+        assert!(group.get::<Seq<u64>>().is_empty());
+        assert_eq!(group.root_hash(), root_hash);
+        assert_eq!(group.as_hash_tree().reconstruct(), root_hash);
+
+        group.get_mut::<Seq<u64>>().append(1);
+        assert_ne!(group.root_hash(), root_hash);
+    }
+
+    #[test]
+    fn lazy() {
+        use std::rc::Rc;
+
+        struct Table(Vec<u8>);
+
+        impl AsHashTree for Table {
+            fn as_hash_tree(&self) -> HashTree<'_> {
+                HashTree::Leaf(Cow::Borrowed(&self.0))
+            }
+        }
+
+        let built = Rc::new(Cell::new(0));
+        let counter = built.clone();
+        let hash = Table(vec![7; 64]).root_hash();
+
+        let mut group = GroupBuilder::new()
+            .insert(["name"], "XTC".to_string())
+            .insert_lazy(["table"], hash, move || {
+                counter.set(counter.get() + 1);
+                Table(vec![7; 64])
+            })
+            .build();
+
+        assert!(!group.is_initialized::<Table>());
+
+        // Neither the root hash nor a witness that prunes the leaf initialize it.
+        let root_hash = group.root_hash();
+        let tree = group.witness().full::<String>().build();
+        assert_eq!(tree.reconstruct(), root_hash);
+        let tree = group.witness_all_except(&[TypeId::of::<Table>()]);
+        assert_eq!(tree.reconstruct(), root_hash);
+        assert_eq!(built.get(), 0);
+
+        let tree = group.witness().full::<Table>().build();
+        assert_eq!(tree.reconstruct(), root_hash);
+        assert!(group.is_initialized::<Table>());
+        assert_eq!(group.get::<Table>().0.len(), 64);
+        assert_eq!(built.get(), 1);
+
+        group.get_mut::<Table>().0.push(0);
+        assert_ne!(group.root_hash(), root_hash);
+        assert_eq!(group.as_hash_tree().reconstruct(), group.root_hash());
+        assert_eq!(built.get(), 1);
+    }
+
+    #[test]
+    fn schema() {
+        let schema = GroupSchema::new()
+            .insert::<Map<String, u32>, _, _>(["users", "balances"])
+            .insert::<String, _, _>(["users", "name"])
+            .insert::<Seq<u32>, _, _>(["log"]);
+
+        for i in 0..3u32 {
+            let mut balances = Map::<String, u32>::new();
+            balances.insert("a".into(), i);
+            let log = (0..i).collect::<Seq<u32>>();
+            let name = format!("user-{}", i);
+
+            let expected = GroupBuilder::new()
+                .insert(["users", "balances"], balances.clone())
+                .insert(["users", "name"], name.clone())
+                .insert(["log"], log.clone())
+                .build();
+
+            let group = schema.instantiate(
+                GroupData::new()
+                    .insert(log)
+                    .insert(name.clone())
+                    .insert(balances),
+            );
+            assert_eq!(group.root_hash(), expected.root_hash());
+            assert_eq!(group.get::<String>(), &name);
+            assert_eq!(
+                group.witness().full::<String>().build(),
+                expected.witness().full::<String>().build()
+            );
+        }
+
+        let error = schema
+            .try_instantiate(GroupData::new().insert(Seq::<u32>::new()))
+            .err()
+            .unwrap();
+        assert!(matches!(error, GroupError::MissingLeafData { .. }));
+
+        let data = GroupData::new()
+            .insert(Map::<String, u32>::new())
+            .insert(String::new())
+            .insert(Seq::<u32>::new())
+            .insert(0u8);
+        let error = schema.try_instantiate(data).err().unwrap();
+        match error {
+            GroupError::UnknownType {
+                requested,
+                available,
+            } => {
+                assert_eq!(requested, type_name::<u8>());
+                assert_eq!(available.len(), 3);
+            }
+            _ => panic!("Expected an unknown type."),
+        }
+
+        let error = schema
+            .clone()
+            .try_insert::<u8, _, _>(["log", "x"])
+            .err()
+            .unwrap();
+        assert_eq!(
+            error,
+            GroupError::InsertIntoLeaf {
+                path: vec!["log".into()]
+            }
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let builder = GroupBuilder::new()
+            .try_insert(["meta", "name"], "XTC".to_string())
+            .unwrap();
+
+        let error = GroupBuilder::new().try_build().err().unwrap();
+        assert_eq!(error, GroupError::EmptyGroup);
+        assert_eq!(
+            GroupBuilder::new().build().root_hash(),
+            HashTree::Empty.reconstruct()
+        );
+
+        // Failed insertions do not change the builder.
+        let error = builder
+            .try_insert(["meta", "logo"], "logo".to_string())
+            .err()
+            .unwrap();
+        assert_eq!(
+            error,
+            GroupError::DuplicateType {
+                type_name: type_name::<String>()
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "Type 'alloc::string::String' is already used in the group."
+        );
+
+        let error = GroupBuilder::new()
+            .insert(["meta", "name"], "XTC".to_string())
+            .try_insert(["meta", "name", "x"], 0u8)
+            .err()
+            .unwrap();
+        assert_eq!(
+            error,
+            GroupError::InsertIntoLeaf {
+                path: vec!["meta".into(), "name".into()]
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "Can not insert to the leaf node at 'meta/name'."
+        );
+
+        for path in [vec!["meta", "name"], vec!["meta"], vec![]] {
+            let error = GroupBuilder::new()
+                .insert(["meta", "name"], "XTC".to_string())
+                .try_insert(path.clone(), 0u8)
+                .err()
+                .unwrap();
+            assert_eq!(
+                error,
+                GroupError::PathReused {
+                    path: path.iter().map(|x| x.to_string()).collect()
+                }
+            );
+        }
+
+        let group = GroupBuilder::new()
+            .insert(["meta", "name"], "XTC".to_string())
+            .try_insert_lazy(["ledger"], 7u64.root_hash(), || 7u64)
+            .unwrap()
+            .try_build()
+            .unwrap();
+        assert_eq!(group.validate(), Ok(()));
+        assert_eq!(group.get::<u64>(), &7);
+    }
+
+    #[test]
+    #[should_panic(expected = "Path 'meta/name' is already used.")]
+    fn insert_path_reused() {
+        let _ = GroupBuilder::new()
+            .insert(["meta", "name"], "XTC".to_string())
+            .insert(["meta", "name"], 0u8);
+    }
+
+    #[test]
+    fn validate() {
+        let mut group = GroupBuilder::new()
+            .insert_lazy(["ledger"], 7u64.root_hash(), || -> u64 {
+                panic!("Can not load the ledger.")
+            })
+            .build();
+        assert_eq!(group.validate(), Ok(()));
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| *group.get::<u64>()));
+        assert!(result.is_err());
+
+        assert_eq!(
+            group.validate(),
+            Err(GroupError::MissingLeafData {
+                type_name: type_name::<u64>()
+            })
+        );
+        assert!(group.try_get_mut::<String>().is_err());
+
+        // The leaf stays missing, and it is reported instead of being initialized again.
+        let missing = Err(GroupError::MissingLeafData {
+            type_name: type_name::<u64>(),
+        });
+        assert_eq!(group.try_get::<u64>(), missing);
+        assert_eq!(group.try_get_mut::<u64>().map(|leaf| &*leaf), missing);
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| *group.get::<u64>()));
+        let message = result.err().unwrap();
+        assert!(message
+            .downcast_ref::<String>()
+            .unwrap()
+            .contains("its initializer has panicked"));
+    }
+
+    #[test]
+    fn manifest() {
+        #[derive(candid::CandidType)]
+        struct Config {
+            fee: u64,
+        }
+
+        impl AsHashTree for Config {
+            fn as_hash_tree(&self) -> HashTree<'_> {
+                self.fee.as_hash_tree()
+            }
+        }
+
+        impl ManifestLeaf for Config {}
+
+        let group = GroupBuilder::new()
+            .insert_typed(["ledger"], Map::<Principal, u64>::new())
+            .insert_typed(["log"], Seq::<String>::new())
+            .insert_typed(["meta", "config"], Config { fee: 1 })
+            .insert_typed(["meta", "logo"], CandidLeaf(vec![1u32]))
+            .insert(["meta", "name"], "XTC".to_string())
+            .build();
+
+        let manifest = group.manifest();
+        assert_eq!(
+            manifest
+                .iter()
+                .map(|leaf| leaf.path.clone())
+                .collect::<Vec<_>>(),
+            group.describe()
+        );
+        assert_eq!(
+            manifest.iter().map(|leaf| leaf.kind).collect::<Vec<_>>(),
+            [
+                LeafKind::Map,
+                LeafKind::Seq,
+                LeafKind::Scalar,
+                LeafKind::Scalar,
+                LeafKind::Unknown
+            ]
+        );
+        assert_eq!(manifest[0].candid_type, Some(Map::<Principal, u64>::ty()));
+        assert_eq!(
+            manifest[1].candid_type,
+            Some(Type::Vec(Box::new(Type::Text)))
+        );
+        assert_eq!(manifest[2].candid_type, Some(Config::ty()));
+        assert_eq!(manifest[2].type_name, type_name::<Config>());
+        assert_eq!(manifest[3].candid_type, Some(Vec::<u32>::ty()));
+        assert_eq!(manifest[4].candid_type, None);
+    }
+
+    #[test]
+    fn try_partial() {
+        let mut ledger = Map::<String, u64>::new();
+        ledger.insert("A".into(), 100);
+
+        let group = GroupBuilder::new()
+            .insert(["ledger"], ledger)
+            .insert(["name"], "XTC".to_string())
+            .build();
+
+        let witness = group
+            .witness()
+            .try_partial(|ledger: &Map<String, u64>| Ok::<_, ()>(ledger.witness("A")))
+            .unwrap()
+            .full::<String>()
+            .build();
+        let expected = group
+            .witness()
+            .partial(|ledger: &Map<String, u64>| ledger.witness("A"))
+            .full::<String>()
+            .build();
+        assert_eq!(witness, expected);
+        assert_eq!(witness.reconstruct(), group.root_hash());
+
+        let result = group
+            .witness()
+            .full::<String>()
+            .try_partial(|_: &Map<String, u64>| Err("absent"));
+        assert_eq!(result.err(), Some("absent"));
+    }
+
+    #[test]
+    fn get_dyn_at() {
+        let mut ledger = Map::<String, u64>::new();
+        ledger.insert("A".into(), 100);
+
+        let group = GroupBuilder::new()
+            .insert(["ledger"], ledger)
+            .insert(["meta", "name"], "XTC".to_string())
+            .insert_lazy(["meta", "supply"], 7u64.root_hash(), || 7u64)
+            .build();
+
+        assert!(!group.is_initialized::<u64>());
+        let supply = group.get_dyn_at(&["meta", "supply"]).unwrap();
+        assert_eq!(supply.as_hash_tree(), 7u64.as_hash_tree());
+        assert!(group.is_initialized::<u64>());
+
+        for path in group.describe() {
+            let path = path.iter().map(String::as_str).collect::<Vec<_>>();
+            let leaf = group.get_dyn_at(&path).unwrap();
+            assert_eq!(leaf.as_hash_tree().reconstruct(), leaf.root_hash());
+        }
+
+        let ledger = group.get_dyn_at(&["ledger"]).unwrap();
+        assert_eq!(
+            ledger.root_hash(),
+            group.get::<Map<String, u64>>().root_hash()
+        );
+
+        for path in [
+            &[][..],
+            &["meta"],
+            &["meta", "name", "x"],
+            &["name"],
+            &["ledger", "A"],
+        ] {
+            assert!(group.get_dyn_at(path).is_none());
+        }
+    }
 }
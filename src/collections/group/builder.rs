@@ -1,12 +1,18 @@
-use super::{Group, GroupLeaf, GroupNode, GroupNodeInner};
+use super::{Group, GroupError, GroupLeaf, GroupNode, GroupNodeInner, GroupSlot, ManifestLeaf};
+use crate::hashtree::label_cmp;
+use crate::{AsHashTree, Hash, HashTree};
 use std::any::{type_name, TypeId};
+use std::collections::btree_map::Entry;
 use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::{self, Debug, Formatter};
+use std::marker::PhantomData;
 
 pub struct GroupBuilder {
     root: GroupBuilderNode,
-    data: HashMap<TypeId, Box<dyn GroupLeaf>>,
+    data: HashMap<TypeId, GroupSlot>,
 }
 
+#[derive(Clone)]
 enum GroupBuilderNode {
     Directory {
         children: BTreeMap<String, Box<GroupBuilderNode>>,
@@ -26,27 +32,119 @@ impl GroupBuilder {
         }
     }
 
+    /// Insert the given data as a leaf at the given path.
+    ///
+    /// # Panics
+    ///
+    /// If the insertion fails, see [`GroupBuilder::try_insert`].
     pub fn insert<T: GroupLeaf, C: Into<String>, P: IntoIterator<Item = C>>(
-        mut self,
+        self,
+        path: P,
+        data: T,
+    ) -> Self {
+        self.try_insert(path, data)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Insert the given data as a leaf at the given path, or return an error if the type
+    /// of the data is already used in the group, if the path is already used, or if the
+    /// path goes through another leaf.
+    pub fn try_insert<T: GroupLeaf, C: Into<String>, P: IntoIterator<Item = C>>(
+        self,
+        path: P,
+        data: T,
+    ) -> Result<Self, GroupError> {
+        self.try_insert_slot::<T, C, P>(path, GroupSlot::new(data))
+    }
+
+    /// Insert the given data as a leaf at the given path, and record its kind and candid
+    /// type for [`Group::manifest`].
+    ///
+    /// # Panics
+    ///
+    /// If the insertion fails, see [`GroupBuilder::try_insert`].
+    pub fn insert_typed<T: ManifestLeaf, C: Into<String>, P: IntoIterator<Item = C>>(
+        self,
         path: P,
         data: T,
     ) -> Self {
-        let path = path
-            .into_iter()
-            .map(|x| x.into())
-            .collect::<VecDeque<String>>();
+        self.try_insert_slot::<T, C, P>(path, GroupSlot::new(data).with_manifest::<T>())
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Insert a leaf that is only built the first time it is accessed, either using one
+    /// of the getters of the group or by a witness that contains the leaf.
+    ///
+    /// Until the leaf is built the given hash is used as its root hash, so computing the
+    /// root hash of the group or a witness in which the leaf is pruned does not build
+    /// the leaf. The hash must be equal to the root hash of the leaf that `init` returns.
+    ///
+    /// # Panics
+    ///
+    /// If the insertion fails, see [`GroupBuilder::try_insert_lazy`].
+    pub fn insert_lazy<T, C, P, F>(self, path: P, hash: Hash, init: F) -> Self
+    where
+        T: GroupLeaf,
+        C: Into<String>,
+        P: IntoIterator<Item = C>,
+        F: FnOnce() -> T + 'static,
+    {
+        self.try_insert_lazy(path, hash, init)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// The fallible version of [`GroupBuilder::insert_lazy`], which fails in the same
+    /// cases as [`GroupBuilder::try_insert`].
+    pub fn try_insert_lazy<T, C, P, F>(
+        self,
+        path: P,
+        hash: Hash,
+        init: F,
+    ) -> Result<Self, GroupError>
+    where
+        T: GroupLeaf,
+        C: Into<String>,
+        P: IntoIterator<Item = C>,
+        F: FnOnce() -> T + 'static,
+    {
+        self.try_insert_slot::<T, C, P>(path, GroupSlot::lazy(hash, init))
+    }
+
+    /// Insert a leaf for data that is not stored in the group, see [`RawLeaf`].
+    pub fn insert_external<M, C, P>(self, path: P, leaf: RawLeaf<M>) -> Self
+    where
+        M: 'static,
+        C: Into<String>,
+        P: IntoIterator<Item = C>,
+    {
+        self.insert(path, leaf)
+    }
+
+    fn try_insert_slot<T: GroupLeaf, C: Into<String>, P: IntoIterator<Item = C>>(
+        mut self,
+        path: P,
+        slot: GroupSlot,
+    ) -> Result<Self, GroupError> {
+        let path = path.into_iter().map(|x| x.into()).collect::<Vec<String>>();
 
         let tid = TypeId::of::<T>();
 
-        if self.data.insert(tid, Box::new(data)).is_some() {
-            panic!("Type '{}' is already used in the group.", type_name::<T>())
+        if self.data.contains_key(&tid) {
+            return Err(GroupError::DuplicateType {
+                type_name: type_name::<T>(),
+            });
         }
 
-        self.root.insert(path, tid);
+        // The tree is not modified when the insertion fails, so the data is only stored
+        // after the leaf is in the tree.
+        self.root.insert(path, tid)?;
+        self.data.insert(tid, slot);
 
-        self
+        Ok(self)
     }
 
+    /// Build the group, a builder without any leaves results in an empty group which has
+    /// the root hash of an empty tree.
     #[must_use = "The constructed group must be used."]
     pub fn build(self) -> Group {
         let mut group = Group {
@@ -59,48 +157,336 @@ impl GroupBuilder {
 
         group
     }
+
+    /// Build the group, or return [`GroupError::EmptyGroup`] if the builder does not have
+    /// any leaves.
+    pub fn try_build(self) -> Result<Group, GroupError> {
+        if self.data.is_empty() {
+            return Err(GroupError::EmptyGroup);
+        }
+
+        Ok(self.build())
+    }
 }
 
-impl Default for GroupBuilder {
+/// The layout of a group without its data: the paths of the leaves and their types. A
+/// schema can be reused to build many groups with the same shape, such as a group for
+/// each user, by giving it the data of each group with [`GroupSchema::instantiate`].
+///
+/// # Example
+///
+/// ```
+/// use certified_vars::collections::group::builder::{GroupData, GroupSchema};
+/// use certified_vars::{AsHashTree, GroupBuilder, Map, Seq};
+///
+/// let schema = GroupSchema::new()
+///     .insert::<Map<String, u64>, _, _>(["balances"])
+///     .insert::<Seq<u64>, _, _>(["log"]);
+///
+/// let users = (0..3u64)
+///     .map(|i| {
+///         let mut balances = Map::<String, u64>::new();
+///         balances.insert("deposit".into(), i);
+///         schema.instantiate(GroupData::new().insert(balances).insert(Seq::<u64>::new()))
+///     })
+///     .collect::<Vec<_>>();
+///
+/// // The group is the same as the one built by a builder with the same layout.
+/// let mut balances = Map::<String, u64>::new();
+/// balances.insert("deposit".into(), 2);
+/// let group = GroupBuilder::new()
+///     .insert(["balances"], balances)
+///     .insert(["log"], Seq::<u64>::new())
+///     .build();
+/// assert_eq!(users[2].root_hash(), group.root_hash());
+/// ```
+#[derive(Clone)]
+pub struct GroupSchema {
+    root: GroupBuilderNode,
+    /// The names of the types of the leaves in the schema.
+    types: HashMap<TypeId, &'static str>,
+}
+
+/// The data of the leaves of a group that is built from a [`GroupSchema`], there should be
+/// exactly one leaf of each type in the schema.
+#[derive(Default)]
+pub struct GroupData {
+    data: HashMap<TypeId, GroupSlot>,
+}
+
+impl GroupSchema {
+    /// Create a new schema without any leaves.
+    pub fn new() -> Self {
+        Self {
+            root: GroupBuilderNode::Directory {
+                children: BTreeMap::new(),
+            },
+            types: HashMap::new(),
+        }
+    }
+
+    /// Add a leaf of type `T` at the given path.
+    ///
+    /// # Panics
+    ///
+    /// If the insertion fails, see [`GroupSchema::try_insert`].
+    pub fn insert<T: GroupLeaf, C: Into<String>, P: IntoIterator<Item = C>>(self, path: P) -> Self {
+        self.try_insert::<T, C, P>(path)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Add a leaf of type `T` at the given path, this fails in the same cases as
+    /// [`GroupBuilder::try_insert`].
+    pub fn try_insert<T: GroupLeaf, C: Into<String>, P: IntoIterator<Item = C>>(
+        mut self,
+        path: P,
+    ) -> Result<Self, GroupError> {
+        let path = path.into_iter().map(|x| x.into()).collect::<Vec<String>>();
+
+        let tid = TypeId::of::<T>();
+
+        if self.types.contains_key(&tid) {
+            return Err(GroupError::DuplicateType {
+                type_name: type_name::<T>(),
+            });
+        }
+
+        self.root.insert(path, tid)?;
+        self.types.insert(tid, type_name::<T>());
+
+        Ok(self)
+    }
+
+    /// Build a group with this layout and the given data.
+    ///
+    /// # Panics
+    ///
+    /// If the data does not match the schema, see [`GroupSchema::try_instantiate`].
+    #[must_use = "The constructed group must be used."]
+    pub fn instantiate(&self, data: GroupData) -> Group {
+        self.try_instantiate(data)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Build a group with this layout and the given data, or return an error if the data
+    /// of a leaf in the schema is missing or if the data has a type that is not in the
+    /// schema.
+    pub fn try_instantiate(&self, data: GroupData) -> Result<Group, GroupError> {
+        if let Some(type_name) = self
+            .types
+            .iter()
+            .find(|(tid, _)| !data.data.contains_key(tid))
+            .map(|(_, type_name)| *type_name)
+        {
+            return Err(GroupError::MissingLeafData { type_name });
+        }
+
+        if let Some(slot) = data
+            .data
+            .iter()
+            .find(|(tid, _)| !self.types.contains_key(tid))
+            .map(|(_, slot)| slot)
+        {
+            let mut available = self.types.values().copied().collect::<Vec<_>>();
+            available.sort_unstable();
+            return Err(GroupError::UnknownType {
+                requested: slot.type_name,
+                available,
+            });
+        }
+
+        let builder = GroupBuilder {
+            root: self.root.clone(),
+            data: data.data,
+        };
+
+        Ok(builder.build())
+    }
+}
+
+impl Default for GroupSchema {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl GroupBuilderNode {
-    pub fn insert(&mut self, mut path: VecDeque<String>, tid: TypeId) {
-        if let GroupBuilderNode::Directory { children } = self {
-            if path.len() == 1 {
-                let name = path.pop_back().unwrap();
+impl GroupData {
+    /// Create a new set of data without any leaves.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the data of the leaf of type `T`, this replaces the previous data of the type.
+    pub fn insert<T: GroupLeaf>(mut self, data: T) -> Self {
+        self.data.insert(TypeId::of::<T>(), GroupSlot::new(data));
+        self
+    }
 
-                let leaf = GroupBuilderNode::Leaf { tid };
+    /// Add a leaf of type `T` that is only built the first time it is accessed, see
+    /// [`GroupBuilder::insert_lazy`].
+    pub fn insert_lazy<T, F>(mut self, hash: Hash, init: F) -> Self
+    where
+        T: GroupLeaf,
+        F: FnOnce() -> T + 'static,
+    {
+        self.data
+            .insert(TypeId::of::<T>(), GroupSlot::lazy(hash, init));
+        self
+    }
+}
 
-                children
-                    .entry(name)
-                    .and_modify(|_| panic!("Path is already used."))
-                    .or_insert_with(|| Box::new(leaf));
+/// A function that returns the hash tree of a [`RawLeaf`].
+type WitnessFn = Box<dyn Fn() -> HashTree<'static>>;
 
-                return;
-            }
-            let dir_name = path.pop_front().unwrap();
+/// A leaf for data that is certified by a group but is not stored in it, such as a hash
+/// that is computed by another part of the canister.
+///
+/// The leaves of a group are identified by their type, so the marker type `M` can be
+/// used to put more than one raw leaf in the same group.
+///
+/// # Example
+///
+/// ```
+/// use certified_vars::collections::group::builder::RawLeaf;
+/// use certified_vars::{AsHashTree, GroupBuilder};
+///
+/// struct Assets;
+///
+/// let hash = [7; 32];
+/// let mut group = GroupBuilder::new()
+///     .insert_external(["assets"], RawLeaf::<Assets>::pruned(hash))
+///     .insert(["name"], "XTC".to_string())
+///     .build();
+///
+/// let tree = group.witness().full::<RawLeaf<Assets>>().build();
+/// assert_eq!(tree.reconstruct(), group.root_hash());
+/// let hash = tree.reconstruct();
+///
+/// // Update the leaf when the external data changes.
+/// group.get_mut::<RawLeaf<Assets>>().set_pruned([8; 32]);
+/// assert_ne!(group.root_hash(), hash);
+/// ```
+pub struct RawLeaf<M = ()> {
+    hash: Hash,
+    witness_fn: WitnessFn,
+    _marker: PhantomData<M>,
+}
+
+impl<M> RawLeaf<M> {
+    /// Create a new leaf with the given root hash, the hash tree returned by `witness_fn`
+    /// must have the same root hash.
+    pub fn new<F>(hash: Hash, witness_fn: F) -> Self
+    where
+        F: Fn() -> HashTree<'static> + 'static,
+    {
+        Self {
+            hash,
+            witness_fn: Box::new(witness_fn),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Create a new leaf that is always pruned in the witnesses of the group.
+    pub fn pruned(hash: Hash) -> Self {
+        Self::new(hash, move || HashTree::Pruned(hash))
+    }
+
+    /// Replace the root hash and the hash tree of this leaf.
+    pub fn set<F>(&mut self, hash: Hash, witness_fn: F)
+    where
+        F: Fn() -> HashTree<'static> + 'static,
+    {
+        self.hash = hash;
+        self.witness_fn = Box::new(witness_fn);
+    }
+
+    /// Replace this leaf with a leaf that is always pruned.
+    pub fn set_pruned(&mut self, hash: Hash) {
+        self.set(hash, move || HashTree::Pruned(hash))
+    }
+}
+
+impl<M> AsHashTree for RawLeaf<M> {
+    #[inline]
+    fn root_hash(&self) -> Hash {
+        self.hash
+    }
+
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        let tree = (self.witness_fn)();
+        debug_assert_eq!(
+            tree.reconstruct(),
+            self.hash,
+            "The hash tree of the raw leaf does not have the expected root hash."
+        );
+        tree
+    }
+}
+
+impl<M> Debug for RawLeaf<M> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawLeaf")
+            .field("hash", &hex::encode(self.hash))
+            .finish()
+    }
+}
+
+impl Default for GroupBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-            children
-                .entry(dir_name)
-                .or_insert_with(|| {
-                    Box::new(GroupBuilderNode::Directory {
-                        children: BTreeMap::new(),
+impl GroupBuilderNode {
+    /// Insert a leaf at the given path, the directories along the path are created if they
+    /// do not exist. The tree is left unchanged if this fails.
+    pub fn insert(&mut self, path: Vec<String>, tid: TypeId) -> Result<(), GroupError> {
+        // The empty path is the root directory, which is always used.
+        let (name, dirs) = match path.split_last() {
+            Some(split) => split,
+            None => return Err(GroupError::PathReused { path }),
+        };
+
+        let mut node = self;
+        for (i, dir_name) in dirs.iter().enumerate() {
+            node = match node {
+                GroupBuilderNode::Directory { children } => children
+                    .entry(dir_name.clone())
+                    .or_insert_with(|| {
+                        Box::new(GroupBuilderNode::Directory {
+                            children: BTreeMap::new(),
+                        })
                     })
-                })
-                .insert(path, tid);
-            return;
+                    .as_mut(),
+                GroupBuilderNode::Leaf { .. } => {
+                    return Err(GroupError::InsertIntoLeaf {
+                        path: path[..i].to_vec(),
+                    })
+                }
+            };
         }
 
-        panic!("Can not insert to a leaf node.");
+        match node {
+            GroupBuilderNode::Directory { children } => match children.entry(name.clone()) {
+                Entry::Occupied(_) => Err(GroupError::PathReused { path }),
+                Entry::Vacant(entry) => {
+                    entry.insert(Box::new(GroupBuilderNode::Leaf { tid }));
+                    Ok(())
+                }
+            },
+            GroupBuilderNode::Leaf { .. } => Err(GroupError::InsertIntoLeaf {
+                path: dirs.to_vec(),
+            }),
+        }
     }
 
     pub fn build(self) -> GroupNode {
         match self {
             GroupBuilderNode::Directory { children } => {
+                let mut children = children.into_iter().collect::<Vec<_>>();
+                // The labels must be in the order that is expected by the IC.
+                children.sort_by(|(a, _), (b, _)| label_cmp(a.as_bytes(), b.as_bytes()));
+
                 let mut children = children
                     .into_iter()
                     .map(|(k, v)| GroupNode {
@@ -130,7 +516,12 @@ impl GroupBuilderNode {
                     children = new_children;
                 }
 
-                children.pop_front().unwrap()
+                // Only the root can be an empty directory, since every other directory
+                // is created when a leaf is inserted into it.
+                children.pop_front().unwrap_or(GroupNode {
+                    id: 0,
+                    data: GroupNodeInner::Empty,
+                })
             }
             GroupBuilderNode::Leaf { tid } => GroupNode {
                 id: 0,
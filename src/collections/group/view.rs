@@ -0,0 +1,139 @@
+use crate::hashtree::LookupResult;
+use crate::{Hash, HashTree};
+
+/// The client side counterpart of a [`Group`](super::Group), it resolves the paths in a
+/// witness of the group using the schema of the group that is returned by
+/// [`Group::describe`](super::Group::describe).
+///
+/// # Example
+///
+/// ```
+/// use certified_vars::collections::group::view::GroupView;
+/// use certified_vars::hashtree::LookupResult;
+/// use certified_vars::{AsHashTree, GroupBuilder, Map};
+///
+/// let mut balances = Map::<String, u64>::new();
+/// balances.insert("alice".into(), 10);
+///
+/// let group = GroupBuilder::new()
+///     .insert(["balances"], balances)
+///     .insert(["meta", "name"], "XTC".to_string())
+///     .build();
+///
+/// let witness = group
+///     .witness()
+///     .partial::<Map<String, u64>, _>(|balances| balances.witness("alice"))
+///     .build();
+///
+/// let view = GroupView::new(group.describe(), witness);
+/// assert_eq!(view.root_hash(), group.root_hash());
+/// assert_eq!(
+///     view.lookup(&["balances", "alice"]),
+///     LookupResult::Found(&10u64.to_be_bytes())
+/// );
+/// assert_eq!(view.lookup(&["balances", "bob"]), LookupResult::Absent);
+/// assert_eq!(view.lookup(&["meta", "name"]), LookupResult::Unknown);
+/// ```
+#[derive(Debug, Clone)]
+pub struct GroupView<'a> {
+    schema: Vec<Vec<String>>,
+    tree: HashTree<'a>,
+}
+
+impl<'a> GroupView<'a> {
+    /// Create a view of the given witness of a group with the given schema.
+    #[inline]
+    pub fn new(schema: Vec<Vec<String>>, tree: HashTree<'a>) -> Self {
+        Self { schema, tree }
+    }
+
+    /// Returns the root hash of the witness, which must be compared with the certified
+    /// data before trusting the result of any lookup.
+    #[inline]
+    pub fn root_hash(&self) -> Hash {
+        self.tree.reconstruct()
+    }
+
+    /// Returns the paths of the leaves of the group.
+    #[inline]
+    pub fn schema(&self) -> &[Vec<String>] {
+        &self.schema
+    }
+
+    /// Lookup the given path in the witness. The path must start with the path of one of
+    /// the leaves in the schema, the rest of the path is resolved inside of the leaf.
+    ///
+    /// Returns [`LookupResult::Error`] if the path is not in any of the leaves of the group.
+    pub fn lookup<P: AsRef<[u8]>>(&self, path: &[P]) -> LookupResult<'_> {
+        let path = path.iter().map(|label| label.as_ref()).collect::<Vec<_>>();
+
+        let known = self.schema.iter().any(|leaf| {
+            leaf.len() <= path.len() && leaf.iter().zip(&path).all(|(a, b)| a.as_bytes() == *b)
+        });
+
+        if !known {
+            return LookupResult::Error;
+        }
+
+        self.tree.lookup_path(&path)
+    }
+
+    /// Returns the witness this view is built on.
+    #[inline]
+    pub fn as_hash_tree(&self) -> &HashTree<'a> {
+        &self.tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collections::group::builder::GroupBuilder;
+    use crate::{AsHashTree, Map, Seq};
+
+    #[test]
+    fn lookup() {
+        let mut ledger = Map::<String, u64>::new();
+        ledger.insert("a".into(), 1);
+        ledger.insert("c".into(), 3);
+
+        let group = GroupBuilder::new()
+            .insert(["ledger"], ledger)
+            .insert(["meta", "log"], (0..3u32).collect::<Seq<_>>())
+            .insert(["meta", "name"], "XTC".to_string())
+            .build();
+
+        assert_eq!(
+            group.describe(),
+            vec![
+                vec!["ledger".to_string()],
+                vec!["meta".to_string(), "log".to_string()],
+                vec!["meta".to_string(), "name".to_string()],
+            ]
+        );
+
+        let witness = group
+            .witness()
+            .partial::<Map<String, u64>, _>(|ledger| ledger.witness("b"))
+            .full::<String>()
+            .build();
+        let view = GroupView::new(group.describe(), witness);
+        assert_eq!(view.root_hash(), group.root_hash());
+
+        assert_eq!(view.lookup(&["ledger", "b"]), LookupResult::Absent);
+        // The neighbors of an absent key are revealed without their values.
+        assert_eq!(view.lookup(&["ledger", "a"]), LookupResult::Unknown);
+        assert_eq!(view.lookup(&["meta", "name"]), LookupResult::Found(b"XTC"));
+        assert_eq!(view.lookup(&["meta", "log", "len"]), LookupResult::Unknown);
+
+        // Paths outside of the schema.
+        assert_eq!(view.lookup(&["meta"]), LookupResult::Error);
+        assert_eq!(view.lookup(&["other", "name"]), LookupResult::Error);
+
+        let full = GroupView::new(group.describe(), group.as_hash_tree());
+        assert_eq!(
+            full.lookup(&["meta", "log", "len"]),
+            LookupResult::Found(&3u64.to_be_bytes())
+        );
+    }
+}
@@ -0,0 +1,403 @@
+use crate::hashtree::{fork, fork_hash, labeled, labeled_hash};
+use crate::label::Label;
+use crate::rbtree::iterator::RbTreeIterator;
+use crate::{AsHashTree, Hash, HashTree, Map};
+use std::borrow::Borrow;
+use std::fmt::{self, Display, Formatter};
+
+/// The label of the subtree containing the entries of an [`IndexedMap`].
+const DATA_LABEL: &[u8] = b"data";
+
+/// The label of the subtree containing the secondary index of an [`IndexedMap`].
+const INDEX_LABEL: &[u8] = b"index";
+
+/// A function that computes the index of a value.
+type Indexer<V, I> = Box<dyn Fn(&V) -> I>;
+
+/// A certified map with a secondary unique index, the index is computed from each value
+/// using the indexer function and it is kept in sync on every insertion, removal and
+/// modification.
+///
+/// # Example
+///
+/// ```
+/// use certified_vars::collections::indexed::IndexedMap;
+///
+/// let mut users = IndexedMap::<u32, String, String>::new(|email| email.to_lowercase());
+///
+/// users.insert(1, "Alice@example.com".into()).unwrap();
+/// assert_eq!(users.get_by_index("alice@example.com"), Some(&"Alice@example.com".into()));
+/// assert!(users.insert(2, "alice@EXAMPLE.com".into()).is_err());
+/// ```
+///
+/// # Hash tree
+///
+/// ```text
+/// ─┬╴"data" ──╴Map<K, V>
+///  └╴"index" ──╴Map<I, K>
+/// ```
+pub struct IndexedMap<K, V, I>
+where
+    K: 'static + Label + AsHashTree,
+    V: 'static + AsHashTree,
+    I: 'static + Label,
+{
+    data: Map<K, V>,
+    index: Map<I, K>,
+    indexer: Indexer<V, I>,
+}
+
+/// The error type for the fallible operations on an [`IndexedMap`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexedMapError {
+    /// The index of the value is already used by another key.
+    DuplicateIndex,
+}
+
+impl Display for IndexedMapError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            IndexedMapError::DuplicateIndex => {
+                write!(f, "The index of the value is already used by another key")
+            }
+        }
+    }
+}
+
+impl std::error::Error for IndexedMapError {}
+
+impl<K, V, I> IndexedMap<K, V, I>
+where
+    K: 'static + Label + AsHashTree,
+    V: 'static + AsHashTree,
+    I: 'static + Label,
+{
+    /// Create a new, empty map that uses the given function to compute the index of
+    /// each value. The function can capture its environment, but it has to compute the
+    /// same index for the same value every time.
+    #[inline]
+    pub fn new<F>(indexer: F) -> Self
+    where
+        F: Fn(&V) -> I + 'static,
+    {
+        Self {
+            data: Map::new(),
+            index: Map::new(),
+            indexer: Box::new(indexer),
+        }
+    }
+
+    /// Returns `true` if the map does not contain any values.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Returns the number of elements in the map.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Insert a key-value pair into the map and returns the previous value associated
+    /// with the key. If the index of the value is already used by another key returns an
+    /// error and the map is not modified.
+    pub fn insert(&mut self, key: K, value: V) -> Result<Option<V>, IndexedMapError>
+    where
+        K: Clone,
+    {
+        let index = (self.indexer)(&value);
+
+        if let Some(owner) = self.index.get(&index) {
+            if *owner != key {
+                return Err(IndexedMapError::DuplicateIndex);
+            }
+        }
+
+        let prev = self.data.insert(key.clone(), value);
+
+        if let Some(prev) = &prev {
+            let prev_index = (self.indexer)(prev);
+            if prev_index != index {
+                self.index.remove(&prev_index);
+            }
+        }
+
+        self.index.insert(index, key);
+
+        Ok(prev)
+    }
+
+    /// Remove the value associated with the given key from the map along with its index,
+    /// returns the previous value associated with the key.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        let value = self.data.remove(key)?;
+        self.index.remove(&(self.indexer)(&value));
+        Some(value)
+    }
+
+    /// Modify the value associated with the given key, the index is updated if the
+    /// modification changes it. If the new index is already used by another key returns
+    /// an error and the map is not modified.
+    ///
+    /// The modification is applied to a clone of the value, which is only stored once the
+    /// new index is known to be available.
+    pub fn modify<Q, F, R>(&mut self, key: &Q, f: F) -> Result<Option<R>, IndexedMapError>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+        V: Clone,
+        F: FnOnce(&mut V) -> R,
+    {
+        let mut value = match self.data.get(key) {
+            Some(value) => value.clone(),
+            None => return Ok(None),
+        };
+
+        let prev_index = (self.indexer)(&value);
+        let result = f(&mut value);
+        let index = (self.indexer)(&value);
+
+        if index != prev_index {
+            if self.index.contains_key(&index) {
+                return Err(IndexedMapError::DuplicateIndex);
+            }
+
+            let owner = self.index.remove(&prev_index).unwrap();
+            self.index.insert(index, owner);
+        }
+
+        self.data.inner.modify(key, move |v| *v = value);
+
+        Ok(Some(result))
+    }
+
+    /// Return the value associated with the given key.
+    #[inline]
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        self.data.get(key)
+    }
+
+    /// Return the key of the value with the given index.
+    #[inline]
+    pub fn get_key_by_index<Q>(&self, index: &Q) -> Option<&K>
+    where
+        I: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        self.index.get(index)
+    }
+
+    /// Return the value with the given index.
+    #[inline]
+    pub fn get_by_index<Q>(&self, index: &Q) -> Option<&V>
+    where
+        I: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        self.index.get(index).and_then(|key| self.data.get(key))
+    }
+
+    /// Return an iterator over the key-values in the map.
+    #[inline]
+    pub fn iter(&self) -> RbTreeIterator<'_, K, V> {
+        self.data.iter()
+    }
+
+    /// Create a HashTree witness for the value associated with given key, the
+    /// index is pruned.
+    pub fn witness<Q>(&self, key: &Q) -> HashTree<'_>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        fork(
+            labeled(DATA_LABEL, self.data.witness(key)),
+            HashTree::Pruned(labeled_hash(INDEX_LABEL, &self.index.root_hash())),
+        )
+    }
+
+    /// Create a HashTree witness for the value with the given index. The witness contains
+    /// the key of the value in the index, and the value associated with that key.
+    ///
+    /// If there is no value with this index, the witness proves its absence from the
+    /// index.
+    pub fn witness_by_index<Q>(&self, index: &Q) -> HashTree<'_>
+    where
+        I: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        let data = match self.index.get(index) {
+            Some(key) => labeled(DATA_LABEL, self.data.witness(key)),
+            None => HashTree::Pruned(labeled_hash(DATA_LABEL, &self.data.root_hash())),
+        };
+
+        fork(data, labeled(INDEX_LABEL, self.index.witness(index)))
+    }
+
+    /// Return the underlying map of the values.
+    #[inline]
+    pub fn as_map(&self) -> &Map<K, V> {
+        &self.data
+    }
+
+    /// Return the underlying map of the index to the keys.
+    #[inline]
+    pub fn as_index(&self) -> &Map<I, K> {
+        &self.index
+    }
+}
+
+impl<K, V, I> AsHashTree for IndexedMap<K, V, I>
+where
+    K: 'static + Label + AsHashTree,
+    V: 'static + AsHashTree,
+    I: 'static + Label,
+{
+    fn root_hash(&self) -> Hash {
+        fork_hash(
+            &labeled_hash(DATA_LABEL, &self.data.root_hash()),
+            &labeled_hash(INDEX_LABEL, &self.index.root_hash()),
+        )
+    }
+
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        fork(
+            labeled(DATA_LABEL, self.data.as_hash_tree()),
+            labeled(INDEX_LABEL, self.index.as_hash_tree()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct User {
+        email: String,
+        age: u8,
+    }
+
+    impl AsHashTree for User {
+        fn as_hash_tree(&self) -> HashTree<'_> {
+            self.email.as_hash_tree()
+        }
+    }
+
+    fn user(email: &str) -> User {
+        User {
+            email: email.into(),
+            age: 0,
+        }
+    }
+
+    #[test]
+    fn capturing_indexer() {
+        let domain = String::from("@x");
+        let mut map = IndexedMap::<String, User, String>::new(move |u| {
+            u.email.trim_end_matches(domain.as_str()).to_string()
+        });
+
+        assert_eq!(map.insert("a".into(), user("alice@x")), Ok(None));
+        assert_eq!(map.get_by_index("alice"), Some(&user("alice@x")));
+        assert_eq!(
+            map.insert("b".into(), user("alice")),
+            Err(IndexedMapError::DuplicateIndex)
+        );
+
+        // The indexer does not have to be thread safe.
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counter = calls.clone();
+        let mut map = IndexedMap::<String, User, String>::new(move |u| {
+            counter.set(counter.get() + 1);
+            u.email.clone()
+        });
+        assert_eq!(map.insert("a".into(), user("a@x")), Ok(None));
+        assert!(calls.get() > 0);
+    }
+
+    #[test]
+    fn index() {
+        let mut map = IndexedMap::<String, User, String>::new(|u| u.email.clone());
+
+        assert_eq!(map.insert("a".into(), user("a@x")), Ok(None));
+        assert_eq!(map.insert("b".into(), user("b@x")), Ok(None));
+        assert_eq!(
+            map.insert("c".into(), user("a@x")),
+            Err(IndexedMapError::DuplicateIndex)
+        );
+        assert_eq!(map.len(), 2);
+
+        assert_eq!(map.get_by_index("a@x"), Some(&user("a@x")));
+        assert_eq!(map.get_key_by_index("b@x"), Some(&"b".to_string()));
+
+        // Replacing a value moves its index.
+        assert_eq!(map.insert("a".into(), user("c@x")), Ok(Some(user("a@x"))));
+        assert_eq!(map.get_by_index("a@x"), None);
+        assert_eq!(map.get_by_index("c@x"), Some(&user("c@x")));
+        assert_eq!(map.as_index().len(), 2);
+
+        assert_eq!(map.remove("b"), Some(user("b@x")));
+        assert_eq!(map.get_by_index("b@x"), None);
+        assert_eq!(map.as_index().len(), 1);
+    }
+
+    #[test]
+    fn modify() {
+        let mut map = IndexedMap::<String, User, String>::new(|u| u.email.clone());
+        map.insert("a".into(), user("a@x")).unwrap();
+        map.insert("b".into(), user("b@x")).unwrap();
+
+        // A modification that does not change the index.
+        assert_eq!(map.modify("a", |u| u.age = 10), Ok(Some(())));
+        assert_eq!(map.get("a").unwrap().age, 10);
+
+        // A modification that changes the index.
+        assert_eq!(map.modify("a", |u| u.email = "c@x".into()), Ok(Some(())));
+        assert_eq!(map.get_by_index("a@x"), None);
+        assert_eq!(map.get_key_by_index("c@x"), Some(&"a".to_string()));
+
+        // A modification that conflicts with another key is not applied.
+        assert_eq!(
+            map.modify("a", |u| u.email = "b@x".into()),
+            Err(IndexedMapError::DuplicateIndex)
+        );
+        assert_eq!(map.get("a").unwrap().email, "c@x");
+        assert_eq!(map.get_key_by_index("b@x"), Some(&"b".to_string()));
+
+        assert_eq!(map.modify("z", |u| u.age = 1), Ok(None));
+        assert_eq!(map.root_hash(), map.as_hash_tree().reconstruct());
+    }
+
+    #[test]
+    fn witness_by_index() {
+        let mut map = IndexedMap::<String, User, String>::new(|u| u.email.clone());
+        map.insert("a".into(), user("a@x")).unwrap();
+        map.insert("b".into(), user("b@x")).unwrap();
+
+        let tree = map.witness_by_index("b@x");
+        assert_eq!(tree.reconstruct(), map.root_hash());
+        assert_eq!(
+            tree.get_labels(),
+            vec![b"data" as &[u8], b"b", b"index", b"b@x"]
+        );
+        assert_eq!(tree.get_leaf_values(), vec![b"b@x" as &[u8], b"b"]);
+
+        let tree = map.witness_by_index("z@x");
+        assert_eq!(tree.reconstruct(), map.root_hash());
+        assert!(tree.get_leaf_values().is_empty());
+
+        let tree = map.witness("a");
+        assert_eq!(tree.reconstruct(), map.root_hash());
+        assert_eq!(tree.get_labels(), vec![b"data" as &[u8], b"a"]);
+    }
+}
@@ -1,48 +1,271 @@
 use crate::collections::seq::Seq;
-use crate::label::{Label, Prefix};
+use crate::hashtree::{fork, fork_hash, labeled, labeled_hash, leaf_hash};
+use crate::label::{KeyCodec, Label, Prefix};
 use crate::rbtree::entry::Entry;
-use crate::rbtree::iterator::RbTreeIterator;
-use crate::rbtree::RbTree;
+use crate::rbtree::iterator::{AfterLabel, RbTreeIterator, RbTreeRangeIterator, RbTreeRevIterator};
+use crate::rbtree::{HashMismatch, RbTree, ValueGuard};
 use crate::{AsHashTree, Hash, HashTree};
 use candid::types::{Compound, Field, Label as CLabel, Type};
 use candid::CandidType;
-use serde::de::{MapAccess, Visitor};
+use serde::de::{DeserializeSeed, MapAccess, SeqAccess, Visitor};
 use serde::ser::SerializeMap;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::borrow::Borrow;
-use std::fmt::{self, Debug, Formatter};
+use std::borrow::{Borrow, Cow};
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug, Display, Formatter};
 use std::iter::FromIterator;
 use std::marker::PhantomData;
+use std::ops::RangeBounds;
+use std::panic::RefUnwindSafe;
+use std::sync::Arc;
 
-#[derive(Default)]
+#[derive(Clone)]
 pub struct Map<K: 'static + Label, V: AsHashTree + 'static> {
     pub(crate) inner: RbTree<K, V>,
+    /// The maximum length of the label of a key that can be inserted using
+    /// [`Map::try_insert`].
+    max_key_len: Option<usize>,
+    /// The normalizer that is set using [`Map::normalized_by`].
+    normalized: Option<NormalizedIndex<K>>,
+}
+
+/// A normalizer that returns the normalized label of a key.
+type Normalizer<K> = Arc<dyn Fn(&K) -> Vec<u8> + Send + Sync + RefUnwindSafe>;
+
+/// The normalizer of a map along with the keys that are inserted using
+/// [`Map::insert_checked`], by their normalized label.
+#[derive(Clone)]
+struct NormalizedIndex<K> {
+    normalize: Normalizer<K>,
+    keys: BTreeMap<Vec<u8>, K>,
+    /// The generation of the map up to which the removed keys are dropped from the index.
+    synced: u64,
+}
+
+impl<K: Ord> NormalizedIndex<K> {
+    /// Drop the given key from the index, if it is the key that is indexed by its
+    /// normalized label.
+    fn forget(&mut self, key: &K) {
+        let normalized = (self.normalize)(key);
+        if self.keys.get(&normalized) == Some(key) {
+            self.keys.remove(&normalized);
+        }
+    }
 }
 
 impl<K: 'static + Label, V: AsHashTree + 'static> Map<K, V> {
+    /// Create a new, empty map. This is the same as [`Map::default`], and the root hash of
+    /// an empty map is the hash of [`HashTree::Empty`].
     #[inline]
     pub fn new() -> Self {
         Self {
             inner: RbTree::new(),
+            max_key_len: None,
+            normalized: None,
+        }
+    }
+
+    /// Build a map from the given entries, which must be sorted by their keys in the
+    /// strictly ascending order. The balanced tree is built bottom-up and every node is
+    /// hashed once, which is O(n) instead of the O(n log n) of inserting the entries one by
+    /// one.
+    ///
+    /// The ordering is only checked in debug builds. Since the map is built at once, its
+    /// shape and hence its root hash can differ from a map with the same entries that is
+    /// built by inserting them one by one.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let map = Map::<u32, u32>::from_sorted_iter((0..1000).map(|i| (i, i * 2)));
+    /// assert_eq!(map.len(), 1000);
+    /// assert_eq!(map.get(&10), Some(&20));
+    /// assert_eq!(map.witness(&10).reconstruct(), map.root_hash());
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If the entries are not sorted in a debug build.
+    pub fn from_sorted_iter<I>(entries: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        Self {
+            inner: RbTree::from_sorted_iter(entries),
+            max_key_len: None,
+            normalized: None,
+        }
+    }
+
+    /// Create a new map that only accepts keys with a label of at most `max` bytes in
+    /// [`Map::try_insert`], bounding the size of the labels in the witnesses of the map.
+    ///
+    /// The bound is not part of the serialized map, and [`Map::insert`] does not check it.
+    #[inline]
+    pub fn with_max_key_len(max: usize) -> Self {
+        Self {
+            inner: RbTree::new(),
+            max_key_len: Some(max),
+            normalized: None,
+        }
+    }
+
+    /// Use the given normalizer to detect the conflicting keys in [`Map::insert_checked`],
+    /// for example `|email: &String| email.to_lowercase()` for case-insensitive emails.
+    /// The keys that are already in the map are indexed once, if some of them conflict
+    /// with each other the smallest one is kept in the index.
+    ///
+    /// The removed keys are dropped from the index, the ones that are removed through an
+    /// [`Entry`] are dropped the next time the index is used.
+    ///
+    /// The normalizer is not part of the serialized map.
+    pub fn normalized_by<N, F>(mut self, normalize: F) -> Self
+    where
+        K: Clone,
+        N: Label,
+        F: Fn(&K) -> N + Send + Sync + RefUnwindSafe + 'static,
+    {
+        let normalize: Normalizer<K> = Arc::new(move |key| normalize(key).as_label().into_owned());
+
+        let mut keys = BTreeMap::new();
+        self.inner.for_each(|key, _| {
+            keys.entry(normalize(key)).or_insert_with(|| key.clone());
+        });
+
+        self.normalized = Some(NormalizedIndex {
+            normalize,
+            keys,
+            synced: self.inner.generation(),
+        });
+        self
+    }
+
+    /// Drop the keys that were removed without going through the map, for example
+    /// through an [`Entry`], from the normalized index.
+    pub(crate) fn sync_index(&mut self) {
+        let inner = &self.inner;
+        if let Some(index) = &mut self.normalized {
+            if inner.removed_since(index.synced) {
+                index.keys.retain(|_, key| inner.contains_key(key));
+            }
+            index.synced = inner.generation();
+        }
+    }
+
+    /// Drop the given removed keys from the normalized index.
+    pub(crate) fn unindex<'a, I>(&mut self, removed: I)
+    where
+        I: IntoIterator<Item = &'a K>,
+    {
+        if let Some(index) = &mut self.normalized {
+            for key in removed {
+                index.forget(key);
+            }
+            index.synced = self.inner.generation();
         }
     }
 
+    /// Returns the maximum length of the keys that is set using [`Map::with_max_key_len`].
+    #[inline]
+    pub fn max_key_len(&self) -> Option<usize> {
+        self.max_key_len
+    }
+
     /// Returns `true` if the map does not contain any values.
     #[inline]
     pub fn is_empty(&self) -> bool {
         self.inner.is_empty()
     }
 
-    /// Returns the number of elements in the map.
+    /// Returns the number of elements in the map, this is O(1).
     #[inline]
     pub fn len(&self) -> usize {
         self.inner.len()
     }
 
+    /// Walk the map and return `true` if the number of entries matches the count that is
+    /// returned by [`Map::len`]. This is O(n) and is meant to be used in a `debug_assert!`
+    /// to catch bugs in the bookkeeping of the map.
+    #[inline]
+    pub fn verify_len(&self) -> bool {
+        self.inner.verify_len()
+    }
+
+    /// Walk the entire map and return an error with the key path of the first node whose
+    /// cached hash is stale, this is O(n). It is meant to be used after the map is loaded
+    /// from stable memory, to make sure that it does not certify corrupted data. See
+    /// [`RbTree::verify_hashes`].
+    #[inline]
+    pub fn verify_hashes(&self) -> Result<(), HashMismatch> {
+        self.inner.verify_hashes()
+    }
+
+    /// Walk the entire map and panic if any of the invariants of the underlying tree does
+    /// not hold or if a key is longer than the maximum key length, this is O(n) and is
+    /// meant to be used in tests. See [`RbTree::assert_invariants`].
+    pub fn assert_invariants(&self) {
+        self.inner.assert_invariants();
+
+        if let Some(max) = self.max_key_len {
+            self.inner.for_each(|key, _| {
+                assert!(key.as_label().len() <= max, "A key is too long.");
+            });
+        }
+    }
+
+    /// Panic if the witness of the given key does not certify that the key maps to the
+    /// given value, this is meant to be used in tests. The witness has to reconstruct to
+    /// the root hash of the map, and the subtree under the label of the key has to be the
+    /// tree of the value, both by its hash and by the bytes of its leaves.
+    ///
+    /// ```
+    /// use certified_vars::Map;
+    ///
+    /// let mut map = Map::<String, u32>::new();
+    /// map.insert("a".into(), 1);
+    /// map.assert_certifies(&"a".into(), &1);
+    /// ```
+    pub fn assert_certifies(&self, key: &K, value: &V) {
+        fn find<'t, 'a>(tree: &'t HashTree<'a>, label: &[u8]) -> Option<&'t HashTree<'a>> {
+            match tree {
+                HashTree::Fork(lr) => find(lr.left(), label).or_else(|| find(lr.right(), label)),
+                HashTree::Labeled(l, t) if l.as_ref() == label => Some(t),
+                _ => None,
+            }
+        }
+
+        let witness = self.witness(key);
+        assert_eq!(
+            witness.reconstruct(),
+            self.root_hash(),
+            "The witness does not reconstruct to the root hash."
+        );
+
+        let label = key.as_label();
+        let tree = find(&witness, &label).expect("The witness does not contain the key.");
+        let expected = value.as_hash_tree();
+        assert_eq!(
+            tree.reconstruct(),
+            expected.reconstruct(),
+            "The witness does not certify the value."
+        );
+        assert_eq!(
+            tree.get_leaf_values(),
+            expected.get_leaf_values(),
+            "The leaves of the witness are not the leaves of the value."
+        );
+    }
+
     /// Clear the map.
     #[inline]
     pub fn clear(&mut self) {
-        self.inner = RbTree::new();
+        self.inner.clear();
+        if let Some(index) = &mut self.normalized {
+            index.keys.clear();
+            index.synced = self.inner.generation();
+        }
     }
 
     /// Insert a key-value pair into the map. Returns [`None`] if the key did not
@@ -53,6 +276,123 @@ impl<K: 'static + Label, V: AsHashTree + 'static> Map<K, V> {
         self.inner.insert(key, value).0
     }
 
+    /// Insert a key-value pair into the map, and return the previous value of the key along
+    /// with a witness for the new value. The witness is built from the tree after the
+    /// insertion, so it reconstructs to the new root hash of the map. This is meant for
+    /// the update calls that return a certified proof of the value they have written.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::hashtree::LookupResult;
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let mut map = Map::<String, u32>::new();
+    /// map.insert("a".into(), 1);
+    ///
+    /// let (previous, witness) = map.insert_and_witness("a".into(), 2);
+    /// assert_eq!(previous, Some(1));
+    /// assert_eq!(witness.lookup_path(&[b"a"]), LookupResult::Found(&2u32.to_be_bytes()));
+    /// let witness = witness.into_owned();
+    /// assert_eq!(witness.reconstruct(), map.root_hash());
+    /// ```
+    #[inline]
+    pub fn insert_and_witness(&mut self, key: K, value: V) -> (Option<V>, HashTree<'_>) {
+        self.inner.insert_and_witness(key, value)
+    }
+
+    /// Like [`Map::insert`] but the map is not modified if the key is already associated
+    /// with a value that has the same root hash as the given value, in which case the root
+    /// hash of the map does not change either. This lets the caller skip the work that
+    /// depends on the certified data, such as invalidating a cached witness.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::collections::map::InsertResult;
+    /// use certified_vars::Map;
+    ///
+    /// let mut map = Map::<String, u32>::new();
+    /// assert_eq!(map.insert_if_changed("a".into(), 1), InsertResult::Inserted);
+    /// assert_eq!(map.insert_if_changed("a".into(), 1), InsertResult::Unchanged(1));
+    /// assert_eq!(map.insert_if_changed("a".into(), 2), InsertResult::Changed(1));
+    /// ```
+    pub fn insert_if_changed(&mut self, key: K, value: V) -> InsertResult<V> {
+        match self.inner.get(&key) {
+            Some(prev) if prev.root_hash() == value.root_hash() => InsertResult::Unchanged(value),
+            Some(_) => InsertResult::Changed(self.inner.insert(key, value).0.unwrap()),
+            None => {
+                self.inner.insert(key, value);
+                InsertResult::Inserted
+            }
+        }
+    }
+
+    /// Like [`Map::insert`] but returns an error if the label of the key is longer than
+    /// the maximum key length of this map, in which case the map is not modified.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<Option<V>, MapError> {
+        if let Some(max) = self.max_key_len {
+            let len = key.as_label().len();
+            if len > max {
+                return Err(MapError::KeyTooLong { len, max });
+            }
+        }
+
+        Ok(self.insert(key, value))
+    }
+
+    /// Like [`Map::try_insert`] but also returns an error if another key in the map is
+    /// equal to the given key once both are normalized using the normalizer of the map that
+    /// is set using [`Map::normalized_by`], for example two emails that only differ in
+    /// their case. Inserting the exact same key replaces its value as usual, and without a
+    /// normalizer this is the same as [`Map::try_insert`].
+    ///
+    /// The map is still ordered by the original keys and their labels are used in the
+    /// hash tree, so the keys that normalize to the same value are not necessarily next to
+    /// each other in the tree. Instead the map keeps an index of the keys that are
+    /// inserted using this method by their normalized label, and the check is O(log n).
+    /// The keys that are inserted using the other methods are not in the index, so they
+    /// are not checked against.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::Map;
+    ///
+    /// let mut emails = Map::<String, u32>::new().normalized_by(|email| email.to_lowercase());
+    ///
+    /// assert!(emails.insert_checked("a@x".into(), 1).is_ok());
+    /// assert!(emails.insert_checked("A@x".into(), 2).is_err());
+    /// assert_eq!(emails.insert_checked("a@x".into(), 3), Ok(Some(1)));
+    /// ```
+    pub fn insert_checked(&mut self, key: K, value: V) -> Result<Option<V>, MapError>
+    where
+        K: Clone,
+    {
+        let normalized = match &self.normalized {
+            Some(index) => (index.normalize)(&key),
+            None => return self.try_insert(key, value),
+        };
+
+        self.sync_index();
+        let index = self.normalized.as_ref().unwrap();
+        if let Some(existing) = index.keys.get(&normalized) {
+            if *existing != key {
+                return Err(MapError::NormalizedKeyConflict {
+                    existing: existing.as_label().into_owned(),
+                });
+            }
+        }
+
+        let previous = self.try_insert(key.clone(), value)?;
+        self.normalized
+            .as_mut()
+            .unwrap()
+            .keys
+            .insert(normalized, key);
+        Ok(previous)
+    }
+
     /// Remove the value associated with the given key from the map, returns the
     /// previous value associated with the key.
     #[inline]
@@ -61,7 +401,7 @@ impl<K: 'static + Label, V: AsHashTree + 'static> Map<K, V> {
         K: Borrow<Q>,
         Q: Ord,
     {
-        self.inner.delete(key).map(|(_, v)| v)
+        self.remove_entry(key).map(|(_, v)| v)
     }
 
     /// Remove an entry from the map and return the key and value.
@@ -71,7 +411,10 @@ impl<K: 'static + Label, V: AsHashTree + 'static> Map<K, V> {
         K: Borrow<Q>,
         Q: Ord,
     {
-        self.inner.delete(key)
+        self.sync_index();
+        let removed = self.inner.delete(key)?;
+        self.unindex(Some(&removed.0));
+        Some(removed)
     }
 
     #[inline]
@@ -79,14 +422,77 @@ impl<K: 'static + Label, V: AsHashTree + 'static> Map<K, V> {
         self.inner.entry(key)
     }
 
-    /// Returns a mutable reference to the value corresponding to the key.
+    /// Returns a mutable reference to the value corresponding to the key, the map is
+    /// rehashed once the returned guard is dropped. The guard is dropped even if a panic
+    /// unwinds while the value is borrowed, so the hashes never go stale.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let mut balances = Map::<String, u64>::new();
+    /// balances.insert("alice".into(), 100);
+    ///
+    /// *balances.get_mut("alice").unwrap() -= 30;
+    ///
+    /// assert_eq!(balances.get("alice"), Some(&70));
+    /// assert_eq!(balances.witness("alice").reconstruct(), balances.root_hash());
+    /// ```
     #[inline]
-    pub fn get_mut<Q: ?Sized>(&mut self, key: &Q) -> Option<&mut V>
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<MapValueGuard<'_, K, V>>
     where
         K: Borrow<Q>,
-        Q: Ord,
+        Q: ?Sized + Ord,
+    {
+        self.inner.get_mut(key)
+    }
+
+    /// Modify the value associated with the given key and return the result of `f`, the
+    /// map is rehashed after the modification. Returns [`None`] if the key is not in the
+    /// map, in which case `f` is not called.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let mut balances = Map::<String, u64>::new();
+    /// balances.insert("alice".into(), 100);
+    ///
+    /// let balance = balances.modify("alice", |balance| {
+    ///     *balance -= 30;
+    ///     *balance
+    /// });
+    ///
+    /// assert_eq!(balance, Some(70));
+    /// assert_eq!(balances.witness("alice").reconstruct(), balances.root_hash());
+    /// ```
+    #[inline]
+    pub fn modify<Q, F, R>(&mut self, key: &Q, f: F) -> Option<R>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+        F: FnOnce(&mut V) -> R,
+    {
+        self.inner.modify(key, f)
+    }
+
+    /// Like [`Map::modify`], but inserts the default value first if the key is not in the
+    /// map.
+    pub fn modify_or_default<F, R>(&mut self, key: K, f: F) -> R
+    where
+        V: Default,
+        F: FnOnce(&mut V) -> R,
     {
-        self.inner.modify(key, |v| v)
+        if self.inner.contains_key(&key) {
+            return self.inner.modify(&key, f).unwrap();
+        }
+
+        let mut value = V::default();
+        let result = f(&mut value);
+        self.inner.insert(key, value);
+        result
     }
 
     /// Return the value associated with the given key.
@@ -99,214 +505,1016 @@ impl<K: 'static + Label, V: AsHashTree + 'static> Map<K, V> {
         self.inner.get(key)
     }
 
+    /// Returns `true` if the map contains the given key. This is the same as
+    /// `map.get(key).is_some()`.
+    #[inline]
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        self.inner.contains_key(key)
+    }
+
+    /// Returns the key before the given key, the value associated with the given key and
+    /// the key after it, in the order of the keys. The neighbors are returned even if the
+    /// given key is not in the map, and they are all found in a single descent of the tree.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::Map;
+    ///
+    /// let mut map = Map::<u32, &str>::new();
+    /// map.insert(1, "a");
+    /// map.insert(3, "b");
+    /// map.insert(5, "c");
+    ///
+    /// assert_eq!(map.get_with_neighbors(&3), (Some(&1), Some(&"b"), Some(&5)));
+    /// assert_eq!(map.get_with_neighbors(&4), (Some(&3), None, Some(&5)));
+    /// assert_eq!(map.get_with_neighbors(&5), (Some(&3), Some(&"c"), None));
+    /// ```
+    #[inline]
+    pub fn get_with_neighbors<Q>(&self, key: &Q) -> (Option<&K>, Option<&V>, Option<&K>)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        self.inner.get_with_neighbors(key)
+    }
+
     /// Return an iterator over the key-values in the map.
     #[inline]
     pub fn iter(&self) -> RbTreeIterator<K, V> {
         RbTreeIterator::new(&self.inner)
     }
 
-    /// Create a HashTree witness for the value associated with given key.
+    /// Return an iterator over the entries of the map where the label of each key is
+    /// decoded as a `T`, the decoded key is [`None`] if the label is not a valid label of
+    /// a `T`. This is meant for presenting keys that are stored as their encoded bytes,
+    /// the map itself is not changed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::label::Label;
+    /// use certified_vars::Map;
+    ///
+    /// let mut map = Map::<Vec<u8>, u32>::new();
+    /// map.insert(("alice".to_string(), 7u64).as_label().into_owned(), 1);
+    /// map.insert(vec![0xff], 2);
+    ///
+    /// let keys = map
+    ///     .view_keys_as::<(String, u64)>()
+    ///     .map(|(key, _)| key)
+    ///     .collect::<Vec<_>>();
+    /// assert_eq!(keys, vec![Some(("alice".to_string(), 7)), None]);
+    /// ```
+    #[inline]
+    pub fn view_keys_as<T: KeyCodec>(&self) -> KeysAs<'_, K, V, T> {
+        KeysAs {
+            inner: self.iter(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Return an iterator over the key-values in the map from the largest key to the
+    /// smallest one, such as the newest entries of a map that is keyed by timestamps.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::Map;
+    ///
+    /// let mut events = Map::<u64, String>::new();
+    /// for timestamp in 0..10 {
+    ///     events.insert(timestamp, format!("event-{}", timestamp));
+    /// }
+    ///
+    /// let latest = events.iter_rev().take(3).map(|(t, _)| *t).collect::<Vec<_>>();
+    /// assert_eq!(latest, vec![9, 8, 7]);
+    /// ```
+    #[inline]
+    pub fn iter_rev(&self) -> RbTreeRevIterator<'_, K, V> {
+        self.inner.iter_rev()
+    }
+
+    /// Return an iterator over the key-values in the map with a key in the given range, in
+    /// the ascending order of the keys. Only the part of the tree that is in the range is
+    /// visited, so this is O(log n + k) for a range with k keys.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::Map;
+    ///
+    /// let mut map = Map::<u32, u32>::new();
+    /// for i in 0..100 {
+    ///     map.insert(i, i * 2);
+    /// }
+    ///
+    /// let page = map.range(10..15).map(|(_, v)| *v).collect::<Vec<_>>();
+    /// assert_eq!(page, vec![20, 22, 24, 26, 28]);
+    /// assert_eq!(map.range(95..).count(), 5);
+    /// ```
     #[inline]
-    pub fn witness<Q: ?Sized>(&self, key: &Q) -> HashTree
+    pub fn range<Q, R>(&self, range: R) -> RbTreeRangeIterator<'_, K, V>
     where
         K: Borrow<Q>,
-        Q: Ord,
+        Q: ?Sized + Ord,
+        R: RangeBounds<Q>,
     {
-        self.inner.witness(key)
+        RbTreeRangeIterator::new(&self.inner, range)
     }
 
-    /// Returns a witness enumerating all the keys in this map.  The
-    /// resulting tree doesn't include values, they are replaced with
-    /// "Pruned" nodes.
-    pub fn witness_keys(&self) -> HashTree {
-        self.inner.keys()
+    /// Return an iterator over clones of the key-values in the map. Each entry is only
+    /// cloned when the iterator reaches it.
+    ///
+    /// Unlike [`Map::iter`] the yielded items do not borrow the map, which makes it
+    /// possible to keep them across an `.await` point that mutates the map.
+    #[inline]
+    pub fn iter_cloned(&self) -> impl ExactSizeIterator<Item = (K, V)> + '_
+    where
+        K: Clone,
+        V: Clone,
+    {
+        self.iter().map(|(k, v)| (k.clone(), v.clone()))
     }
 
-    /// Returns a witness for the key-value pairs in the specified range.
-    /// The resulting tree contains both keys and values.
+    /// Return a snapshot of all of the key-values in the map in the ascending order of
+    /// the keys. This performs a single in-order walk over the tree and has the cost of
+    /// cloning every key and value in the map, so it is O(n).
+    pub fn to_owned_vec(&self) -> Vec<(K, V)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let mut result = Vec::with_capacity(self.len());
+        self.inner.for_each(|k, v| {
+            result.push((k.clone(), v.clone()));
+        });
+        result
+    }
+
+    /// Rebuild the underlying tree with the minimal depth for the number of entries, and
+    /// return the new root hash of the map.
+    ///
+    /// A red-black tree that went through many insertions and removals can be up to twice
+    /// as deep as needed, and every extra level adds a pruned hash to each witness. This is
+    /// an optional tuning for maps that are read much more often than they are modified:
+    /// it takes O(n) time, needs to keep all of the entries in a buffer during the rebuild,
+    /// and it computes the hash of every node once.
+    ///
+    /// The root hash of a map depends on the shape of its tree, so the root hash usually
+    /// changes even though the entries are the same, and the certified data has to be
+    /// updated afterwards.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let mut map = Map::<u32, u32>::new();
+    /// for i in 0..1000 {
+    ///     map.insert(i, i);
+    /// }
+    /// for i in 0..900 {
+    ///     map.remove(&i);
+    /// }
+    ///
+    /// let entries = map.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+    /// let root_hash = map.rebalance();
+    ///
+    /// assert_eq!(map.root_hash(), root_hash);
+    /// assert!(map.iter().map(|(k, v)| (*k, *v)).eq(entries));
+    /// ```
+    #[inline]
+    pub fn rebalance(&mut self) -> Hash {
+        self.inner.rebalance();
+        self.inner.root_hash()
+    }
+
+    /// Retains only the entries for which the predicate returns `true`.
     #[inline]
-    pub fn witness_value_range<Q1: ?Sized, Q2: ?Sized>(&self, first: &K, last: &K) -> HashTree<'_>
+    pub fn retain<F>(&mut self, f: F)
     where
-        K: Borrow<Q1> + Borrow<Q2>,
-        Q1: Ord,
-        Q2: Ord,
+        F: FnMut(&K, &V) -> bool,
     {
-        self.inner.value_range(first, last)
+        self.sync_index();
+        let removed = self.inner.retain(f);
+        self.unindex(removed.iter().map(|(k, _)| k));
     }
 
-    /// Returns a witness for the keys in the specified range.
-    /// The resulting tree only contains the keys, and the values are replaced with
-    /// "Pruned" nodes.
+    /// Insert all of the entries of `other` into this map, and return the new root hash of
+    /// the map. For the keys that are in both of the maps `resolve` is called with the key,
+    /// the current value and the value in `other`, and the returned value is kept.
+    ///
+    /// The maps are merged in one ordered walk and the map is rehashed once, so this is
+    /// faster than inserting the entries of `other` one by one. Since the map is rebuilt,
+    /// its shape and hence its root hash can differ from the result of those insertions.
+    /// If `other` is small compared to this map, its entries are inserted one by one
+    /// instead.
+    ///
+    /// If `resolve` panics, the map keeps all of its entries except for the one whose value
+    /// was being resolved.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::Map;
+    ///
+    /// let mut a = Map::<String, u64>::new();
+    /// a.insert("x".into(), 1);
+    /// a.insert("y".into(), 2);
+    ///
+    /// let mut b = Map::<String, u64>::new();
+    /// b.insert("y".into(), 5);
+    /// b.insert("z".into(), 3);
+    ///
+    /// a.merge_with(b, |_, x, y| x.max(y));
+    /// assert_eq!(a.get("y"), Some(&5));
+    /// assert_eq!(a.len(), 3);
+    /// ```
+    pub fn merge_with<F>(&mut self, other: Map<K, V>, resolve: F) -> Hash
+    where
+        F: FnMut(&K, V, V) -> V,
+    {
+        self.inner.merge_with(other.inner, resolve);
+        self.inner.root_hash()
+    }
+
+    /// Insert a batch of entries that are sorted by their keys in the strictly ascending
+    /// order. The batch is merged with the map in one pass and the map is rebuilt and
+    /// rehashed once, which is much faster than inserting a large batch one entry at a
+    /// time. This is O(n + m) for a map of n entries and a batch of m entries, so a batch
+    /// that is small compared to the map, where O(m log n) is cheaper, is inserted one
+    /// entry at a time instead.
+    ///
+    /// If the batch is not sorted, the entries are inserted one by one as well, which has
+    /// the same result except for the shape of the tree. The shape, and hence the root
+    /// hash, of the map can also differ from a map that is built by inserting the same
+    /// entries one by one.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let mut map = Map::<u32, u32>::new();
+    /// map.insert(5, 0);
+    /// map.extend_sorted((0..10_000).map(|i| (i, i)));
+    ///
+    /// assert_eq!(map.len(), 10_000);
+    /// assert_eq!(map.get(&5), Some(&5));
+    /// assert_eq!(map.witness(&5).reconstruct(), map.root_hash());
+    /// ```
     #[inline]
-    pub fn witness_key_range<Q1: ?Sized, Q2: ?Sized>(&self, first: &K, last: &K) -> HashTree<'_>
+    pub fn extend_sorted<I>(&mut self, entries: I)
     where
-        K: Borrow<Q1> + Borrow<Q2>,
-        Q1: Ord,
-        Q2: Ord,
+        I: IntoIterator<Item = (K, V)>,
     {
-        self.inner.key_range(first, last)
+        self.inner.extend_sorted(entries)
     }
 
-    /// Returns a witness for the keys with the given prefix, this replaces the values with
-    /// "Pruned" nodes.
+    /// Remove all of the entries with a key in the given range, and return the number of
+    /// removed entries. The entries are removed in order from the first key in the range,
+    /// which only rehashes the paths to the removed entries, and the map is rebuilt once
+    /// instead when the range is a large share of the map. See [`RbTree::remove_range`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::Map;
+    ///
+    /// let mut events = Map::<u64, u32>::new();
+    /// for timestamp in 0..100 {
+    ///     events.insert(timestamp, 0);
+    /// }
+    ///
+    /// // Remove all of the events that are older than 60.
+    /// assert_eq!(events.remove_range(..60), 60);
+    /// assert_eq!(events.len(), 40);
+    /// ```
     #[inline]
-    pub fn witness_keys_with_prefix<P: ?Sized>(&self, prefix: &P) -> HashTree<'_>
+    pub fn remove_range<Q, R>(&mut self, range: R) -> usize
     where
-        K: Prefix<P>,
-        P: Ord,
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+        R: RangeBounds<Q>,
     {
-        self.inner.keys_with_prefix(prefix)
+        let removed = self.inner.remove_range(range);
+        self.sync_index();
+        removed
     }
 
-    /// Return the underlying [`RbTree`] for this map.
+    /// Like [`Map::retain`], but also returns the removed keys in ascending order along
+    /// with a witness proving that none of them exist in the map after the removal.
+    ///
+    /// A replica can apply the same removal on its own copy of the map, and use the
+    /// witness to verify the new root hash; the witness is checked against the state
+    /// after the removal and not the one before it.
+    pub fn retain_delta<F>(&mut self, f: F) -> (Vec<K>, HashTree<'_>)
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        self.sync_index();
+        let removed = self
+            .inner
+            .retain(f)
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect::<Vec<_>>();
+        self.unindex(&removed);
+
+        let inner = &self.inner;
+        let witness = removed
+            .iter()
+            .map(|k| inner.witness(k))
+            .reduce(HashTree::merge)
+            .unwrap_or_else(|| HashTree::Pruned(inner.root_hash()));
+
+        (removed, witness)
+    }
+
+    /// Returns the current generation of the map, which is incremented on every insertion,
+    /// modification and removal.
     #[inline]
-    pub fn as_tree(&self) -> &RbTree<K, V> {
-        &self.inner
+    pub fn generation(&self) -> u64 {
+        self.inner.generation()
     }
-}
 
-impl<K: 'static + Label, V: AsHashTree> Map<K, Seq<V>> {
-    /// Perform a [`Seq::append`] on the seq associated with the give value, if
-    /// the seq does not exists, creates an empty one and inserts it to the map.
-    pub fn append_deep(&mut self, key: K, value: V) {
-        let mut value = Some(value);
+    /// Compute the changes since the given generation for a light client that has already
+    /// synced the map up to that generation. The delta contains the keys that were inserted
+    /// or modified since then, and a witness revealing their values against the current
+    /// root hash.
+    ///
+    /// Removed keys are not tracked individually, the delta only flags if any key was
+    /// removed, in which case the client should discard the keys it can not find in a
+    /// witness of its own. If the given generation is ahead of the map, for example
+    /// because the map was reloaded from the stable memory, every entry is reported.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let mut map = Map::<String, u32>::new();
+    /// map.insert("a".into(), 1);
+    /// map.insert("b".into(), 2);
+    ///
+    /// let synced = map.generation();
+    /// map.insert("c".into(), 3);
+    ///
+    /// let delta = map.frontier(synced);
+    /// assert_eq!(delta.keys, vec!["c"]);
+    /// assert!(!delta.removed);
+    /// assert_eq!(delta.witness.reconstruct(), map.root_hash());
+    /// ```
+    pub fn frontier(&self, since_generation: u64) -> SyncDelta<'_, K> {
+        let generation = self.inner.generation();
+        let since = if since_generation > generation {
+            0
+        } else {
+            since_generation
+        };
 
-        self.inner.modify(&key, |seq| {
-            seq.append(value.take().unwrap());
-        });
+        SyncDelta {
+            generation,
+            keys: self.inner.changed_since(since),
+            removed: self.inner.removed_since(since) || since != since_generation,
+            witness: self.inner.witness_changed_since(since),
+        }
+    }
 
-        if let Some(value) = value.take() {
-            let mut seq = Seq::new();
-            seq.append(value);
-            self.inner.insert(key, seq);
+    /// Returns a pagination cursor that points right after the given key, at the current
+    /// generation of the map. The key does not have to be in the map.
+    #[inline]
+    pub fn cursor_after(&self, key: &K) -> Cursor {
+        Cursor {
+            key: key.as_label().into_owned(),
+            generation: self.generation(),
         }
     }
 
+    /// Returns an iterator over the key-values that come after the key of the given cursor,
+    /// so the next page starts right where the previous one ended even if keys were
+    /// inserted or removed in between.
+    ///
+    /// Use [`Map::is_cursor_stale`] to find out if the map has changed since the cursor was
+    /// created, in which case the pages that were already returned may be outdated and the
+    /// client can decide whether to restart.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::Map;
+    ///
+    /// let mut map = Map::<u32, u32>::new();
+    /// for i in 0..10 {
+    ///     map.insert(i, i);
+    /// }
+    ///
+    /// let page = map.iter().take(4).collect::<Vec<_>>();
+    /// let cursor = map.cursor_after(page.last().unwrap().0);
+    ///
+    /// map.remove(&5);
+    /// assert!(map.is_cursor_stale(&cursor));
+    ///
+    /// let next = map.iter_from_cursor(&cursor).take(4).map(|(k, _)| *k);
+    /// assert_eq!(next.collect::<Vec<_>>(), vec![4, 6, 7, 8]);
+    /// ```
     #[inline]
-    pub fn len_deep<Q: ?Sized>(&mut self, key: &Q) -> usize
+    pub fn iter_from_cursor(&self, cursor: &Cursor) -> AfterLabel<'_, K, V> {
+        RbTreeIterator::after_label(&self.inner, &cursor.key)
+    }
+
+    /// Returns `true` if the map has changed since the given cursor was created. Mutations
+    /// through an [`Entry`] that do not insert or remove a value are not detected, see
+    /// [`Map::generation`].
+    #[inline]
+    pub fn is_cursor_stale(&self, cursor: &Cursor) -> bool {
+        cursor.generation != self.generation()
+    }
+
+    /// Create a HashTree witness for the value associated with given key, or a witness
+    /// of its absence if the key is not in the map.
+    ///
+    /// The key can be any borrowed form of the key type, so for example a `&str` can be
+    /// used to query a map with [`String`] keys without allocating.
+    #[inline]
+    pub fn witness<Q: ?Sized>(&self, key: &Q) -> HashTree<'_>
     where
         K: Borrow<Q>,
         Q: Ord,
     {
-        self.inner.get(key).map(|seq| seq.len()).unwrap_or(0)
+        self.inner.witness(key)
     }
-}
 
-impl<K: 'static + Label, V: 'static + AsHashTree> AsRef<RbTree<K, V>> for Map<K, V> {
+    /// Create a witness that lets a client check that the given key does not map to the
+    /// `forbidden` value. This is the same as [`Map::witness`]: the full hash tree of the
+    /// actual value is in the witness, and never just its hash like in
+    /// [`Map::witness_hash_only`], so the client can compare it with the forbidden value
+    /// itself. If the key is not in the map the witness proves its absence, which also
+    /// proves that it does not map to the forbidden value.
+    ///
+    /// Proving that no key maps to a value requires the values of the entire map, so this
+    /// is only meant for checking a single key.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::hashtree::LookupResult;
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let mut owners = Map::<String, String>::new();
+    /// owners.insert("token-1".into(), "alice".into());
+    ///
+    /// let forbidden = "bob".to_string();
+    /// let witness = owners.witness_not_equal("token-1", &forbidden);
+    /// assert_eq!(witness.reconstruct(), owners.root_hash());
+    ///
+    /// // The client compares the certified value with the forbidden one.
+    /// match witness.lookup_path(&[b"token-1"]) {
+    ///     LookupResult::Found(owner) => assert_ne!(owner, forbidden.as_bytes()),
+    ///     LookupResult::Absent => {}
+    ///     _ => panic!("The witness does not prove anything about the key."),
+    /// }
+    /// ```
     #[inline]
-    fn as_ref(&self) -> &RbTree<K, V> {
-        &self.inner
+    pub fn witness_not_equal<Q>(&self, key: &Q, forbidden: &V) -> HashTree<'_>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        // The client does the comparison, so the forbidden value is not in the witness.
+        let _ = forbidden;
+        self.inner.witness(key)
     }
-}
 
-impl<K: 'static + Label, V: AsHashTree + 'static> Serialize for Map<K, V>
-where
-    K: Serialize,
-    V: Serialize,
-{
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    /// Returns a view of this map in the counted mode, in which the root hash also commits
+    /// to the number of entries in the map. See [`Annotated`].
+    #[inline]
+    pub fn counted(&self) -> Annotated<'_, K, V> {
+        Annotated {
+            map: self,
+            len: true,
+            version: false,
+        }
+    }
+
+    /// Like [`Map::witness`], but for the counted mode of the map, so the witness also
+    /// proves the number of entries in the map. The witness reconstructs to the root hash
+    /// of [`Map::counted`] and not to the root hash of the map.
+    #[inline]
+    pub fn witness_with_len<Q>(&self, key: &Q) -> HashTree<'_>
     where
-        S: Serializer,
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
     {
-        let mut s = serializer.serialize_map(Some(self.len()))?;
+        self.counted().witness(key)
+    }
 
-        for (key, value) in self.iter() {
-            s.serialize_entry(key, value)?;
+    /// Returns a view of this map in the versioned mode, in which the root hash also
+    /// commits to the generation of the map. See [`Annotated`].
+    #[inline]
+    pub fn versioned(&self) -> Annotated<'_, K, V> {
+        Annotated {
+            map: self,
+            len: false,
+            version: true,
         }
+    }
 
-        s.end()
+    /// Returns a witness that only proves the current generation of the map, with all of
+    /// the entries pruned. The witness reconstructs to the root hash of [`Map::versioned`]
+    /// and not to the root hash of the map.
+    #[inline]
+    pub fn witness_version(&self) -> HashTree<'static> {
+        self.versioned().witness_version()
     }
-}
 
-impl<'de, K: 'static + Label, V: AsHashTree + 'static> Deserialize<'de> for Map<K, V>
-where
-    K: Deserialize<'de>,
-    V: Deserialize<'de>,
-{
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    /// Create a HashTree witness for the given key where the value is pruned, or a witness
+    /// of its absence if the key is not in the map. This is meant for a client that already
+    /// knows the value it expects and only wants to confirm that it is still the one in the
+    /// map, without downloading the value again.
+    ///
+    /// The witness is the same as the one returned by [`Map::witness`] except for the
+    /// value, which is replaced by a pruned node with its root hash. So a client that
+    /// substitutes its own copy of the value for the pruned node gets a tree that
+    /// reconstructs to the certified root hash iff the value in the map is the same.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::hashtree::LookupResult;
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let mut nonces = Map::<String, u64>::new();
+    /// nonces.insert("alice".into(), 7);
+    ///
+    /// let witness = nonces.witness_hash_only("alice");
+    /// assert_eq!(witness.reconstruct(), nonces.root_hash());
+    /// // The value is not revealed.
+    /// assert_eq!(witness.lookup_path(&[b"alice"]), LookupResult::Unknown);
+    /// assert!(witness.get_labels().contains(&(b"alice" as &[u8])));
+    /// ```
+    #[inline]
+    pub fn witness_hash_only<Q>(&self, key: &Q) -> HashTree<'_>
     where
-        D: Deserializer<'de>,
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
     {
-        deserializer.deserialize_map(MapVisitor(PhantomData::default()))
+        self.inner
+            .nested_witness(key, |value| HashTree::Pruned(value.root_hash()))
     }
-}
 
-struct MapVisitor<K, V>(PhantomData<(K, V)>);
+    /// Like [`Map::witness`], but return an error instead of the witness if its encoded
+    /// size would be more than `max_bytes`, so a response never goes over its budget.
+    ///
+    /// The size is computed from the witness of [`Map::witness_hash_only`] and the hash
+    /// tree of the value, so the full witness is only built when it fits. The hash only
+    /// witness is the usual fallback when the value is too large, a client can then get the
+    /// value with a separate query and check it against the pruned hash.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::Map;
+    ///
+    /// let mut blobs = Map::<String, Vec<u8>>::new();
+    /// blobs.insert("small".into(), vec![0; 10]);
+    /// blobs.insert("large".into(), vec![0; 10_000]);
+    ///
+    /// assert!(blobs.try_witness("small", 1024).is_ok());
+    ///
+    /// let error = blobs.try_witness("large", 1024).unwrap_err();
+    /// assert!(error.size > 10_000);
+    /// assert!(blobs.witness_hash_only("large").encoded_size() <= 1024);
+    /// ```
+    pub fn try_witness<Q>(&self, key: &Q, max_bytes: usize) -> Result<HashTree<'_>, WitnessTooLarge>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        let mut size = self.witness_hash_only(key).encoded_size();
+        if let Some(value) = self.inner.get(key) {
+            // The value replaces a pruned node in the hash only witness.
+            size = size - HashTree::Pruned([0; 32]).encoded_size()
+                + value.as_hash_tree().encoded_size();
+        }
 
-impl<'de, K: 'static + Label, V: AsHashTree + 'static> Visitor<'de> for MapVisitor<K, V>
-where
-    K: Deserialize<'de>,
-    V: Deserialize<'de>,
-{
-    type Value = Map<K, V>;
+        if size > max_bytes {
+            return Err(WitnessTooLarge { size, max_bytes });
+        }
 
-    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
-        write!(formatter, "expected a map")
+        Ok(self.witness(key))
     }
 
-    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    /// Create one witness for all of the given keys, the keys that are not in the map are
+    /// proven to be absent.
+    pub fn witness_many<Q>(&self, keys: &[Q]) -> HashTree<'_>
     where
-        A: MapAccess<'de>,
+        K: Borrow<Q>,
+        Q: Ord,
     {
-        let mut result = Map::new();
+        keys.iter()
+            .map(|key| self.inner.witness(key))
+            .reduce(HashTree::merge)
+            .unwrap_or_else(|| HashTree::Pruned(self.inner.root_hash()))
+    }
 
-        loop {
-            if let Some((key, value)) = map.next_entry::<K, V>()? {
-                result.insert(key, value);
-                continue;
-            }
+    /// Create a set of witnesses that together certify the values of all of the given keys,
+    /// where each witness is encoded in at most `max_bytes` bytes so it can be returned in a
+    /// single response. The only witness that can be larger is one that contains a single
+    /// value that does not fit in `max_bytes` on its own.
+    ///
+    /// The values of the keys are split like [`HashTree::split_by_size`], and the absence
+    /// of the keys that are not in the map is proven in separate witnesses at the end.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let mut map = Map::<u32, Vec<u8>>::new();
+    /// for i in 0..100 {
+    ///     map.insert(i, vec![0; 100]);
+    /// }
+    ///
+    /// let batch = map.certified_batch(&[1, 20, 50, 70, 200], 512);
+    /// assert!(batch.len() > 1);
+    /// for tree in &batch {
+    ///     assert_eq!(tree.reconstruct(), map.root_hash());
+    ///     assert!(tree.encoded_size() <= 512);
+    /// }
+    /// ```
+    pub fn certified_batch<Q>(&self, keys: &[Q], max_bytes: usize) -> Vec<HashTree<'_>>
+    where
+        K: Borrow<Q>,
+        Q: Ord,
+    {
+        let (present, absent): (Vec<&Q>, Vec<&Q>) =
+            keys.iter().partition(|key| self.inner.contains_key(*key));
 
-            break;
+        let mut result = if present.is_empty() {
+            Vec::new()
+        } else {
+            present
+                .into_iter()
+                .map(|key| self.inner.witness(key))
+                .reduce(HashTree::merge)
+                .unwrap()
+                .split_by_size(max_bytes)
+        };
+
+        let mut current: Option<HashTree<'_>> = None;
+        for key in absent {
+            let witness = self.inner.witness(key);
+            current = match current {
+                None => Some(witness),
+                Some(tree) => {
+                    let merged = tree.clone().merge(witness.clone());
+                    if merged.encoded_size() <= max_bytes {
+                        Some(merged)
+                    } else {
+                        result.push(tree);
+                        Some(witness)
+                    }
+                }
+            };
         }
+        result.extend(current);
 
-        Ok(result)
+        if result.is_empty() {
+            result.push(HashTree::Pruned(self.inner.root_hash()));
+        }
+
+        result
+    }
+
+    /// Create a witness for the given key where the value is replaced with the witness
+    /// that `sub` returns, so a lookup into a nested certified collection can be proven
+    /// with one tree that spans both levels.
+    ///
+    /// If the key is not in the map `sub` is not called, and the witness proves the
+    /// absence of the key instead.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let mut balances = Map::<String, Map<String, u64>>::new();
+    /// let mut tokens = Map::new();
+    /// tokens.insert("icp".into(), 10);
+    /// balances.insert("alice".into(), tokens);
+    ///
+    /// let tree = balances.witness_path("alice", |tokens| tokens.witness("icp"));
+    /// assert_eq!(tree.reconstruct(), balances.root_hash());
+    /// assert_eq!(tree.get_labels(), vec![b"alice" as &[u8], b"icp"]);
+    /// ```
+    #[inline]
+    pub fn witness_path<'a, Q>(
+        &'a self,
+        key: &Q,
+        sub: impl FnOnce(&'a V) -> HashTree<'a>,
+    ) -> HashTree<'a>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        self.inner.nested_witness(key, sub)
+    }
+
+    /// Returns a witness enumerating all the keys in this map.  The
+    /// resulting tree doesn't include values, they are replaced with
+    /// "Pruned" nodes.
+    pub fn witness_keys(&self) -> HashTree {
+        self.inner.keys()
+    }
+
+    /// Extract the certified skeleton of the map, which is the labels of the keys and the
+    /// hashes of the values. See [`Skeleton`].
+    pub fn skeleton(&self) -> Skeleton {
+        let tree = self.inner.owned_keys();
+        Skeleton {
+            root_hash: tree.reconstruct(),
+            tree,
+        }
+    }
+
+    /// Returns a witness for the key-value pairs in the specified range.
+    /// The resulting tree contains both keys and values.
+    #[inline]
+    pub fn witness_value_range<Q1: ?Sized, Q2: ?Sized>(&self, first: &Q1, last: &Q2) -> HashTree<'_>
+    where
+        K: Borrow<Q1> + Borrow<Q2>,
+        Q1: Ord,
+        Q2: Ord,
+    {
+        self.inner.value_range(first, last)
+    }
+
+    /// Returns a witness for the keys in the specified range.
+    /// The resulting tree only contains the keys, and the values are replaced with
+    /// "Pruned" nodes.
+    #[inline]
+    pub fn witness_key_range<Q1: ?Sized, Q2: ?Sized>(&self, first: &Q1, last: &Q2) -> HashTree<'_>
+    where
+        K: Borrow<Q1> + Borrow<Q2>,
+        Q1: Ord,
+        Q2: Ord,
+    {
+        self.inner.key_range(first, last)
+    }
+
+    /// Returns a witness for the `n` entries with the smallest keys, along with the proof
+    /// that there are no smaller keys in the map. `n` is clamped to the length of the map.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::hashtree::LookupResult;
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let map = (0..10u32).map(|i| (i, i * 10)).collect::<Map<u32, u32>>();
+    /// let witness = map.witness_first_n(3);
+    ///
+    /// assert_eq!(witness.reconstruct(), map.root_hash());
+    /// assert_eq!(
+    ///     witness.get_labels(),
+    ///     vec![&0u32.to_be_bytes(), &1u32.to_be_bytes(), &2u32.to_be_bytes()]
+    /// );
+    /// ```
+    #[inline]
+    pub fn witness_first_n(&self, n: usize) -> HashTree<'_> {
+        self.inner.first_n(n)
+    }
+
+    /// Returns a witness for the `n` entries with the largest keys, along with the proof
+    /// that there are no larger keys in the map. `n` is clamped to the length of the map.
+    #[inline]
+    pub fn witness_last_n(&self, n: usize) -> HashTree<'_> {
+        self.inner.last_n(n)
+    }
+
+    /// Returns a witness for the keys with the given prefix, this replaces the values with
+    /// "Pruned" nodes.
+    #[inline]
+    pub fn witness_keys_with_prefix<P: ?Sized>(&self, prefix: &P) -> HashTree<'_>
+    where
+        K: Prefix<P>,
+        P: Ord,
+    {
+        self.inner.keys_with_prefix(prefix)
+    }
+
+    /// Return the underlying [`RbTree`] for this map.
+    #[inline]
+    pub fn as_tree(&self) -> &RbTree<K, V> {
+        &self.inner
     }
 }
 
-impl<K: 'static + Label, V: AsHashTree + 'static> CandidType for Map<K, V>
-where
-    K: CandidType,
-    V: CandidType,
-{
-    fn _ty() -> Type {
-        let tuple = Type::Record(vec![
-            Field {
-                id: CLabel::Id(0),
-                ty: K::ty(),
-            },
-            Field {
-                id: CLabel::Id(1),
-                ty: V::ty(),
-            },
-        ]);
-        Type::Vec(Box::new(tuple))
+impl<K: 'static + Label, V: AsHashTree + 'static> Map<K, V> {
+    /// Turn this map into an immutable [`FrozenMap`] that can be cheaply cloned and
+    /// shared between readers.
+    #[inline]
+    pub fn freeze(self) -> FrozenMap<K, V> {
+        FrozenMap {
+            inner: Arc::new(self.inner),
+            max_key_len: self.max_key_len,
+            normalized: self.normalized.map(Arc::new),
+        }
     }
 
-    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    /// Start a read epoch, the returned handle is a consistent view of the map as it is
+    /// now, and it is not affected by the writes to the map after this call. So a canister
+    /// can serve a series of reads, and the witnesses of these reads, from the same state
+    /// while it keeps accepting writes.
+    ///
+    /// The view is an [`Arc`] shared snapshot like a [`FrozenMap`], cloning the handle
+    /// is cheap but starting an epoch copies the entire tree once. The nodes are not
+    /// shared between the map and the view.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::collections::map::MapRead;
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let mut map = Map::<String, u32>::new();
+    /// map.insert("a".into(), 1);
+    ///
+    /// let epoch = map.begin_epoch();
+    /// let root_hash = map.root_hash();
+    ///
+    /// map.insert("a".into(), 2);
+    /// map.insert("b".into(), 3);
+    ///
+    /// assert!(!epoch.is_current(&map));
+    /// assert_eq!(epoch.get("a"), Some(&1));
+    /// assert_eq!(epoch.get("b"), None);
+    /// assert_eq!(epoch.witness("a").reconstruct(), root_hash);
+    /// ```
+    pub fn begin_epoch(&self) -> Epoch<K, V>
     where
-        S: candid::types::Serializer,
+        K: Clone,
+        V: Clone,
     {
-        let mut ser = serializer.serialize_vec(self.len())?;
-        for e in self.iter() {
-            Compound::serialize_element(&mut ser, &e)?;
+        Epoch {
+            snapshot: FrozenMap {
+                inner: Arc::new(self.inner.clone()),
+                max_key_len: self.max_key_len,
+                normalized: self.normalized.clone().map(Arc::new),
+            },
+            generation: self.generation(),
         }
-        Ok(())
     }
 }
 
-impl<K: 'static + Label, V: AsHashTree + 'static> FromIterator<(K, V)> for Map<K, V> {
-    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
-        let mut result = Map::new();
+/// The read-only operations of a certified map, implemented by both [`Map`] and
+/// [`FrozenMap`] in terms of the underlying [`RbTree`].
+pub trait MapRead<K: 'static + Label, V: AsHashTree + 'static> {
+    /// Return the underlying [`RbTree`] for this map.
+    fn as_tree(&self) -> &RbTree<K, V>;
 
-        for (key, value) in iter {
-            result.insert(key, value);
+    /// Returns `true` if the map does not contain any values.
+    #[inline]
+    fn is_empty(&self) -> bool {
+        self.as_tree().is_empty()
+    }
+
+    /// Returns the number of elements in the map.
+    #[inline]
+    fn len(&self) -> usize {
+        self.as_tree().len()
+    }
+
+    /// Return the value associated with the given key.
+    #[inline]
+    fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        self.as_tree().get(key)
+    }
+
+    /// Return an iterator over the key-values in the map.
+    #[inline]
+    fn iter(&self) -> RbTreeIterator<'_, K, V> {
+        RbTreeIterator::new(self.as_tree())
+    }
+
+    /// Create a HashTree witness for the value associated with given key.
+    #[inline]
+    fn witness<Q>(&self, key: &Q) -> HashTree<'_>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        self.as_tree().witness(key)
+    }
+
+    /// Returns a witness enumerating all the keys in this map.  The
+    /// resulting tree doesn't include values, they are replaced with
+    /// "Pruned" nodes.
+    #[inline]
+    fn witness_keys(&self) -> HashTree<'_> {
+        self.as_tree().keys()
+    }
+
+    /// Returns a witness for the key-value pairs in the specified range.
+    /// The resulting tree contains both keys and values.
+    #[inline]
+    fn witness_value_range<Q1, Q2>(&self, first: &Q1, last: &Q2) -> HashTree<'_>
+    where
+        K: Borrow<Q1> + Borrow<Q2>,
+        Q1: ?Sized + Ord,
+        Q2: ?Sized + Ord,
+    {
+        self.as_tree().value_range(first, last)
+    }
+
+    /// Returns a witness for the keys in the specified range.
+    /// The resulting tree only contains the keys, and the values are replaced with
+    /// "Pruned" nodes.
+    #[inline]
+    fn witness_key_range<Q1, Q2>(&self, first: &Q1, last: &Q2) -> HashTree<'_>
+    where
+        K: Borrow<Q1> + Borrow<Q2>,
+        Q1: ?Sized + Ord,
+        Q2: ?Sized + Ord,
+    {
+        self.as_tree().key_range(first, last)
+    }
+
+    /// Returns a witness for the keys with the given prefix, this replaces the values with
+    /// "Pruned" nodes.
+    #[inline]
+    fn witness_keys_with_prefix<P>(&self, prefix: &P) -> HashTree<'_>
+    where
+        K: Prefix<P>,
+        P: ?Sized + Ord,
+    {
+        self.as_tree().keys_with_prefix(prefix)
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> MapRead<K, V> for Map<K, V> {
+    #[inline]
+    fn as_tree(&self) -> &RbTree<K, V> {
+        &self.inner
+    }
+}
+
+/// An immutable snapshot of a [`Map`] behind an [`Arc`], cloning it only increments
+/// the reference count. The read operations are provided by the [`MapRead`] trait.
+pub struct FrozenMap<K: 'static + Label, V: AsHashTree + 'static> {
+    inner: Arc<RbTree<K, V>>,
+    max_key_len: Option<usize>,
+    normalized: Option<Arc<NormalizedIndex<K>>>,
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> FrozenMap<K, V> {
+    /// Turn this snapshot back into a mutable [`Map`]. If this is the only reference
+    /// to the snapshot the tree is moved out of it, otherwise it is cloned.
+    pub fn thaw(self) -> Map<K, V>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let inner = Arc::try_unwrap(self.inner).unwrap_or_else(|shared| (*shared).clone());
+        Map {
+            inner,
+            max_key_len: self.max_key_len,
+            normalized: self
+                .normalized
+                .map(|index| Arc::try_unwrap(index).unwrap_or_else(|shared| (*shared).clone())),
         }
+    }
+}
 
-        result
+impl<K: 'static + Label, V: AsHashTree + 'static> MapRead<K, V> for FrozenMap<K, V> {
+    #[inline]
+    fn as_tree(&self) -> &RbTree<K, V> {
+        &self.inner
     }
 }
 
-impl<K: 'static + Label, V: AsHashTree + 'static> Debug for Map<K, V>
+impl<K: 'static + Label, V: AsHashTree + 'static> Clone for FrozenMap<K, V> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            max_key_len: self.max_key_len,
+            normalized: self.normalized.clone(),
+        }
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> Debug for FrozenMap<K, V>
 where
     K: Debug,
     V: Debug,
@@ -316,7 +1524,7 @@ where
     }
 }
 
-impl<K: 'static + Label, V: AsHashTree + 'static> AsHashTree for Map<K, V> {
+impl<K: 'static + Label, V: AsHashTree + 'static> AsHashTree for FrozenMap<K, V> {
     #[inline]
     fn root_hash(&self) -> Hash {
         self.inner.root_hash()
@@ -328,60 +1536,1907 @@ impl<K: 'static + Label, V: AsHashTree + 'static> AsHashTree for Map<K, V> {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// A mutable reference to a value in a [`Map`], see [`Map::get_mut`].
+pub type MapValueGuard<'a, K, V> = ValueGuard<'a, K, V>;
 
-    #[test]
-    fn insert() {
-        let mut map = Map::<String, u32>::new();
-        assert_eq!(map.insert("A".into(), 0), None);
-        assert_eq!(map.insert("A".into(), 1), Some(0));
-        assert_eq!(map.insert("B".into(), 2), None);
-        assert_eq!(map.insert("C".into(), 3), None);
-        assert_eq!(map.insert("B".into(), 4), Some(2));
-        assert_eq!(map.insert("C".into(), 5), Some(3));
-        assert_eq!(map.insert("B".into(), 6), Some(4));
-        assert_eq!(map.insert("C".into(), 7), Some(5));
-        assert_eq!(map.insert("A".into(), 8), Some(1));
+/// The label of the subtree containing the entries of an [`Annotated`] map.
+const ENTRIES_LABEL: &[u8] = b"entries";
 
-        assert_eq!(map.get("A"), Some(&8));
-        assert_eq!(map.get("B"), Some(&6));
-        assert_eq!(map.get("C"), Some(&7));
-        assert_eq!(map.get("D"), None);
-    }
+/// The label of the leaf containing the length of a counted map.
+const LEN_LABEL: &[u8] = b"len";
 
-    #[test]
-    fn remove() {
-        let mut map = Map::<String, u32>::new();
+/// The label of the leaf containing the generation of a versioned map.
+const VERSION_LABEL: &[u8] = b"version";
 
-        for i in 0..200u32 {
-            map.insert(hex::encode(&i.to_be_bytes()), i);
-        }
+/// A [`Map`] with an annotated root, see [`Map::counted`] and [`Map::versioned`]. The root
+/// hash of this view commits to the entries of the map and to the annotations that are
+/// enabled on the view:
+///
+/// - In the counted mode the root also commits to the number of entries, so a client
+///   reading a range of the map can also check how many entries the map has in total.
+/// - In the versioned mode the root also commits to the generation of the map, which is
+///   incremented on every modification of the map. So a client can poll the small
+///   witness of [`Map::witness_version`] and only fetch the data again once the version
+///   changes.
+///
+/// Both modes can be enabled together, with `map.counted().versioned()`.
+///
+/// The modes are opt-in: a canister that uses them has to certify the root hash of this
+/// view instead of the root hash of the map, and every witness it returns has to be put
+/// under the annotated root using [`Annotated::annotate`]. The root hash of the view is
+/// different from the root hash of the map, so switching a certified map to an annotated
+/// mode changes its certified root hash even though the entries stay the same.
+///
+/// The generation is not persisted: it is not part of the serialized form of a map, and
+/// it is reset when the map is deserialized, for example after an upgrade. So the version
+/// of a restored map is usually smaller than before, and a client has to treat any change
+/// of the version as a change of the map, and not only an increase.
+///
+/// # Example
+///
+/// ```
+/// use certified_vars::hashtree::LookupResult;
+/// use certified_vars::{AsHashTree, Map};
+///
+/// let mut map = Map::<String, u32>::new();
+/// map.insert("a".into(), 1);
+/// map.insert("b".into(), 2);
+///
+/// let root_hash = map.counted().root_hash();
+/// let witness = map.witness_with_len("a");
+/// assert_eq!(witness.reconstruct(), root_hash);
+/// assert_eq!(
+///     witness.lookup_path(&[b"entries", b"a"]),
+///     LookupResult::Found(&1u32.to_be_bytes())
+/// );
+/// assert_eq!(
+///     witness.lookup_path(&[b"len"]),
+///     LookupResult::Found(&2u64.to_be_bytes())
+/// );
+///
+/// let keys = map.counted().annotate(map.witness_keys());
+/// assert_eq!(keys.reconstruct(), root_hash);
+///
+/// let witness = map.witness_version();
+/// assert_eq!(witness.reconstruct(), map.versioned().root_hash());
+/// let version = match witness.lookup_path(&[b"version"]) {
+///     LookupResult::Found(version) => version.to_vec(),
+///     _ => unreachable!(),
+/// };
+///
+/// map.insert("c".into(), 3);
+/// let witness = map.witness_version();
+/// assert_ne!(
+///     witness.lookup_path(&[b"version"]),
+///     LookupResult::Found(&version)
+/// );
+/// ```
+///
+/// # Hash tree
+///
+/// The tree of the map is next to the leaves of the annotations, like the tree of a
+/// [`Seq`]. With both of the modes:
+///
+/// ```text
+/// ─┬╴"entries" ──╴M
+///  └─┬╴"len" ──╴Leaf(n)
+///    └╴"version" ──╴Leaf(g)
+/// ```
+///
+/// Where `M` is the hash tree of the map, `n` is the number of its entries and `g` is its
+/// generation, both encoded as a big endian `u64`. With only one of the modes the fork of
+/// the annotations is replaced by the single annotation.
+pub struct Annotated<'a, K: 'static + Label, V: AsHashTree + 'static> {
+    map: &'a Map<K, V>,
+    len: bool,
+    version: bool,
+}
 
-        for i in 0..200u32 {
-            assert_eq!(map.remove(&hex::encode(&i.to_be_bytes())), Some(i));
-        }
+impl<'a, K: 'static + Label, V: AsHashTree + 'static> Annotated<'a, K, V> {
+    /// Enable the counted mode on this view.
+    #[inline]
+    pub fn counted(self) -> Self {
+        Self { len: true, ..self }
+    }
 
-        for i in 0..200u32 {
-            assert_eq!(map.get(&hex::encode(&i.to_be_bytes())), None);
+    /// Enable the versioned mode on this view.
+    #[inline]
+    pub fn versioned(self) -> Self {
+        Self {
+            version: true,
+            ..self
         }
     }
 
-    #[test]
-    fn remove_rev() {
-        let mut map = Map::<String, u32>::new();
+    /// Returns the number of entries in the map.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
 
-        for i in 0..200u32 {
-            map.insert(hex::encode(&i.to_be_bytes()), i);
-        }
+    /// Returns `true` if the map is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
 
-        for i in (0..200u32).rev() {
-            assert_eq!(map.remove(&hex::encode(&i.to_be_bytes())), Some(i));
-        }
+    /// Returns the current version of the map, which is its generation.
+    #[inline]
+    pub fn version(&self) -> u64 {
+        self.map.generation()
+    }
 
-        for i in 0..200u32 {
-            assert_eq!(map.get(&hex::encode(&i.to_be_bytes())), None);
+    /// Returns a witness for the given key along with the annotations of the map.
+    #[inline]
+    pub fn witness<Q>(&self, key: &Q) -> HashTree<'a>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        self.annotate(self.map.witness(key))
+    }
+
+    /// Returns a witness that only proves the annotations of the map, with all of the
+    /// entries pruned.
+    #[inline]
+    pub fn witness_version(&self) -> HashTree<'static> {
+        self.annotate(HashTree::Pruned(self.map.root_hash()))
+    }
+
+    /// Put the given witness of the map under the annotated root, the result reconstructs
+    /// to the root hash of this view if the given tree reconstructs to the root hash of
+    /// the map.
+    pub fn annotate<'b>(&self, tree: HashTree<'b>) -> HashTree<'b> {
+        let entries = labeled(ENTRIES_LABEL, tree);
+        let annotations = self
+            .annotations()
+            .map(|(label, n)| labeled(label, HashTree::Leaf(Cow::Owned(n.to_be_bytes().to_vec()))))
+            .rev()
+            .reduce(|right, left| fork(left, right));
+
+        match annotations {
+            Some(annotations) => fork(entries, annotations),
+            None => entries,
         }
     }
+
+    /// Returns the enabled annotations in the order of their labels.
+    fn annotations(&self) -> impl DoubleEndedIterator<Item = (&'static [u8], u64)> {
+        let len = (LEN_LABEL, self.len() as u64);
+        let version = (VERSION_LABEL, self.version());
+        std::iter::empty()
+            .chain(Some(len).filter(|_| self.len))
+            .chain(Some(version).filter(|_| self.version))
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> Clone for Annotated<'_, K, V> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> Copy for Annotated<'_, K, V> {}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> AsHashTree for Annotated<'_, K, V> {
+    fn root_hash(&self) -> Hash {
+        let entries = labeled_hash(ENTRIES_LABEL, &self.map.root_hash());
+        let annotations = self
+            .annotations()
+            .map(|(label, n)| labeled_hash(label, &leaf_hash(&n.to_be_bytes())))
+            .rev()
+            .reduce(|right, left| fork_hash(&left, &right));
+
+        match annotations {
+            Some(annotations) => fork_hash(&entries, &annotations),
+            None => entries,
+        }
+    }
+
+    #[inline]
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        self.annotate(self.map.as_hash_tree())
+    }
+}
+
+/// A consistent read view of a [`Map`] at the start of an epoch, see [`Map::begin_epoch`].
+/// The read operations are provided by the [`MapRead`] trait.
+pub struct Epoch<K: 'static + Label, V: AsHashTree + 'static> {
+    snapshot: FrozenMap<K, V>,
+    generation: u64,
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> Epoch<K, V> {
+    /// Returns the generation of the map at the start of the epoch, the changes since then
+    /// can be found using [`Map::frontier`].
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns `true` if the map did not change since the start of the epoch, in which case
+    /// the view has the same root hash as the map.
+    #[inline]
+    pub fn is_current(&self, map: &Map<K, V>) -> bool {
+        map.generation() == self.generation
+    }
+
+    /// Returns the snapshot of the map at the start of the epoch.
+    #[inline]
+    pub fn snapshot(&self) -> &FrozenMap<K, V> {
+        &self.snapshot
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> MapRead<K, V> for Epoch<K, V> {
+    #[inline]
+    fn as_tree(&self) -> &RbTree<K, V> {
+        self.snapshot.as_tree()
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> Clone for Epoch<K, V> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self {
+            snapshot: self.snapshot.clone(),
+            generation: self.generation,
+        }
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> AsHashTree for Epoch<K, V> {
+    #[inline]
+    fn root_hash(&self) -> Hash {
+        self.snapshot.root_hash()
+    }
+
+    #[inline]
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        self.snapshot.as_hash_tree()
+    }
+}
+
+/// The result of [`Map::insert_if_changed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertResult<V> {
+    /// The key was not in the map, and it is inserted.
+    Inserted,
+    /// The key was associated with a value with a different hash, the previous value is
+    /// replaced and it is returned.
+    Changed(V),
+    /// The key was associated with a value with the same hash, the map is not modified and
+    /// the given value is returned.
+    Unchanged(V),
+}
+
+impl<V> InsertResult<V> {
+    /// Returns `true` if the root hash of the map has changed.
+    #[inline]
+    pub fn is_changed(&self) -> bool {
+        !matches!(self, InsertResult::Unchanged(_))
+    }
+}
+
+/// The changes of a [`Map`] since a given generation, see [`Map::frontier`].
+#[derive(Debug)]
+pub struct SyncDelta<'a, K> {
+    /// The generation of the map when the delta was computed, the client should use it as
+    /// the starting point of its next sync.
+    pub generation: u64,
+    /// The keys that were inserted or modified since the given generation, in ascending
+    /// order.
+    pub keys: Vec<&'a K>,
+    /// Whether any key was removed since the given generation.
+    pub removed: bool,
+    /// A witness which reveals the values of the changed keys.
+    pub witness: HashTree<'a>,
+}
+
+/// An iterator over the entries of a [`Map`] with the keys decoded as a `T`, see
+/// [`Map::view_keys_as`].
+pub struct KeysAs<'a, K: 'static + Label, V: AsHashTree + 'static, T> {
+    inner: RbTreeIterator<'a, K, V>,
+    _marker: PhantomData<T>,
+}
+
+impl<'a, K: 'static + Label, V: AsHashTree + 'static, T: KeyCodec> Iterator
+    for KeysAs<'a, K, V, T>
+{
+    type Item = (Option<T>, &'a V);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = self.inner.next()?;
+        Some((T::from_label(&key.as_label()), value))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static, T: KeyCodec> ExactSizeIterator
+    for KeysAs<'_, K, V, T>
+{
+}
+
+/// A pagination cursor for a [`Map`], it contains the label of the last key that was seen
+/// and the generation of the map at the time. See [`Map::iter_from_cursor`].
+///
+/// The cursor does not borrow the map and only contains plain data, so it can be sent to
+/// a client and be passed back to request the next page.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, CandidType)]
+pub struct Cursor {
+    /// The label of the last key that was seen.
+    #[serde(with = "serde_bytes")]
+    pub key: Vec<u8>,
+    /// The generation of the map when the cursor was created.
+    pub generation: u64,
+}
+
+/// The certified structure of a [`Map`] without its values, it contains the label of every
+/// key and the hash of every value.
+///
+/// A client that only verifies the witnesses of a map can store its skeleton instead of
+/// a full copy of the map.
+///
+/// # Example
+///
+/// ```
+/// use certified_vars::Map;
+///
+/// let mut map = Map::<String, String>::new();
+/// map.insert("a".into(), "a very long value".into());
+///
+/// let skeleton = map.skeleton();
+/// assert!(skeleton.verify(&map.witness("a")));
+/// assert_eq!(skeleton.labels(), vec![b"a"]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Skeleton {
+    tree: HashTree<'static>,
+    root_hash: Hash,
+}
+
+impl Skeleton {
+    /// Returns the root hash of the map this skeleton was extracted from.
+    #[inline]
+    pub fn root_hash(&self) -> Hash {
+        self.root_hash
+    }
+
+    /// Returns the labels of the keys in the map, in ascending order.
+    #[inline]
+    pub fn labels(&self) -> Vec<&[u8]> {
+        self.tree.get_labels()
+    }
+
+    /// Returns the hash tree of the skeleton, where every value is pruned.
+    #[inline]
+    pub fn as_hash_tree(&self) -> &HashTree<'static> {
+        &self.tree
+    }
+
+    /// Returns `true` if the given witness is a witness of the map this skeleton was
+    /// extracted from.
+    #[inline]
+    pub fn verify(&self, witness: &HashTree) -> bool {
+        witness.reconstruct() == self.root_hash
+    }
+}
+
+/// The error type for the fallible operations on a [`Map`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapError {
+    /// The label of the key is longer than the maximum key length of the map.
+    KeyTooLong { len: usize, max: usize },
+    /// Another key with the given label is equal to the key once both are normalized.
+    NormalizedKeyConflict { existing: Vec<u8> },
+}
+
+impl Display for MapError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::KeyTooLong { len, max } => write!(
+                f,
+                "Key is {} bytes long, but the map only accepts keys of at most {} bytes",
+                len, max
+            ),
+            MapError::NormalizedKeyConflict { existing } => write!(
+                f,
+                "Key conflicts with the existing key 0x{} once normalized",
+                hex::encode(existing)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+/// The error returned by [`Map::try_witness`] when the witness does not fit in the given
+/// number of bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WitnessTooLarge {
+    /// The encoded size of the witness.
+    pub size: usize,
+    /// The maximum size that was requested.
+    pub max_bytes: usize,
+}
+
+impl Display for WitnessTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Witness is {} bytes long, but at most {} bytes are allowed, \
+             use witness_hash_only to leave out the value",
+            self.size, self.max_bytes
+        )
+    }
+}
+
+impl std::error::Error for WitnessTooLarge {}
+
+impl<K: 'static + Label, V: AsHashTree> Map<K, Seq<V>> {
+    /// Perform a [`Seq::append`] on the seq associated with the give value, if
+    /// the seq does not exists, creates an empty one and inserts it to the map.
+    pub fn append_deep(&mut self, key: K, value: V) {
+        let mut value = Some(value);
+
+        self.inner.modify(&key, |seq| {
+            seq.append(value.take().unwrap());
+        });
+
+        if let Some(value) = value.take() {
+            let mut seq = Seq::new();
+            seq.append(value);
+            self.inner.insert(key, seq);
+        }
+    }
+
+    #[inline]
+    pub fn len_deep<Q: ?Sized>(&mut self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: Ord,
+    {
+        self.inner.get(key).map(|seq| seq.len()).unwrap_or(0)
+    }
+}
+
+impl<K: 'static + Label, V: 'static + AsHashTree> AsRef<RbTree<K, V>> for Map<K, V> {
+    #[inline]
+    fn as_ref(&self) -> &RbTree<K, V> {
+        &self.inner
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> Serialize for Map<K, V>
+where
+    K: Serialize,
+    V: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_map(Some(self.len()))?;
+
+        for (key, value) in self.iter() {
+            s.serialize_entry(key, value)?;
+        }
+
+        s.end()
+    }
+}
+
+impl<'de, K: 'static + Label, V: AsHashTree + 'static> Deserialize<'de> for Map<K, V>
+where
+    K: Deserialize<'de>,
+    V: Deserialize<'de>,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_map(MapVisitor(PhantomData::default()))
+    }
+}
+
+struct MapVisitor<K, V>(PhantomData<(K, V)>);
+
+impl<'de, K: 'static + Label, V: AsHashTree + 'static> Visitor<'de> for MapVisitor<K, V>
+where
+    K: Deserialize<'de>,
+    V: Deserialize<'de>,
+{
+    type Value = Map<K, V>;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        write!(formatter, "expected a map")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut result = Map::new();
+
+        loop {
+            if let Some((key, value)) = map.next_entry::<K, V>()? {
+                result.insert(key, value);
+                continue;
+            }
+
+            break;
+        }
+
+        Ok(result)
+    }
+}
+
+/// A map with byte keys that borrow from the buffer it is deserialized from instead of
+/// being copied out of it, which halves the memory that is used by a large map with large
+/// keys that is loaded from a buffer and read for a bounded scope.
+///
+/// The map can not outlive the buffer, and it is dropped before the buffer is freed. The
+/// keys are only borrowed when the format can provide borrowed bytes, such as the byte
+/// strings of CBOR, and they are copied otherwise. The hash tree is the one of a [`Map`]
+/// with the same entries inserted in the same order.
+///
+/// # Example
+///
+/// ```
+/// use certified_vars::collections::map::BorrowedMap;
+/// use certified_vars::AsHashTree;
+/// use serde_bytes::ByteBuf;
+/// use std::collections::BTreeMap;
+///
+/// let mut entries = BTreeMap::new();
+/// entries.insert(ByteBuf::from(b"alice".to_vec()), 10u32);
+/// let buffer = serde_cbor::to_vec(&entries).unwrap();
+///
+/// let map: BorrowedMap<u32> = serde_cbor::from_slice(&buffer).unwrap();
+/// assert_eq!(map.get(b"alice"), Some(&10));
+/// assert_eq!(map.witness(b"alice").reconstruct(), map.root_hash());
+/// ```
+pub struct BorrowedMap<'de, V: AsHashTree + 'static> {
+    /// The keys of a map must be `'static`, so the borrowed keys are stored with an
+    /// extended lifetime. They are only handed out with the lifetime of `self`, which can
+    /// not be longer than `'de`.
+    inner: Map<Cow<'static, [u8]>, V>,
+    buffer: PhantomData<&'de [u8]>,
+}
+
+impl<'de, V: AsHashTree + 'static> BorrowedMap<'de, V> {
+    /// Create a new, empty map.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            inner: Map::new(),
+            buffer: PhantomData,
+        }
+    }
+
+    /// Returns `true` if the map does not contain any values.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Returns the number of elements in the map.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Insert a key-value pair into the map, and return the previous value of the key.
+    #[inline]
+    pub fn insert(&mut self, key: Cow<'de, [u8]>, value: V) -> Option<V> {
+        self.inner.insert(unsafe { extend_key(key) }, value)
+    }
+
+    /// Remove the value associated with the given key from the map, and return it.
+    #[inline]
+    pub fn remove(&mut self, key: &[u8]) -> Option<V> {
+        self.inner.remove(key)
+    }
+
+    /// Returns the value associated with the given key.
+    #[inline]
+    pub fn get(&self, key: &[u8]) -> Option<&V> {
+        self.inner.get(key)
+    }
+
+    /// Returns `true` if the map contains the given key.
+    #[inline]
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.inner.contains_key(key)
+    }
+
+    /// Returns an iterator over the entries of the map in the ascending order of the keys.
+    #[inline]
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = (&[u8], &V)> + '_ {
+        self.inner.iter().map(|(key, value)| (key.as_ref(), value))
+    }
+
+    /// Create a witness for the value associated with the given key, or a witness of its
+    /// absence, see [`Map::witness`].
+    #[inline]
+    pub fn witness(&self, key: &[u8]) -> HashTree<'_> {
+        self.inner.witness(key)
+    }
+}
+
+/// Extend the lifetime of the given key to `'static`.
+///
+/// # Safety
+///
+/// The key must not be used after the end of its original lifetime.
+unsafe fn extend_key(key: Cow<'_, [u8]>) -> Cow<'static, [u8]> {
+    match key {
+        Cow::Borrowed(bytes) => {
+            Cow::Borrowed(std::slice::from_raw_parts(bytes.as_ptr(), bytes.len()))
+        }
+        Cow::Owned(bytes) => Cow::Owned(bytes),
+    }
+}
+
+impl<V: AsHashTree + 'static> Default for BorrowedMap<'_, V> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V: AsHashTree + 'static> AsHashTree for BorrowedMap<'_, V> {
+    #[inline]
+    fn root_hash(&self) -> Hash {
+        self.inner.root_hash()
+    }
+
+    #[inline]
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        self.inner.as_hash_tree()
+    }
+}
+
+impl<V: AsHashTree + 'static> Debug for BorrowedMap<'_, V>
+where
+    V: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'de, V> Deserialize<'de> for BorrowedMap<'de, V>
+where
+    V: AsHashTree + Deserialize<'de> + 'static,
+{
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_map(BorrowedMapVisitor(PhantomData))
+    }
+}
+
+struct BorrowedMapVisitor<'de, V>(PhantomData<(&'de (), V)>);
+
+impl<'de, V> Visitor<'de> for BorrowedMapVisitor<'de, V>
+where
+    V: AsHashTree + Deserialize<'de> + 'static,
+{
+    type Value = BorrowedMap<'de, V>;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        write!(formatter, "expected a map")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut result = BorrowedMap::new();
+
+        while let Some(key) = map.next_key_seed(BorrowedKey)? {
+            let value = map.next_value::<V>()?;
+            result.insert(key, value);
+        }
+
+        Ok(result)
+    }
+}
+
+/// Deserialize the bytes of a key, borrowing them from the input when possible.
+struct BorrowedKey;
+
+impl<'de> DeserializeSeed<'de> for BorrowedKey {
+    type Value = Cow<'de, [u8]>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_bytes(self)
+    }
+}
+
+impl<'de> Visitor<'de> for BorrowedKey {
+    type Value = Cow<'de, [u8]>;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        write!(formatter, "expected bytes")
+    }
+
+    fn visit_borrowed_bytes<E>(self, v: &'de [u8]) -> Result<Self::Value, E> {
+        Ok(Cow::Borrowed(v))
+    }
+
+    fn visit_borrowed_str<E>(self, v: &'de str) -> Result<Self::Value, E> {
+        Ok(Cow::Borrowed(v.as_bytes()))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(Cow::Owned(v.to_vec()))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(Cow::Owned(v.as_bytes().to_vec()))
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(Cow::Owned(v))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(Cow::Owned(bytes))
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> CandidType for Map<K, V>
+where
+    K: CandidType,
+    V: CandidType,
+{
+    fn _ty() -> Type {
+        let tuple = Type::Record(vec![
+            Field {
+                id: CLabel::Id(0),
+                ty: K::ty(),
+            },
+            Field {
+                id: CLabel::Id(1),
+                ty: V::ty(),
+            },
+        ]);
+        Type::Vec(Box::new(tuple))
+    }
+
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: candid::types::Serializer,
+    {
+        let mut ser = serializer.serialize_vec(self.len())?;
+        for e in self.iter() {
+            Compound::serialize_element(&mut ser, &e)?;
+        }
+        Ok(())
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> Default for Map<K, V> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> FromIterator<(K, V)> for Map<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut result = Map::new();
+
+        for (key, value) in iter {
+            result.insert(key, value);
+        }
+
+        result
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> Debug for Map<K, V>
+where
+    K: Debug,
+    V: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> AsHashTree for Map<K, V> {
+    #[inline]
+    fn root_hash(&self) -> Hash {
+        self.inner.root_hash()
+    }
+
+    #[inline]
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        self.inner.as_hash_tree()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collections::expiring::Expiring;
+    use crate::hashtree::{ForkInner, LookupResult};
+    use candid::Principal;
+
+    #[test]
+    fn insert() {
+        let mut map = Map::<String, u32>::new();
+        assert_eq!(map.insert("A".into(), 0), None);
+        assert_eq!(map.insert("A".into(), 1), Some(0));
+        assert_eq!(map.insert("B".into(), 2), None);
+        assert_eq!(map.insert("C".into(), 3), None);
+        assert_eq!(map.insert("B".into(), 4), Some(2));
+        assert_eq!(map.insert("C".into(), 5), Some(3));
+        assert_eq!(map.insert("B".into(), 6), Some(4));
+        assert_eq!(map.insert("C".into(), 7), Some(5));
+        assert_eq!(map.insert("A".into(), 8), Some(1));
+
+        assert_eq!(map.get("A"), Some(&8));
+        assert_eq!(map.get("B"), Some(&6));
+        assert_eq!(map.get("C"), Some(&7));
+        assert_eq!(map.get("D"), None);
+
+        assert!(map.contains_key("A"));
+        assert!(map.contains_key("C"));
+        assert!(!map.contains_key("D"));
+        assert!(!map.contains_key(""));
+    }
+
+    #[test]
+    fn get_mut() {
+        let mut map = (0..20u32).map(|i| (i, i)).collect::<Map<u32, u32>>();
+        assert!(map.get_mut(&20).is_none());
+
+        let generation = map.generation();
+        {
+            let mut value = map.get_mut(&7).unwrap();
+            assert_eq!(*value.key(), 7);
+            *value += 100;
+        }
+        assert_eq!(map.get(&7), Some(&107));
+        assert_eq!(map.generation(), generation + 1);
+        assert_eq!(map.as_hash_tree().reconstruct(), map.root_hash());
+        assert_eq!(map.witness(&7).reconstruct(), map.root_hash());
+        map.assert_invariants();
+
+        // The map is rehashed even if the mutation panics midway.
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut value = map.get_mut(&3).unwrap();
+            *value = 1000;
+            panic!("Failure after the mutation.");
+        }));
+        assert!(result.is_err());
+        assert_eq!(map.get(&3), Some(&1000));
+        assert_eq!(map.as_hash_tree().reconstruct(), map.root_hash());
+    }
+
+    #[test]
+    fn insert_and_witness() {
+        let mut map = Map::<u32, u32>::new();
+        for i in 0..50u32 {
+            let (previous, witness) = map.insert_and_witness(i * 2, i);
+            assert_eq!(previous, None);
+            let witness = witness.into_owned();
+            assert_eq!(witness.reconstruct(), map.root_hash());
+            assert_eq!(witness, map.witness(&(i * 2)));
+        }
+
+        let (previous, witness) = map.insert_and_witness(10, 100);
+        assert_eq!(previous, Some(5));
+        assert_eq!(
+            witness.lookup_path(&[&10u32.to_be_bytes()]),
+            LookupResult::Found(&100u32.to_be_bytes())
+        );
+        let witness = witness.into_owned();
+        assert_eq!(witness.reconstruct(), map.root_hash());
+        assert_eq!(map.len(), 50);
+    }
+
+    #[test]
+    fn remove() {
+        let mut map = Map::<String, u32>::new();
+
+        for i in 0..200u32 {
+            map.insert(hex::encode(&i.to_be_bytes()), i);
+        }
+
+        for i in 0..200u32 {
+            assert_eq!(map.remove(&hex::encode(&i.to_be_bytes())), Some(i));
+            assert!(map.verify_len());
+        }
+
+        for i in 0..200u32 {
+            assert_eq!(map.get(&hex::encode(&i.to_be_bytes())), None);
+        }
+    }
+
+    #[test]
+    fn remove_rev() {
+        let mut map = Map::<String, u32>::new();
+
+        for i in 0..200u32 {
+            map.insert(hex::encode(&i.to_be_bytes()), i);
+        }
+
+        for i in (0..200u32).rev() {
+            assert_eq!(map.remove(&hex::encode(&i.to_be_bytes())), Some(i));
+        }
+
+        for i in 0..200u32 {
+            assert_eq!(map.get(&hex::encode(&i.to_be_bytes())), None);
+        }
+    }
+
+    #[test]
+    fn frontier() {
+        let mut map = Map::<String, u32>::new();
+        for i in 0..50u32 {
+            map.insert(hex::encode(i.to_be_bytes()), i);
+        }
+
+        let synced = map.generation();
+        map.insert(hex::encode(3u32.to_be_bytes()), 0);
+        map.remove(&hex::encode(7u32.to_be_bytes()));
+
+        let delta = map.frontier(synced);
+        assert_eq!(delta.generation, map.generation());
+        assert_eq!(delta.keys, vec![&hex::encode(3u32.to_be_bytes())]);
+        assert!(delta.removed);
+        assert_eq!(delta.witness.reconstruct(), map.root_hash());
+        assert_eq!(delta.witness.get_leaf_values().len(), 1);
+
+        let delta = map.frontier(map.generation());
+        assert!(delta.keys.is_empty());
+        assert!(!delta.removed);
+        assert_eq!(delta.witness.reconstruct(), map.root_hash());
+
+        // A generation that is ahead of the map reports every entry.
+        let delta = map.frontier(map.generation() + 1);
+        assert_eq!(delta.keys.len(), map.len());
+        assert!(delta.removed);
+        assert_eq!(delta.witness, map.as_hash_tree());
+    }
+
+    #[test]
+    fn skeleton() {
+        let mut map = Map::<String, u32>::new();
+        for i in 0..20u32 {
+            map.insert(hex::encode(i.to_be_bytes()), i);
+        }
+
+        let skeleton = map.skeleton();
+        assert_eq!(skeleton.root_hash(), map.root_hash());
+        assert_eq!(skeleton.as_hash_tree(), &map.witness_keys());
+        assert_eq!(skeleton.labels().len(), 20);
+        assert!(skeleton.verify(&map.witness(&hex::encode(3u32.to_be_bytes()))));
+        assert!(skeleton.verify(&map.witness("missing")));
+
+        drop(map);
+        let mut other = Map::<String, u32>::new();
+        other.insert("a".into(), 0);
+        assert!(!skeleton.verify(&other.witness("a")));
+    }
+
+    #[test]
+    fn certified_batch() {
+        let mut map = Map::<u32, Vec<u8>>::new();
+        for i in 0..200 {
+            map.insert(i * 2, vec![i as u8; 64]);
+        }
+
+        let keys = (0..60).map(|i| i * 7).collect::<Vec<_>>();
+        let batch = map.certified_batch(&keys, 1024);
+        assert!(batch.len() > 2);
+
+        for tree in &batch {
+            assert_eq!(tree.reconstruct(), map.root_hash());
+            assert!(tree.encoded_size() <= 1024);
+        }
+
+        // Each of the keys is resolved by one of the witnesses.
+        for key in &keys {
+            let label = key.to_be_bytes();
+            let found = batch
+                .iter()
+                .map(|tree| tree.lookup_path(&[&label]))
+                .find(|r| !matches!(r, LookupResult::Unknown));
+            match map.get(key) {
+                Some(value) => assert_eq!(found, Some(LookupResult::Found(value))),
+                None => assert_eq!(found, Some(LookupResult::Absent)),
+            }
+        }
+
+        let merged = batch.into_iter().reduce(HashTree::merge).unwrap();
+        assert_eq!(merged, map.witness_many(&keys));
+
+        assert_eq!(
+            map.certified_batch::<u32>(&[], 1024),
+            vec![HashTree::Pruned(map.root_hash())]
+        );
+    }
+
+    #[test]
+    fn witness_hash_only() {
+        /// What a client does with the witness: replace the pruned value under the label
+        /// with its own copy of the value, the result only reconstructs to the certified
+        /// root hash if the copy is the value in the map.
+        fn with_value<'a>(tree: HashTree<'a>, label: &[u8], value: HashTree<'a>) -> HashTree<'a> {
+            match tree {
+                HashTree::Fork(children) => {
+                    let ForkInner(l, r) = *children;
+                    let l = with_value(l, label, value.clone());
+                    HashTree::Fork(Box::new(ForkInner(l, with_value(r, label, value))))
+                }
+                HashTree::Labeled(l, t) if l.as_ref() == label => match *t {
+                    HashTree::Pruned(_) => HashTree::Labeled(l, Box::new(value)),
+                    t => HashTree::Labeled(l, Box::new(t)),
+                },
+                HashTree::Labeled(l, t) => {
+                    HashTree::Labeled(l, Box::new(with_value(*t, label, value)))
+                }
+                tree => tree,
+            }
+        }
+
+        let mut map = Map::<String, u64>::new();
+        for i in 0..20u64 {
+            map.insert(format!("nonce-{}", i), i * 3);
+        }
+        let root = map.root_hash();
+
+        let witness = map.witness_hash_only("nonce-5");
+        assert_eq!(witness.reconstruct(), root);
+        assert_eq!(witness.get_leaf_values(), Vec::<&[u8]>::new());
+        assert_eq!(witness.lookup_path(&[b"nonce-5"]), LookupResult::Unknown);
+
+        // The client knows the value it expects.
+        let expected = 15u64;
+        let tree = with_value(witness.clone(), b"nonce-5", expected.as_hash_tree());
+        assert_eq!(tree.reconstruct(), root);
+        assert_eq!(tree, map.witness("nonce-5"));
+        assert_eq!(
+            tree.lookup_path(&[b"nonce-5"]),
+            LookupResult::Found(&expected.to_be_bytes())
+        );
+
+        // A stale copy does not verify.
+        let stale = 12u64;
+        let tree = with_value(witness, b"nonce-5", stale.as_hash_tree());
+        assert_ne!(tree.reconstruct(), root);
+
+        // A missing key is proven to be absent.
+        let witness = map.witness_hash_only("nonce-50");
+        assert_eq!(witness.reconstruct(), root);
+        assert_eq!(witness, map.witness("nonce-50"));
+        assert_eq!(witness.lookup_path(&[b"nonce-50"]), LookupResult::Absent);
+    }
+
+    #[test]
+    fn try_witness() {
+        let mut map = Map::<u32, Vec<u8>>::new();
+        for i in 0..100u32 {
+            map.insert(i, vec![1; i as usize * 20]);
+        }
+
+        for key in [0, 1, 50, 99, 100] {
+            let size = map.witness(&key).encoded_size();
+            assert_eq!(map.try_witness(&key, size), Ok(map.witness(&key)));
+            assert_eq!(
+                map.try_witness(&key, size - 1),
+                Err(WitnessTooLarge {
+                    size,
+                    max_bytes: size - 1
+                })
+            );
+        }
+
+        let error = map.try_witness(&99, 100).unwrap_err();
+        assert!(error.to_string().contains("witness_hash_only"));
+    }
+
+    #[test]
+    fn view_keys_as() {
+        let mut map = Map::<Vec<u8>, u32>::new();
+        for (i, id) in [[1u8, 2], [3, 4], [0, 0]].iter().enumerate() {
+            let key = (Principal::from_slice(id), i as u64);
+            map.insert(key.as_label().into_owned(), i as u32);
+        }
+        map.insert(vec![1], 10);
+
+        let view = map.view_keys_as::<(Principal, u64)>();
+        assert_eq!(view.size_hint(), (4, Some(4)));
+        assert_eq!(
+            view.collect::<Vec<_>>(),
+            vec![
+                (None, &10),
+                (Some((Principal::from_slice(&[0, 0]), 2)), &2),
+                (Some((Principal::from_slice(&[1, 2]), 0)), &0),
+                (Some((Principal::from_slice(&[3, 4]), 1)), &1),
+            ]
+        );
+    }
+
+    #[test]
+    fn cursor() {
+        let mut map = Map::<u32, u32>::new();
+        for i in 0..100 {
+            map.insert(i * 2, i);
+        }
+
+        // Paginate over the entire map while it is being modified.
+        let mut seen = Vec::new();
+        let mut cursor: Option<Cursor> = None;
+        loop {
+            let page = match &cursor {
+                Some(cursor) => map.iter_from_cursor(cursor).take(7).collect::<Vec<_>>(),
+                None => map.iter().take(7).collect::<Vec<_>>(),
+            };
+            let last = match page.last() {
+                Some((k, _)) => **k,
+                None => break,
+            };
+            seen.extend(page.iter().map(|(k, _)| **k));
+            let next = map.cursor_after(&last);
+            assert!(!map.is_cursor_stale(&next));
+
+            // Insert a key before the cursor, and until the end of the map is reached
+            // insert a key right after the cursor and remove the one after it.
+            map.insert(last - 1, 0);
+            if last < 198 {
+                map.insert(last + 1, 0);
+                map.remove(&(last + 2));
+            }
+            assert!(map.is_cursor_stale(&next));
+            cursor = Some(next);
+        }
+
+        // Every page resumes right after the previous one.
+        assert!(seen.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(seen[..9], [0, 2, 4, 6, 8, 10, 12, 13, 16]);
+
+        let cursor = map.cursor_after(&u32::MAX);
+        assert_eq!(map.iter_from_cursor(&cursor).count(), 0);
+
+        let bytes = serde_cbor::to_vec(&cursor).unwrap();
+        assert_eq!(serde_cbor::from_slice::<Cursor>(&bytes).unwrap(), cursor);
+    }
+
+    #[test]
+    fn cursor_signed_keys() {
+        let mut map = Map::<i32, u32>::new();
+        for i in [-2, -1, 1, 2] {
+            map.insert(i, i.unsigned_abs());
+        }
+
+        let keys = |cursor| {
+            map.iter_from_cursor(&cursor)
+                .map(|(k, _)| *k)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys(map.cursor_after(&-2)), vec![-1, 1, 2]);
+        assert_eq!(keys(map.cursor_after(&-1)), vec![1, 2]);
+        assert_eq!(keys(map.cursor_after(&0)), vec![1, 2]);
+        assert_eq!(keys(map.cursor_after(&i32::MIN)), vec![-2, -1, 1, 2]);
+        assert_eq!(keys(map.cursor_after(&2)), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn default() {
+        // Neither the keys nor the values have to implement `Default`.
+        let map = Map::<Principal, Expiring<u32>>::default();
+        assert!(map.is_empty());
+        assert_eq!(map.max_key_len(), None);
+        assert_eq!(map.root_hash(), HashTree::Empty.reconstruct());
+        assert_eq!(
+            map.root_hash(),
+            Map::<Principal, Expiring<u32>>::new().root_hash()
+        );
+        assert_eq!(map.as_hash_tree(), HashTree::Empty);
+    }
+
+    #[test]
+    fn from_sorted_iter() {
+        for n in [0u32, 1, 2, 3, 7, 100, 1000] {
+            let map = Map::<u32, u32>::from_sorted_iter((0..n).map(|i| (i * 3, i)));
+            map.assert_invariants();
+            assert_eq!(map.len(), n as usize);
+            assert!(map
+                .iter()
+                .map(|(k, v)| (*k, *v))
+                .eq((0..n).map(|i| (i * 3, i))));
+            assert_eq!(map.as_hash_tree().reconstruct(), map.root_hash());
+
+            for i in 0..n {
+                assert_eq!(map.get(&(i * 3)), Some(&i));
+                assert_eq!(map.get(&(i * 3 + 1)), None);
+                assert_eq!(map.witness(&(i * 3 + 1)).reconstruct(), map.root_hash());
+            }
+        }
+
+        // An iterator without an exact length, such as a filter, is accepted.
+        let map =
+            Map::<u32, u32>::from_sorted_iter((0..100).filter(|i| i % 3 == 0).map(|i| (i, i)));
+        map.assert_invariants();
+        assert_eq!(map.len(), 34);
+
+        // The map can be used as usual after it is loaded.
+        let mut map = Map::<u32, u32>::from_sorted_iter((0..10).map(|i| (i, i)));
+        assert_eq!(map.generation(), 1);
+        map.insert(100, 0);
+        map.remove(&5);
+        map.assert_invariants();
+        assert_eq!(map.frontier(1).keys, vec![&100]);
+    }
+
+    #[test]
+    fn extend_sorted() {
+        let mut map = (0..100u32).map(|i| (i * 2, 0)).collect::<Map<u32, u32>>();
+        let generation = map.generation();
+        map.extend_sorted((50..150u32).map(|i| (i, i)));
+        map.assert_invariants();
+        assert_eq!(map.generation(), generation + 1);
+        assert_eq!(map.as_hash_tree().reconstruct(), map.root_hash());
+
+        let mut expected = std::collections::BTreeMap::new();
+        expected.extend((0..100u32).map(|i| (i * 2, 0)));
+        expected.extend((50..150u32).map(|i| (i, i)));
+        assert!(map.iter().map(|(k, v)| (*k, *v)).eq(expected.clone()));
+
+        // An unsorted batch, or one with a duplicate key, is inserted one by one.
+        map.extend_sorted(vec![(500, 1), (400, 1), (500, 2)]);
+        map.assert_invariants();
+        expected.extend(vec![(500, 1), (400, 1), (500, 2)]);
+        assert!(map.iter().map(|(k, v)| (*k, *v)).eq(expected));
+
+        map.extend_sorted(Vec::new());
+        assert_eq!(map.len(), 152);
+
+        // A small batch is inserted one by one, so the shape of the tree is unchanged.
+        let mut inserted = map.clone();
+        inserted.insert(1, 7);
+        inserted.insert(1000, 7);
+        map.extend_sorted(vec![(1, 7), (1000, 7)]);
+        map.assert_invariants();
+        assert_eq!(map.root_hash(), inserted.root_hash());
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "The entries are not sorted.")]
+    fn from_sorted_iter_unsorted() {
+        let _ = Map::<u32, u32>::from_sorted_iter(vec![(1, 0), (3, 0), (2, 0)]);
+    }
+
+    #[test]
+    fn signed_and_principal_keys() {
+        // The labels of signed integers and of principals of different lengths are in the
+        // order of the keys, so the witnesses are valid and prove the absence of a key.
+        let mut map = Map::<i32, u32>::new();
+        for i in [-2, -1, 1, 2] {
+            map.insert(i, i.unsigned_abs());
+        }
+        let witness = map.witness(&0);
+        assert_eq!(witness.validate(), Ok(()));
+        assert_eq!(witness.reconstruct(), map.root_hash());
+        assert_eq!(witness.lookup_path(&[&0.as_label()]), LookupResult::Absent);
+
+        for i in -10..10 {
+            map.insert(i, i.unsigned_abs());
+        }
+        for i in -12..12 {
+            let witness = map.witness(&i);
+            assert_eq!(witness.validate(), Ok(()));
+            assert_eq!(witness.reconstruct(), map.root_hash());
+        }
+        assert_eq!(map.as_hash_tree().validate(), Ok(()));
+        map.assert_invariants();
+
+        let mut map = Map::<Principal, u32>::new();
+        map.insert(Principal::from_slice(&[2]), 0);
+        map.insert(Principal::from_slice(&[1, 1]), 1);
+        map.insert(Principal::from_slice(&[0, 0, 0]), 2);
+        assert_eq!(map.as_hash_tree().validate(), Ok(()));
+        for (key, value) in map.iter() {
+            let witness = map.witness(key);
+            assert_eq!(witness.validate(), Ok(()));
+            assert_eq!(
+                witness.lookup_path(&[&key.as_label()]),
+                LookupResult::Found(&value.to_be_bytes())
+            );
+        }
+        let missing = Principal::from_slice(&[5, 5]);
+        assert_eq!(
+            map.witness(&missing).lookup_path(&[&missing.as_label()]),
+            LookupResult::Absent
+        );
+        map.assert_invariants();
+    }
+
+    #[test]
+    fn witness_path() {
+        let mut map = Map::<String, Seq<u32>>::new();
+        for i in 0..10u32 {
+            map.insert(i.to_string(), (0..i).collect());
+        }
+
+        let tree = map.witness_path("5", |seq| seq.witness_with_len(&[2]));
+        assert_eq!(tree.reconstruct(), map.root_hash());
+        assert_eq!(
+            tree.get_labels(),
+            vec![b"5" as &[u8], b"items", &2u64.to_be_bytes(), b"len"]
+        );
+
+        let mut called = false;
+        let tree = map.witness_path("missing", |seq| {
+            called = true;
+            seq.as_hash_tree()
+        });
+        assert!(!called);
+        assert_eq!(tree.reconstruct(), map.root_hash());
+        assert_eq!(tree, map.witness("missing"));
+    }
+
+    #[test]
+    fn modify_or_default() {
+        let mut map = Map::<String, u32>::new();
+        assert_eq!(map.modify("a", |v| *v += 1), None);
+        map.modify_or_default("a".into(), |v| *v += 1);
+        assert_eq!(map.modify_or_default("a".into(), |v| *v + 1), 2);
+        assert_eq!(map.modify("a", |v| std::mem::replace(v, 10)), Some(1));
+        assert_eq!(map.get("a"), Some(&10));
+        assert_eq!(map.root_hash(), map.as_hash_tree().reconstruct());
+    }
+
+    #[test]
+    fn insert_checked() {
+        let mut map = Map::<String, u32>::with_max_key_len(8).normalized_by(|k| k.to_lowercase());
+
+        assert_eq!(map.insert_checked("b@x".into(), 0), Ok(None));
+        assert_eq!(map.insert_checked("a@x".into(), 1), Ok(None));
+        assert_eq!(
+            map.insert_checked("A@x".into(), 2),
+            Err(MapError::NormalizedKeyConflict {
+                existing: b"a@x".to_vec()
+            })
+        );
+        assert_eq!(map.insert_checked("a@x".into(), 3), Ok(Some(1)));
+        assert_eq!(
+            map.insert_checked("long@example".into(), 4),
+            Err(MapError::KeyTooLong { len: 12, max: 8 })
+        );
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get("A@x"), None);
+
+        // A removed key does not conflict anymore.
+        map.remove("a@x");
+        assert_eq!(map.insert_checked("A@x".into(), 5), Ok(None));
+        assert!(map.insert_checked("a@x".into(), 6).is_err());
+
+        // The normalizer survives a freeze, and indexes the keys that are in the map.
+        let mut map = map.freeze().thaw();
+        assert!(map.insert_checked("B@X".into(), 7).is_err());
+        let mut map = map.clone();
+        map.insert("c@x".into(), 8);
+        let mut map = map.normalized_by(|k| k.to_lowercase());
+        assert!(map.insert_checked("C@x".into(), 9).is_err());
+
+        // Without a normalizer nothing conflicts.
+        let mut map = Map::<String, u32>::new();
+        assert_eq!(map.insert_checked("a@x".into(), 1), Ok(None));
+        assert_eq!(map.insert_checked("A@x".into(), 2), Ok(None));
+    }
+
+    #[test]
+    fn insert_checked_removals() {
+        fn indexed(map: &Map<String, u32>) -> usize {
+            map.normalized.as_ref().unwrap().keys.len()
+        }
+
+        let mut map = Map::<String, u32>::new().normalized_by(|k| k.to_lowercase());
+        for i in 0..10 {
+            map.insert_checked(format!("K{}", i), i).unwrap();
+        }
+        assert_eq!(indexed(&map), 10);
+
+        map.remove("K0");
+        map.remove_entry("K1");
+        assert_eq!(indexed(&map), 8);
+
+        map.retain(|_, v| *v != 2);
+        map.retain_delta(|_, v| *v != 3);
+        assert_eq!(indexed(&map), 6);
+
+        assert_eq!(
+            map.remove_range::<String, _>("K4".to_string().."K6".to_string()),
+            2
+        );
+        assert_eq!(indexed(&map), 4);
+
+        // The keys removed through an entry are dropped once the index is used.
+        if let Entry::Occupied(entry) = map.entry("K6".into()) {
+            entry.remove();
+        }
+        assert_eq!(map.insert_checked("k6".into(), 6), Ok(None));
+        assert_eq!(indexed(&map), 4);
+        assert!(map.insert_checked("K6".into(), 6).is_err());
+
+        map.clear();
+        assert_eq!(indexed(&map), 0);
+    }
+
+    #[test]
+    fn merge_with() {
+        let mut a = (0..10u32).map(|i| (i, i)).collect::<Map<_, _>>();
+        let b = (5..15u32).map(|i| (i, 100)).collect::<Map<_, _>>();
+
+        let hash = a.merge_with(b, |_, x, y| x + y);
+        assert_eq!(hash, a.root_hash());
+        assert_eq!(a.len(), 15);
+        assert_eq!(a.get(&4), Some(&4));
+        assert_eq!(a.get(&5), Some(&105));
+        assert_eq!(a.get(&14), Some(&100));
+        assert_eq!(hash, a.as_hash_tree().reconstruct());
+
+        assert_eq!(a.merge_with(Map::new(), |_, x, _| x), hash);
+
+        // A small map is merged one entry at a time, no entry is reported as removed.
+        let mut a = (0..1000u32).map(|i| (i, i)).collect::<Map<_, _>>();
+        let generation = a.generation();
+        let b = vec![(5, 100), (2000, 100)]
+            .into_iter()
+            .collect::<Map<u32, u32>>();
+        a.merge_with(b, |_, x, y| x + y);
+        a.assert_invariants();
+        assert_eq!(a.len(), 1001);
+        assert_eq!(a.get(&5), Some(&105));
+        assert_eq!(a.get(&2000), Some(&100));
+        assert!(!a.inner.removed_since(generation));
+    }
+
+    #[test]
+    fn merge_with_panic() {
+        for n in [10u32, 1000] {
+            let mut a = (0..n).map(|i| (i, i)).collect::<Map<_, _>>();
+            let generation = a.generation();
+            let b = (5..8u32).map(|i| (i, 100)).collect::<Map<_, _>>();
+
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                a.merge_with(b, |k, x, y| if *k == 6 { panic!() } else { x + y })
+            }));
+            assert!(result.is_err());
+
+            // Only the entry that was being resolved is lost.
+            a.assert_invariants();
+            assert_eq!(a.len(), n as usize - 1);
+            assert_eq!(a.get(&5), Some(&105));
+            assert_eq!(a.get(&6), None);
+            assert_eq!(a.get(&7), Some(&7));
+            assert_eq!(a.as_hash_tree().reconstruct(), a.root_hash());
+            assert!(a.inner.removed_since(generation));
+        }
+    }
+
+    #[test]
+    fn insert_if_changed() {
+        let mut map = Map::<String, Seq<u32>>::new();
+        let seq = |n: u32| (0..n).collect::<Seq<_>>();
+
+        assert_eq!(
+            map.insert_if_changed("a".into(), seq(2)),
+            InsertResult::Inserted
+        );
+        let (hash, generation) = (map.root_hash(), map.generation());
+
+        let result = map.insert_if_changed("a".into(), seq(2));
+        assert_eq!(result, InsertResult::Unchanged(seq(2)));
+        assert!(!result.is_changed());
+        assert_eq!(map.root_hash(), hash);
+        assert_eq!(map.generation(), generation);
+
+        let result = map.insert_if_changed("a".into(), seq(3));
+        assert_eq!(result, InsertResult::Changed(seq(2)));
+        assert!(result.is_changed());
+        assert_ne!(map.root_hash(), hash);
+        assert_eq!(map.get("a"), Some(&seq(3)));
+    }
+
+    #[test]
+    fn try_insert() {
+        let mut map = Map::<String, u32>::with_max_key_len(4);
+        assert_eq!(map.max_key_len(), Some(4));
+        assert_eq!(map.try_insert("abcd".into(), 0), Ok(None));
+        assert_eq!(map.try_insert("abcd".into(), 1), Ok(Some(0)));
+        assert_eq!(
+            map.try_insert("abcde".into(), 2),
+            Err(MapError::KeyTooLong { len: 5, max: 4 })
+        );
+        assert_eq!(map.get("abcde"), None);
+        assert_eq!(map.len(), 1);
+
+        // The bound survives a clear and a freeze.
+        map.clear();
+        let map = map.freeze().thaw();
+        assert_eq!(map.max_key_len(), Some(4));
+
+        let mut map = Map::<String, u32>::new();
+        assert_eq!(map.try_insert("abcde".into(), 2), Ok(None));
+    }
+
+    #[test]
+    fn assert_certifies() {
+        let mut map = Map::<String, Seq<u32>>::new();
+        for i in 0..20u32 {
+            map.insert(i.to_string(), (0..i).collect());
+        }
+
+        for i in 0..20u32 {
+            map.assert_certifies(&i.to_string(), &(0..i).collect());
+        }
+
+        let result = std::panic::catch_unwind(|| map.assert_certifies(&"3".into(), &Seq::new()));
+        assert!(result.is_err());
+        let result = std::panic::catch_unwind(|| map.assert_certifies(&"x".into(), &Seq::new()));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn witness_not_equal() {
+        let seq = |n: u32| (0..n).collect::<Seq<u32>>();
+        let mut map = Map::<String, Seq<u32>>::new();
+        map.insert("a".into(), seq(3));
+        map.insert("b".into(), seq(5));
+
+        // The full tree of the value is revealed, so the client can hash its own copy of
+        // the forbidden value and compare.
+        for (key, forbidden) in [("a", seq(4)), ("b", seq(5))] {
+            let witness = map.witness_not_equal(key, &forbidden);
+            assert_eq!(witness.reconstruct(), map.root_hash());
+
+            let value = match &witness {
+                HashTree::Fork(lr) => [lr.left(), lr.right()]
+                    .iter()
+                    .find_map(|node| match node {
+                        HashTree::Labeled(label, value) if label.as_ref() == key.as_bytes() => {
+                            Some(value.as_ref())
+                        }
+                        _ => None,
+                    })
+                    .unwrap(),
+                _ => panic!("Expected a fork."),
+            };
+            assert_eq!(value, &map.get(key).unwrap().as_hash_tree());
+            assert_eq!(value.reconstruct() == forbidden.root_hash(), key == "b");
+        }
+    }
+
+    #[test]
+    fn counted() {
+        let mut map = Map::<u32, u32>::new();
+        let mut roots = vec![];
+
+        for n in 0..20u32 {
+            let counted = map.counted();
+            assert_eq!(counted.len(), n as usize);
+            assert_eq!(counted.as_hash_tree().reconstruct(), counted.root_hash());
+            assert_ne!(counted.root_hash(), map.root_hash());
+
+            for key in [0, n / 2, n] {
+                let witness = map.witness_with_len(&key);
+                assert_eq!(witness.reconstruct(), counted.root_hash());
+                assert_eq!(
+                    witness.lookup_path(&[b"len"]),
+                    LookupResult::Found(&(n as u64).to_be_bytes())
+                );
+            }
+
+            let range = counted.annotate(map.witness_value_range(&0, &n));
+            assert_eq!(range.reconstruct(), counted.root_hash());
+
+            roots.push(counted.root_hash());
+            map.insert(n, n);
+        }
+
+        // The root hash of every length is different.
+        roots.sort_unstable();
+        roots.dedup();
+        assert_eq!(roots.len(), 20);
+    }
+
+    #[test]
+    fn versioned() {
+        let mut map = Map::<u32, u32>::new();
+        let mut versions = vec![];
+
+        for n in 0..10u32 {
+            let versioned = map.versioned();
+            assert_eq!(versioned.version(), map.generation());
+            assert_eq!(
+                versioned.as_hash_tree().reconstruct(),
+                versioned.root_hash()
+            );
+
+            let witness = map.witness_version();
+            assert_eq!(witness.reconstruct(), versioned.root_hash());
+            assert_eq!(witness.get_labels(), vec![ENTRIES_LABEL, VERSION_LABEL]);
+            let version = match witness.lookup_path(&[b"version"]) {
+                LookupResult::Found(version) => version.to_vec(),
+                _ => panic!("Expected the version."),
+            };
+            assert_eq!(version, versioned.version().to_be_bytes());
+            versions.push(version);
+
+            let witness = versioned.witness(&n);
+            assert_eq!(witness.reconstruct(), versioned.root_hash());
+            assert_eq!(
+                witness.lookup_path(&[b"entries", &n.to_be_bytes()]),
+                LookupResult::Absent
+            );
+
+            map.insert(n, n);
+        }
+
+        // Modifying a value changes the version even if the entries end up the same.
+        let root_hash = map.versioned().root_hash();
+        map.insert(0, 1);
+        map.insert(0, 0);
+        assert_ne!(map.versioned().root_hash(), root_hash);
+
+        versions.dedup();
+        assert_eq!(versions.len(), 10);
+    }
+
+    #[test]
+    fn counted_and_versioned() {
+        let mut map = Map::<u32, u32>::new();
+        for n in 0..10u32 {
+            map.insert(n, n);
+        }
+
+        let both = map.counted().versioned();
+        assert_eq!(both.root_hash(), map.versioned().counted().root_hash());
+        assert_eq!(both.as_hash_tree().reconstruct(), both.root_hash());
+        assert_ne!(both.root_hash(), map.counted().root_hash());
+        assert_ne!(both.root_hash(), map.versioned().root_hash());
+
+        let witness = both.witness(&3);
+        assert_eq!(witness.reconstruct(), both.root_hash());
+        assert_eq!(
+            witness.get_labels(),
+            vec![ENTRIES_LABEL, &3u32.to_be_bytes(), LEN_LABEL, VERSION_LABEL]
+        );
+        assert_eq!(
+            witness.lookup_path(&[b"len"]),
+            LookupResult::Found(&10u64.to_be_bytes())
+        );
+        assert_eq!(
+            witness.lookup_path(&[b"version"]),
+            LookupResult::Found(&map.generation().to_be_bytes())
+        );
+
+        let witness = both.witness_version();
+        assert_eq!(witness.reconstruct(), both.root_hash());
+        assert_eq!(
+            witness.get_labels(),
+            vec![ENTRIES_LABEL, LEN_LABEL, VERSION_LABEL]
+        );
+    }
+
+    #[test]
+    fn begin_epoch() {
+        let mut map = (0..50u32).map(|i| (i, i)).collect::<Map<u32, u32>>();
+        let root_hash = map.root_hash();
+        let epoch = map.begin_epoch();
+        assert!(epoch.is_current(&map));
+        assert_eq!(epoch.root_hash(), root_hash);
+        assert_eq!(epoch.generation(), map.generation());
+
+        let reader = epoch.clone();
+        for i in 0..50u32 {
+            map.insert(i, i + 1);
+        }
+        assert_ne!(map.root_hash(), root_hash);
+        map.clear();
+        assert!(!reader.is_current(&map));
+
+        assert_eq!(reader.len(), 50);
+        assert_eq!(reader.root_hash(), root_hash);
+        for i in 0..50u32 {
+            assert_eq!(reader.get(&i), Some(&i));
+            assert_eq!(reader.witness(&i).reconstruct(), root_hash);
+        }
+        assert_eq!(reader.snapshot().root_hash(), root_hash);
+    }
+
+    #[test]
+    fn freeze() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<FrozenMap<String, u32>>();
+
+        let mut map = Map::<String, u32>::new();
+
+        for i in 0..50u32 {
+            map.insert(hex::encode(i.to_be_bytes()), i);
+        }
+
+        let root_hash = map.root_hash();
+        let frozen = map.freeze();
+        let reader = frozen.clone();
+        assert_eq!(reader.len(), 50);
+        assert_eq!(reader.get(&hex::encode(7u32.to_be_bytes())), Some(&7));
+        assert_eq!(reader.root_hash(), root_hash);
+        let key = hex::encode(7u32.to_be_bytes());
+        assert_eq!(reader.witness(&key).reconstruct(), root_hash);
+
+        // The reader still holds the snapshot, so thaw has to clone the tree.
+        let mut map = frozen.thaw();
+        assert_eq!(map.root_hash(), root_hash);
+        map.insert("x".into(), 100);
+        assert_eq!(map.len(), 51);
+        assert_eq!(reader.len(), 50);
+        assert_eq!(reader.get("x"), None);
+        assert_eq!(reader.root_hash(), root_hash);
+
+        let map = reader.thaw();
+        assert_eq!(map.root_hash(), root_hash);
+    }
+
+    #[test]
+    fn borrowed_witness() {
+        let mut map = Map::<String, u32>::new();
+        map.insert("b".into(), 1);
+        map.insert("d".into(), 2);
+        map.insert("f".into(), 3);
+
+        assert_eq!(map.witness("d").get_labels(), vec![b"d"]);
+        assert_eq!(map.witness("c").reconstruct(), map.root_hash());
+        assert_eq!(map.witness("c").get_labels(), vec![b"b", b"d"]);
+        assert!(map.witness("c").get_leaf_values().is_empty());
+
+        assert_eq!(
+            map.witness_key_range("a", "e").get_labels(),
+            vec![b"b", b"d", b"f"]
+        );
+        assert_eq!(
+            map.witness_value_range("c", "z").get_leaf_values(),
+            vec![&2u32.to_be_bytes(), &3u32.to_be_bytes()]
+        );
+
+        let frozen = map.freeze();
+        assert_eq!(
+            frozen.witness_key_range("a", "c").get_labels(),
+            vec![b"b", b"d"]
+        );
+    }
+
+    #[test]
+    fn to_owned_vec() {
+        let mut map = Map::<String, u32>::new();
+
+        for i in (0..50u32).rev() {
+            map.insert(hex::encode(i.to_be_bytes()), i);
+        }
+
+        let snapshot = map.to_owned_vec();
+        let expected = (0..50u32)
+            .map(|i| (hex::encode(i.to_be_bytes()), i))
+            .collect::<Vec<_>>();
+        assert_eq!(snapshot, expected);
+        assert_eq!(map.iter_cloned().collect::<Vec<_>>(), expected);
+
+        // The snapshot outlives the mutations on the map.
+        map.clear();
+        assert_eq!(snapshot.len(), 50);
+    }
+
+    #[test]
+    fn deserialize_borrowed() {
+        let mut entries = std::collections::BTreeMap::new();
+        let mut expected = Map::<Vec<u8>, u32>::new();
+        for i in 0..20u32 {
+            let key = format!("key-{:02}", i).into_bytes();
+            entries.insert(serde_bytes::ByteBuf::from(key.clone()), i);
+            expected.insert(key, i);
+        }
+
+        let buffer = serde_cbor::to_vec(&entries).unwrap();
+        let range = buffer.as_ptr_range();
+
+        let mut map: BorrowedMap<u32> = serde_cbor::from_slice(&buffer).unwrap();
+        assert_eq!(map.len(), 20);
+        assert_eq!(map.root_hash(), expected.root_hash());
+        for (key, value) in map.iter() {
+            // The keys point into the input buffer.
+            assert!(range.contains(&key.as_ptr()));
+            assert_eq!(expected.get(key), Some(value));
+        }
+        assert_eq!(
+            map.witness(b"key-03").reconstruct(),
+            expected.witness(b"key-03" as &[u8]).reconstruct()
+        );
+
+        map.insert(Cow::Owned(b"new".to_vec()), 100);
+        expected.insert(b"new".to_vec(), 100);
+        assert_eq!(map.remove(b"key-00"), Some(0));
+        expected.remove(b"key-00" as &[u8]);
+        assert_eq!(map.root_hash(), expected.root_hash());
+        drop(map);
+        drop(buffer);
+
+        // A format without borrowed bytes falls back to copying the keys.
+        let buffer = serde_cbor::to_vec(&expected).unwrap();
+        let map: BorrowedMap<u32> = serde_cbor::from_slice(&buffer).unwrap();
+        let range = buffer.as_ptr_range();
+        assert!(map.iter().all(|(key, _)| !range.contains(&key.as_ptr())));
+        let expected: Map<Vec<u8>, u32> = serde_cbor::from_slice(&buffer).unwrap();
+        assert_eq!(map.root_hash(), expected.root_hash());
+    }
+
+    #[test]
+    fn retain_delta() {
+        let mut map = Map::<String, u32>::new();
+
+        for i in 0..100u32 {
+            map.insert(hex::encode(i.to_be_bytes()), i);
+        }
+
+        assert!(map.verify_len());
+        let (removed, _) = map.retain_delta(|_, _| true);
+        assert!(removed.is_empty());
+        assert_eq!(map.len(), 100);
+
+        let (removed, witness) = map.retain_delta(|_, v| v % 3 != 0);
+        let expected = (0..100u32)
+            .filter(|i| i % 3 == 0)
+            .map(|i| hex::encode(i.to_be_bytes()))
+            .collect::<Vec<_>>();
+        assert_eq!(removed, expected);
+        let witness_hash = witness.reconstruct();
+        let labels = witness
+            .get_labels()
+            .into_iter()
+            .map(|l| l.to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(witness_hash, map.root_hash());
+
+        for key in &removed {
+            assert!(!labels.contains(&key.as_bytes().to_vec()));
+        }
+
+        assert_eq!(map.len(), 66);
+        assert!(map.verify_len());
+        for i in 0..100u32 {
+            let value = map.get(&hex::encode(i.to_be_bytes()));
+            assert_eq!(value, Some(&i).filter(|i| *i % 3 != 0));
+        }
+
+        let (removed, witness) = map.retain_delta(|_, _| false);
+        assert_eq!(removed.len(), 66);
+        assert_eq!(witness.reconstruct(), map.root_hash());
+        assert!(map.is_empty());
+    }
 }
@@ -1,6 +1,11 @@
 //! Useful collections that implement [`crate::AsHashTree`]
 
+pub mod expiring;
 pub mod group;
+pub mod indexed;
 pub mod map;
+pub mod namespaced;
+pub mod oplog;
 pub mod paged;
 pub mod seq;
+pub mod versioned;
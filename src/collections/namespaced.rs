@@ -0,0 +1,265 @@
+use crate::collections::group::GroupLeaf;
+use crate::hashtree::{balanced_fork, labeled_hash};
+use crate::{AsHashTree, Hash, HashTree};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+/// A certified root that is made of independent sources, each under its own top-level
+/// label. This is meant for a canister with multiple certified features that all have to
+/// share the single `certified_data` of the canister.
+///
+/// It is a dynamic and flat version of a [`Group`](super::group::Group): the sources are
+/// looked up by the name of their namespace instead of their type, so the same type can be
+/// used more than once, and namespaces can be added and removed at any time.
+///
+/// # Example
+///
+/// ```
+/// use certified_vars::collections::namespaced::NamespacedRoot;
+/// use certified_vars::hashtree::LookupResult;
+/// use certified_vars::{AsHashTree, Map, Seq};
+///
+/// let mut balances = Map::<String, u64>::new();
+/// balances.insert("alice".into(), 10);
+///
+/// let mut root = NamespacedRoot::new();
+/// root.insert("balances", balances);
+/// root.insert("log", Seq::<u64>::new());
+///
+/// let witness = root
+///     .witness("balances", |balances: &Map<String, u64>| balances.witness("alice"))
+///     .unwrap();
+///
+/// assert_eq!(witness.reconstruct(), root.root_hash());
+/// assert_eq!(
+///     witness.lookup_path(&[b"balances", b"alice"]),
+///     LookupResult::Found(&10u64.to_be_bytes())
+/// );
+/// ```
+///
+/// # Hash tree
+///
+/// The namespaces are sorted and their labeled trees are put in a balanced tree of forks
+/// using [`balanced_fork`], so a root with the namespaces `a`, `b` and `c` has the tree:
+///
+/// ```text
+/// ─┬─┬╴"a" ──╴A
+///  │ └╴"b" ──╴B
+///  └╴"c" ──╴C
+/// ```
+#[derive(Default)]
+pub struct NamespacedRoot {
+    /// The sources by their namespace, the order of the strings is the order of their
+    /// labels.
+    namespaces: BTreeMap<String, Box<dyn GroupLeaf>>,
+}
+
+impl NamespacedRoot {
+    /// Create a new root without any namespaces.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Put the given source under the given namespace, and return `true` if it replaced
+    /// the previous source of the namespace.
+    pub fn insert<T: GroupLeaf, N: Into<String>>(&mut self, namespace: N, source: T) -> bool {
+        self.namespaces
+            .insert(namespace.into(), Box::new(source))
+            .is_some()
+    }
+
+    /// Remove the given namespace, and return `true` if it was in the root.
+    pub fn remove(&mut self, namespace: &str) -> bool {
+        self.namespaces.remove(namespace).is_some()
+    }
+
+    /// Returns `true` if the root contains the given namespace.
+    #[inline]
+    pub fn contains(&self, namespace: &str) -> bool {
+        self.namespaces.contains_key(namespace)
+    }
+
+    /// Returns the names of the namespaces in the order of the hash tree.
+    pub fn namespaces(&self) -> impl Iterator<Item = &str> + '_ {
+        self.namespaces.keys().map(String::as_str)
+    }
+
+    /// Returns the source of the given namespace, or [`None`] if the namespace does not
+    /// exist or its source is not a `T`.
+    pub fn get<T: GroupLeaf>(&self, namespace: &str) -> Option<&T> {
+        self.namespaces.get(namespace)?.downcast_ref()
+    }
+
+    /// Returns a mutable reference to the source of the given namespace, or [`None`] if the
+    /// namespace does not exist or its source is not a `T`.
+    pub fn get_mut<T: GroupLeaf>(&mut self, namespace: &str) -> Option<&mut T> {
+        self.namespaces.get_mut(namespace)?.downcast_mut()
+    }
+
+    /// Returns a witness for the given namespace, the tree of the namespace is the one
+    /// returned by `f` and every other namespace is pruned. Returns [`None`] if the
+    /// namespace does not exist or its source is not a `T`.
+    pub fn witness<'a, T, F>(&'a self, namespace: &str, f: F) -> Option<HashTree<'a>>
+    where
+        T: GroupLeaf,
+        F: FnOnce(&'a T) -> HashTree<'a>,
+    {
+        let source = self.get::<T>(namespace)?;
+        Some(self.witness_with(namespace, f(source)))
+    }
+
+    /// Returns a witness that contains the full tree of the given namespace, or [`None`] if
+    /// the namespace does not exist.
+    pub fn witness_full(&self, namespace: &str) -> Option<HashTree<'_>> {
+        let source = self.namespaces.get(namespace)?;
+        Some(self.witness_with(namespace, source.as_hash_tree()))
+    }
+
+    /// Put the given tree under the given namespace, and prune every other namespace.
+    fn witness_with<'a>(&'a self, namespace: &str, tree: HashTree<'a>) -> HashTree<'a> {
+        let mut tree = Some(tree);
+        let children = self
+            .namespaces
+            .iter()
+            .map(|(name, source)| {
+                if name == namespace {
+                    let tree = tree.take().unwrap();
+                    HashTree::Labeled(Cow::Borrowed(name.as_bytes()), Box::new(tree))
+                } else {
+                    HashTree::Pruned(labeled_hash(name.as_bytes(), &source.root_hash()))
+                }
+            })
+            .collect();
+
+        // Collapse the forks that only contain pruned namespaces.
+        balanced_fork(children)
+            .prune_by(|node| matches!(node, HashTree::Fork(_)) && is_pruned(node))
+    }
+}
+
+/// Returns `true` if the given tree does not reveal anything other than hashes.
+fn is_pruned(tree: &HashTree) -> bool {
+    match tree {
+        HashTree::Pruned(_) => true,
+        HashTree::Fork(lr) => is_pruned(lr.left()) && is_pruned(lr.right()),
+        _ => false,
+    }
+}
+
+impl AsHashTree for NamespacedRoot {
+    fn root_hash(&self) -> Hash {
+        let children = self
+            .namespaces
+            .iter()
+            .map(|(name, source)| {
+                HashTree::Pruned(labeled_hash(name.as_bytes(), &source.root_hash()))
+            })
+            .collect();
+
+        balanced_fork(children).reconstruct()
+    }
+
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        let children = self
+            .namespaces
+            .iter()
+            .map(|(name, source)| {
+                HashTree::Labeled(
+                    Cow::Borrowed(name.as_bytes()),
+                    Box::new(source.as_hash_tree()),
+                )
+            })
+            .collect();
+
+        balanced_fork(children)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashtree::LookupResult;
+    use crate::{GroupBuilder, Map, Seq};
+
+    #[test]
+    fn witness() {
+        let mut root = NamespacedRoot::new();
+        assert_eq!(root.root_hash(), HashTree::Empty.reconstruct());
+        assert_eq!(root.as_hash_tree(), HashTree::Empty);
+
+        let mut a = Map::<String, u32>::new();
+        a.insert("x".into(), 1);
+        let mut b = Map::<String, u32>::new();
+        b.insert("x".into(), 2);
+
+        // The same type can be used in more than one namespace.
+        assert!(!root.insert("b", b));
+        assert!(!root.insert("a", a));
+        assert!(!root.insert("c", Seq::<u32>::new()));
+        assert!(!root.insert("d", "name".to_string()));
+        assert!(!root.insert("e", GroupBuilder::new().insert(["x"], 7u32).build()));
+        assert_eq!(
+            root.namespaces().collect::<Vec<_>>(),
+            ["a", "b", "c", "d", "e"]
+        );
+
+        let root_hash = root.root_hash();
+        assert_eq!(root.as_hash_tree().reconstruct(), root_hash);
+
+        for (namespace, value) in [(b"a", 1u32), (b"b", 2)] {
+            let name = std::str::from_utf8(namespace).unwrap();
+            let witness = root
+                .witness(name, |map: &Map<String, u32>| map.witness("x"))
+                .unwrap();
+            assert_eq!(witness.reconstruct(), root_hash);
+            assert_eq!(
+                witness.lookup_path(&[namespace, b"x"]),
+                LookupResult::Found(&value.to_be_bytes())
+            );
+            assert_eq!(witness.get_labels(), vec![namespace as &[u8], b"x"]);
+        }
+
+        // The inner witness can come from a group.
+        let witness = root
+            .witness("e", |group: &crate::Group| {
+                group.witness().full::<u32>().build()
+            })
+            .unwrap();
+        assert_eq!(witness.reconstruct(), root_hash);
+        assert_eq!(
+            witness.lookup_path(&[b"e", b"x"]),
+            LookupResult::Found(&7u32.to_be_bytes())
+        );
+
+        let witness = root.witness_full("d").unwrap();
+        assert_eq!(witness.reconstruct(), root_hash);
+        assert_eq!(witness.get_leaf_values(), vec![b"name"]);
+        // The fork of `a` and `b` is collapsed into one pruned node.
+        match &witness {
+            HashTree::Fork(lr) => match lr.left() {
+                HashTree::Fork(lr) => assert!(matches!(lr.left(), HashTree::Pruned(_))),
+                _ => panic!("Expected a fork."),
+            },
+            _ => panic!("Expected a fork."),
+        }
+
+        // The namespace does not exist, or it has another type.
+        assert!(root.witness_full("f").is_none());
+        assert!(root
+            .witness("c", |map: &Map<String, u32>| map.witness("x"))
+            .is_none());
+        assert!(root.get::<Seq<u64>>("c").is_none());
+
+        root.get_mut::<Seq<u32>>("c").unwrap().append(1);
+        assert_ne!(root.root_hash(), root_hash);
+        assert_eq!(root.as_hash_tree().reconstruct(), root.root_hash());
+
+        assert!(root.insert("c", Seq::<u32>::new()));
+        assert_eq!(root.root_hash(), root_hash);
+        assert!(root.remove("c"));
+        assert!(!root.remove("c"));
+        assert!(!root.contains("c"));
+        assert_eq!(root.as_hash_tree().reconstruct(), root.root_hash());
+    }
+}
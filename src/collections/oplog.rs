@@ -0,0 +1,373 @@
+use crate::hashtree::{fork, fork_hash, labeled, labeled_hash, leaf_hash};
+use crate::label::Label;
+use crate::rbtree::iterator::RbTreeIterator;
+use crate::{AsHashTree, Hash, HashTree, Map, Seq};
+use std::borrow::{Borrow, Cow};
+
+/// The label of the subtree containing the entries of a [`LoggedMap`].
+const DATA_LABEL: &[u8] = b"data";
+
+/// The label of the subtree containing the operation log of a [`LoggedMap`].
+const LOG_LABEL: &[u8] = b"log";
+
+/// A certified map that records every mutation in a certified, append only log. So the
+/// history of the map is certified along with its current state, and an auditor can
+/// replay the log to check how the state came to be.
+///
+/// The log only contains the root hash of the values, an auditor still needs the values
+/// themselves from another source to replay the log, and it can check them against the
+/// hashes in the log.
+///
+/// # Example
+///
+/// ```
+/// use certified_vars::collections::oplog::{OpKind, LoggedMap};
+/// use certified_vars::{AsHashTree, Map};
+///
+/// let mut map = Map::<String, u32>::with_oplog();
+/// map.insert("a".into(), 1);
+/// map.modify(&"a".to_string(), |v| *v += 1);
+/// map.remove("a");
+///
+/// let log = map.oplog();
+/// assert_eq!(log.len(), 3);
+/// assert_eq!(log[1].kind, OpKind::Modify);
+/// assert_eq!(log[1].value, Some(2u32.root_hash()));
+///
+/// let witness = map.oplog_witness_range(1, 3);
+/// assert_eq!(witness.reconstruct(), map.root_hash());
+/// ```
+///
+/// # Hash tree
+///
+/// ```text
+/// ─┬╴"data" ──╴Map<K, V>
+///  └╴"log" ──╴Seq<Op>
+/// ```
+///
+/// See [`Op`] for the hash tree of each operation.
+pub struct LoggedMap<K: 'static + Label, V: 'static + AsHashTree> {
+    data: Map<K, V>,
+    log: Seq<Op>,
+}
+
+/// The kind of an [`Op`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OpKind {
+    /// A value was inserted, either for a new key or in place of the previous value.
+    Insert,
+    /// A key was removed.
+    Remove,
+    /// The value of a key was modified in place.
+    Modify,
+}
+
+impl OpKind {
+    /// Returns the content of the leaf used for this kind of operation.
+    #[inline]
+    fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            OpKind::Insert => b"insert",
+            OpKind::Remove => b"remove",
+            OpKind::Modify => b"modify",
+        }
+    }
+}
+
+/// An entry in the log of a [`LoggedMap`].
+///
+/// # Hash tree
+///
+/// ```text
+/// ─┬╴"key" ──╴Leaf(key)
+///  ├╴"op" ──╴Leaf("insert" | "remove" | "modify")
+///  └╴"value" ──╴Pruned(value)
+/// ```
+///
+/// Where `key` is the label of the key, and `value` is the root hash of the new value,
+/// which is omitted for a removal.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Op {
+    pub kind: OpKind,
+    pub key: Vec<u8>,
+    pub value: Option<Hash>,
+}
+
+impl AsHashTree for Op {
+    fn root_hash(&self) -> Hash {
+        let key = labeled_hash(b"key", &leaf_hash(&self.key));
+        let op = labeled_hash(b"op", &leaf_hash(self.kind.as_bytes()));
+
+        let rest = match &self.value {
+            Some(value) => fork_hash(&op, &labeled_hash(b"value", value)),
+            None => op,
+        };
+
+        fork_hash(&key, &rest)
+    }
+
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        let key = labeled(b"key", HashTree::Leaf(Cow::Borrowed(&self.key)));
+        let op = labeled(b"op", HashTree::Leaf(Cow::Borrowed(self.kind.as_bytes())));
+
+        let rest = match &self.value {
+            Some(value) => fork(op, labeled(b"value", HashTree::Pruned(*value))),
+            None => op,
+        };
+
+        fork(key, rest)
+    }
+}
+
+impl<K: 'static + Label, V: 'static + AsHashTree> Map<K, V> {
+    /// Create a new, empty map that records its mutations, see [`LoggedMap`].
+    #[inline]
+    pub fn with_oplog() -> LoggedMap<K, V> {
+        LoggedMap::new()
+    }
+}
+
+impl<K: 'static + Label, V: 'static + AsHashTree> LoggedMap<K, V> {
+    /// Create a new, empty map with an empty log.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            data: Map::new(),
+            log: Seq::new(),
+        }
+    }
+
+    /// Returns `true` if the map does not contain any values.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Returns the number of elements in the map.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Insert a key-value pair into the map and returns the previous value associated
+    /// with the key.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.log.append(Op {
+            kind: OpKind::Insert,
+            key: key.as_label().into_owned(),
+            value: Some(value.root_hash()),
+        });
+
+        self.data.insert(key, value)
+    }
+
+    /// Remove the value associated with the given key from the map, returns the
+    /// previous value associated with the key. Nothing is logged if the key is not in
+    /// the map.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        let (key, value) = self.data.remove_entry(key)?;
+
+        self.log.append(Op {
+            kind: OpKind::Remove,
+            key: key.as_label().into_owned(),
+            value: None,
+        });
+
+        Some(value)
+    }
+
+    /// Modify the value associated with the given key and return the result of `f`.
+    /// Returns [`None`] if the key is not in the map, in which case `f` is not called and
+    /// nothing is logged.
+    pub fn modify<F, R>(&mut self, key: &K, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut V) -> R,
+    {
+        let (result, hash) = self.data.modify(key, |value| {
+            let result = f(value);
+            (result, value.root_hash())
+        })?;
+
+        self.log.append(Op {
+            kind: OpKind::Modify,
+            key: key.as_label().into_owned(),
+            value: Some(hash),
+        });
+
+        Some(result)
+    }
+
+    /// Return the value associated with the given key.
+    #[inline]
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        self.data.get(key)
+    }
+
+    /// Return an iterator over the key-values in the map.
+    #[inline]
+    pub fn iter(&self) -> RbTreeIterator<'_, K, V> {
+        self.data.iter()
+    }
+
+    /// Create a HashTree witness for the value associated with given key, the log is
+    /// pruned.
+    pub fn witness<Q>(&self, key: &Q) -> HashTree<'_>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        fork(
+            labeled(DATA_LABEL, self.data.witness(key)),
+            HashTree::Pruned(labeled_hash(LOG_LABEL, &self.log.root_hash())),
+        )
+    }
+
+    /// Create a HashTree witness for the operations in `start..end` along with the length
+    /// of the log, the map is pruned. The indices that are out of range are ignored.
+    pub fn oplog_witness_range(&self, start: usize, end: usize) -> HashTree<'_> {
+        let indices = (start..end.min(self.log.len())).collect::<Vec<_>>();
+
+        fork(
+            HashTree::Pruned(labeled_hash(DATA_LABEL, &self.data.root_hash())),
+            labeled(LOG_LABEL, self.log.witness_with_len(&indices)),
+        )
+    }
+
+    /// Return the underlying map of the values.
+    #[inline]
+    pub fn as_map(&self) -> &Map<K, V> {
+        &self.data
+    }
+
+    /// Return the log of all of the mutations of the map, from the oldest to the newest.
+    #[inline]
+    pub fn oplog(&self) -> &Seq<Op> {
+        &self.log
+    }
+}
+
+impl<K: 'static + Label, V: 'static + AsHashTree> Default for LoggedMap<K, V> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: 'static + Label, V: 'static + AsHashTree> AsHashTree for LoggedMap<K, V> {
+    fn root_hash(&self) -> Hash {
+        fork_hash(
+            &labeled_hash(DATA_LABEL, &self.data.root_hash()),
+            &labeled_hash(LOG_LABEL, &self.log.root_hash()),
+        )
+    }
+
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        fork(
+            labeled(DATA_LABEL, self.data.as_hash_tree()),
+            labeled(LOG_LABEL, self.log.as_hash_tree()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashtree::LookupResult;
+
+    #[test]
+    fn log() {
+        let mut map = Map::<String, u32>::with_oplog();
+        assert_eq!(map.insert("a".into(), 1), None);
+        assert_eq!(map.insert("b".into(), 2), None);
+        assert_eq!(map.insert("a".into(), 3), Some(1));
+        assert_eq!(map.modify(&"b".to_string(), |v| *v *= 10), Some(()));
+        assert_eq!(map.modify(&"c".to_string(), |v| *v *= 10), None);
+        assert_eq!(map.remove("a"), Some(3));
+        assert_eq!(map.remove("a"), None);
+
+        let ops = map
+            .oplog()
+            .iter()
+            .map(|op| (op.kind, op.key.as_slice(), op.value))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ops,
+            vec![
+                (OpKind::Insert, b"a" as &[u8], Some(1u32.root_hash())),
+                (OpKind::Insert, b"b", Some(2u32.root_hash())),
+                (OpKind::Insert, b"a", Some(3u32.root_hash())),
+                (OpKind::Modify, b"b", Some(20u32.root_hash())),
+                (OpKind::Remove, b"a", None),
+            ]
+        );
+
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get("b"), Some(&20));
+        assert_eq!(map.root_hash(), map.as_hash_tree().reconstruct());
+        assert_eq!(map.witness("b").reconstruct(), map.root_hash());
+
+        // Replaying the log on an empty map results in the same state.
+        let mut replay = Map::<String, u32>::new();
+        let values = [1, 2, 3, 20];
+        let mut values = values.iter();
+        for op in map.oplog().iter() {
+            let key = String::from_utf8(op.key.clone()).unwrap();
+            match op.kind {
+                OpKind::Insert | OpKind::Modify => {
+                    let value = *values.next().unwrap();
+                    assert_eq!(op.value, Some(value.root_hash()));
+                    replay.insert(key, value);
+                }
+                OpKind::Remove => {
+                    replay.remove(&key);
+                }
+            }
+        }
+        assert_eq!(replay.root_hash(), map.as_map().root_hash());
+    }
+
+    #[test]
+    fn oplog_witness_range() {
+        let mut map = LoggedMap::<u32, u32>::new();
+        for i in 0..10 {
+            map.insert(i, i);
+        }
+
+        let witness = map.oplog_witness_range(3, 5);
+        assert_eq!(witness.reconstruct(), map.root_hash());
+        assert_eq!(
+            witness.lookup_path(&[b"log", b"len"]),
+            LookupResult::Found(&10u64.to_be_bytes())
+        );
+        assert_eq!(
+            witness.lookup_path(&[b"log", b"items", &3u64.to_be_bytes(), b"key"]),
+            LookupResult::Found(&3u32.to_be_bytes())
+        );
+        assert_eq!(
+            witness.lookup_path(&[b"log", b"items", &5u64.to_be_bytes(), b"key"]),
+            LookupResult::Unknown
+        );
+
+        let op = map.oplog()[4].clone();
+        assert_eq!(op.as_hash_tree().reconstruct(), op.root_hash());
+        let removal = Op {
+            kind: OpKind::Remove,
+            key: vec![1],
+            value: None,
+        };
+        assert_eq!(removal.as_hash_tree().reconstruct(), removal.root_hash());
+
+        assert_eq!(
+            map.oplog_witness_range(8, 20).reconstruct(),
+            map.root_hash()
+        );
+    }
+}
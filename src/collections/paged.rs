@@ -3,18 +3,42 @@ use crate::{AsHashTree, Hash, HashTree, Map, Seq};
 use candid::CandidType;
 use serde::{Deserialize, Serialize};
 use std::borrow::{Borrow, Cow};
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
 
 #[derive(CandidType, Serialize, Deserialize, Debug)]
 pub struct Paged<K: Label + Ord + 'static, V: AsHashTree + 'static, const S: usize> {
     data: Map<PagedKey<K>, Seq<V>>,
 }
 
+/// The key of a page in the underlying map of a [`Paged`], see [`Paged::inner`].
+///
+/// # Label
+///
+/// The label of a page is the label of its key followed by the number of the page as a
+/// big endian `u32`. This encoding is part of the public API, since it is what a client
+/// uses to look up a page in a witness.
 #[derive(Ord, CandidType, Serialize, Deserialize, PartialOrd, Eq, PartialEq, Debug)]
-struct PagedKey<K: Label + Ord + 'static> {
+pub struct PagedKey<K: Label + Ord + 'static> {
     key: K,
     page: u32,
 }
 
+impl<K: Label + Ord + 'static> PagedKey<K> {
+    /// Returns the key that the page belongs to.
+    #[inline]
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Returns the number of the page as it is stored in the label, which is the number
+    /// of the page in a [`Paged`] but not in a [`PagedNewestFirst`].
+    #[inline]
+    pub fn page(&self) -> u32 {
+        self.page
+    }
+}
+
 impl<K: Label + Ord + 'static> Label for PagedKey<K> {
     #[inline]
     fn as_label(&self) -> Cow<[u8]> {
@@ -40,10 +64,19 @@ impl<K: Label + Ord + 'static, V: AsHashTree + 'static, const S: usize> Default
 }
 
 impl<K: Label + Ord + 'static, V: AsHashTree + 'static, const S: usize> Paged<K, V, S> {
+    /// Create a new, empty paged map. This is the same as [`Paged::default`], and the root
+    /// hash is the one of an empty [`Map`].
     pub fn new() -> Self {
         Self { data: Map::new() }
     }
 
+    /// Returns the underlying map from the pages to their items, for the queries that are
+    /// not provided by [`Paged`] such as custom prefix scans.
+    #[inline]
+    pub fn inner(&self) -> &Map<PagedKey<K>, Seq<V>> {
+        &self.data
+    }
+
     pub fn insert(&mut self, key: K, item: V) {
         let tree = &mut self.data.inner;
         let mut item = Some(item);
@@ -108,6 +141,54 @@ impl<K: Label + Ord + 'static, V: AsHashTree + 'static, const S: usize> Paged<K,
         };
         self.data.witness(&key)
     }
+
+    /// Replace the items of an existing page at once and rehash the map a single time, this
+    /// is meant for correcting a batch of items. The page keeps its number and the other
+    /// pages are not touched, so a page before the last one that is replaced with less than
+    /// `S` items stays that way.
+    pub fn replace_page(&mut self, key: &K, page: usize, items: Vec<V>) -> Result<(), PageError> {
+        if items.len() > S {
+            return Err(PageError::TooManyItems {
+                len: items.len(),
+                max: S,
+            });
+        }
+
+        let page = u32::try_from(page).map_err(|_| PageError::PageNotFound { page })?;
+        let idx = (key, page);
+        let mut items = Some(items);
+        self.data
+            .inner
+            .modify_with(
+                |k| idx.cmp(&(&k.key, k.page)),
+                |seq| *seq = Seq::from(items.take().unwrap()),
+            )
+            .ok_or(PageError::PageNotFound {
+                page: page as usize,
+            })
+    }
+
+    /// Returns a witness for the length of the given page without any of its items, so a
+    /// client can tell whether the page is full (it has exactly `S` items) and it should
+    /// fetch the next page, or it has reached the end of the data.
+    ///
+    /// If the page does not exist, the witness proves its absence instead.
+    pub fn witness_page_with_fullness(&self, key: &K, page: usize) -> HashTree<'_>
+    where
+        K: Clone,
+    {
+        let page = match u32::try_from(page) {
+            Ok(page) => page,
+            // No page can have this number, so prove that there is no page after the last one.
+            Err(_) => return self.witness_last_page_number(key),
+        };
+        let key = PagedKey {
+            key: key.clone(),
+            page,
+        };
+        self.data
+            .witness_path(&key, |seq| seq.witness_with_len(&[]))
+    }
 }
 
 impl<K: Label + Ord + 'static, V: AsHashTree + 'static, const S: usize> AsHashTree
@@ -122,6 +203,157 @@ impl<K: Label + Ord + 'static, V: AsHashTree + 'static, const S: usize> AsHashTr
     }
 }
 
+/// The error type for the fallible operations on a [`Paged`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PageError {
+    /// The key does not have a page with the given number.
+    PageNotFound { page: usize },
+    /// The page can not fit the given number of items.
+    TooManyItems { len: usize, max: usize },
+}
+
+impl Display for PageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PageError::PageNotFound { page } => write!(f, "Page {} does not exist", page),
+            PageError::TooManyItems { len, max } => write!(
+                f,
+                "Page can hold at most {} items, but {} items were given",
+                max, len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PageError {}
+
+/// Like [`Paged`] but the pages of each key are numbered from the newest page, so page `0`
+/// is always the page that the next item is appended to, page `1` is the one before it and
+/// so on. The items in a page are still in the order of their insertion.
+///
+/// # Hash tree
+///
+/// The page numbers are only shifted in the API, the label of a page never changes once it
+/// is created. The `n`-th page of a key (counting from zero, from the oldest page) is
+/// stored under the label of the key followed by `u32::MAX - n` as a big endian `u32`, so
+/// the newest page of a key is the one with the smallest label.
+#[derive(CandidType, Serialize, Deserialize, Debug)]
+pub struct PagedNewestFirst<K: Label + Ord + 'static, V: AsHashTree + 'static, const S: usize> {
+    data: Map<PagedKey<K>, Seq<V>>,
+}
+
+impl<K: Label + Ord + 'static, V: AsHashTree + 'static, const S: usize> Default
+    for PagedNewestFirst<K, V, S>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Label + Ord + 'static, V: AsHashTree + 'static, const S: usize> PagedNewestFirst<K, V, S> {
+    /// Create a new, empty paged map. This is the same as [`PagedNewestFirst::default`],
+    /// and the root hash is the one of an empty [`Map`].
+    pub fn new() -> Self {
+        Self { data: Map::new() }
+    }
+
+    pub fn insert(&mut self, key: K, item: V) {
+        let tree = &mut self.data.inner;
+        let mut item = Some(item);
+
+        let page = tree
+            .modify_min_with_prefix(&key, |key, seq| {
+                if seq.len() == S {
+                    return Some(key.page.checked_sub(1).expect("Too many pages."));
+                }
+                seq.append(item.take().unwrap());
+                None
+            })
+            .unwrap_or(Some(u32::MAX));
+
+        if let Some(page) = page {
+            let key = PagedKey { key, page };
+            let mut value = Seq::new();
+            value.append(item.take().unwrap());
+            tree.insert(key, value);
+        }
+    }
+
+    /// Returns the number of pages of the given key.
+    pub fn page_count(&self, key: &K) -> usize {
+        self.data
+            .inner
+            .min_entry_with_prefix(key)
+            .map(|(k, _)| (u32::MAX - k.page) as usize + 1)
+            .unwrap_or(0)
+    }
+
+    /// Returns the given page of the key, where page `0` is the newest page.
+    pub fn get(&self, key: &K, page: usize) -> Option<&Seq<V>> {
+        let page = self.physical_page(key, page)?;
+        let key = (key, page);
+        self.data.inner.get_with(|k| key.cmp(&(&k.key, k.page)))
+    }
+
+    /// Returns a witness for the given page of the key, where page `0` is the newest page.
+    /// If the page does not exist the witness is for the oldest page of the key instead,
+    /// which proves that there is no older page, or the absence of any page if the key does
+    /// not have one.
+    pub fn witness(&self, key: &K, page: usize) -> HashTree<'_>
+    where
+        K: Clone,
+    {
+        let page = self.physical_page(key, page).unwrap_or(u32::MAX);
+        self.witness_page(key, page)
+    }
+
+    /// Returns a witness for the newest page of the key, which also proves that there is
+    /// no page that is newer than it.
+    pub fn witness_newest(&self, key: &K) -> HashTree<'_>
+    where
+        K: Clone,
+    {
+        let page = self.physical_page(key, 0).unwrap_or(u32::MAX);
+        let witness = self.witness_page(key, page);
+
+        match page.checked_sub(1) {
+            Some(newer) => witness.merge(self.witness_page(key, newer)),
+            None => witness,
+        }
+    }
+
+    /// Returns the label of the given page, and `None` if the page does not exist.
+    fn physical_page(&self, key: &K, page: usize) -> Option<u32> {
+        let (newest, _) = self.data.inner.min_entry_with_prefix(key)?;
+        u32::try_from(page)
+            .ok()
+            .and_then(|page| newest.page.checked_add(page))
+    }
+
+    // TODO: Remove the Clone.
+    fn witness_page(&self, key: &K, page: u32) -> HashTree<'_>
+    where
+        K: Clone,
+    {
+        self.data.witness(&PagedKey {
+            key: key.clone(),
+            page,
+        })
+    }
+}
+
+impl<K: Label + Ord + 'static, V: AsHashTree + 'static, const S: usize> AsHashTree
+    for PagedNewestFirst<K, V, S>
+{
+    fn root_hash(&self) -> Hash {
+        self.data.root_hash()
+    }
+
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        self.data.as_hash_tree()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -175,6 +407,51 @@ mod tests {
         assert_eq!(paged.data.inner.modify_max_with_prefix(&6, |k, _| k), None);
     }
 
+    #[test]
+    fn inner() {
+        let mut paged = Paged::<u32, u32, 2>::new();
+        for i in 0..5 {
+            paged.insert(7, i);
+        }
+        paged.insert(9, 5);
+
+        let pages = paged
+            .inner()
+            .iter()
+            .map(|(k, seq)| (*k.key(), k.page(), seq.as_slice().to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            pages,
+            vec![
+                (7, 0, vec![0, 1]),
+                (7, 1, vec![2, 3]),
+                (7, 2, vec![4]),
+                (9, 0, vec![5]),
+            ]
+        );
+
+        let (key, _) = paged.inner().iter().next().unwrap();
+        let mut label = 7u32.to_be_bytes().to_vec();
+        label.extend_from_slice(&0u32.to_be_bytes());
+        assert_eq!(key.as_label(), label);
+        assert_eq!(paged.inner().root_hash(), paged.root_hash());
+    }
+
+    #[test]
+    fn default() {
+        let empty = HashTree::Empty.reconstruct();
+        assert_eq!(Paged::<i32, i32, 3>::default().root_hash(), empty);
+        assert_eq!(Paged::<i32, i32, 3>::new().root_hash(), empty);
+        assert_eq!(
+            PagedNewestFirst::<i32, i32, 3>::default().root_hash(),
+            empty
+        );
+
+        let paged = Paged::<i32, i32, 3>::default();
+        assert_eq!(paged.get_last_page_number(&0), None);
+        assert_eq!(paged.as_hash_tree(), HashTree::Empty);
+    }
+
     #[test]
     fn get() {
         let mut paged = Paged::<i32, i32, 3>::new();
@@ -203,4 +480,129 @@ mod tests {
             assert_eq!(paged.get(&k, 4), None);
         }
     }
+
+    #[test]
+    fn newest_first() {
+        let mut paged = PagedNewestFirst::<i32, i32, 3>::new();
+        assert_eq!(paged.page_count(&0), 0);
+        assert_eq!(paged.get(&0, 0), None);
+
+        // 0: [0 2 4] [6 8 10] [12]
+        // 1: [1 3 5] [7 9 11] [13]
+        for i in 0..14 {
+            paged.insert(i % 2, i);
+        }
+
+        for k in 0..2 {
+            assert_eq!(paged.page_count(&k), 3);
+            let pages = [
+                vec![12 + k],
+                vec![6 + k, 8 + k, 10 + k],
+                vec![k, 2 + k, 4 + k],
+            ];
+            for (page, items) in pages.iter().enumerate() {
+                let seq = items.iter().copied().collect::<Seq<_>>();
+                assert_eq!(paged.get(&k, page), Some(&seq));
+
+                let witness = paged.witness(&k, page);
+                assert_eq!(witness.reconstruct(), paged.root_hash());
+                let mut label = k.as_label().into_owned();
+                label.extend_from_slice(&(u32::MAX - 2 + page as u32).to_be_bytes());
+                assert!(witness.get_labels().contains(&label.as_slice()));
+            }
+            assert_eq!(paged.get(&k, 3), None);
+            assert_eq!(paged.get(&k, usize::MAX), None);
+        }
+
+        // The new page becomes the newest page.
+        paged.insert(0, 14);
+        paged.insert(0, 16);
+        paged.insert(0, 18);
+        assert_eq!(paged.page_count(&0), 4);
+        assert_eq!(paged.get(&0, 0), Some(&vec![18].into_iter().collect()));
+        assert_eq!(
+            paged.get(&0, 1),
+            Some(&vec![12, 14, 16].into_iter().collect())
+        );
+
+        let witness = paged.witness_newest(&0);
+        assert_eq!(witness.reconstruct(), paged.root_hash());
+        assert_eq!(
+            witness.get_leaf_values(),
+            vec![&18i32.to_be_bytes()[..], &1u64.to_be_bytes()]
+        );
+
+        let witness = paged.witness_newest(&5);
+        assert_eq!(witness.reconstruct(), paged.root_hash());
+        assert!(witness.get_leaf_values().is_empty());
+    }
+
+    #[test]
+    fn witness_page_with_fullness() {
+        let mut paged = Paged::<i32, i32, 3>::new();
+
+        for i in 0..10 {
+            paged.insert(i % 2, i);
+        }
+
+        // 0: [0 2 4] [6 8]
+        // 1: [1 3 5] [7 9]
+        for k in 0..2 {
+            for (page, len) in [(0, 3u64), (1, 2u64)] {
+                let witness = paged.witness_page_with_fullness(&k, page);
+                assert_eq!(witness.reconstruct(), paged.root_hash());
+                assert_eq!(witness.get_leaf_values(), vec![&len.to_be_bytes()[..]]);
+            }
+
+            for page in [2, usize::MAX] {
+                let witness = paged.witness_page_with_fullness(&k, page);
+                assert_eq!(witness.reconstruct(), paged.root_hash());
+                assert!(witness.get_leaf_values().is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn replace_page() {
+        let mut paged = Paged::<i32, i32, 3>::new();
+
+        for i in 0..10 {
+            paged.insert(i % 2, i);
+        }
+
+        // 0: [0 2 4] [6 8]
+        // 1: [1 3 5] [7 9]
+        let root_hash = paged.root_hash();
+        assert_eq!(paged.replace_page(&0, 0, vec![10, 12, 14]), Ok(()));
+        assert_eq!(paged.get(&0, 0), Some(&Seq::from(vec![10, 12, 14])));
+        assert_eq!(paged.get(&1, 0), Some(&Seq::from(vec![1, 3, 5])));
+        assert_ne!(paged.root_hash(), root_hash);
+        assert_eq!(paged.as_hash_tree().reconstruct(), paged.root_hash());
+        assert_eq!(paged.witness(&0, 0).reconstruct(), paged.root_hash());
+
+        // The last page can be filled up, and the next insertion starts a new page.
+        assert_eq!(paged.replace_page(&1, 1, vec![7, 9, 11]), Ok(()));
+        paged.insert(1, 13);
+        assert_eq!(paged.get(&1, 2), Some(&Seq::from(vec![13])));
+
+        let root_hash = paged.root_hash();
+        assert_eq!(
+            paged.replace_page(&0, 2, vec![1]),
+            Err(PageError::PageNotFound { page: 2 })
+        );
+        assert_eq!(
+            paged.replace_page(&2, 0, vec![1]),
+            Err(PageError::PageNotFound { page: 0 })
+        );
+        assert_eq!(
+            paged.replace_page(&0, usize::MAX, vec![1]),
+            Err(PageError::PageNotFound { page: usize::MAX })
+        );
+        assert_eq!(
+            paged.replace_page(&0, 0, vec![1, 2, 3, 4]),
+            Err(PageError::TooManyItems { len: 4, max: 3 })
+        );
+        assert_eq!(paged.root_hash(), root_hash);
+        assert_eq!(paged.get(&0, 0), Some(&Seq::from(vec![10, 12, 14])));
+    }
 }
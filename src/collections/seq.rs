@@ -1,13 +1,21 @@
+use crate::hashtree::{fork, fork_hash, labeled, labeled_hash, leaf_hash};
 use crate::{AsHashTree, Hash, HashTree};
 use candid::types::Type;
 use candid::CandidType;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use sha2::{Digest, Sha256};
-use std::borrow::Borrow;
+use std::borrow::{Borrow, Cow};
+use std::cmp::Ordering;
+use std::fmt::{self, Debug, Formatter};
 use std::iter::FromIterator;
-use std::ops::Index;
+use std::ops::{Deref, DerefMut, Index};
 use std::slice::{Iter, SliceIndex};
 
+/// The label of the subtree containing the elements of a [`Seq`].
+const ITEMS_LABEL: &[u8] = b"items";
+
+/// The label of the leaf containing the length of a [`Seq`].
+const LEN_LABEL: &[u8] = b"len";
+
 /// An append only list of `T`.
 ///
 /// # Example
@@ -22,55 +30,220 @@ use std::slice::{Iter, SliceIndex};
 ///
 /// assert_eq!(seq.len(), 2);
 /// ```
-#[derive(Default, Eq, PartialEq, Clone, Debug)]
+///
+/// # Hash tree
+///
+/// The hash tree of a sequence with `n` elements commits to both the elements and the
+/// length of the sequence:
+///
+/// ```text
+/// ─┬╴"items" ──╴T(0..n)
+///  └╴"len" ──╴Leaf(n)
+/// ```
+///
+/// Where `n` is encoded as a big endian `u64`, and `T(a..b)` is:
+///
+/// 1. `Empty` if the range is empty.
+/// 2. `Labeled(i, item)` if the range only contains `i`, where `i` is encoded as a big
+///    endian `u64` and `item` is the hash tree of the element.
+/// 3. Otherwise `Fork(T(a..a + k), T(a + k..b))` where `k` is the largest power of two
+///    that is smaller than `b - a`.
+///
+/// So the tree of the elements is a balanced binary tree and the witness of a single element
+/// only contains the hashes of its `⌈log2 n⌉` siblings next to the element itself, no
+/// matter how large the other elements are. For example with 1KiB elements:
+///
+/// | Length | Witness of one element |
+/// |--------|------------------------|
+/// | 1      | 1067 bytes             |
+/// | 1024   | 1447 bytes             |
+/// | 65536  | 1675 bytes             |
+///
+/// # Root hash
+///
+/// A client can recompute the root hash of a sequence from its elements using the hash
+/// functions of the IC, which are available in [`crate::hashtree`]:
+///
+/// ```text
+/// root       = fork_hash(labeled_hash("items", H(0..n)), labeled_hash("len", leaf_hash(n)))
+/// H(a..b)    = HashTree::Empty.reconstruct()               if a == b
+///            = labeled_hash(i, root_hash(item_i))          if b == a + 1
+///            = fork_hash(H(a..a + k), H(a + k..b))         otherwise
+/// ```
+///
+/// Since the root is always a fork of the two labeled subtrees, the root hash of a sequence
+/// is never the root hash of a leaf, so a sequence `[x]` can not be confused with `x`
+/// itself, and the length makes the root hash of an empty sequence different from the
+/// root hash of an empty tree or an empty [`Map`](crate::Map). Every value of the
+/// sequence is also under the label of its index, so a sequence of `n` elements can not
+/// have the root hash of a sequence with another length or order.
+#[derive(Clone)]
 pub struct Seq<T> {
-    hash: Hash,
     items: Vec<T>,
+    /// The cached hashes of the complete subtrees, `hashes[l][j]` is the root hash of
+    /// `T(j * 2^l..(j + 1) * 2^l)`.
+    hashes: Vec<Vec<Hash>>,
 }
 
 impl<T> Seq<T> {
-    /// Create a new, empty Stack<T>
+    /// Create a new, empty sequence. This is the same as [`Seq::default`], and the root
+    /// hash of an empty sequence commits to the length `0`.
     #[inline]
     pub const fn new() -> Self {
         Self {
             items: Vec::new(),
-            hash: [0; 32],
+            hashes: Vec::new(),
         }
     }
 
-    /// Construct a new, empty Stack<T> with the specified capacity.
+    /// Construct a new, empty sequence with the specified capacity.
     #[inline]
     pub fn with_capacity(capacity: usize) -> Self {
         Self {
             items: Vec::with_capacity(capacity),
-            hash: [0; 32],
+            hashes: Vec::new(),
         }
     }
 }
 
 impl<T: AsHashTree> Seq<T> {
+    /// Create a sequence from the given elements in their order, this is the same as
+    /// [`Seq::from`]. The elements are moved without being copied, and the hash of every
+    /// element and of every complete subtree is computed exactly once.
+    #[inline]
+    pub fn from_vec(items: Vec<T>) -> Self {
+        Self::from(items)
+    }
+
+    /// Returns the elements of the sequence in their order, without copying them.
+    #[inline]
+    pub fn into_vec(self) -> Vec<T> {
+        self.items
+    }
+
     /// Append a new item to the sequence and update the hash.
     pub fn append(&mut self, item: T) {
-        let mut h = Sha256::new();
-        h.update(&self.hash);
-        h.update(item.root_hash());
-
-        self.hash = h.finalize().into();
         self.items.push(item);
+        self.push_hash(self.items.len() - 1);
+    }
+
+    /// Move all of the elements of `other` to the end of this sequence, the element at
+    /// index `i` of `other` lands at index `self.len() + i`.
+    ///
+    /// The label of each element is its index, so the cached hashes of `other` can only
+    /// be reused when this sequence is empty. Otherwise the hash of every moved element is
+    /// computed once, just like [`Seq::extend`].
+    pub fn append_seq(&mut self, other: Seq<T>) {
+        if self.is_empty() {
+            *self = other;
+            return;
+        }
+
+        let prev_len = self.items.len();
+        let mut items = other.items;
+        self.items.append(&mut items);
+        self.recompute_hash(prev_len);
+    }
+
+    /// Returns a mutable reference to the element at the given index, the hash of the
+    /// sequence is updated once the returned guard is dropped. The element stays at the
+    /// same index, so this is meant for correcting an element in place.
+    #[inline]
+    pub fn get_mut(&mut self, index: usize) -> Option<SeqGuard<'_, T>> {
+        if index < self.items.len() {
+            Some(SeqGuard { seq: self, index })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the index that the next call to [`Seq::append`] will use, which is the
+    /// current length of the sequence.
+    #[inline]
+    pub fn next_index(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Reserve the next index of the sequence for a value that is built later, the index
+    /// is known before the value is built and the value is appended with
+    /// [`Reservation::fulfill`]. The sequence can not be modified until then.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::Seq;
+    ///
+    /// let mut seq = Seq::<String>::new();
+    /// seq.append("a".into());
+    ///
+    /// let (index, reservation) = seq.reserve_index();
+    /// assert_eq!(index, 1);
+    /// reservation.fulfill(format!("the event #{}", index));
+    ///
+    /// assert_eq!(seq.next_index(), 2);
+    /// assert_eq!(seq[1], "the event #1");
+    /// ```
+    #[inline]
+    pub fn reserve_index(&mut self) -> (usize, Reservation<'_, T>) {
+        let index = self.next_index();
+        (
+            index,
+            Reservation {
+                seq: self,
+                fulfilled: false,
+            },
+        )
     }
 
     /// Clear the sequence by removing all of the items. This method does not have
     /// any effects on the allocated memory.
     #[inline]
     pub fn clear(&mut self) {
-        self.hash = [0; 32];
+        self.hashes.iter_mut().for_each(Vec::clear);
         self.items.clear();
     }
 
+    /// Shorten the sequence to its first `len` elements and drop the rest, this does
+    /// nothing if the sequence is not longer than `len`. The cached hashes of the complete
+    /// subtrees that are kept are still valid, so this does not hash anything.
+    /// The root hash is then the one of a sequence that only had the first `len` elements
+    /// appended to it.
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.items.len() {
+            return;
+        }
+
+        self.items.truncate(len);
+        for (level, hashes) in self.hashes.iter_mut().enumerate() {
+            hashes.truncate(len >> level);
+        }
+    }
+
+    /// Remove the first element of the sequence and return it, or [`None`] if the sequence
+    /// is empty.
+    ///
+    /// The indices are re-based, so the element at index `i` moves to index `i - 1`. The
+    /// index of an element is the label of its subtree, so the hash of every remaining
+    /// element has to be computed again and this is O(n). A witness that is created before
+    /// the pop does not reconstruct to the new root hash.
+    pub fn pop_front(&mut self) -> Option<T> {
+        if self.items.is_empty() {
+            return None;
+        }
+
+        let item = self.items.remove(0);
+        self.hashes.iter_mut().for_each(Vec::clear);
+        self.recompute_hash(0);
+        Some(item)
+    }
+
     /// Shrinks the capacity of the seq as much as possible.
     #[inline]
     pub fn shrink_to_fit(&mut self) {
         self.items.shrink_to_fit();
+        self.hashes.retain(|level| !level.is_empty());
+        self.hashes.iter_mut().for_each(Vec::shrink_to_fit);
+        self.hashes.shrink_to_fit();
     }
 
     /// Reserve space for at least `additional` more elements.
@@ -91,6 +264,13 @@ impl<T: AsHashTree> Seq<T> {
         &self.items
     }
 
+    /// Returns the elements of the sequence as a slice. The elements are always stored
+    /// contiguously and the hashes are kept separately, so this is free.
+    #[inline]
+    pub fn as_slice(&self) -> &[T] {
+        &self.items
+    }
+
     /// Returns `true` if the sequence does not have any elements.
     #[inline]
     pub fn is_empty(&self) -> bool {
@@ -98,11 +278,25 @@ impl<T: AsHashTree> Seq<T> {
     }
 
     /// Returns the number of elements in the sequence, also referred to as its ‘length’.
+    /// The length is stored, so this is O(1).
     #[inline]
     pub fn len(&self) -> usize {
         self.items.len()
     }
 
+    /// Returns the element at the given index in the order of the appends, or [`None`] if
+    /// the index is out of bounds. The elements are stored contiguously, so this is O(1).
+    #[inline]
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.items.get(index)
+    }
+
+    /// Returns the last appended element, or [`None`] if the sequence is empty.
+    #[inline]
+    pub fn last(&self) -> Option<&T> {
+        self.items.last()
+    }
+
     /// Returns the number of elements the sequence can hold without reallocating.
     #[inline]
     pub fn capacity(&self) -> usize {
@@ -115,31 +309,472 @@ impl<T: AsHashTree> Seq<T> {
         self.items.iter()
     }
 
+    /// Binary search the sequence with a comparator function, see
+    /// [`slice::binary_search_by`]. This takes O(log n) comparisons.
+    ///
+    /// The sequence does not keep its elements sorted, this assumes that the elements were
+    /// appended in the order of `f`, such as a log of events appended by their timestamps.
+    /// The result is unspecified if they were not.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::Seq;
+    /// use std::cmp::Ordering::{Greater, Less};
+    ///
+    /// // The timestamps of the events in a chronological log.
+    /// let log = Seq::from(vec![10u64, 20, 20, 35, 50]);
+    ///
+    /// // Find the first event after the time 20.
+    /// let first = log.binary_search_by(|time| if *time <= 20 { Less } else { Greater });
+    /// assert_eq!(first, Err(3));
+    /// assert_eq!(log.binary_search_by(|time| time.cmp(&35)), Ok(3));
+    /// assert_eq!(log.binary_search_by(|time| time.cmp(&40)), Err(4));
+    /// ```
+    #[inline]
+    pub fn binary_search_by<F>(&self, f: F) -> Result<usize, usize>
+    where
+        F: FnMut(&T) -> Ordering,
+    {
+        self.items.binary_search_by(f)
+    }
+
+    /// Returns a witness for the elements at the given indices, the witness also contains
+    /// the length of the sequence. So a client can be sure that there are no elements after
+    /// the last index it knows about.
+    ///
+    /// The indices that are out of range are ignored, since the certified length is already
+    /// the proof of their absence.
+    pub fn witness_with_len(&self, indices: &[usize]) -> HashTree<'_> {
+        let mut indices = indices
+            .iter()
+            .copied()
+            .filter(|i| *i < self.len())
+            .collect::<Vec<_>>();
+        indices.sort_unstable();
+        indices.dedup();
+
+        self.with_len(self.witness_items(0, self.len(), &indices))
+    }
+
+    /// Returns a witness for the length of the sequence without any of its elements. This
+    /// is the smallest witness of a sequence, so a client following a log can poll it and
+    /// only fetch the new elements once the certified length grows.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::hashtree::LookupResult;
+    /// use certified_vars::{AsHashTree, Seq};
+    ///
+    /// let mut log = Seq::<u32>::new();
+    /// log.append(1);
+    ///
+    /// let head = log.witness_head();
+    /// assert_eq!(head.reconstruct(), log.root_hash());
+    /// assert_eq!(
+    ///     head.lookup_path(&[b"len"]),
+    ///     LookupResult::Found(&1u64.to_be_bytes())
+    /// );
+    /// ```
+    #[inline]
+    pub fn witness_head(&self) -> HashTree<'_> {
+        self.witness_with_len(&[])
+    }
+
+    /// Returns a witness for the first `k` elements of the sequence along with its length,
+    /// so a client can be sure that these are exactly the first `k` elements. If `k` is
+    /// larger than the length of the sequence, the entire sequence is revealed.
+    pub fn witness_prefix(&self, k: usize) -> HashTree<'_> {
+        let k = k.min(self.len());
+        self.with_len(self.witness_items_range(0, self.len(), 0, k))
+    }
+
+    /// Returns a witness for the elements in `start..end` along with the length of the
+    /// sequence, which is one proof for a page of the sequence. The end is clamped to the
+    /// length of the sequence, and if `start` is not in the sequence the witness is only
+    /// the pruned root, [`Seq::witness_head`] can prove that a page is past the end.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::hashtree::LookupResult;
+    /// use certified_vars::{AsHashTree, Seq};
+    ///
+    /// let log = (0..100u32).collect::<Seq<_>>();
+    /// let page = log.witness_range(20, 30);
+    ///
+    /// assert_eq!(page.reconstruct(), log.root_hash());
+    /// assert_eq!(
+    ///     page.lookup_path(&[b"items", &25u64.to_be_bytes()]),
+    ///     LookupResult::Found(&25u32.to_be_bytes())
+    /// );
+    /// assert_eq!(
+    ///     page.lookup_path(&[b"items", &30u64.to_be_bytes()]),
+    ///     LookupResult::Unknown
+    /// );
+    /// ```
+    pub fn witness_range(&self, start: usize, end: usize) -> HashTree<'_> {
+        if start >= self.len() {
+            return HashTree::Pruned(self.root_hash());
+        }
+
+        let end = end.min(self.len());
+        self.with_len(self.witness_items_range(0, self.len(), start, end))
+    }
+
+    /// Returns the root hash of a sequence that only contains the first `k` elements of this
+    /// sequence, so two sequences agree on their first `k` elements if and only if they
+    /// have the same prefix hash. If `k` is larger than the length of the sequence, this is
+    /// the root hash of the entire sequence.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::Seq;
+    ///
+    /// let a = (0..100u32).collect::<Seq<_>>();
+    /// let b = (0..100u32).map(|i| if i < 37 { i } else { 0 }).collect::<Seq<_>>();
+    ///
+    /// // Find the length of the common prefix.
+    /// let (mut lo, mut hi) = (0, 100);
+    /// while lo < hi {
+    ///     let mid = (lo + hi + 1) / 2;
+    ///     if a.prefix_hash(mid) == b.prefix_hash(mid) {
+    ///         lo = mid;
+    ///     } else {
+    ///         hi = mid - 1;
+    ///     }
+    /// }
+    ///
+    /// assert_eq!(lo, 37);
+    /// ```
+    pub fn prefix_hash(&self, k: usize) -> Hash {
+        let k = k.min(self.len());
+        items_root_hash(&self.range_hash(0, k), k)
+    }
+
+    /// Like [`Seq::witness_with_len`], but for the sequence as it was when it only had its
+    /// first `len` elements. `len` must not be larger than the length of the sequence.
+    pub(crate) fn witness_with_len_at(&self, len: usize, indices: &[usize]) -> HashTree<'_> {
+        debug_assert!(len <= self.len());
+        let mut indices = indices
+            .iter()
+            .copied()
+            .filter(|i| *i < len)
+            .collect::<Vec<_>>();
+        indices.sort_unstable();
+        indices.dedup();
+
+        tree_with_len(self.witness_items(0, len, &indices), len)
+    }
+
+    /// Like [`Seq::witness_prefix`], but for the sequence as it was when it only had its
+    /// first `len` elements. `len` must not be larger than the length of the sequence.
+    pub(crate) fn witness_prefix_at(&self, len: usize, k: usize) -> HashTree<'_> {
+        debug_assert!(len <= self.len());
+        tree_with_len(self.witness_items_range(0, len, 0, k.min(len)), len)
+    }
+
+    /// Returns the full hash tree of the sequence as it was when it only had its first `len`
+    /// elements. `len` must not be larger than the length of the sequence.
+    pub(crate) fn hash_tree_at(&self, len: usize) -> HashTree<'_> {
+        debug_assert!(len <= self.len());
+        tree_with_len(self.items_tree(0, len), len)
+    }
+
     /// Recompute the hash of the sequence.
     #[inline]
     fn recompute_hash(&mut self, prev_len: usize) {
-        let mut hash = self.hash;
+        for index in prev_len..self.items.len() {
+            self.push_hash(index);
+        }
+    }
+
+    /// Store the hash of the element at the given index, and the hash of every complete
+    /// subtree that ends with this element.
+    fn push_hash(&mut self, index: usize) {
+        let mut hash = labeled_hash(&item_label(index), &self.items[index].root_hash());
+        let mut level = 0;
+        let mut position = index;
 
-        for item in &self.items[prev_len..] {
-            let mut h = Sha256::new();
-            h.update(&hash);
-            h.update(item.root_hash());
-            hash = h.finalize().into();
+        loop {
+            if self.hashes.len() == level {
+                self.hashes.push(Vec::new());
+            }
+
+            debug_assert_eq!(self.hashes[level].len(), position);
+            self.hashes[level].push(hash);
+
+            if position & 1 == 0 {
+                break;
+            }
+
+            hash = fork_hash(&self.hashes[level][position - 1], &hash);
+            level += 1;
+            position /= 2;
         }
+    }
 
-        self.hash = hash;
+    /// Recompute the hash of the element at the given index, and the hash of every complete
+    /// subtree that contains it.
+    fn update_hash(&mut self, index: usize) {
+        self.hashes[0][index] = labeled_hash(&item_label(index), &self.items[index].root_hash());
+        let mut level = 0;
+        let mut position = index / 2;
+
+        while level + 1 < self.hashes.len() && position < self.hashes[level + 1].len() {
+            let hash = fork_hash(
+                &self.hashes[level][2 * position],
+                &self.hashes[level][2 * position + 1],
+            );
+            self.hashes[level + 1][position] = hash;
+            level += 1;
+            position /= 2;
+        }
+    }
+
+    /// Returns the root hash of `T(start..end)`.
+    fn range_hash(&self, start: usize, end: usize) -> Hash {
+        let size = end - start;
+
+        if size == 0 {
+            return HashTree::Empty.reconstruct();
+        }
+
+        if size.is_power_of_two() && start & (size - 1) == 0 {
+            let level = size.trailing_zeros() as usize;
+            return self.hashes[level][start >> level];
+        }
+
+        let mid = start + split_point(size);
+        fork_hash(&self.range_hash(start, mid), &self.range_hash(mid, end))
+    }
+
+    /// Returns the hash tree of `T(start..end)`, where only the elements at the given
+    /// indices are present. The indices must be sorted and be in the range.
+    fn witness_items(&self, start: usize, end: usize, indices: &[usize]) -> HashTree<'_> {
+        if start == end {
+            return HashTree::Empty;
+        }
+
+        if indices.is_empty() {
+            return HashTree::Pruned(self.range_hash(start, end));
+        }
+
+        if end - start == 1 {
+            return self.item_tree(start);
+        }
+
+        let mid = start + split_point(end - start);
+        let at = indices.partition_point(|i| *i < mid);
+
+        fork(
+            self.witness_items(start, mid, &indices[..at]),
+            self.witness_items(mid, end, &indices[at..]),
+        )
+    }
+
+    /// Returns the hash tree of `T(start..end)`, where only the elements in `from..to`
+    /// are present.
+    fn witness_items_range(
+        &self,
+        start: usize,
+        end: usize,
+        from: usize,
+        to: usize,
+    ) -> HashTree<'_> {
+        if start == end {
+            return HashTree::Empty;
+        }
+
+        if to <= start || end <= from || from >= to {
+            return HashTree::Pruned(self.range_hash(start, end));
+        }
+
+        if from <= start && end <= to {
+            return self.items_tree(start, end);
+        }
+
+        let mid = start + split_point(end - start);
+
+        fork(
+            self.witness_items_range(start, mid, from, to),
+            self.witness_items_range(mid, end, from, to),
+        )
+    }
+
+    /// Returns the full hash tree of `T(start..end)`.
+    fn items_tree(&self, start: usize, end: usize) -> HashTree<'_> {
+        match end - start {
+            0 => HashTree::Empty,
+            1 => self.item_tree(start),
+            size => {
+                let mid = start + split_point(size);
+                fork(self.items_tree(start, mid), self.items_tree(mid, end))
+            }
+        }
+    }
+
+    #[inline]
+    fn item_tree(&self, index: usize) -> HashTree<'_> {
+        HashTree::Labeled(
+            Cow::Owned(item_label(index).to_vec()),
+            Box::new(self.items[index].as_hash_tree()),
+        )
+    }
+
+    /// Put the given tree of the items next to the length of this sequence.
+    #[inline]
+    fn with_len<'a>(&self, items: HashTree<'a>) -> HashTree<'a> {
+        tree_with_len(items, self.len())
+    }
+}
+
+/// Returns the label used for the element at the given index.
+#[inline]
+fn item_label(index: usize) -> [u8; 8] {
+    (index as u64).to_be_bytes()
+}
+
+/// Returns the content of the leaf used for the length of a sequence.
+#[inline]
+fn len_label(len: usize) -> [u8; 8] {
+    (len as u64).to_be_bytes()
+}
+
+/// Put the given tree of the items next to the given length.
+#[inline]
+fn tree_with_len(items: HashTree<'_>, len: usize) -> HashTree<'_> {
+    let len = len_label(len);
+    fork(
+        labeled(ITEMS_LABEL, items),
+        labeled(LEN_LABEL, HashTree::Leaf(Cow::Owned(len.to_vec()))),
+    )
+}
+
+/// Returns the root hash of a sequence with the given length, given the root hash of its
+/// items.
+#[inline]
+fn items_root_hash(items: &Hash, len: usize) -> Hash {
+    let len = leaf_hash(&len_label(len));
+    fork_hash(
+        &labeled_hash(ITEMS_LABEL, items),
+        &labeled_hash(LEN_LABEL, &len),
+    )
+}
+
+/// Returns the largest power of two that is smaller than `n`, `n` must be at least 2.
+#[inline]
+fn split_point(n: usize) -> usize {
+    debug_assert!(n > 1);
+    1 << (usize::BITS - 1 - (n - 1).leading_zeros())
+}
+
+/// A mutable reference to an element of a [`Seq`] which updates the hash of the sequence
+/// when it is dropped, see [`Seq::get_mut`].
+pub struct SeqGuard<'a, T: AsHashTree> {
+    seq: &'a mut Seq<T>,
+    index: usize,
+}
+
+impl<'a, T: AsHashTree> Deref for SeqGuard<'a, T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.seq.items[self.index]
+    }
+}
+
+impl<'a, T: AsHashTree> DerefMut for SeqGuard<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.seq.items[self.index]
+    }
+}
+
+impl<'a, T: AsHashTree> Drop for SeqGuard<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.seq.update_hash(self.index);
+    }
+}
+
+/// A reserved index of a [`Seq`], see [`Seq::reserve_index`].
+///
+/// # Panics
+///
+/// A reservation panics if it is dropped without being fulfilled or cancelled, since that
+/// means the index that was handed out is never used.
+#[must_use = "The reservation must be fulfilled or cancelled."]
+pub struct Reservation<'a, T: AsHashTree> {
+    seq: &'a mut Seq<T>,
+    fulfilled: bool,
+}
+
+impl<'a, T: AsHashTree> Reservation<'a, T> {
+    /// Returns the reserved index.
+    #[inline]
+    pub fn index(&self) -> usize {
+        self.seq.next_index()
+    }
+
+    /// Append the given value at the reserved index.
+    #[inline]
+    pub fn fulfill(mut self, value: T) {
+        self.fulfilled = true;
+        self.seq.append(value);
+    }
+
+    /// Release the reserved index without appending anything, the index is then used by
+    /// the next append.
+    #[inline]
+    pub fn cancel(mut self) {
+        self.fulfilled = true;
+    }
+}
+
+impl<'a, T: AsHashTree> Drop for Reservation<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        if !self.fulfilled && !std::thread::panicking() {
+            panic!("The reserved index {} is not fulfilled.", self.index());
+        }
+    }
+}
+
+impl<T> Default for Seq<T> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: PartialEq> PartialEq for Seq<T> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.items == other.items
+    }
+}
+
+impl<T: Eq> Eq for Seq<T> {}
+
+impl<T: Debug> Debug for Seq<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.items.iter()).finish()
     }
 }
 
 impl<T: AsHashTree> AsHashTree for Seq<T> {
     #[inline]
     fn root_hash(&self) -> Hash {
-        self.hash
+        items_root_hash(&self.range_hash(0, self.len()), self.len())
     }
 
     #[inline]
     fn as_hash_tree(&self) -> HashTree<'_> {
-        HashTree::Pruned(self.hash)
+        self.with_len(self.items_tree(0, self.len()))
     }
 }
 
@@ -148,7 +783,7 @@ impl<T: AsHashTree> From<Vec<T>> for Seq<T> {
     fn from(items: Vec<T>) -> Self {
         let mut seq = Seq {
             items,
-            hash: [0; 32],
+            hashes: Vec::new(),
         };
 
         seq.recompute_hash(0);
@@ -162,7 +797,7 @@ impl<T: AsHashTree> FromIterator<T> for Seq<T> {
     fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
         let mut seq = Seq {
             items: iter.into_iter().collect(),
-            hash: [0; 32],
+            hashes: Vec::new(),
         };
 
         seq.recompute_hash(0);
@@ -174,7 +809,27 @@ impl<T: AsHashTree> FromIterator<T> for Seq<T> {
 impl<T: AsHashTree> From<Seq<T>> for Vec<T> {
     #[inline]
     fn from(seq: Seq<T>) -> Self {
-        seq.items
+        seq.into_vec()
+    }
+}
+
+impl<'a, T: AsHashTree> IntoIterator for &'a Seq<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.iter()
+    }
+}
+
+impl<T: AsHashTree> IntoIterator for Seq<T> {
+    type Item = T;
+    type IntoIter = std::vec::IntoIter<T>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.into_vec().into_iter()
     }
 }
 
@@ -215,7 +870,7 @@ impl<'a, T: AsHashTree + Clone> From<&'a [T]> for Seq<T> {
     fn from(items: &'a [T]) -> Self {
         let mut seq = Seq {
             items: items.into(),
-            hash: [0; 32],
+            hashes: Vec::new(),
         };
         seq.recompute_hash(0);
         seq
@@ -227,7 +882,7 @@ impl<'a, T: AsHashTree + Clone> From<&'a mut [T]> for Seq<T> {
     fn from(items: &'a mut [T]) -> Self {
         let mut seq = Seq {
             items: items.into(),
-            hash: [0; 32],
+            hashes: Vec::new(),
         };
         seq.recompute_hash(0);
         seq
@@ -259,7 +914,7 @@ impl<'de, T: AsHashTree + Deserialize<'de>> Deserialize<'de> for Seq<T> {
     {
         let mut seq = Seq {
             items: <Vec<T>>::deserialize(deserializer)?,
-            hash: [0; 32],
+            hashes: Vec::new(),
         };
 
         seq.recompute_hash(0);
@@ -295,6 +950,9 @@ mod tests {
         for i in 0..1000 {
             seq.append(i);
             assert_eq!(seq.len(), i + 1);
+            assert_eq!(seq.last(), Some(&i));
+            assert_eq!(seq.get(i / 2), Some(&(i / 2)));
+            assert_eq!(seq.get(i + 1), None);
             let new_hash = seq.root_hash();
             assert_ne!(hash, new_hash);
             hash = new_hash;
@@ -304,6 +962,7 @@ mod tests {
 
         seq.clear();
         assert_eq!(seq.len(), 0);
+        assert_eq!(seq.last(), None);
         assert_eq!(seq.is_empty(), true);
         assert_eq!(seq.root_hash(), Seq::<usize>::new().root_hash());
 
@@ -350,6 +1009,47 @@ mod tests {
         }
     }
 
+    #[test]
+    fn append_seq() {
+        let manual = (0..100).collect::<Seq<usize>>();
+
+        for split in [0, 1, 37, 64, 99, 100] {
+            let mut seq = (0..split).collect::<Seq<usize>>();
+            seq.append_seq((split..100).collect());
+            assert_eq!(seq, manual);
+            assert_eq!(seq.root_hash(), manual.root_hash());
+            assert_eq!(seq.witness_with_len(&[50]).reconstruct(), seq.root_hash());
+        }
+
+        let mut seq = (0..10).collect::<Seq<usize>>();
+        seq.clear();
+        seq.append_seq((0..100).collect());
+        assert_eq!(seq.root_hash(), manual.root_hash());
+    }
+
+    #[test]
+    fn get_mut() {
+        let mut seq = (0..100).collect::<Seq<usize>>();
+        assert!(seq.get_mut(100).is_none());
+
+        for i in [0, 1, 63, 64, 98, 99] {
+            *seq.get_mut(i).unwrap() += 1000;
+            let expected = seq.iter().copied().collect::<Seq<usize>>();
+            assert_eq!(seq.root_hash(), expected.root_hash());
+            assert_eq!(seq.witness_with_len(&[i]).reconstruct(), seq.root_hash());
+        }
+
+        assert_eq!(seq[64], 1064);
+    }
+
+    #[test]
+    fn as_slice() {
+        let seq = (0..5).collect::<Seq<u32>>();
+        assert_eq!(seq.as_slice(), &[0, 1, 2, 3, 4]);
+        assert_eq!(&seq.as_slice()[1..3], &[1, 2]);
+        assert!(Seq::<u32>::new().as_slice().is_empty());
+    }
+
     #[test]
     fn index() {
         let seq = (0..100).collect::<Seq<_>>();
@@ -372,7 +1072,7 @@ mod tests {
         let serialized = serde_cbor::to_vec(&seq).unwrap();
         let actual: Seq<i32> = serde_cbor::from_slice(&serialized).unwrap();
         assert_eq!(actual.len(), 10);
-        assert_eq!(actual.hash, seq.hash);
+        assert_eq!(actual.root_hash(), seq.root_hash());
         assert_eq!(actual, seq);
         let expected = (0..10).collect::<Vec<_>>();
         let deserialized_as_vec: Vec<i32> = serde_cbor::from_slice(&serialized).unwrap();
@@ -386,4 +1086,359 @@ mod tests {
         let decoded: Seq<i32> = decode_one(&encoded).unwrap();
         assert_eq!(seq, decoded);
     }
+
+    #[test]
+    fn as_hash_tree() {
+        for n in 0..40 {
+            let seq = (0..n).collect::<Seq<u32>>();
+            let tree = seq.as_hash_tree();
+            assert_eq!(tree.reconstruct(), seq.root_hash());
+
+            let mut labels = vec![ITEMS_LABEL];
+            let indices = (0..n as u64).map(u64::to_be_bytes).collect::<Vec<_>>();
+            labels.extend(indices.iter().map(|i| i as &[u8]));
+            labels.push(LEN_LABEL);
+            assert_eq!(tree.get_labels(), labels);
+        }
+    }
+
+    #[test]
+    fn witness_head() {
+        let mut seq = Seq::<u32>::new();
+        let mut heads = vec![];
+
+        for i in 0..20u32 {
+            let head = seq.witness_head();
+            assert_eq!(head.reconstruct(), seq.root_hash());
+            assert_eq!(head.get_leaf_values(), vec![&(i as u64).to_be_bytes()]);
+            heads.push(head.reconstruct());
+            seq.append(i);
+        }
+
+        heads.dedup();
+        assert_eq!(heads.len(), 20);
+    }
+
+    #[test]
+    fn witness_with_len() {
+        let empty = Seq::<u32>::new();
+        let tree = empty.witness_with_len(&[0, 1]);
+        assert_eq!(tree.reconstruct(), empty.root_hash());
+        assert_eq!(tree.get_leaf_values(), vec![&0u64.to_be_bytes()]);
+
+        let seq = (0..100).collect::<Seq<u32>>();
+
+        for i in 0..100 {
+            let tree = seq.witness_with_len(&[i]);
+            assert_eq!(tree.reconstruct(), seq.root_hash());
+            assert_eq!(
+                tree.get_leaf_values(),
+                vec![&(i as u32).to_be_bytes() as &[u8], &100u64.to_be_bytes()]
+            );
+        }
+
+        let tree = seq.witness_with_len(&[70, 3, 3, 150, 42]);
+        assert_eq!(tree.reconstruct(), seq.root_hash());
+        assert_eq!(
+            tree.get_leaf_values(),
+            vec![
+                &3u32.to_be_bytes() as &[u8],
+                &42u32.to_be_bytes(),
+                &70u32.to_be_bytes(),
+                &100u64.to_be_bytes()
+            ]
+        );
+    }
+
+    #[test]
+    fn default() {
+        let seq = Seq::<u32>::default();
+        assert!(seq.is_empty());
+
+        let expected = fork_hash(
+            &labeled_hash(b"items", &HashTree::Empty.reconstruct()),
+            &labeled_hash(b"len", &leaf_hash(&0u64.to_be_bytes())),
+        );
+        assert_eq!(seq.root_hash(), expected);
+        assert_eq!(Seq::<u32>::new().root_hash(), expected);
+        assert_eq!(seq.as_hash_tree().reconstruct(), expected);
+
+        // A non-empty sequence that is cleared is back to the same hash.
+        let mut seq = (0..10u32).collect::<Seq<_>>();
+        seq.clear();
+        assert_eq!(seq.root_hash(), expected);
+    }
+
+    #[test]
+    fn witness_prefix() {
+        for n in 0..40u32 {
+            let seq = (0..n).collect::<Seq<u32>>();
+
+            for k in 0..n as usize + 3 {
+                let tree = seq.witness_prefix(k);
+                assert_eq!(tree.reconstruct(), seq.root_hash());
+
+                let indices = (0..k).collect::<Vec<_>>();
+                assert_eq!(tree, seq.witness_with_len(&indices));
+
+                let values = (0..k.min(n as usize) as u32)
+                    .map(|i| i.to_be_bytes().to_vec())
+                    .chain(std::iter::once((n as u64).to_be_bytes().to_vec()))
+                    .collect::<Vec<_>>();
+                assert_eq!(tree.get_leaf_values(), values);
+            }
+        }
+    }
+
+    #[test]
+    fn exact_size_iterators() {
+        let seq = (0..10u32).collect::<Seq<_>>();
+
+        let mut iter = seq.iter();
+        iter.next();
+        assert_eq!(iter.len(), 9);
+        assert_eq!((&seq).into_iter().len(), 10);
+
+        let mut iter = seq.into_iter();
+        iter.next_back();
+        assert_eq!(iter.size_hint(), (9, Some(9)));
+        assert_eq!(iter.collect::<Vec<_>>(), (0..9).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn vec_round_trip() {
+        for n in 0..40u32 {
+            let items = (0..n).map(|i| i * 5).collect::<Vec<_>>();
+
+            let mut appended = Seq::new();
+            for item in &items {
+                appended.append(*item);
+            }
+
+            let seq = Seq::from_vec(items.clone());
+            assert_eq!(seq.root_hash(), appended.root_hash());
+            assert_eq!(seq.as_hash_tree().reconstruct(), seq.root_hash());
+
+            let root_hash = seq.root_hash();
+            let vec = seq.into_vec();
+            assert_eq!(vec, items);
+            assert_eq!(Seq::from_vec(vec).root_hash(), root_hash);
+        }
+    }
+
+    #[test]
+    fn binary_search_by() {
+        let seq = (0..100u32).map(|i| i * 3).collect::<Seq<_>>();
+        for x in 0..310u32 {
+            let result = seq.binary_search_by(|item| item.cmp(&x));
+            if x % 3 == 0 && x < 300 {
+                assert_eq!(result, Ok(x as usize / 3));
+            } else {
+                assert_eq!(result, Err((x as usize).div_ceil(3).min(100)));
+            }
+        }
+
+        assert_eq!(
+            Seq::<u32>::new().binary_search_by(|_| Ordering::Less),
+            Err(0)
+        );
+    }
+
+    #[test]
+    fn reserve_index() {
+        let mut seq = (0..10).collect::<Seq<u32>>();
+        assert_eq!(seq.next_index(), 10);
+
+        let (index, reservation) = seq.reserve_index();
+        assert_eq!(index, 10);
+        assert_eq!(reservation.index(), 10);
+        reservation.cancel();
+        assert_eq!(seq.len(), 10);
+
+        let (index, reservation) = seq.reserve_index();
+        reservation.fulfill(index as u32 * 2);
+        assert_eq!(seq.as_slice()[10], 20);
+        assert_eq!(seq.next_index(), 11);
+
+        let mut expected = (0..10).collect::<Seq<u32>>();
+        expected.append(20);
+        assert_eq!(seq.root_hash(), expected.root_hash());
+    }
+
+    #[test]
+    #[should_panic(expected = "The reserved index 0 is not fulfilled.")]
+    fn reserve_index_unfulfilled() {
+        let mut seq = Seq::<u32>::new();
+        let (_, reservation) = seq.reserve_index();
+        drop(reservation);
+    }
+
+    #[test]
+    fn root_hash_scheme() {
+        fn items_hash(items: &[u32], start: usize) -> Hash {
+            match items.len() {
+                0 => HashTree::Empty.reconstruct(),
+                1 => labeled_hash(&(start as u64).to_be_bytes(), &items[0].root_hash()),
+                n => {
+                    let k = split_point(n);
+                    fork_hash(
+                        &items_hash(&items[..k], start),
+                        &items_hash(&items[k..], start + k),
+                    )
+                }
+            }
+        }
+
+        for n in 0..20u32 {
+            let items = (0..n).map(|i| i * 7).collect::<Vec<_>>();
+            let seq = Seq::from(items.clone());
+            let expected = fork_hash(
+                &labeled_hash(b"items", &items_hash(&items, 0)),
+                &labeled_hash(b"len", &leaf_hash(&(n as u64).to_be_bytes())),
+            );
+            assert_eq!(seq.root_hash(), expected);
+        }
+
+        // A sequence is not confusable with its only element, or with an empty tree.
+        let seq = Seq::from(vec![7u32]);
+        assert_ne!(seq.root_hash(), 7u32.root_hash());
+        assert_ne!(seq.root_hash(), leaf_hash(&7u32.to_be_bytes()));
+        assert_ne!(Seq::<u32>::new().root_hash(), HashTree::Empty.reconstruct());
+        assert_ne!(
+            Seq::<u32>::new().root_hash(),
+            crate::Map::<u32, u32>::new().root_hash()
+        );
+    }
+
+    #[test]
+    fn prefix_hash() {
+        let seq = (0..40u32).collect::<Seq<u32>>();
+
+        for k in 0..45 {
+            let prefix = (0..k.min(40) as u32).collect::<Seq<u32>>();
+            assert_eq!(seq.prefix_hash(k), prefix.root_hash());
+        }
+
+        let mut other = seq.clone();
+        *other.get_mut(20).unwrap() = 0;
+        assert_eq!(seq.prefix_hash(20), other.prefix_hash(20));
+        assert_ne!(seq.prefix_hash(21), other.prefix_hash(21));
+    }
+
+    #[test]
+    fn witness_size() {
+        // Every level of the tree adds one pruned sibling to the witness of an element,
+        // which is 38 bytes in CBOR.
+        let item = vec![7u8; 1024];
+        let single = (0..1).map(|_| item.clone()).collect::<Seq<_>>();
+        let base = single.witness_with_len(&[0]).encoded_size();
+
+        let mut seq = Seq::new();
+        for n in 1..=4096usize {
+            seq.append(item.clone());
+            let depth = usize::BITS - (n - 1).leading_zeros();
+
+            let indices: Vec<usize> = if n <= 64 {
+                (0..n).collect()
+            } else {
+                vec![0, n / 2, n - 1]
+            };
+
+            for index in indices {
+                let size = seq.witness_with_len(&[index]).encoded_size();
+                assert!(
+                    size <= base + 38 * depth as usize,
+                    "{} {} {}",
+                    n,
+                    index,
+                    size
+                );
+            }
+        }
+
+        assert_eq!(seq.witness_with_len(&[2048]).encoded_size(), base + 38 * 12);
+    }
+
+    #[test]
+    fn root_hash_commits_to_len() {
+        let a = (0..10).collect::<Seq<u32>>();
+        let b = (0..11).collect::<Seq<u32>>();
+        assert_ne!(a.root_hash(), b.root_hash());
+        assert_ne!(Seq::<u32>::new().root_hash(), HashTree::Empty.reconstruct());
+    }
+
+    #[test]
+    fn truncate() {
+        let mut seq = (0..37u32).collect::<Seq<_>>();
+        let root_hash = seq.root_hash();
+        seq.truncate(37);
+        seq.truncate(100);
+        assert_eq!(seq.root_hash(), root_hash);
+
+        for len in (0..37).rev() {
+            seq.truncate(len);
+            let expected = (0..len as u32).collect::<Seq<_>>();
+            assert_eq!(seq.as_slice(), expected.as_slice());
+            assert_eq!(seq.root_hash(), expected.root_hash());
+            assert_eq!(seq.as_hash_tree().reconstruct(), seq.root_hash());
+        }
+
+        // The sequence can grow again after it is truncated.
+        for i in 0..37 {
+            seq.append(i);
+        }
+        assert_eq!(seq.root_hash(), root_hash);
+    }
+
+    #[test]
+    fn pop_front() {
+        let mut seq = (0..20u32).collect::<Seq<_>>();
+
+        for i in 0..20u32 {
+            assert_eq!(seq.pop_front(), Some(i));
+            let expected = (i + 1..20).collect::<Seq<_>>();
+            assert_eq!(seq.as_slice(), expected.as_slice());
+            assert_eq!(seq.root_hash(), expected.root_hash());
+            assert_eq!(seq.as_hash_tree().reconstruct(), seq.root_hash());
+        }
+
+        assert_eq!(seq.pop_front(), None);
+        assert_eq!(seq.root_hash(), Seq::<u32>::new().root_hash());
+    }
+
+    #[test]
+    fn witness_range() {
+        let seq = (0..37u32).collect::<Seq<_>>();
+        let root_hash = seq.root_hash();
+
+        for start in 0..40 {
+            for end in start..42 {
+                let witness = seq.witness_range(start, end);
+                assert_eq!(witness.reconstruct(), root_hash);
+
+                if start >= seq.len() {
+                    assert_eq!(witness, HashTree::Pruned(root_hash));
+                    continue;
+                }
+
+                let end = end.min(seq.len());
+                let values = (start as u32..end as u32)
+                    .map(u32::to_be_bytes)
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    witness.get_leaf_values(),
+                    values
+                        .iter()
+                        .map(|v| v.as_ref())
+                        .chain([37u64.to_be_bytes().as_ref()])
+                        .collect::<Vec<_>>()
+                );
+            }
+        }
+
+        assert_eq!(
+            Seq::<u32>::new().witness_range(0, 10),
+            HashTree::Pruned(Seq::<u32>::new().root_hash())
+        );
+    }
 }
@@ -0,0 +1,259 @@
+use crate::{AsHashTree, Hash, HashTree, Seq};
+use std::slice::Iter;
+
+/// An append only list of `T` that can answer certified queries about any of its previous
+/// versions.
+///
+/// Every append bumps the version of the sequence by one, and since elements are never
+/// modified or removed, the sequence at version `v` is exactly its first `v` elements. The
+/// state at any version can be accessed with [`VersionedSeq::at_version`], which returns a
+/// read-only view whose root hash and witnesses are the ones the sequence had at the time.
+///
+/// So a client that certified the root hash of version `v` at some point can still get
+/// witnesses that it can verify against that root hash, no matter how many elements have
+/// been appended since.
+///
+/// # Example
+///
+/// ```
+/// use certified_vars::collections::versioned::VersionedSeq;
+/// use certified_vars::AsHashTree;
+///
+/// let mut seq = VersionedSeq::<u32>::new();
+/// seq.append(10);
+/// seq.append(20);
+/// let old_root = seq.root_hash();
+///
+/// assert_eq!(seq.append(30), 3);
+///
+/// let v2 = seq.at_version(2).unwrap();
+/// assert_eq!(v2.as_slice(), &[10, 20]);
+/// assert_eq!(v2.root_hash(), old_root);
+/// assert_eq!(v2.witness_with_len(&[1]).reconstruct(), old_root);
+/// assert!(seq.at_version(4).is_none());
+/// ```
+///
+/// # Hash tree
+///
+/// The hash tree is the same as the one of a [`Seq`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionedSeq<T> {
+    seq: Seq<T>,
+}
+
+/// A read-only view of a [`VersionedSeq`] at one of its versions, see
+/// [`VersionedSeq::at_version`].
+#[derive(Debug)]
+pub struct SeqVersion<'a, T> {
+    seq: &'a Seq<T>,
+    version: u64,
+}
+
+impl<T> VersionedSeq<T> {
+    /// Create a new, empty sequence at version zero.
+    #[inline]
+    pub const fn new() -> Self {
+        Self { seq: Seq::new() }
+    }
+}
+
+impl<T: AsHashTree> VersionedSeq<T> {
+    /// Append a new item to the sequence and return the new version.
+    #[inline]
+    pub fn append(&mut self, item: T) -> u64 {
+        self.seq.append(item);
+        self.version()
+    }
+
+    /// Returns the current version of the sequence, which is the number of appends so far.
+    #[inline]
+    pub fn version(&self) -> u64 {
+        self.seq.len() as u64
+    }
+
+    /// Returns `true` if the sequence does not contain any elements.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.seq.is_empty()
+    }
+
+    /// Returns the number of elements in the sequence.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.seq.len()
+    }
+
+    /// Returns the element at the given index.
+    #[inline]
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.seq.get(index)
+    }
+
+    /// Returns an iterator over the elements.
+    #[inline]
+    pub fn iter(&self) -> Iter<'_, T> {
+        self.seq.iter()
+    }
+
+    /// Returns a view of the sequence as it was at the given version, or [`None`] if the
+    /// version is newer than the current version.
+    #[inline]
+    pub fn at_version(&self, version: u64) -> Option<SeqVersion<'_, T>> {
+        if version > self.version() {
+            return None;
+        }
+
+        Some(SeqVersion {
+            seq: &self.seq,
+            version,
+        })
+    }
+
+    /// Returns a view of the current version of the sequence.
+    #[inline]
+    pub fn latest(&self) -> SeqVersion<'_, T> {
+        SeqVersion {
+            seq: &self.seq,
+            version: self.version(),
+        }
+    }
+
+    /// Returns the underlying sequence.
+    #[inline]
+    pub fn as_seq(&self) -> &Seq<T> {
+        &self.seq
+    }
+}
+
+impl<'a, T: AsHashTree> SeqVersion<'a, T> {
+    /// Returns the version this view is at.
+    #[inline]
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns `true` if the sequence did not contain any elements at this version.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.version == 0
+    }
+
+    /// Returns the number of elements in the sequence at this version.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.version as usize
+    }
+
+    /// Returns the element at the given index, or [`None`] if it was not appended yet at
+    /// this version.
+    #[inline]
+    pub fn get(&self, index: usize) -> Option<&'a T> {
+        self.as_slice().get(index)
+    }
+
+    /// Returns an iterator over the elements of the sequence at this version.
+    #[inline]
+    pub fn iter(&self) -> Iter<'a, T> {
+        self.as_slice().iter()
+    }
+
+    /// Returns the elements of the sequence at this version.
+    #[inline]
+    pub fn as_slice(&self) -> &'a [T] {
+        &self.seq.as_slice()[..self.len()]
+    }
+
+    /// Returns a witness for the elements at the given indices along with the length of
+    /// the sequence at this version, see [`Seq::witness_with_len`].
+    #[inline]
+    pub fn witness_with_len(&self, indices: &[usize]) -> HashTree<'a> {
+        self.seq.witness_with_len_at(self.len(), indices)
+    }
+
+    /// Returns a witness for the first `k` elements along with the length of the sequence
+    /// at this version, see [`Seq::witness_prefix`].
+    #[inline]
+    pub fn witness_prefix(&self, k: usize) -> HashTree<'a> {
+        self.seq.witness_prefix_at(self.len(), k)
+    }
+}
+
+impl<T> Clone for SeqVersion<'_, T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for SeqVersion<'_, T> {}
+
+impl<T: AsHashTree> AsHashTree for SeqVersion<'_, T> {
+    #[inline]
+    fn root_hash(&self) -> Hash {
+        self.seq.prefix_hash(self.len())
+    }
+
+    #[inline]
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        self.seq.hash_tree_at(self.len())
+    }
+}
+
+impl<T> Default for VersionedSeq<T> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: AsHashTree> AsHashTree for VersionedSeq<T> {
+    #[inline]
+    fn root_hash(&self) -> Hash {
+        self.seq.root_hash()
+    }
+
+    #[inline]
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        self.seq.as_hash_tree()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn at_version() {
+        let mut seq = VersionedSeq::<u32>::new();
+        let mut roots = vec![seq.root_hash()];
+        for i in 0..40 {
+            assert_eq!(seq.append(i * 3), i as u64 + 1);
+            roots.push(seq.root_hash());
+        }
+
+        for (v, root) in roots.iter().enumerate() {
+            let view = seq.at_version(v as u64).unwrap();
+            let expected = (0..v as u32).map(|i| i * 3).collect::<Seq<_>>();
+
+            assert_eq!(view.len(), v);
+            assert_eq!(view.as_slice(), expected.as_slice());
+            assert_eq!(view.get(v), None);
+            assert_eq!(view.root_hash(), *root);
+            assert_eq!(view.as_hash_tree(), expected.as_hash_tree());
+            assert_eq!(view.as_hash_tree().reconstruct(), *root);
+
+            for k in 0..v + 2 {
+                assert_eq!(view.witness_prefix(k), expected.witness_prefix(k));
+            }
+
+            let indices = [0, v / 2, v, v + 10];
+            let witness = view.witness_with_len(&indices);
+            assert_eq!(witness, expected.witness_with_len(&indices));
+            assert_eq!(witness.reconstruct(), *root);
+        }
+
+        assert!(seq.at_version(41).is_none());
+        assert_eq!(seq.latest().root_hash(), seq.root_hash());
+        assert_eq!(seq.latest().version(), 40);
+    }
+}
@@ -4,11 +4,14 @@ use serde::{ser::SerializeSeq, Serialize, Serializer};
 use serde_bytes::Bytes;
 use sha2::{Digest, Sha256};
 use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display, Formatter};
 
 /// SHA-256 hash bytes.
 pub type Hash = [u8; 32];
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct ForkInner<'a>(pub HashTree<'a>, pub HashTree<'a>);
 
 impl<'a> ForkInner<'a> {
@@ -23,8 +26,11 @@ impl<'a> ForkInner<'a> {
 
 /// HashTree as defined in the interfaces spec.
 /// https://sdk.dfinity.org/docs/interface-spec/index.html#_certificate
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum HashTree<'a> {
+    /// The empty tree, which is the tree of an empty collection. Its hash is the hash of
+    /// the `ic-hashtree-empty` domain separator, and unlike a pruned node it proves that
+    /// there is no data in its place.
     Empty,
     Fork(Box<ForkInner<'a>>),
     Labeled(Cow<'a, [u8]>, Box<HashTree<'a>>),
@@ -32,46 +38,1366 @@ pub enum HashTree<'a> {
     Pruned(Hash),
 }
 
+/// The result of looking up a path in a [`HashTree`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LookupResult<'a> {
+    /// The path leads to a leaf with the given value.
+    Found(&'a [u8]),
+    /// The tree proves that the path does not exist.
+    Absent,
+    /// The path might exist, but it is pruned in the tree.
+    Unknown,
+    /// The path leads to a node that is not a leaf.
+    Error,
+}
+
+/// A child of a directory in a [`HashTree`], see [`HashTree::directory`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirectoryEntry<'t, 'a> {
+    /// A child with its label and its subtree.
+    Labeled(&'t [u8], &'t HashTree<'a>),
+    /// A pruned part of the directory, which hides one or more children.
+    Pruned(&'t Hash),
+}
+
+/// A step from the leaf at a path in a [`HashTree`] to the root of the tree, see
+/// [`HashTree::auth_cache_for`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthStep {
+    /// The node on the path is the right child of a fork with this left child.
+    Left(Hash),
+    /// The node on the path is the left child of a fork with this right child.
+    Right(Hash),
+    /// The node on the path is under the next label of the path, counting from its end.
+    Label,
+}
+
+/// The error type for decoding a [`HashTree`] from the compact format, see
+/// [`HashTree::to_compact_bytes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactError {
+    /// The input ended before the entire tree was decoded.
+    UnexpectedEnd,
+    /// The input contains a node with an unknown tag.
+    InvalidTag(u8),
+    /// A length does not fit in a `usize`.
+    InvalidLength,
+    /// The tree is nested deeper than what the decoder accepts.
+    TooDeep,
+    /// There are bytes left after the end of the tree.
+    TrailingBytes,
+    /// A pruned node refers to a hash that does not appear before it.
+    InvalidReference,
+}
+
+impl Display for CompactError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CompactError::UnexpectedEnd => write!(f, "Unexpected end of the input"),
+            CompactError::InvalidTag(tag) => write!(f, "Invalid node tag {}", tag),
+            CompactError::InvalidLength => write!(f, "Invalid length"),
+            CompactError::TooDeep => write!(f, "The tree is too deep"),
+            CompactError::TrailingBytes => write!(f, "Trailing bytes after the tree"),
+            CompactError::InvalidReference => write!(f, "Invalid reference to a pruned hash"),
+        }
+    }
+}
+
+impl std::error::Error for CompactError {}
+
+/// The error type for decoding and verifying a [`HashTree`] that is received from an
+/// untrusted party, see [`HashTree::verify_from_cbor`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The input is not valid CBOR.
+    InvalidCbor(String),
+    /// The input is valid CBOR, but it is not the encoding of a hash tree.
+    MalformedNode,
+    /// The labels of the children of a node are not in the ascending order, or a label
+    /// is used more than once.
+    UnsortedLabels,
+    /// The root hash of the tree is not the expected one.
+    RootHashMismatch { expected: Hash, actual: Hash },
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::InvalidCbor(e) => write!(f, "Invalid CBOR: {}", e),
+            VerifyError::MalformedNode => write!(f, "The CBOR value is not a hash tree"),
+            VerifyError::UnsortedLabels => write!(f, "The labels of the tree are not sorted"),
+            VerifyError::RootHashMismatch { expected, actual } => write!(
+                f,
+                "Expected the root hash {} but the tree has the root hash {}",
+                hex::encode(expected),
+                hex::encode(actual)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// The error type for verifying a chunked response, see [`ChunkVerifier`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChunkError {
+    /// The chunk with the given index does not reconstruct to the expected root hash.
+    RootHashMismatch {
+        index: usize,
+        expected: Hash,
+        actual: Hash,
+    },
+    /// The chunk with the given index was already added.
+    DuplicateChunk(usize),
+    /// The index of the chunk is not smaller than the number of chunks.
+    OutOfRange { index: usize, count: usize },
+    /// The chunks with the given indices were never added.
+    MissingChunks(Vec<usize>),
+}
+
+impl Display for ChunkError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkError::RootHashMismatch {
+                index,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Expected the root hash {} but the chunk {} has the root hash {}",
+                hex::encode(expected),
+                index,
+                hex::encode(actual)
+            ),
+            ChunkError::DuplicateChunk(index) => write!(f, "The chunk {} is duplicated", index),
+            ChunkError::OutOfRange { index, count } => write!(
+                f,
+                "The chunk {} is out of range, there are only {} chunks",
+                index, count
+            ),
+            ChunkError::MissingChunks(indices) => {
+                write!(f, "Missing the chunks {:?}", indices)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ChunkError {}
+
+/// A client-side verifier for a response that is split into multiple witnesses of the
+/// same tree, such as the ones returned by [`HashTree::split_by_size`] or
+/// [`Map::certified_batch`](crate::Map::certified_batch).
+///
+/// Each chunk is checked against the certified root hash as soon as it is added, and the
+/// chunks are merged into a single witness once all of them are received.
+///
+/// # Example
+///
+/// ```
+/// use certified_vars::hashtree::{ChunkVerifier, LookupResult};
+/// use certified_vars::{AsHashTree, Map};
+///
+/// let mut map = Map::<u32, Vec<u8>>::new();
+/// for i in 0..100 {
+///     map.insert(i, vec![i as u8; 100]);
+/// }
+///
+/// let chunks = map.certified_batch(&[1, 20, 50, 70], 256);
+/// let mut verifier = ChunkVerifier::new(map.root_hash(), chunks.len());
+///
+/// // The chunks can arrive in any order.
+/// for (index, chunk) in chunks.into_iter().enumerate().rev() {
+///     verifier.add_chunk(index, chunk).unwrap();
+/// }
+///
+/// let witness = verifier.finish().unwrap();
+/// assert_eq!(
+///     witness.lookup_path(&[&20u32.to_be_bytes()]),
+///     LookupResult::Found(&[20; 100])
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct ChunkVerifier<'a> {
+    /// The root hash that every chunk must reconstruct to.
+    root_hash: Hash,
+    /// The chunks by their index, [`None`] for the chunks that are not received yet.
+    chunks: Vec<Option<HashTree<'a>>>,
+}
+
+impl<'a> ChunkVerifier<'a> {
+    /// Create a verifier that expects `count` chunks which reconstruct to the given
+    /// certified root hash.
+    pub fn new(root_hash: Hash, count: usize) -> Self {
+        Self {
+            root_hash,
+            chunks: vec![None; count],
+        }
+    }
+
+    /// Returns the number of chunks that are added so far.
+    pub fn received(&self) -> usize {
+        self.chunks.iter().filter(|chunk| chunk.is_some()).count()
+    }
+
+    /// Returns `true` if every chunk is added.
+    pub fn is_complete(&self) -> bool {
+        self.chunks.iter().all(Option::is_some)
+    }
+
+    /// Verify the chunk with the given index and add it to the response.
+    pub fn add_chunk(&mut self, index: usize, chunk: HashTree<'a>) -> Result<(), ChunkError> {
+        let count = self.chunks.len();
+        let slot = self
+            .chunks
+            .get_mut(index)
+            .ok_or(ChunkError::OutOfRange { index, count })?;
+
+        if slot.is_some() {
+            return Err(ChunkError::DuplicateChunk(index));
+        }
+
+        let actual = chunk.reconstruct();
+        if actual != self.root_hash {
+            return Err(ChunkError::RootHashMismatch {
+                index,
+                expected: self.root_hash,
+                actual,
+            });
+        }
+
+        *slot = Some(chunk);
+        Ok(())
+    }
+
+    /// Merge the chunks into a single witness that reveals everything that is revealed
+    /// by any of them, or return the indices of the chunks that were never added.
+    ///
+    /// A response without any chunks is the pruned root.
+    pub fn finish(self) -> Result<HashTree<'a>, ChunkError> {
+        let missing = self
+            .chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, chunk)| chunk.is_none())
+            .map(|(index, _)| index)
+            .collect::<Vec<_>>();
+
+        if !missing.is_empty() {
+            return Err(ChunkError::MissingChunks(missing));
+        }
+
+        Ok(self
+            .chunks
+            .into_iter()
+            .flatten()
+            .reduce(HashTree::merge)
+            .unwrap_or(HashTree::Pruned(self.root_hash)))
+    }
+}
+
+/// The maximum depth of a tree that is accepted by [`HashTree::from_compact_bytes`].
+const COMPACT_MAX_DEPTH: usize = 1024;
+
+/// Compute the root hash of a tree from the value of the leaf at the given path and the
+/// steps from the leaf to the root that are returned by [`HashTree::auth_cache_for`].
+///
+/// Returns `None` if the number of labels in the steps is not the length of the path, so
+/// the cache does not belong to this path.
+pub fn reconstruct_from_auth_cache(
+    key_path: &[&[u8]],
+    leaf: &[u8],
+    cache: &[AuthStep],
+) -> Option<Hash> {
+    let mut labels = key_path.iter().rev();
+    let mut hash = leaf_hash(leaf);
+
+    for step in cache {
+        hash = match step {
+            AuthStep::Left(left) => fork_hash(left, &hash),
+            AuthStep::Right(right) => fork_hash(&hash, right),
+            AuthStep::Label => labeled_hash(labels.next()?, &hash),
+        };
+    }
+
+    match labels.next() {
+        Some(_) => None,
+        None => Some(hash),
+    }
+}
+
+/// The panic message of a path that is expected to lead to a leaf of a tree.
+const NOT_A_LEAF_PATH: &str = "The path does not lead to a leaf in the tree.";
+
+/// Render the graph under `root` as a Graphviz DOT graph with the given name, `describe`
+/// returns the attributes of a node and its children.
+pub(crate) fn to_dot<'t, T, F>(name: &str, root: &'t T, describe: F) -> String
+where
+    F: Fn(&'t T) -> (String, Vec<&'t T>),
+{
+    fn go<'t, T, F>(node: &'t T, describe: &F, out: &mut String, next_id: &mut usize) -> usize
+    where
+        F: Fn(&'t T) -> (String, Vec<&'t T>),
+    {
+        let id = *next_id;
+        *next_id += 1;
+
+        let (attributes, children) = describe(node);
+        out.push_str(&format!("  n{} [{}];\n", id, attributes));
+
+        for child in children {
+            let child_id = go(child, describe, out, next_id);
+            out.push_str(&format!("  n{} -> n{};\n", id, child_id));
+        }
+
+        id
+    }
+
+    let mut out = format!("digraph {} {{\n", name);
+    go(root, &describe, &mut out, &mut 0);
+    out.push_str("}\n");
+    out
+}
+
+/// Create a fork of the given trees.
+///
+/// # Panics
+///
+/// In debug builds, if the last label in `l` is not smaller than the first label in `r`,
+/// which would make the labels of the fork unsorted. The labels that are hidden behind a
+/// pruned node are not checked.
 pub fn fork<'a>(l: HashTree<'a>, r: HashTree<'a>) -> HashTree<'a> {
+    #[cfg(debug_assertions)]
+    if let (Some(last), Some(first)) = (edge_label(&l, true), edge_label(&r, false)) {
+        assert!(
+            label_cmp(last, first) == Ordering::Less,
+            "The labels of the fork are not sorted: {:?} is followed by {:?}.",
+            last,
+            first
+        );
+    }
+
     HashTree::Fork(Box::new(ForkInner(l, r)))
 }
 
+/// Returns the last (or the first) label of a fork, or `None` if it is not known because
+/// it is pruned or because there is no label.
+#[cfg(debug_assertions)]
+fn edge_label<'t>(tree: &'t HashTree<'_>, last: bool) -> Option<&'t [u8]> {
+    match tree {
+        HashTree::Labeled(label, _) => Some(label),
+        HashTree::Fork(lr) => {
+            let (near, far) = if last {
+                (lr.right(), lr.left())
+            } else {
+                (lr.left(), lr.right())
+            };
+
+            match near {
+                HashTree::Empty => edge_label(far, last),
+                near => edge_label(near, last),
+            }
+        }
+        _ => None,
+    }
+}
+
 pub fn labeled<'a>(l: &'a [u8], t: HashTree<'a>) -> HashTree<'a> {
     HashTree::Labeled(Cow::Borrowed(l), Box::new(t))
 }
 
-pub fn fork_hash(l: &Hash, r: &Hash) -> Hash {
-    let mut h = domain_sep("ic-hashtree-fork");
-    h.update(&l[..]);
-    h.update(&r[..]);
-    h.finalize().into()
-}
+/// Create a tree out of the given labeled subtrees. The children are sorted by their
+/// labels and are put in a balanced tree of forks.
+///
+/// # Panics
+///
+/// If the same label is used more than once.
+pub fn labeled_fork<'a>(mut children: Vec<(Cow<'a, [u8]>, HashTree<'a>)>) -> HashTree<'a> {
+    children.sort_by(|(a, _), (b, _)| label_cmp(a, b));
+
+    if let Some(w) = children.windows(2).find(|w| w[0].0 == w[1].0) {
+        panic!("Label {:?} is used more than once.", w[0].0);
+    }
+
+    balanced_fork(
+        children
+            .into_iter()
+            .map(|(label, tree)| HashTree::Labeled(label, Box::new(tree)))
+            .collect(),
+    )
+}
+
+/// Put the given trees in a balanced tree of forks while preserving their order, the
+/// same way the [`GroupBuilder`] creates the tree of a directory.
+///
+/// [`GroupBuilder`]: crate::GroupBuilder
+pub fn balanced_fork(trees: Vec<HashTree<'_>>) -> HashTree<'_> {
+    let mut trees = VecDeque::from(trees);
+
+    while trees.len() > 1 {
+        let mut next = VecDeque::with_capacity(trees.len() / 2 + 1);
+
+        while trees.len() > 1 {
+            let l = trees.pop_front().unwrap();
+            let r = trees.pop_front().unwrap();
+            next.push_back(fork(l, r));
+        }
+
+        if let Some(last) = trees.pop_front() {
+            next.push_back(last);
+        }
+
+        trees = next;
+    }
+
+    trees.pop_front().unwrap_or(HashTree::Empty)
+}
+
+/// Create a [`HashTree`] in a declarative way, the labeled children of each node are
+/// sorted and put in a balanced tree using [`labeled_fork`].
+///
+/// Each child is one of `leaf(data)`, `pruned(hash)`, `empty`, `tree(hash_tree)` or a
+/// nested list of children in braces.
+///
+/// # Example
+///
+/// ```
+/// use certified_vars::hashtree;
+///
+/// let tree = hashtree! {
+///     labeled "b" => leaf(b"good"),
+///     labeled "a" => {
+///         labeled "x" => leaf(b"hello"),
+///         labeled "y" => leaf("world"),
+///     },
+///     labeled "c" => empty,
+/// };
+///
+/// assert_eq!(tree.get_labels(), vec![b"a" as &[u8], b"x", b"y", b"b", b"c"]);
+/// ```
+///
+/// [`HashTree`]: crate::HashTree
+/// [`labeled_fork`]: crate::hashtree::labeled_fork
+#[macro_export]
+macro_rules! hashtree {
+    () => {
+        $crate::HashTree::Empty
+    };
+    ($(labeled $label:expr => $kind:tt $(($arg:expr))?),+ $(,)?) => {
+        $crate::hashtree::labeled_fork(vec![
+            $((
+                ::std::borrow::Cow::Owned(::std::convert::AsRef::<[u8]>::as_ref(&$label).to_vec()),
+                $crate::hashtree!(@node $kind $(($arg))?),
+            )),+
+        ])
+    };
+    (@node leaf($data:expr)) => {
+        $crate::HashTree::Leaf(::std::borrow::Cow::Owned(
+            ::std::convert::AsRef::<[u8]>::as_ref(&$data).to_vec(),
+        ))
+    };
+    (@node pruned($hash:expr)) => {
+        $crate::HashTree::Pruned($hash)
+    };
+    (@node tree($tree:expr)) => {
+        $tree
+    };
+    (@node empty) => {
+        $crate::HashTree::Empty
+    };
+    (@node { $($inner:tt)* }) => {
+        $crate::hashtree!($($inner)*)
+    };
+}
+
+/// Compare two labels using the ordering the IC certificate verifier expects.
+///
+/// Labels are compared as plain blobs: byte by byte, and if one label is a prefix of
+/// the other the shorter one comes first. The labeled children of a tree must be in
+/// ascending order according to this function, otherwise a client can not look them up.
+#[inline]
+pub fn label_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    a.cmp(b)
+}
+
+pub fn fork_hash(l: &Hash, r: &Hash) -> Hash {
+    let mut h = domain_sep("ic-hashtree-fork");
+    h.update(&l[..]);
+    h.update(&r[..]);
+    h.finalize().into()
+}
+
+pub fn leaf_hash(data: &[u8]) -> Hash {
+    let mut h = domain_sep("ic-hashtree-leaf");
+    h.update(data);
+    h.finalize().into()
+}
+
+pub fn labeled_hash(label: &[u8], content_hash: &Hash) -> Hash {
+    let mut h = domain_sep("ic-hashtree-labeled");
+    h.update(label);
+    h.update(&content_hash[..]);
+    h.finalize().into()
+}
+
+impl<'a> HashTree<'a> {
+    pub fn reconstruct(&self) -> Hash {
+        match self {
+            Self::Empty => domain_sep("ic-hashtree-empty").finalize().into(),
+            Self::Fork(f) => fork_hash(&f.0.reconstruct(), &f.1.reconstruct()),
+            Self::Labeled(l, t) => {
+                let thash = t.reconstruct();
+                labeled_hash(l, &thash)
+            }
+            Self::Leaf(data) => leaf_hash(data),
+            Self::Pruned(h) => *h,
+        }
+    }
+
+    /// Replace every subtree for which the predicate returns `true` with a pruned node, the
+    /// predicate is called on the nodes from the root down and is not called on the nodes
+    /// inside a subtree that is already pruned. The result reconstructs to the same hash.
+    pub fn prune_by<F>(self, mut f: F) -> HashTree<'a>
+    where
+        F: FnMut(&HashTree<'a>) -> bool,
+    {
+        fn prune<'a, F>(tree: HashTree<'a>, f: &mut F) -> HashTree<'a>
+        where
+            F: FnMut(&HashTree<'a>) -> bool,
+        {
+            if f(&tree) {
+                return HashTree::Pruned(tree.reconstruct());
+            }
+
+            match tree {
+                HashTree::Fork(children) => {
+                    let ForkInner(l, r) = *children;
+                    fork(prune(l, f), prune(r, f))
+                }
+                HashTree::Labeled(label, t) => HashTree::Labeled(label, Box::new(prune(*t, f))),
+                tree => tree,
+            }
+        }
+
+        prune(self, &mut f)
+    }
+
+    /// Replace every leaf with a pruned node of its hash, which keeps the labels and the
+    /// shape of the tree but none of the values. This is the whole tree version of
+    /// [`Map::witness_hash_only`], for a client that already has the values and only needs
+    /// the proof: it checks a value by comparing its [`leaf_hash`] with the pruned node at
+    /// the path of the value. The result reconstructs to the same hash.
+    ///
+    /// [`Map::witness_hash_only`]: crate::Map::witness_hash_only
+    #[inline]
+    pub fn values_to_pruned(self) -> HashTree<'a> {
+        self.prune_by(|tree| matches!(tree, HashTree::Leaf(_)))
+    }
+
+    /// Replace the content of the leaf at the given path with `new_leaf`, and return the new
+    /// root hash of the tree. The path is looked up like in [`HashTree::lookup_path`].
+    ///
+    /// Only the nodes along the path are hashed again, and the other children of the forks
+    /// along the path are hashed with [`HashTree::reconstruct`]. So this is cheap for a
+    /// witness, in which those children are mostly pruned nodes, and it avoids rehashing
+    /// the whole tree when a single leaf of a witness is known to have changed.
+    ///
+    /// # Panics
+    ///
+    /// If the path does not lead to a leaf in this tree, the tree is not changed in this
+    /// case.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let mut map = Map::<String, String>::new();
+    /// map.insert("a".into(), "old".into());
+    /// map.insert("b".into(), "other".into());
+    /// let mut witness = map.witness("a").into_owned();
+    ///
+    /// map.insert("a".into(), "new".into());
+    /// let root_hash = witness.recompute_along_path(&[b"a"], b"new");
+    /// assert_eq!(root_hash, map.root_hash());
+    /// assert_eq!(witness, map.witness("a"));
+    /// ```
+    pub fn recompute_along_path(&mut self, path: &[&[u8]], new_leaf: &[u8]) -> Hash {
+        let cache = self.path_to_leaf(path).expect(NOT_A_LEAF_PATH);
+
+        // The steps lead from the leaf to the root, so they are followed in reverse.
+        let mut tree = self;
+        for step in cache.iter().rev() {
+            tree = match (step, tree) {
+                (AuthStep::Right(_), HashTree::Fork(lr)) => &mut lr.0,
+                (AuthStep::Left(_), HashTree::Fork(lr)) => &mut lr.1,
+                (AuthStep::Label, HashTree::Labeled(_, subtree)) => subtree,
+                _ => unreachable!(),
+            };
+        }
+        *tree = HashTree::Leaf(Cow::Owned(new_leaf.to_vec()));
+
+        reconstruct_from_auth_cache(path, new_leaf, &cache).unwrap()
+    }
+
+    /// Returns the hashes of the siblings of the nodes along the path to the leaf at the
+    /// given path, starting from the leaf and ending at the root, with a marker for each
+    /// label along the way. Along with the labels of the path, this is all a client has to
+    /// store to compute the root hash of the tree from the value of the leaf, see
+    /// [`reconstruct_from_auth_cache`].
+    ///
+    /// So a client that only cares about a single key can keep the cache instead of the
+    /// witness, and check a new value of the key against a new root hash as long as the
+    /// rest of the tree has not changed.
+    ///
+    /// # Panics
+    ///
+    /// If the path does not lead to a leaf in this tree.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::hashtree::reconstruct_from_auth_cache;
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let mut map = Map::<String, String>::new();
+    /// map.insert("a".into(), "x".into());
+    /// map.insert("b".into(), "y".into());
+    ///
+    /// let cache = map.witness("a").auth_cache_for(&[b"a"]);
+    /// assert_eq!(
+    ///     reconstruct_from_auth_cache(&[b"a"], b"x", &cache),
+    ///     Some(map.root_hash())
+    /// );
+    /// ```
+    pub fn auth_cache_for(&self, key_path: &[&[u8]]) -> Vec<AuthStep> {
+        self.path_to_leaf(key_path).expect(NOT_A_LEAF_PATH)
+    }
+
+    /// Returns the steps from the leaf at the given path to the root, see
+    /// [`HashTree::auth_cache_for`], or `None` if the path does not lead to a leaf.
+    fn path_to_leaf(&self, path: &[&[u8]]) -> Option<Vec<AuthStep>> {
+        fn go(tree: &HashTree<'_>, path: &[&[u8]], cache: &mut Vec<AuthStep>) -> bool {
+            let (label, rest) = match path.split_first() {
+                Some(split) => split,
+                None => return matches!(tree, HashTree::Leaf(_)),
+            };
+
+            match tree {
+                HashTree::Fork(lr) => {
+                    if go(&lr.0, path, cache) {
+                        cache.push(AuthStep::Right(lr.1.reconstruct()));
+                        return true;
+                    }
+                    if go(&lr.1, path, cache) {
+                        cache.push(AuthStep::Left(lr.0.reconstruct()));
+                        return true;
+                    }
+                    false
+                }
+                HashTree::Labeled(l, subtree) if l.as_ref() == *label => {
+                    let found = go(subtree, rest, cache);
+                    if found {
+                        cache.push(AuthStep::Label);
+                    }
+                    found
+                }
+                _ => false,
+            }
+        }
+
+        let mut cache = Vec::new();
+        if go(self, path, &mut cache) {
+            Some(cache)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of bytes in the CBOR encoding of this tree, without encoding it.
+    pub fn encoded_size(&self) -> usize {
+        /// The size of the header of a byte string with the given length.
+        fn bytes_header(len: usize) -> usize {
+            match len {
+                0..=23 => 1,
+                24..=0xff => 2,
+                0x100..=0xffff => 3,
+                0x10000..=0xffff_ffff => 5,
+                _ => 9,
+            }
+        }
+
+        // Each node is an array with less than 24 items, that starts with a tag that is
+        // smaller than 24, so both the header of the array and the tag take one byte.
+        match self {
+            HashTree::Empty => 2,
+            HashTree::Fork(lr) => 2 + lr.0.encoded_size() + lr.1.encoded_size(),
+            HashTree::Labeled(l, t) => 2 + bytes_header(l.len()) + l.len() + t.encoded_size(),
+            HashTree::Leaf(data) => 2 + bytes_header(data.len()) + data.len(),
+            HashTree::Pruned(_) => 2 + bytes_header(32) + 32,
+        }
+    }
+
+    /// Encode the tree in a compact binary format, each node is encoded as its tag, which
+    /// is the same as the one used in the CBOR encoding, followed by:
+    ///
+    /// 1. Nothing for `Empty`.
+    /// 2. The left and right subtrees for `Fork`.
+    /// 3. The length of the label as a LEB128 varint, the label and the subtree for
+    ///    `Labeled`.
+    /// 4. The length of the data as a LEB128 varint and the data for `Leaf`.
+    /// 5. The 32 bytes of the hash for `Pruned`.
+    ///
+    /// A pruned node with a hash that already appeared in an earlier pruned node is encoded
+    /// as the tag `5` followed by the index of that hash among the distinct pruned hashes
+    /// in the order of their first appearance, as a LEB128 varint. So every repeated hash
+    /// takes two or three bytes instead of 33, which matters for the trees that contain
+    /// many identical subtrees. For example the [`Map::witness_keys`] of a map with 1000
+    /// entries that all have the same value is 43999 bytes without the references and
+    /// 13030 bytes with them.
+    ///
+    /// This is **not** the format that the IC and its clients understand, and it must
+    /// only be used to pass witnesses between trusted parties, for example between two
+    /// canisters of the same service. A witness that is sent to a client should always
+    /// be encoded as CBOR.
+    ///
+    /// The format saves one byte for every node and three more for every pruned node, for
+    /// example the witness of a 200 byte value in a map with 1000 entries is about 8%
+    /// smaller than its CBOR encoding.
+    ///
+    /// [`Map::witness_keys`]: crate::Map::witness_keys
+    pub fn to_compact_bytes(&self) -> Vec<u8> {
+        fn write_len(out: &mut Vec<u8>, mut len: usize) {
+            while len >= 0x80 {
+                out.push((len as u8) | 0x80);
+                len >>= 7;
+            }
+            out.push(len as u8);
+        }
+
+        fn go<'t>(tree: &'t HashTree, out: &mut Vec<u8>, hashes: &mut HashMap<&'t Hash, usize>) {
+            match tree {
+                HashTree::Empty => out.push(0),
+                HashTree::Fork(lr) => {
+                    out.push(1);
+                    go(lr.left(), out, hashes);
+                    go(lr.right(), out, hashes);
+                }
+                HashTree::Labeled(label, tree) => {
+                    out.push(2);
+                    write_len(out, label.len());
+                    out.extend_from_slice(label);
+                    go(tree, out, hashes);
+                }
+                HashTree::Leaf(data) => {
+                    out.push(3);
+                    write_len(out, data.len());
+                    out.extend_from_slice(data);
+                }
+                HashTree::Pruned(hash) => {
+                    if let Some(index) = hashes.get(hash) {
+                        out.push(5);
+                        write_len(out, *index);
+                    } else {
+                        hashes.insert(hash, hashes.len());
+                        out.push(4);
+                        out.extend_from_slice(hash);
+                    }
+                }
+            }
+        }
+
+        let mut out = Vec::with_capacity(self.encoded_size());
+        go(self, &mut out, &mut HashMap::new());
+        out
+    }
+
+    /// Decode a tree that was encoded using [`HashTree::to_compact_bytes`], the labels and
+    /// the leaves of the tree borrow from the input.
+    pub fn from_compact_bytes(bytes: &'a [u8]) -> Result<HashTree<'a>, CompactError> {
+        fn take<'a>(bytes: &mut &'a [u8], n: usize) -> Result<&'a [u8], CompactError> {
+            if bytes.len() < n {
+                return Err(CompactError::UnexpectedEnd);
+            }
+
+            let (head, tail) = bytes.split_at(n);
+            *bytes = tail;
+            Ok(head)
+        }
+
+        fn read_len(bytes: &mut &[u8]) -> Result<usize, CompactError> {
+            let mut len = 0usize;
+            let mut shift = 0;
+
+            loop {
+                let byte = take(bytes, 1)?[0];
+                let part = ((byte & 0x7f) as usize)
+                    .checked_shl(shift)
+                    .filter(|part| part >> shift == (byte & 0x7f) as usize)
+                    .ok_or(CompactError::InvalidLength)?;
+                len |= part;
+
+                if byte & 0x80 == 0 {
+                    return Ok(len);
+                }
+
+                shift += 7;
+            }
+        }
+
+        fn go<'a>(
+            bytes: &mut &'a [u8],
+            depth: usize,
+            hashes: &mut Vec<Hash>,
+        ) -> Result<HashTree<'a>, CompactError> {
+            if depth > COMPACT_MAX_DEPTH {
+                return Err(CompactError::TooDeep);
+            }
+
+            match take(bytes, 1)?[0] {
+                0 => Ok(HashTree::Empty),
+                1 => {
+                    let left = go(bytes, depth + 1, hashes)?;
+                    let right = go(bytes, depth + 1, hashes)?;
+                    Ok(HashTree::Fork(Box::new(ForkInner(left, right))))
+                }
+                2 => {
+                    let len = read_len(bytes)?;
+                    let label = take(bytes, len)?;
+                    let tree = go(bytes, depth + 1, hashes)?;
+                    Ok(HashTree::Labeled(Cow::Borrowed(label), Box::new(tree)))
+                }
+                3 => {
+                    let len = read_len(bytes)?;
+                    Ok(HashTree::Leaf(Cow::Borrowed(take(bytes, len)?)))
+                }
+                4 => {
+                    let mut hash = [0; 32];
+                    hash.copy_from_slice(take(bytes, 32)?);
+                    hashes.push(hash);
+                    Ok(HashTree::Pruned(hash))
+                }
+                5 => {
+                    let index = read_len(bytes)?;
+                    let hash = hashes.get(index).ok_or(CompactError::InvalidReference)?;
+                    Ok(HashTree::Pruned(*hash))
+                }
+                tag => Err(CompactError::InvalidTag(tag)),
+            }
+        }
+
+        let mut bytes = bytes;
+        let tree = go(&mut bytes, 0, &mut Vec::new())?;
+
+        if !bytes.is_empty() {
+            return Err(CompactError::TrailingBytes);
+        }
+
+        Ok(tree)
+    }
+
+    /// Returns a copy of this tree that does not borrow any data, so it can be kept after
+    /// the data it was built from is changed, for example to cache a witness.
+    pub fn into_owned(self) -> HashTree<'static> {
+        match self {
+            HashTree::Empty => HashTree::Empty,
+            HashTree::Fork(lr) => {
+                let ForkInner(l, r) = *lr;
+                HashTree::Fork(Box::new(ForkInner(l.into_owned(), r.into_owned())))
+            }
+            HashTree::Labeled(label, tree) => {
+                HashTree::Labeled(Cow::Owned(label.into_owned()), Box::new(tree.into_owned()))
+            }
+            HashTree::Leaf(data) => HashTree::Leaf(Cow::Owned(data.into_owned())),
+            HashTree::Pruned(hash) => HashTree::Pruned(hash),
+        }
+    }
+
+    /// Decode a tree from its CBOR encoding, as it is sent by the IC and by the canisters.
+    /// The self-describing CBOR tag is accepted but not required.
+    ///
+    /// This only decodes the tree, use [`HashTree::verify_from_cbor`] to also check the
+    /// tree before using it. Unlike [`fork`] the decoder never panics on unsorted labels.
+    pub fn from_cbor(bytes: &[u8]) -> Result<HashTree<'static>, VerifyError> {
+        use serde_cbor::Value;
+
+        fn blob(value: Value) -> Result<Vec<u8>, VerifyError> {
+            match value {
+                Value::Bytes(bytes) => Ok(bytes),
+                _ => Err(VerifyError::MalformedNode),
+            }
+        }
+
+        fn go(value: Value) -> Result<HashTree<'static>, VerifyError> {
+            let mut items = match value {
+                Value::Array(items) => items.into_iter(),
+                Value::Tag(_, value) => return go(*value),
+                _ => return Err(VerifyError::MalformedNode),
+            };
+
+            let tag = match items.next() {
+                Some(Value::Integer(tag)) => tag,
+                _ => return Err(VerifyError::MalformedNode),
+            };
+
+            let tree = match (tag, items.len()) {
+                (0, 0) => HashTree::Empty,
+                (1, 2) => {
+                    let left = go(items.next().unwrap())?;
+                    let right = go(items.next().unwrap())?;
+                    HashTree::Fork(Box::new(ForkInner(left, right)))
+                }
+                (2, 2) => {
+                    let label = blob(items.next().unwrap())?;
+                    let tree = go(items.next().unwrap())?;
+                    HashTree::Labeled(Cow::Owned(label), Box::new(tree))
+                }
+                (3, 1) => HashTree::Leaf(Cow::Owned(blob(items.next().unwrap())?)),
+                (4, 1) => {
+                    let bytes = blob(items.next().unwrap())?;
+                    if bytes.len() != 32 {
+                        return Err(VerifyError::MalformedNode);
+                    }
+
+                    let mut hash = [0; 32];
+                    hash.copy_from_slice(&bytes);
+                    HashTree::Pruned(hash)
+                }
+                _ => return Err(VerifyError::MalformedNode),
+            };
+
+            Ok(tree)
+        }
+
+        let value = serde_cbor::from_slice::<Value>(bytes)
+            .map_err(|e| VerifyError::InvalidCbor(e.to_string()))?;
+
+        go(value)
+    }
+
+    /// Check that the labels of the children of every node of the tree are in the strictly
+    /// ascending order, which is what the lookups in the tree rely on to prove the absence
+    /// of a label.
+    pub fn validate(&self) -> Result<(), VerifyError> {
+        fn labels<'t>(tree: &'t HashTree<'_>, out: &mut Vec<(&'t [u8], &'t HashTree<'t>)>) {
+            match tree {
+                HashTree::Fork(lr) => {
+                    labels(lr.left(), out);
+                    labels(lr.right(), out);
+                }
+                HashTree::Labeled(label, tree) => out.push((label, tree)),
+                _ => {}
+            }
+        }
+
+        fn go(tree: &HashTree<'_>) -> Result<(), VerifyError> {
+            let mut children = Vec::new();
+            labels(tree, &mut children);
+
+            if children
+                .windows(2)
+                .any(|w| label_cmp(w[0].0, w[1].0) != Ordering::Less)
+            {
+                return Err(VerifyError::UnsortedLabels);
+            }
+
+            children.into_iter().try_for_each(|(_, tree)| go(tree))
+        }
+
+        go(self)
+    }
+
+    /// Decode a witness from its CBOR encoding, check that it is well formed and that its
+    /// root hash is the expected one, and return the witness if all of the checks pass.
+    /// This is the one call a client needs to make before looking up any path in a witness
+    /// that it received along with a certificate for `expected_root`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::hashtree::{HashTree, LookupResult, VerifyError};
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let mut map = Map::<String, u32>::new();
+    /// map.insert("a".into(), 1);
+    /// let bytes = serde_cbor::to_vec(&map.witness("a")).unwrap();
+    ///
+    /// let tree = HashTree::verify_from_cbor(&bytes, &map.root_hash()).unwrap();
+    /// assert_eq!(tree.lookup_path(&[b"a"]), LookupResult::Found(&1u32.to_be_bytes()));
+    ///
+    /// assert!(matches!(
+    ///     HashTree::verify_from_cbor(&bytes, &[0; 32]),
+    ///     Err(VerifyError::RootHashMismatch { .. })
+    /// ));
+    /// ```
+    pub fn verify_from_cbor(
+        bytes: &[u8],
+        expected_root: &Hash,
+    ) -> Result<HashTree<'static>, VerifyError> {
+        let tree = HashTree::from_cbor(bytes)?;
+        tree.validate()?;
+
+        let actual = tree.reconstruct();
+        if actual != *expected_root {
+            return Err(VerifyError::RootHashMismatch {
+                expected: *expected_root,
+                actual,
+            });
+        }
+
+        Ok(tree)
+    }
+
+    /// Split this witness into multiple witnesses of the same tree, such that each leaf
+    /// of this tree is present in exactly one of them and the others are pruned. Each
+    /// witness is encoded in at most `max_bytes` bytes, unless it only contains a single
+    /// leaf that does not fit in `max_bytes` on its own.
+    ///
+    /// The leaves are grouped in order, so each witness contains a continuous run of the
+    /// leaves. A tree without any leaves is returned as is.
+    pub fn split_by_size(self, max_bytes: usize) -> Vec<HashTree<'a>> {
+        let leaves = self.get_leaf_values().len();
+
+        if leaves == 0 || self.encoded_size() <= max_bytes {
+            return vec![self];
+        }
+
+        let fits = |from: usize, to: usize| self.keep_leaves(from, to).encoded_size() <= max_bytes;
+
+        let mut result = Vec::new();
+        let mut from = 0;
+
+        while from < leaves {
+            // Find the largest `to` such that the leaves in `from..to` fit, by doubling
+            // the size of the group and then doing a binary search.
+            let mut size = 1;
+            while from + size < leaves && fits(from, from + size * 2) {
+                size *= 2;
+            }
+
+            let (mut lo, mut hi) = (from + size, (from + size * 2).min(leaves));
+            while lo < hi {
+                let mid = hi - (hi - lo) / 2;
+                if fits(from, mid) {
+                    lo = mid;
+                } else {
+                    hi = mid - 1;
+                }
+            }
+
+            result.push(self.keep_leaves(from, lo));
+            from = lo;
+        }
+
+        result
+    }
+
+    /// Returns a copy of this tree in which only the leaves with an index in `from..to`
+    /// are present, and every subtree without any of those leaves is pruned.
+    fn keep_leaves(&self, from: usize, to: usize) -> HashTree<'a> {
+        fn go<'a>(tree: &HashTree<'a>, from: usize, to: usize, next: &mut usize) -> HashTree<'a> {
+            match tree {
+                HashTree::Leaf(data) => {
+                    let index = *next;
+                    *next += 1;
+                    if from <= index && index < to {
+                        HashTree::Leaf(data.clone())
+                    } else {
+                        HashTree::Pruned(leaf_hash(data))
+                    }
+                }
+                HashTree::Fork(lr) => {
+                    match (go(&lr.0, from, to, next), go(&lr.1, from, to, next)) {
+                        (
+                            l @ (HashTree::Pruned(_) | HashTree::Empty),
+                            r @ (HashTree::Pruned(_) | HashTree::Empty),
+                        ) => HashTree::Pruned(fork_hash(&l.reconstruct(), &r.reconstruct())),
+                        (l, r) => fork(l, r),
+                    }
+                }
+                HashTree::Labeled(label, t) => match go(t, from, to, next) {
+                    t @ (HashTree::Pruned(_) | HashTree::Empty) => {
+                        HashTree::Pruned(labeled_hash(label, &t.reconstruct()))
+                    }
+                    t => HashTree::Labeled(label.clone(), Box::new(t)),
+                },
+                HashTree::Empty => HashTree::Empty,
+                HashTree::Pruned(h) => HashTree::Pruned(*h),
+            }
+        }
+
+        go(self, from, to, &mut 0)
+    }
+
+    /// Merge two witnesses of the same tree into one witness that reveals everything that
+    /// is revealed by either of them.
+    ///
+    /// # Panics
+    ///
+    /// If the two trees do not have the same structure, which happens when they are not
+    /// witnesses of the same tree.
+    pub fn merge(self, other: HashTree<'a>) -> HashTree<'a> {
+        match (self, other) {
+            (Self::Pruned(h), Self::Pruned(_)) => Self::Pruned(h),
+            (Self::Pruned(h), tree) | (tree, Self::Pruned(h)) => {
+                debug_assert_eq!(h, tree.reconstruct(), "Merging unrelated trees.");
+                tree
+            }
+            (Self::Empty, Self::Empty) => Self::Empty,
+            (Self::Fork(a), Self::Fork(b)) => {
+                let (ForkInner(al, ar), ForkInner(bl, br)) = (*a, *b);
+                fork(al.merge(bl), ar.merge(br))
+            }
+            (Self::Labeled(l, a), Self::Labeled(m, b)) if l == m => {
+                Self::Labeled(l, Box::new(a.merge(*b)))
+            }
+            (Self::Leaf(a), Self::Leaf(b)) if a == b => Self::Leaf(a),
+            (a, b) => panic!(
+                "Can not merge trees with different structures: {:?} and {:?}",
+                a, b
+            ),
+        }
+    }
+
+    /// Returns `true` if both trees describe the same underlying tree, regardless of which
+    /// branches are pruned in each of them. A pruned node matches any subtree with the
+    /// same root hash, and every other node must have the same shape and contents.
+    ///
+    /// This is stronger than comparing the root hashes, since the revealed parts of the
+    /// two trees must agree with each other and not only with the root hash.
+    pub fn structurally_equivalent(&self, other: &HashTree<'_>) -> bool {
+        match (self, other) {
+            (HashTree::Pruned(a), HashTree::Pruned(b)) => a == b,
+            (HashTree::Pruned(h), tree) | (tree, HashTree::Pruned(h)) => tree.reconstruct() == *h,
+            (HashTree::Empty, HashTree::Empty) => true,
+            (HashTree::Fork(a), HashTree::Fork(b)) => {
+                a.left().structurally_equivalent(b.left())
+                    && a.right().structurally_equivalent(b.right())
+            }
+            (HashTree::Labeled(l, a), HashTree::Labeled(m, b)) => {
+                l == m && a.structurally_equivalent(b)
+            }
+            (HashTree::Leaf(a), HashTree::Leaf(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// Returns the children of this tree as a flat directory, or [`None`] if this tree is
+    /// not a directory because it contains a leaf between its forks.
+    ///
+    /// The tree only has binary forks, a directory with many children is a tree of forks
+    /// whose leaves are the labeled children, which is how [`labeled_fork`] and the
+    /// [`GroupBuilder`] create them. This method undoes that and returns the children in
+    /// the order of the tree, so a client does not have to care about the shape of the
+    /// forks. The hash of the directory is still the hash of its forks.
+    ///
+    /// [`GroupBuilder`]: crate::GroupBuilder
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::hashtree::{labeled_fork, DirectoryEntry, HashTree};
+    ///
+    /// let tree = labeled_fork(vec![
+    ///     (b"b".as_ref().into(), HashTree::Leaf(b"2".as_ref().into())),
+    ///     (b"a".as_ref().into(), HashTree::Leaf(b"1".as_ref().into())),
+    ///     (b"c".as_ref().into(), HashTree::Leaf(b"3".as_ref().into())),
+    /// ]);
+    ///
+    /// let labels = tree
+    ///     .directory()
+    ///     .unwrap()
+    ///     .into_iter()
+    ///     .map(|entry| match entry {
+    ///         DirectoryEntry::Labeled(label, _) => label,
+    ///         DirectoryEntry::Pruned(_) => unreachable!(),
+    ///     })
+    ///     .collect::<Vec<_>>();
+    /// assert_eq!(labels, [b"a", b"b", b"c"]);
+    /// ```
+    pub fn directory(&self) -> Option<Vec<DirectoryEntry<'_, 'a>>> {
+        fn go<'t, 'a>(
+            tree: &'t HashTree<'a>,
+            entries: &mut Vec<DirectoryEntry<'t, 'a>>,
+        ) -> Option<()> {
+            match tree {
+                HashTree::Empty => {}
+                HashTree::Fork(lr) => {
+                    go(lr.left(), entries)?;
+                    go(lr.right(), entries)?;
+                }
+                HashTree::Labeled(label, tree) => {
+                    entries.push(DirectoryEntry::Labeled(label, tree))
+                }
+                HashTree::Pruned(hash) => entries.push(DirectoryEntry::Pruned(hash)),
+                HashTree::Leaf(_) => return None,
+            }
+
+            Some(())
+        }
+
+        let mut entries = Vec::new();
+        go(self, &mut entries)?;
+        Some(entries)
+    }
+
+    /// Lookup the value at the given path using the `lookup_path` algorithm defined in
+    /// the interface spec. An [`HashTree::Empty`] node proves the absence of any path
+    /// under it, while a pruned node makes the result unknown.
+    pub fn lookup_path(&self, path: &[&[u8]]) -> LookupResult<'_> {
+        let (label, rest) = match path.split_first() {
+            Some(split) => split,
+            None => {
+                return match self {
+                    HashTree::Leaf(value) => LookupResult::Found(value),
+                    HashTree::Empty => LookupResult::Absent,
+                    HashTree::Pruned(_) => LookupResult::Unknown,
+                    HashTree::Fork(_) | HashTree::Labeled(_, _) => LookupResult::Error,
+                };
+            }
+        };
+
+        let mut nodes = Vec::new();
+        self.flatten_forks(&mut nodes);
+
+        // Whether there is a node that could hide the label after the last label that
+        // is smaller than the one we are looking for.
+        let mut unknown = false;
+
+        for node in nodes {
+            match node {
+                HashTree::Labeled(l, tree) => match label_cmp(l, label) {
+                    Ordering::Equal => return tree.lookup_path(rest),
+                    Ordering::Less => unknown = false,
+                    Ordering::Greater => break,
+                },
+                _ => unknown = true,
+            }
+        }
+
+        if unknown {
+            LookupResult::Unknown
+        } else {
+            LookupResult::Absent
+        }
+    }
+
+    /// Push the non-fork nodes of this tree to the given vector in order, empty nodes are
+    /// skipped since they do not contain anything.
+    fn flatten_forks<'b>(&'b self, nodes: &mut Vec<&'b HashTree<'a>>) {
+        match self {
+            HashTree::Empty => {}
+            HashTree::Fork(lr) => {
+                lr.left().flatten_forks(nodes);
+                lr.right().flatten_forks(nodes);
+            }
+            node => nodes.push(node),
+        }
+    }
+
+    /// Returns the `certified_data` of the given canister in this tree, which must be the
+    /// state tree of a certificate issued by the IC. The canister id is in its binary form,
+    /// and the value is at the path `/canister/<canister_id>/certified_data`.
+    ///
+    /// The returned value is the root hash of the canister's own witness, so a client can
+    /// verify a response by checking the signature of the certificate, and then comparing
+    /// this value with the root hash of the witness in the response. Checking the signature
+    /// and the delegation of the certificate is not done by this crate.
+    ///
+    /// Returns [`None`] if the tree does not contain the `certified_data` of the canister.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use certified_vars::hashtree::{fork, labeled, HashTree};
+    /// use certified_vars::{AsHashTree, Map};
+    /// use std::borrow::Cow;
+    ///
+    /// let mut map = Map::<String, u32>::new();
+    /// map.insert("a".into(), 1);
+    /// let root_hash = map.root_hash();
+    ///
+    /// let canister_id = [0, 0, 0, 0, 0, 0, 0, 1, 1, 1];
+    /// let state = fork(
+    ///     labeled(
+    ///         b"canister",
+    ///         labeled(
+    ///             &canister_id,
+    ///             labeled(b"certified_data", HashTree::Leaf(Cow::Borrowed(&root_hash))),
+    ///         ),
+    ///     ),
+    ///     labeled(b"time", HashTree::Leaf(Cow::Borrowed(&[0]))),
+    /// );
+    ///
+    /// let certified_data = state.extract_certified_data(&canister_id).unwrap();
+    /// assert_eq!(certified_data, &map.witness("a").reconstruct());
+    /// assert_eq!(state.extract_certified_data(&[0]), None);
+    /// ```
+    pub fn extract_certified_data(&self, canister_id: &[u8]) -> Option<&[u8]> {
+        match self.lookup_path(&[b"canister", canister_id, b"certified_data"]) {
+            LookupResult::Found(data) => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Render this tree as a [Graphviz](https://graphviz.org) DOT graph, which is useful to
+    /// compare the shape of a witness that does not verify with the expected shape.
+    ///
+    /// Each kind of node has its own style, the labels and the leaves are printed as text
+    /// if they are printable and as hex otherwise, and the leaves and the hashes are
+    /// truncated.
+    ///
+    /// ```
+    /// use certified_vars::{AsHashTree, Map};
+    ///
+    /// let mut map = Map::<String, u32>::new();
+    /// map.insert("a".into(), 1);
+    ///
+    /// let dot = map.witness("a").to_dot();
+    /// assert!(dot.starts_with("digraph HashTree {"));
+    /// ```
+    pub fn to_dot(&self) -> String {
+        /// The number of bytes of a leaf or a hash that are printed.
+        const MAX_BYTES: usize = 8;
 
-pub fn leaf_hash(data: &[u8]) -> Hash {
-    let mut h = domain_sep("ic-hashtree-leaf");
-    h.update(data);
-    h.finalize().into()
-}
+        fn text(data: &[u8]) -> String {
+            let printable = data.iter().all(|b| b.is_ascii_graphic() || *b == b' ');
+            // Twice as many characters are printed as text than as hex.
+            let max = if printable { MAX_BYTES * 2 } else { MAX_BYTES };
+            let head = &data[..data.len().min(max)];
 
-pub fn labeled_hash(label: &[u8], content_hash: &Hash) -> Hash {
-    let mut h = domain_sep("ic-hashtree-labeled");
-    h.update(label);
-    h.update(&content_hash[..]);
-    h.finalize().into()
-}
+            let text = if printable {
+                String::from_utf8_lossy(head).escape_default().to_string()
+            } else {
+                hex::encode(head)
+            };
 
-impl<'a> HashTree<'a> {
-    pub fn reconstruct(&self) -> Hash {
-        match self {
-            Self::Empty => domain_sep("ic-hashtree-empty").finalize().into(),
-            Self::Fork(f) => fork_hash(&f.0.reconstruct(), &f.1.reconstruct()),
-            Self::Labeled(l, t) => {
-                let thash = t.reconstruct();
-                labeled_hash(l, &thash)
+            if head.len() < data.len() {
+                format!("{}...", text)
+            } else {
+                text
             }
-            Self::Leaf(data) => leaf_hash(data),
-            Self::Pruned(h) => *h,
         }
+
+        to_dot("HashTree", self, |tree| {
+            let (label, style) = match tree {
+                HashTree::Empty => ("empty".to_string(), "shape=plaintext"),
+                HashTree::Fork(_) => (String::new(), "shape=circle, width=0.2"),
+                HashTree::Labeled(label, _) => (text(label), "shape=box"),
+                HashTree::Leaf(data) => (text(data), "shape=note, color=blue"),
+                HashTree::Pruned(hash) => (
+                    hex::encode(&hash[..MAX_BYTES / 2]),
+                    "shape=box, style=dashed, color=gray",
+                ),
+            };
+
+            let children = match tree {
+                HashTree::Fork(lr) => vec![lr.left(), lr.right()],
+                HashTree::Labeled(_, tree) => vec![tree.as_ref()],
+                _ => vec![],
+            };
+
+            (format!("label=\"{}\", {}", label, style), children)
+        })
     }
 
     /// Collect and return all of the labels in this HashTree.
@@ -175,10 +1501,14 @@ fn domain_sep(s: &str) -> sha2::Sha256 {
 #[cfg(test)]
 mod tests {
     use super::{
-        fork, labeled,
-        HashTree::{Empty, Leaf},
+        fork, label_cmp, labeled, leaf_hash, reconstruct_from_auth_cache, AuthStep, ChunkError,
+        ChunkVerifier, ForkInner, HashTree,
+        HashTree::{Empty, Leaf, Pruned},
+        LookupResult, VerifyError,
     };
+    use crate::{AsHashTree, Map};
     use std::borrow::Cow;
+    use std::cmp::Ordering::{Equal, Greater, Less};
 
     //─┬─┬╴"a" ─┬─┬╴"x" ─╴"hello"
     // │ │      │ └╴Empty
@@ -214,4 +1544,723 @@ mod tests {
             hex::encode(serde_cbor::to_vec(&t).unwrap()),
             "8301830183024161830183018302417882034568656c6c6f810083024179820345776f726c6483024162820344676f6f648301830241638100830241648203476d6f726e696e67".to_string());
     }
+
+    #[test]
+    fn test_hashtree_macro() {
+        let a = fork(
+            fork(labeled(b"x", Leaf(Cow::Borrowed(b"hello"))), Empty),
+            labeled(b"y", Leaf(Cow::Borrowed(b"world"))),
+        );
+
+        let t = hashtree! {
+            labeled "d" => leaf(b"morning"),
+            labeled "c" => empty,
+            labeled "b" => leaf("good"),
+            labeled b"a" => tree(a),
+        };
+
+        assert_eq!(
+            hex::encode(&t.reconstruct()[..]),
+            "eb5c5b2195e62d996b84c9bcc8259d19a83786a2f59e0878cec84c811f669aa0".to_string()
+        );
+
+        let hash = leaf_hash(b"x");
+        let t = hashtree! {
+            labeled "a" => {
+                labeled "y" => pruned(hash),
+                labeled "x" => leaf(vec![1, 2, 3]),
+                labeled "z" => {},
+            }
+        };
+
+        assert_eq!(
+            t,
+            labeled(
+                b"a",
+                fork(
+                    fork(
+                        labeled(b"x", Leaf(Cow::Borrowed(&[1, 2, 3]))),
+                        labeled(b"y", Pruned(hash))
+                    ),
+                    labeled(b"z", Empty)
+                )
+            )
+        );
+        assert_eq!(hashtree! {}, Empty);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_labeled_fork_duplicate_label() {
+        let _ = hashtree! {
+            labeled "a" => empty,
+            labeled "a" => leaf(b"x"),
+        };
+    }
+
+    #[test]
+    fn test_prune_by() {
+        let t = hashtree! {
+            labeled "a" => {
+                labeled "x" => leaf(b"hello"),
+                labeled "y" => leaf(vec![0; 64]),
+            },
+            labeled "b" => leaf(vec![1; 128]),
+            labeled "c" => empty,
+        };
+        let root_hash = t.reconstruct();
+
+        let pruned = t
+            .clone()
+            .prune_by(|t| matches!(t, Leaf(data) if data.len() > 32));
+        assert_eq!(pruned.reconstruct(), root_hash);
+        assert_eq!(
+            pruned.get_labels(),
+            vec![b"a" as &[u8], b"x", b"y", b"b", b"c"]
+        );
+        assert_eq!(pruned.get_leaf_values(), vec![b"hello"]);
+
+        let mut calls = 0;
+        let pruned = t.prune_by(|_| {
+            calls += 1;
+            true
+        });
+        assert_eq!(pruned, Pruned(root_hash));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_empty() {
+        let map = Map::<String, u32>::new();
+        assert_eq!(map.as_hash_tree(), Empty);
+        assert_eq!(map.root_hash(), Empty.reconstruct());
+        assert_eq!(map.witness("x"), Empty);
+        assert_eq!(map.witness_keys(), Empty);
+
+        assert_eq!(hex::encode(serde_cbor::to_vec(&Empty).unwrap()), "8100");
+        assert_eq!(Empty.lookup_path(&[]), LookupResult::Absent);
+        assert_eq!(Empty.lookup_path(&[b"x"]), LookupResult::Absent);
+        assert_eq!(Empty.clone().merge(Empty), Empty);
+        assert_eq!(Pruned(Empty.reconstruct()).merge(Empty), Empty);
+        assert_eq!(Empty.prune_by(|_| true), Pruned(Empty.reconstruct()));
+        assert!(Empty.get_labels().is_empty());
+        assert!(Empty.get_leaf_values().is_empty());
+
+        let t = hashtree! { labeled "a" => empty };
+        assert_eq!(t, labeled(b"a", Empty));
+        assert_eq!(t.lookup_path(&[b"a"]), LookupResult::Absent);
+        assert_eq!(t.lookup_path(&[b"a", b"b"]), LookupResult::Absent);
+        assert_eq!(t.lookup_path(&[b"b"]), LookupResult::Absent);
+        assert_eq!(
+            fork(Empty, Empty).lookup_path(&[b"a"]),
+            LookupResult::Absent
+        );
+    }
+
+    #[test]
+    fn test_lookup_path() {
+        let t = hashtree! {
+            labeled "a" => {
+                labeled "x" => leaf(b"hello"),
+                labeled "z" => pruned([0; 32]),
+            },
+            labeled "c" => leaf(b"good"),
+        };
+        let t = t.prune_by(|t| t.get_labels() == vec![b"c"]);
+
+        assert_eq!(t.lookup_path(&[b"a", b"x"]), LookupResult::Found(b"hello"));
+        assert_eq!(t.lookup_path(&[b"a", b"y"]), LookupResult::Absent);
+        assert_eq!(t.lookup_path(&[b"a", b"z"]), LookupResult::Unknown);
+        assert_eq!(t.lookup_path(&[b"a", b"z", b"w"]), LookupResult::Unknown);
+        assert_eq!(t.lookup_path(&[b"a"]), LookupResult::Error);
+        assert_eq!(t.lookup_path(&[b"0"]), LookupResult::Absent);
+        assert_eq!(t.lookup_path(&[b"b"]), LookupResult::Unknown);
+        assert_eq!(t.lookup_path(&[b"c"]), LookupResult::Unknown);
+        assert_eq!(t.lookup_path(&[b"d"]), LookupResult::Unknown);
+        assert_eq!(t.lookup_path(&[]), LookupResult::Error);
+    }
+
+    #[test]
+    fn test_extract_certified_data() {
+        let t = hashtree! {
+            labeled "canister" => {
+                labeled "a" => {
+                    labeled "certified_data" => leaf(b"root a"),
+                    labeled "module_hash" => leaf(b"module"),
+                },
+                labeled "b" => pruned([0; 32]),
+                labeled "c" => {
+                    labeled "controllers" => leaf(b""),
+                },
+            },
+            labeled "time" => leaf(b"0"),
+        };
+
+        assert_eq!(t.extract_certified_data(b"a"), Some(&b"root a"[..]));
+        assert_eq!(t.extract_certified_data(b"b"), None);
+        assert_eq!(t.extract_certified_data(b"c"), None);
+        assert_eq!(t.extract_certified_data(b"d"), None);
+        assert_eq!(HashTree::Empty.extract_certified_data(b"a"), None);
+    }
+
+    #[test]
+    fn test_recompute_along_path() {
+        let mut t = hashtree! {
+            labeled "a" => {
+                labeled "x" => leaf(b"hello"),
+                labeled "y" => leaf(b"world"),
+                labeled "z" => pruned([0; 32]),
+            },
+            labeled "b" => leaf(b"good"),
+            labeled "c" => empty,
+        };
+
+        for (path, value) in [
+            (&[b"a" as &[u8], b"y"] as &[&[u8]], b"moon" as &[u8]),
+            (&[b"b"], b""),
+            (&[b"a", b"x"], b"hi"),
+        ] {
+            let hash = t.recompute_along_path(path, value);
+            assert_eq!(hash, t.reconstruct());
+            assert_eq!(t.lookup_path(path), LookupResult::Found(value));
+        }
+
+        assert_eq!(t.get_leaf_values(), vec![b"hi" as &[u8], b"moon", b""]);
+    }
+
+    #[test]
+    fn test_auth_cache_for() {
+        let t = hashtree! {
+            labeled "a" => {
+                labeled "x" => leaf(b"hello"),
+                labeled "y" => leaf(b"world"),
+                labeled "z" => pruned([0; 32]),
+            },
+            labeled "b" => leaf(b"good"),
+            labeled "c" => empty,
+        };
+
+        for (path, value) in [
+            (&[b"a" as &[u8], b"y"] as &[&[u8]], b"world" as &[u8]),
+            (&[b"a", b"x"], b"hello"),
+            (&[b"b"], b"good"),
+        ] {
+            let cache = t.auth_cache_for(path);
+            assert_eq!(
+                cache
+                    .iter()
+                    .filter(|step| **step == AuthStep::Label)
+                    .count(),
+                path.len()
+            );
+            assert_eq!(
+                reconstruct_from_auth_cache(path, value, &cache),
+                Some(t.reconstruct())
+            );
+            assert_ne!(
+                reconstruct_from_auth_cache(path, b"other", &cache),
+                Some(t.reconstruct())
+            );
+
+            // A path with a different number of labels does not match the cache.
+            assert_eq!(reconstruct_from_auth_cache(&path[1..], value, &cache), None);
+            let longer = [path, &[b"x"]].concat();
+            assert_eq!(reconstruct_from_auth_cache(&longer, value, &cache), None);
+        }
+
+        // The cache of a key in a map only has the siblings along its path.
+        let mut map = Map::<u32, u32>::new();
+        for i in 0..64u32 {
+            map.insert(i, i);
+        }
+        let label = 40u32.to_be_bytes();
+        let cache = map.witness(&40).auth_cache_for(&[&label]);
+        assert!(cache.len() <= 16);
+        assert_eq!(
+            reconstruct_from_auth_cache(&[&label], &40u32.to_be_bytes(), &cache),
+            Some(map.root_hash())
+        );
+    }
+
+    #[test]
+    fn test_values_to_pruned() {
+        let mut map = Map::<String, Map<String, u32>>::new();
+        for i in 0..10u32 {
+            let mut inner = Map::new();
+            inner.insert(i.to_string(), i);
+            map.insert(i.to_string(), inner);
+        }
+
+        let tree = map.as_hash_tree();
+        let proof = tree.clone().values_to_pruned();
+        assert_eq!(proof.reconstruct(), map.root_hash());
+        assert!(proof.get_leaf_values().is_empty());
+        assert_eq!(proof.get_labels(), tree.get_labels());
+
+        let leaf = 3u32.to_be_bytes();
+        match proof.lookup_path(&[b"3", b"3"]) {
+            LookupResult::Unknown => {}
+            r => panic!("Expected an unknown result, got {:?}.", r),
+        }
+        assert!(has_pruned(&proof, &leaf_hash(&leaf)));
+
+        let witness = map.witness("3").into_owned().values_to_pruned();
+        assert_eq!(witness.reconstruct(), map.root_hash());
+        assert!(has_pruned(&witness, &leaf_hash(&leaf)));
+
+        assert_eq!(Empty.values_to_pruned(), Empty);
+        assert_eq!(
+            Leaf(Cow::Borrowed(b"x")).values_to_pruned(),
+            Pruned(leaf_hash(b"x"))
+        );
+
+        fn has_pruned(tree: &HashTree, hash: &[u8; 32]) -> bool {
+            match tree {
+                Pruned(h) => h == hash,
+                HashTree::Fork(lr) => has_pruned(lr.left(), hash) || has_pruned(lr.right(), hash),
+                HashTree::Labeled(_, t) => has_pruned(t, hash),
+                _ => false,
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "The path does not lead to a leaf in the tree.")]
+    fn test_auth_cache_for_pruned() {
+        let t = hashtree! {
+            labeled "a" => pruned([0; 32]),
+        };
+        t.auth_cache_for(&[b"a"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "The path does not lead to a leaf in the tree.")]
+    fn test_recompute_along_path_pruned() {
+        let mut t = hashtree! {
+            labeled "a" => {
+                labeled "z" => pruned([0; 32]),
+            },
+        };
+        t.recompute_along_path(&[b"a", b"z"], b"value");
+    }
+
+    #[test]
+    fn test_encoded_size() {
+        let t = hashtree! {
+            labeled "a" => {
+                labeled "x" => leaf(b"hello"),
+                labeled "y" => leaf(vec![0; 300]),
+                labeled "z" => pruned([0; 32]),
+            },
+            labeled "b" => leaf(vec![1; 70000]),
+            labeled vec![2; 30] => empty,
+        };
+
+        assert_eq!(t.encoded_size(), serde_cbor::to_vec(&t).unwrap().len());
+        assert_eq!(
+            Empty.encoded_size(),
+            serde_cbor::to_vec(&Empty).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_compact_bytes() {
+        let mut map = Map::<String, Vec<u8>>::new();
+        for i in 0..1000u32 {
+            map.insert(hex::encode(i.to_be_bytes()), vec![0; 200]);
+        }
+
+        for tree in [
+            map.witness(&hex::encode(500u32.to_be_bytes())),
+            map.witness("missing"),
+            map.witness_keys(),
+            HashTree::Empty,
+        ] {
+            let bytes = tree.to_compact_bytes();
+            assert_eq!(HashTree::from_compact_bytes(&bytes), Ok(tree.clone()));
+            assert!(bytes.len() < tree.encoded_size());
+        }
+
+        let tree = map.witness(&hex::encode(500u32.to_be_bytes()));
+        let (compact, cbor) = (tree.to_compact_bytes().len(), tree.encoded_size());
+        assert!(compact * 100 <= cbor * 93, "{} vs {}", compact, cbor);
+
+        // A label that needs a multi byte length.
+        let tree = labeled(&[7; 300], Leaf(Cow::Borrowed(&[1; 128])));
+        let bytes = tree.to_compact_bytes();
+        assert_eq!(&bytes[..3], &[2, 0xac, 0x02]);
+        assert_eq!(HashTree::from_compact_bytes(&bytes), Ok(tree));
+
+        use super::CompactError;
+        assert_eq!(
+            HashTree::from_compact_bytes(&[]),
+            Err(CompactError::UnexpectedEnd)
+        );
+        assert_eq!(
+            HashTree::from_compact_bytes(&[1, 0]),
+            Err(CompactError::UnexpectedEnd)
+        );
+        assert_eq!(
+            HashTree::from_compact_bytes(&[6]),
+            Err(CompactError::InvalidTag(6))
+        );
+        assert_eq!(
+            HashTree::from_compact_bytes(&[5, 0]),
+            Err(CompactError::InvalidReference)
+        );
+        let mut bytes = vec![1, 4];
+        bytes.extend_from_slice(&[0; 32]);
+        bytes.extend_from_slice(&[5, 1]);
+        assert_eq!(
+            HashTree::from_compact_bytes(&bytes),
+            Err(CompactError::InvalidReference)
+        );
+        assert_eq!(
+            HashTree::from_compact_bytes(&[0, 0]),
+            Err(CompactError::TrailingBytes)
+        );
+        assert_eq!(
+            HashTree::from_compact_bytes(&[
+                3, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f
+            ]),
+            Err(CompactError::InvalidLength)
+        );
+        assert_eq!(
+            HashTree::from_compact_bytes(&[2, 0].repeat(2000)),
+            Err(CompactError::TooDeep)
+        );
+    }
+
+    #[test]
+    fn test_compact_bytes_pruned_references() {
+        let mut map = Map::<String, Vec<u8>>::new();
+        for i in 0..1000u32 {
+            map.insert(hex::encode(i.to_be_bytes()), vec![0; 200]);
+        }
+
+        // Every value is pruned to the same hash.
+        let tree = map.witness_keys();
+        let bytes = tree.to_compact_bytes();
+        assert_eq!(HashTree::from_compact_bytes(&bytes), Ok(tree.clone()));
+
+        // The first occurrence takes 33 bytes, and each of the other 999 takes 2.
+        assert_eq!(bytes.len(), 13030);
+        assert!(bytes.len() * 3 < tree.encoded_size());
+
+        // A repeated hash refers to the first occurrence of the hash.
+        let tree = fork(
+            fork(Pruned([1; 32]), Pruned([2; 32])),
+            fork(Pruned([2; 32]), Pruned([1; 32])),
+        );
+        let bytes = tree.to_compact_bytes();
+        assert_eq!(bytes.len(), 3 + 66 + 4);
+        assert_eq!(&bytes[bytes.len() - 5..], &[1, 5, 1, 5, 0]);
+        assert_eq!(HashTree::from_compact_bytes(&bytes), Ok(tree));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "not sorted")]
+    fn test_fork_unsorted_labels() {
+        let _ = fork(
+            fork(labeled(b"a", Empty), labeled(b"c", Empty)),
+            fork(labeled(b"b", Empty), Pruned([0; 32])),
+        );
+    }
+
+    #[test]
+    fn test_fork_sorted_labels() {
+        // The labels behind a pruned node are unknown, and empty nodes are skipped.
+        let _ = fork(
+            fork(labeled(b"a", Empty), Empty),
+            fork(Pruned([0; 32]), labeled(b"c", Empty)),
+        );
+        let _ = fork(labeled(b"b", Empty), Pruned([0; 32]));
+        let _ = fork(
+            Pruned([0; 32]),
+            fork(labeled(b"b", Empty), labeled(b"c", Empty)),
+        );
+    }
+
+    #[test]
+    fn test_verify_from_cbor() {
+        let mut map = Map::<String, u32>::new();
+        for i in 0..100u32 {
+            map.insert(hex::encode(i.to_be_bytes()), i);
+        }
+        let root = map.root_hash();
+
+        for tree in [
+            map.witness(&hex::encode(50u32.to_be_bytes())),
+            map.witness("missing"),
+            map.as_hash_tree(),
+        ] {
+            let bytes = serde_cbor::to_vec(&tree).unwrap();
+            assert_eq!(HashTree::verify_from_cbor(&bytes, &root), Ok(tree.clone()));
+
+            // The self-describing tag that the IC puts before the certificates.
+            let mut tagged = vec![0xd9, 0xd9, 0xf7];
+            tagged.extend_from_slice(&bytes);
+            assert_eq!(HashTree::from_cbor(&tagged), Ok(tree));
+        }
+
+        let bytes = serde_cbor::to_vec(&map.witness("missing")).unwrap();
+        assert_eq!(
+            HashTree::verify_from_cbor(&bytes, &[0; 32]),
+            Err(VerifyError::RootHashMismatch {
+                expected: [0; 32],
+                actual: root
+            })
+        );
+
+        // The labels of the children of a node must be sorted, the fork constructor would
+        // reject this tree in debug builds.
+        let unsorted = HashTree::Fork(Box::new(ForkInner(
+            labeled(b"b", Leaf(Cow::Borrowed(b"1"))),
+            fork(labeled(b"a", Empty), Pruned([0; 32])),
+        )));
+        let bytes = serde_cbor::to_vec(&unsorted).unwrap();
+        assert_eq!(
+            HashTree::verify_from_cbor(&bytes, &unsorted.reconstruct()),
+            Err(VerifyError::UnsortedLabels)
+        );
+        let nested = labeled(b"x", unsorted);
+        assert_eq!(nested.validate(), Err(VerifyError::UnsortedLabels));
+
+        // Invalid nodes.
+        for value in [
+            serde_cbor::to_vec(&(5u8,)).unwrap(),
+            serde_cbor::to_vec(&(3u8, "leaf")).unwrap(),
+            serde_cbor::to_vec(&(4u8, serde_bytes::Bytes::new(&[0; 31]))).unwrap(),
+            serde_cbor::to_vec(&(1u8, (0u8,))).unwrap(),
+            serde_cbor::to_vec(&0u8).unwrap(),
+        ] {
+            assert_eq!(HashTree::from_cbor(&value), Err(VerifyError::MalformedNode));
+        }
+        assert!(matches!(
+            HashTree::from_cbor(&[0x82]),
+            Err(VerifyError::InvalidCbor(_))
+        ));
+    }
+
+    #[test]
+    fn test_split_by_size() {
+        let mut map = Map::<String, Vec<u8>>::new();
+        for i in 0..100u32 {
+            map.insert(hex::encode(i.to_be_bytes()), vec![i as u8; 100]);
+        }
+
+        let tree = map.witness_value_range("", "z");
+        let size = tree.encoded_size();
+
+        let pieces = tree.clone().split_by_size(1000);
+        assert!(pieces.len() > size / 1000);
+
+        let mut values = Vec::new();
+        for piece in &pieces {
+            assert!(piece.encoded_size() <= 1000);
+            assert_eq!(piece.reconstruct(), map.root_hash());
+            values.extend(piece.get_leaf_values());
+        }
+        assert_eq!(values, tree.get_leaf_values());
+
+        let merged = pieces.into_iter().reduce(HashTree::merge).unwrap();
+        assert_eq!(merged, tree);
+
+        // A single leaf that does not fit is still returned on its own.
+        let pieces = tree.clone().split_by_size(10);
+        assert_eq!(pieces.len(), 100);
+
+        assert_eq!(tree.clone().split_by_size(size), vec![tree]);
+        assert_eq!(Empty.split_by_size(0), vec![Empty]);
+    }
+
+    #[test]
+    fn test_chunk_verifier() {
+        let mut map = Map::<String, Vec<u8>>::new();
+        for i in 0..50u32 {
+            map.insert(hex::encode(i.to_be_bytes()), vec![i as u8; 100]);
+        }
+
+        let root_hash = map.root_hash();
+        let tree = map.witness_value_range("", "z");
+        let chunks = tree.clone().split_by_size(1000);
+        let count = chunks.len();
+        assert!(count > 2);
+
+        let mut verifier = ChunkVerifier::new(root_hash, count);
+        for (index, chunk) in chunks.iter().enumerate().skip(1) {
+            verifier.add_chunk(index, chunk.clone()).unwrap();
+        }
+        assert_eq!(verifier.received(), count - 1);
+        assert!(!verifier.is_complete());
+
+        assert_eq!(
+            verifier.add_chunk(1, chunks[1].clone()),
+            Err(ChunkError::DuplicateChunk(1))
+        );
+        assert_eq!(
+            verifier.add_chunk(count, chunks[0].clone()),
+            Err(ChunkError::OutOfRange {
+                index: count,
+                count
+            })
+        );
+        assert_eq!(
+            verifier.add_chunk(0, Leaf(Cow::Borrowed(b"x"))),
+            Err(ChunkError::RootHashMismatch {
+                index: 0,
+                expected: root_hash,
+                actual: leaf_hash(b"x"),
+            })
+        );
+        assert_eq!(
+            verifier.clone().finish(),
+            Err(ChunkError::MissingChunks(vec![0]))
+        );
+
+        verifier.add_chunk(0, chunks[0].clone()).unwrap();
+        assert!(verifier.is_complete());
+        assert_eq!(verifier.finish(), Ok(tree));
+
+        assert_eq!(
+            ChunkVerifier::new(root_hash, 0).finish(),
+            Ok(Pruned(root_hash))
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let tree = fork(
+            labeled(b"a", Leaf(Cow::Borrowed(b"x"))),
+            fork(
+                labeled(b"b", Leaf(Cow::Borrowed(b"y"))),
+                labeled(b"c", Empty),
+            ),
+        );
+        let root = tree.reconstruct();
+
+        let left = fork(
+            labeled(b"a", Leaf(Cow::Borrowed(b"x"))),
+            Pruned(
+                fork(
+                    labeled(b"b", Leaf(Cow::Borrowed(b"y"))),
+                    labeled(b"c", Empty),
+                )
+                .reconstruct(),
+            ),
+        );
+        let right = fork(
+            Pruned(labeled(b"a", Leaf(Cow::Borrowed(b"x"))).reconstruct()),
+            fork(
+                Pruned(labeled(b"b", Leaf(Cow::Borrowed(b"y"))).reconstruct()),
+                labeled(b"c", Empty),
+            ),
+        );
+        assert_eq!(left.reconstruct(), root);
+        assert_eq!(right.reconstruct(), root);
+
+        let merged = left.merge(right);
+        assert_eq!(merged.reconstruct(), root);
+        assert_eq!(merged.get_labels(), vec![b"a", b"c"]);
+        assert_eq!(merged.get_leaf_values(), vec![b"x"]);
+
+        assert_eq!(merged.merge(tree), tree_copy());
+
+        fn tree_copy() -> HashTree<'static> {
+            fork(
+                labeled(b"a", Leaf(Cow::Borrowed(b"x"))),
+                fork(
+                    labeled(b"b", Leaf(Cow::Borrowed(b"y"))),
+                    labeled(b"c", Empty),
+                ),
+            )
+        }
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let tree = fork(
+            labeled(b"a", Leaf(Cow::Borrowed(b"hello"))),
+            fork(
+                labeled(b"\xff\x01", Leaf(Cow::Borrowed(&[0; 40]))),
+                Pruned([0xab; 32]),
+            ),
+        );
+
+        assert_eq!(
+            tree.to_dot(),
+            "digraph HashTree {
+  n0 [label=\"\", shape=circle, width=0.2];
+  n1 [label=\"a\", shape=box];
+  n2 [label=\"hello\", shape=note, color=blue];
+  n1 -> n2;
+  n0 -> n1;
+  n3 [label=\"\", shape=circle, width=0.2];
+  n4 [label=\"ff01\", shape=box];
+  n5 [label=\"0000000000000000...\", shape=note, color=blue];
+  n4 -> n5;
+  n3 -> n4;
+  n6 [label=\"abababab\", shape=box, style=dashed, color=gray];
+  n3 -> n6;
+  n0 -> n3;
+}
+"
+        );
+        assert_eq!(
+            Empty.to_dot(),
+            "digraph HashTree {\n  n0 [label=\"empty\", shape=plaintext];\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_structurally_equivalent() {
+        let mut map = Map::<String, u32>::new();
+        for i in 0..10u32 {
+            map.insert(i.to_string(), i);
+        }
+
+        let full = map.as_hash_tree();
+        let a = map.witness("3");
+        let b = map.witness("7");
+        assert!(a.structurally_equivalent(&b));
+        assert!(a.structurally_equivalent(&full));
+        assert!(full.structurally_equivalent(&a.clone().merge(b.clone())));
+        assert!(Pruned(map.root_hash()).structurally_equivalent(&full));
+
+        let mut other = map.clone();
+        other.insert("3".into(), 100);
+        assert!(!other.witness("3").structurally_equivalent(&a));
+
+        let x = || Leaf(Cow::Borrowed(b"x"));
+        let y = || Leaf(Cow::Borrowed(b"y"));
+        let tree = fork(x(), y());
+        assert!(Pruned(tree.reconstruct()).structurally_equivalent(&tree));
+        assert!(fork(Pruned(leaf_hash(b"x")), y()).structurally_equivalent(&tree));
+        assert!(!fork(y(), Pruned(leaf_hash(b"x"))).structurally_equivalent(&tree));
+        assert!(!x().structurally_equivalent(&labeled(b"x", Empty)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_merge_different_structures() {
+        let a = labeled(b"a", Leaf(Cow::Borrowed(b"x")));
+        let b = labeled(b"b", Leaf(Cow::Borrowed(b"x")));
+        let _ = a.merge(b);
+    }
+
+    #[test]
+    fn test_label_cmp() {
+        assert_eq!(label_cmp(b"", b""), Equal);
+        assert_eq!(label_cmp(b"", b"a"), Less);
+        assert_eq!(label_cmp(b"a", b""), Greater);
+        assert_eq!(label_cmp(b"a", b"a"), Equal);
+        assert_eq!(label_cmp(b"a", b"b"), Less);
+        assert_eq!(label_cmp(b"a", b"aa"), Less);
+        assert_eq!(label_cmp(b"aa", b"b"), Less);
+        assert_eq!(label_cmp(b"ab", b"aab"), Greater);
+        assert_eq!(label_cmp(&[0x00], &[0x00, 0x00]), Less);
+        assert_eq!(label_cmp(&[0xff], &[0x00, 0x00]), Greater);
+        assert_eq!(label_cmp(&[0x01, 0xff], &[0x02]), Less);
+    }
 }
@@ -1,5 +1,6 @@
 use candid::Principal;
 use std::borrow::{Borrow, Cow};
+use std::convert::TryInto;
 use std::ptr::NonNull;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -7,12 +8,31 @@ use std::sync::Arc;
 /// Any value that can be used as a label in the [`HashTree`] and can be a key
 /// in the [`RbTree`].
 ///
+/// The [`RbTree`] is ordered by the [`Ord`] implementation of the key, while a client
+/// verifying a witness uses the ordering of the label bytes (see [`label_cmp`]). So for
+/// any two values `a` and `b` the implementation must guarantee that `a.cmp(&b)` is the
+/// same as `label_cmp(&a.as_label(), &b.as_label())`. This is why the label of a signed
+/// integer has its sign bit flipped, and the label of a [`Principal`] starts with its
+/// length.
+///
 /// [`HashTree`]: crate::HashTree
 /// [`RbTree`]: crate::rbtree::RbTree
+/// [`label_cmp`]: crate::hashtree::label_cmp
 pub trait Label: Ord {
     fn as_label(&self) -> Cow<[u8]>;
 }
 
+/// A [`Label`] that can be decoded back from its label bytes, this is the inverse of
+/// [`Label::as_label`] and it is used to present the keys of a map when only their labels
+/// are known, see [`Map::view_keys_as`].
+///
+/// [`Map::view_keys_as`]: crate::Map::view_keys_as
+pub trait KeyCodec: Sized {
+    /// Decode a value from its label, or return [`None`] if the bytes are not the label of
+    /// any value of this type.
+    fn from_label(label: &[u8]) -> Option<Self>;
+}
+
 /// A type `T` can be defined as prefix of type `U`, if they follow the same
 /// representation and any valid value of `T` is also a valid head for a value
 /// of type `U`.
@@ -59,9 +79,27 @@ impl Prefix<[u8]> for Box<[u8]> {
     }
 }
 
+impl Label for Cow<'_, [u8]> {
+    fn as_label(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl Prefix<[u8]> for Cow<'_, [u8]> {
+    fn is_prefix(&self, prefix: &[u8]) -> bool {
+        self.starts_with(prefix)
+    }
+}
+
+/// The label of a principal is its length followed by its bytes, since principals are
+/// ordered by their length first.
 impl Label for Principal {
     fn as_label(&self) -> Cow<[u8]> {
-        Cow::Borrowed(self.as_slice())
+        let bytes = self.as_slice();
+        let mut label = Vec::with_capacity(bytes.len() + 1);
+        label.push(bytes.len() as u8);
+        label.extend_from_slice(bytes);
+        Cow::Owned(label)
     }
 }
 
@@ -127,6 +165,50 @@ where
     }
 }
 
+impl KeyCodec for Vec<u8> {
+    #[inline]
+    fn from_label(label: &[u8]) -> Option<Self> {
+        Some(label.to_vec())
+    }
+}
+
+impl KeyCodec for Box<[u8]> {
+    #[inline]
+    fn from_label(label: &[u8]) -> Option<Self> {
+        Some(label.into())
+    }
+}
+
+impl KeyCodec for Principal {
+    #[inline]
+    fn from_label(label: &[u8]) -> Option<Self> {
+        match label.split_first() {
+            Some((len, bytes)) if *len as usize == bytes.len() => {
+                Principal::try_from_slice(bytes).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+impl KeyCodec for String {
+    #[inline]
+    fn from_label(label: &[u8]) -> Option<Self> {
+        String::from_utf8(label.to_vec()).ok()
+    }
+}
+
+impl KeyCodec for bool {
+    #[inline]
+    fn from_label(label: &[u8]) -> Option<Self> {
+        match label {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+}
+
 macro_rules! impl_fixed_size {
     ( $($size:expr),* ) => {
         $(
@@ -143,6 +225,13 @@ macro_rules! impl_fixed_size {
                     self.starts_with(prefix)
                 }
             }
+
+            impl KeyCodec for [u8; $size] {
+                #[inline]
+                fn from_label(label: &[u8]) -> Option<Self> {
+                    label.try_into().ok()
+                }
+            }
         )*
     }
 }
@@ -160,8 +249,243 @@ macro_rules! impl_num {
                     Cow::Owned(self.to_be_bytes().into())
                 }
             }
+
+            impl KeyCodec for $name {
+                #[inline]
+                fn from_label(label: &[u8]) -> Option<Self> {
+                    Some(<$name>::from_be_bytes(label.try_into().ok()?))
+                }
+            }
         )*
     }
 }
 
-impl_num!(u8, i8, u16, i16, u32, i32, u64, i64, u128, i128, usize, isize);
+macro_rules! impl_signed {
+    ( $($name:ty => $unsigned:ty),* ) => {
+        $(
+            /// The label of a signed integer is its big endian encoding with the sign bit
+            /// flipped, so the negative numbers come before the positive ones.
+            impl Label for $name {
+                fn as_label(&self) -> Cow<'_, [u8]> {
+                    let flipped = (*self as $unsigned) ^ !(<$unsigned>::MAX >> 1);
+                    Cow::Owned(flipped.to_be_bytes().into())
+                }
+            }
+
+            impl KeyCodec for $name {
+                #[inline]
+                fn from_label(label: &[u8]) -> Option<Self> {
+                    let flipped = <$unsigned>::from_be_bytes(label.try_into().ok()?);
+                    Some((flipped ^ !(<$unsigned>::MAX >> 1)) as $name)
+                }
+            }
+        )*
+    }
+}
+
+impl_num!(u8, u16, u32, u64, u128, usize);
+impl_signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128, isize => usize);
+
+/// Append the label of a component of a tuple that is followed by another component, the
+/// `0x00` bytes are escaped as `0x00 0xff` and the label is terminated by `0x00 0x00`.
+fn push_escaped_label(out: &mut Vec<u8>, label: &[u8]) {
+    for byte in label {
+        out.push(*byte);
+        if *byte == 0 {
+            out.push(0xff);
+        }
+    }
+
+    out.extend_from_slice(&[0, 0]);
+}
+
+/// Read a component that is written by [`push_escaped_label`] from the start of the given
+/// label, and return the component along with the rest of the label.
+fn pop_escaped_label(label: &[u8]) -> Option<(Vec<u8>, &[u8])> {
+    let mut out = Vec::new();
+    let mut bytes = label.iter().enumerate();
+
+    while let Some((i, byte)) = bytes.next() {
+        if *byte != 0 {
+            out.push(*byte);
+            continue;
+        }
+
+        match bytes.next()? {
+            (_, 0) => return Some((out, &label[i + 2..])),
+            (_, 0xff) => out.push(0),
+            _ => return None,
+        }
+    }
+
+    None
+}
+
+macro_rules! impl_tuple {
+    ( $( ($($name:ident $index:tt),+; $last:ident $last_index:tt) ),* ) => {
+        $(
+            /// The label of a tuple is the concatenation of the labels of its components,
+            /// where every component except the last one is escaped and terminated, so the
+            /// labels compare by the first component and then by the next ones, just like
+            /// the tuples themselves, as long as the labels of each component have the same
+            /// ordering as the component. A component can never bleed into the next one.
+            ///
+            /// In the escaped components each `0x00` byte is replaced with `0x00 0xff` and a
+            /// `0x00 0x00` is added at the end. A length prefix is not used since it would
+            /// order the labels by the length of the first component.
+            impl<$($name: Label,)+ $last: Label> Label for ($($name,)+ $last) {
+                fn as_label(&self) -> Cow<'_, [u8]> {
+                    let mut data = Vec::new();
+                    $(push_escaped_label(&mut data, &self.$index.as_label());)+
+                    data.extend_from_slice(&self.$last_index.as_label());
+                    Cow::Owned(data)
+                }
+            }
+
+            impl<$($name: KeyCodec,)+ $last: KeyCodec> KeyCodec for ($($name,)+ $last) {
+                fn from_label(label: &[u8]) -> Option<Self> {
+                    $(
+                        let (component, label) = pop_escaped_label(label)?;
+                        #[allow(non_snake_case)]
+                        let $name = $name::from_label(&component)?;
+                    )+
+                    Some(($($name,)+ $last::from_label(label)?))
+                }
+            }
+        )*
+    }
+}
+
+impl_tuple!((A 0; B 1), (A 0, B 1; C 2));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashtree::label_cmp;
+    use std::cmp::Ordering;
+
+    #[test]
+    fn tuple() {
+        let blobs = [
+            vec![],
+            vec![0],
+            vec![0, 0],
+            vec![0, 1],
+            vec![0, 255],
+            vec![1],
+            vec![1, 0, 255],
+            vec![255; 29],
+        ];
+
+        let mut keys = Vec::new();
+        for blob in &blobs {
+            for n in [0u64, 1, 256, u64::MAX] {
+                keys.push((blob.clone(), n));
+            }
+        }
+
+        for a in &keys {
+            for b in &keys {
+                assert_eq!(a.cmp(b), label_cmp(&a.as_label(), &b.as_label()));
+            }
+        }
+
+        let keys = [
+            (Principal::from_slice(&[1, 2]), 5u64),
+            (Principal::from_slice(&[1, 3]), 0),
+            (Principal::from_slice(&[2, 0]), 1),
+            (Principal::from_slice(&[0, 0, 0]), 1),
+        ];
+        for w in keys.windows(2) {
+            assert_eq!(
+                label_cmp(&w[0].as_label(), &w[1].as_label()),
+                Ordering::Less
+            );
+        }
+
+        assert_eq!(
+            (vec![0u8, 1], 2u8).as_label().as_ref(),
+            &[0, 0xff, 1, 0, 0, 2]
+        );
+
+        let triple = ("a".to_string(), vec![0u8], 7u8);
+        assert_eq!(triple.as_label().as_ref(), b"a\0\0\0\xff\0\0\x07");
+    }
+
+    fn assert_same_order<T: Label + std::fmt::Debug>(keys: &[T]) {
+        for a in keys {
+            for b in keys {
+                assert_eq!(
+                    a.cmp(b),
+                    label_cmp(&a.as_label(), &b.as_label()),
+                    "{:?} {:?}",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn signed_order() {
+        assert_same_order(&[i8::MIN, -100, -2, -1, 0, 1, 2, 100, i8::MAX]);
+        assert_same_order(&[i32::MIN, -65536, -256, -1, 0, 1, 256, 65536, i32::MAX]);
+        assert_same_order(&[i64::MIN, -1, 0, 1, i64::MAX]);
+        assert_same_order(&[i128::MIN, -1, 0, 1, i128::MAX]);
+        assert_same_order(&[isize::MIN, -1, 0, 1, isize::MAX]);
+        assert_same_order(&[0u32, 1, 256, u32::MAX]);
+        assert_eq!((-1i32).as_label().as_ref(), &[0x7f, 0xff, 0xff, 0xff]);
+        assert_eq!(1i32.as_label().as_ref(), &[0x80, 0, 0, 1]);
+    }
+
+    #[test]
+    fn principal_order() {
+        assert_same_order(&[
+            Principal::from_slice(&[]),
+            Principal::from_slice(&[2]),
+            Principal::from_slice(&[255]),
+            Principal::from_slice(&[0, 0]),
+            Principal::from_slice(&[1, 1]),
+            Principal::from_slice(&[0, 0, 0]),
+            Principal::from_slice(&[255; 29]),
+        ]);
+        assert_eq!(
+            Principal::from_slice(&[7, 8]).as_label().as_ref(),
+            &[2, 7, 8]
+        );
+    }
+
+    fn round_trip<T: Label + KeyCodec + std::fmt::Debug>(value: T) {
+        assert_eq!(T::from_label(&value.as_label()), Some(value));
+    }
+
+    #[test]
+    fn key_codec() {
+        round_trip(vec![0u8, 1, 2]);
+        round_trip("hello".to_string());
+        round_trip(Principal::from_slice(&[1, 2, 3]));
+        round_trip(true);
+        round_trip(false);
+        round_trip([7u8; 32]);
+        round_trip(-5i32);
+        round_trip(i64::MIN);
+        round_trip(i8::MAX);
+        round_trip(u128::MAX);
+        round_trip((vec![0u8, 0, 255, 0], 7u64));
+        round_trip((String::new(), vec![0u8], 0u8));
+        round_trip((Principal::from_slice(&[0, 0]), "x".to_string()));
+
+        assert_eq!(u32::from_label(&[0, 1]), None);
+        assert_eq!(bool::from_label(&[2]), None);
+        assert_eq!(String::from_label(&[0xff]), None);
+        assert_eq!(<[u8; 2]>::from_label(&[1, 2, 3]), None);
+        assert_eq!(Principal::from_label(&[0; 30]), None);
+        assert_eq!(Principal::from_label(&[2, 1]), None);
+        assert_eq!(Principal::from_label(&[]), None);
+        // An unterminated first component, and an invalid escape.
+        assert_eq!(<(Vec<u8>, u8)>::from_label(&[1, 2]), None);
+        assert_eq!(<(Vec<u8>, u8)>::from_label(&[1, 0, 1, 0, 0, 2]), None);
+        // The last component has the wrong length.
+        assert_eq!(<(Vec<u8>, u16)>::from_label(&[1, 0, 0, 2]), None);
+    }
+}
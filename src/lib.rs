@@ -1,3 +1,13 @@
+//! Certified variable friendly data structures for the Internet Computer.
+//!
+//! # Candid
+//!
+//! Candid is a hard dependency of this crate, the collections implement [`CandidType`] so
+//! they can be returned from the canister methods. So the candid specific helpers, such as
+//! [`as_hash_tree::CandidLeaf`], are always available and are not behind a feature.
+//!
+//! [`CandidType`]: candid::CandidType
+
 pub mod as_hash_tree;
 pub mod collections;
 pub mod hashtree;
@@ -9,9 +9,10 @@ use std::borrow::{Borrow, Cow};
 use std::cmp::Ordering;
 use std::cmp::Ordering::{Equal, Greater, Less};
 use std::fmt;
+use std::ops::RangeBounds;
 
 use crate::hashtree::{
-    fork, fork_hash, labeled_hash, Hash,
+    fork, fork_hash, label_cmp, labeled_hash, Hash,
     HashTree::{self, Empty, Pruned},
 };
 use crate::label::{Label, Prefix};
@@ -21,6 +22,7 @@ use crate::AsHashTree;
 pub(crate) mod debug_alloc;
 
 pub mod entry;
+pub mod flat;
 pub mod iterator;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -126,11 +128,18 @@ struct Node<K, V> {
     /// Hash of the full hash tree built from this node and its
     /// children. It needs to be recomputed after every rotation.
     subtree_hash: Hash,
+
+    /// The generation of the tree in which the value of this node was last set.
+    generation: u64,
+
+    /// The largest generation of the nodes in this subtree, it is maintained along with
+    /// the subtree hash.
+    max_generation: u64,
 }
 
 impl<K: 'static + Label, V: AsHashTree + 'static> Node<K, V> {
     #[allow(clippy::let_and_return)]
-    fn new(key: K, value: V) -> *mut Self {
+    fn new(key: K, value: V, generation: u64) -> *mut Self {
         let value_hash = value.root_hash();
         let data_hash = labeled_hash(&key.as_label(), &value_hash);
         let node = Box::into_raw(Box::new(Self {
@@ -140,6 +149,8 @@ impl<K: 'static + Label, V: AsHashTree + 'static> Node<K, V> {
             right: Node::null(),
             color: Color::Red,
             subtree_hash: data_hash,
+            generation,
+            max_generation: generation,
         }));
 
         #[cfg(test)]
@@ -195,6 +206,15 @@ impl<K: 'static + Label, V: AsHashTree + 'static> Node<K, V> {
         HashTree::Labeled((*n).key.as_label(), Box::new(Pruned(value_hash)))
     }
 
+    /// Like [`Node::witness_tree`], but the label is copied so the tree does not borrow
+    /// from the node.
+    unsafe fn owned_witness_tree<'a>(n: *mut Self) -> HashTree<'a> {
+        debug_assert!(!n.is_null());
+        let label = (*n).key.as_label().into_owned();
+        let value_hash = (*n).value.root_hash();
+        HashTree::Labeled(Cow::Owned(label), Box::new(Pruned(value_hash)))
+    }
+
     unsafe fn full_witness_tree<'a>(
         n: *mut Self,
         f: unsafe fn(*mut Self) -> HashTree<'a>,
@@ -223,12 +243,65 @@ impl<K: 'static + Label, V: AsHashTree + 'static> Node<K, V> {
         Some((node.key, node.value))
     }
 
+    /// Deep copy the subtree rooted at the given node, the copy has the exact same shape
+    /// and colors and reuses the already computed hashes.
+    unsafe fn deep_clone(n: *mut Self) -> *mut Self
+    where
+        K: Clone,
+        V: Clone,
+    {
+        if n.is_null() {
+            return Node::null();
+        }
+
+        let node = Box::into_raw(Box::new(Self {
+            key: (*n).key.clone(),
+            value: (*n).value.clone(),
+            left: Self::deep_clone((*n).left),
+            right: Self::deep_clone((*n).right),
+            color: (*n).color,
+            subtree_hash: (*n).subtree_hash,
+            generation: (*n).generation,
+            max_generation: (*n).max_generation,
+        }));
+
+        #[cfg(test)]
+        debug_alloc::mark_pointer_allocated(node);
+
+        node
+    }
+
+    /// Recompute the subtree hash and the max generation of the given node after either
+    /// its data or its children have changed.
+    unsafe fn update(n: *mut Self) {
+        (*n).subtree_hash = Node::subtree_hash(n);
+        (*n).max_generation = Node::max_generation(n);
+    }
+
+    unsafe fn max_generation(n: *mut Self) -> u64 {
+        debug_assert!(!n.is_null());
+        let mut max = (*n).generation;
+        if !(*n).left.is_null() {
+            max = max.max((*(*n).left).max_generation);
+        }
+        if !(*n).right.is_null() {
+            max = max.max((*(*n).right).max_generation);
+        }
+        max
+    }
+
     unsafe fn subtree_hash(n: *mut Self) -> Hash {
         if n.is_null() {
             return Empty.reconstruct();
         }
 
-        let h = Node::data_hash(n);
+        Node::subtree_hash_with(n, Node::data_hash(n))
+    }
+
+    /// Like [`Node::subtree_hash`] but uses the given data hash of the node instead of
+    /// computing it.
+    unsafe fn subtree_hash_with(n: *mut Self, h: Hash) -> Hash {
+        debug_assert!(!n.is_null());
 
         match ((*n).left.is_null(), (*n).right.is_null()) {
             (true, true) => h,
@@ -247,6 +320,10 @@ impl<K: 'static + Label, V: AsHashTree + 'static> Node<K, V> {
 pub struct RbTree<K: 'static + Label, V: AsHashTree + 'static> {
     len: usize,
     root: *mut Node<K, V>,
+    /// Incremented on every mutation of the tree.
+    generation: u64,
+    /// The generation in which an entry was last removed from the tree.
+    removed_at: u64,
 }
 
 impl<K: 'static + Label, V: AsHashTree + 'static> Drop for RbTree<K, V> {
@@ -257,6 +334,102 @@ impl<K: 'static + Label, V: AsHashTree + 'static> Drop for RbTree<K, V> {
     }
 }
 
+// The tree uniquely owns all of its nodes, and never mutates them behind a shared
+// reference, so it is as thread safe as a `Box` of the same keys and values would be.
+unsafe impl<K: 'static + Label + Send, V: AsHashTree + Send + 'static> Send for RbTree<K, V> {}
+unsafe impl<K: 'static + Label + Sync, V: AsHashTree + Sync + 'static> Sync for RbTree<K, V> {}
+
+/// The error returned by [`RbTree::verify_hashes`] when the cached subtree hash of a node
+/// is not the hash that is computed from its contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashMismatch {
+    /// The labels of the keys of the nodes from the root to the offending node, the last
+    /// one is the label of the key of the offending node.
+    pub path: Vec<Vec<u8>>,
+    /// The hash that is cached in the node.
+    pub stored: Hash,
+    /// The hash that is computed from the value and the children of the node.
+    pub computed: Hash,
+}
+
+impl fmt::Display for HashMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The node of the key {} has the hash {} but its contents hash to {}",
+            hex::encode(self.path.last().map(Vec::as_slice).unwrap_or_default()),
+            hex::encode(self.stored),
+            hex::encode(self.computed)
+        )
+    }
+}
+
+impl std::error::Error for HashMismatch {}
+
+/// A mutable reference to a value in a [`RbTree`] which updates the hashes of the tree when
+/// it is dropped, see [`RbTree::get_mut`]. The hashes are also updated if a panic unwinds
+/// while the value is borrowed, so the tree stays valid.
+pub struct ValueGuard<'a, K: 'static + Label, V: AsHashTree + 'static> {
+    tree: &'a mut RbTree<K, V>,
+    /// The nodes from the root to the node of the value.
+    path: Vec<*mut Node<K, V>>,
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> ValueGuard<'_, K, V> {
+    /// Returns the key of the value.
+    #[inline]
+    pub fn key(&self) -> &K {
+        unsafe { &(**self.path.last().unwrap()).key }
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> std::ops::Deref for ValueGuard<'_, K, V> {
+    type Target = V;
+
+    #[inline]
+    fn deref(&self) -> &V {
+        unsafe { &(**self.path.last().unwrap()).value }
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> std::ops::DerefMut for ValueGuard<'_, K, V> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut V {
+        unsafe { &mut (**self.path.last().unwrap()).value }
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> Drop for ValueGuard<'_, K, V> {
+    fn drop(&mut self) {
+        let generation = self.tree.generation + 1;
+        self.tree.generation = generation;
+
+        unsafe {
+            (**self.path.last().unwrap()).generation = generation;
+            for node in self.path.iter().rev() {
+                Node::update(*node);
+            }
+        }
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> Clone for RbTree<K, V>
+where
+    K: Clone,
+    V: Clone,
+{
+    /// Deep copy the tree, the clone has the same shape as the original tree and thus
+    /// the same root hash.
+    fn clone(&self) -> Self {
+        Self {
+            len: self.len,
+            root: unsafe { Node::deep_clone(self.root) },
+            generation: self.generation,
+            removed_at: self.removed_at,
+        }
+    }
+}
+
 impl<K: 'static + Label, V: AsHashTree + 'static> Default for RbTree<K, V> {
     fn default() -> Self {
         Self::new()
@@ -269,14 +442,148 @@ impl<K: 'static + Label, V: AsHashTree + 'static> RbTree<K, V> {
         Self {
             len: 0,
             root: Node::null(),
+            generation: 0,
+            removed_at: 0,
         }
     }
 
+    /// Returns the current generation of the tree, the generation is incremented on every
+    /// insertion, modification and removal.
+    ///
+    /// Mutations through an [`entry::Entry`] that do not insert or remove a value are not
+    /// tracked.
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns the number of entries in the tree, the count is maintained on every
+    /// insertion and deletion so this is O(1).
     #[inline]
     pub fn len(&self) -> usize {
         self.len
     }
 
+    /// Walk the entire tree and return `true` if the number of nodes matches the cached
+    /// length, this is O(n) and is meant to be used in a `debug_assert!`.
+    pub fn verify_len(&self) -> bool {
+        let mut count = 0;
+        self.for_each(|_, _| count += 1);
+        count == self.len
+    }
+
+    /// Walk the entire tree and recompute the subtree hash of every node from its value and
+    /// its children, and return the first node whose cached hash is not the computed one.
+    /// This is O(n) and is meant to detect a corrupted or stale tree, for example after it
+    /// is loaded from stable memory.
+    ///
+    /// The children are checked before their parent, so the returned node is the deepest
+    /// one on its path whose hash is wrong. Only the hashes of this tree are checked, the
+    /// hash of a value is the one returned by its [`AsHashTree::root_hash`].
+    pub fn verify_hashes(&self) -> Result<(), HashMismatch> {
+        unsafe fn go<K, V>(n: *mut Node<K, V>, path: &mut Vec<Vec<u8>>) -> Result<(), HashMismatch>
+        where
+            K: 'static + Label,
+            V: AsHashTree + 'static,
+        {
+            if n.is_null() {
+                return Ok(());
+            }
+
+            path.push((*n).key.as_label().into_owned());
+            go((*n).left, path)?;
+            go((*n).right, path)?;
+
+            let computed = Node::subtree_hash(n);
+            if (*n).subtree_hash != computed {
+                return Err(HashMismatch {
+                    path: std::mem::take(path),
+                    stored: (*n).subtree_hash,
+                    computed,
+                });
+            }
+
+            path.pop();
+            Ok(())
+        }
+
+        unsafe { go(self.root, &mut Vec::new()) }
+    }
+
+    /// Walk the entire tree and panic if any of the invariants of the tree does not hold,
+    /// this is O(n) and is meant to be used in tests. The checked invariants are:
+    ///
+    /// 1. The tree is a left-leaning red-black tree with a black root.
+    /// 2. The keys are strictly ascending both by their [`Ord`] and by their labels.
+    /// 3. The cached subtree hash and maximum generation of every node are up to date.
+    /// 4. The cached length is the number of nodes.
+    pub fn assert_invariants(&self) {
+        /// Check the subtree and return its black height.
+        unsafe fn go<'a, K, V>(
+            n: *mut Node<K, V>,
+            prev: &mut Option<&'a K>,
+            count: &mut usize,
+        ) -> usize
+        where
+            K: 'static + Label,
+            V: AsHashTree + 'static,
+        {
+            if n.is_null() {
+                return 0;
+            }
+
+            assert!(!is_red((*n).right), "A right link is red.");
+            assert!(
+                !(is_red(n) && is_red((*n).left)),
+                "There are two consecutive red links."
+            );
+
+            let left = go((*n).left, prev, count);
+
+            let key: &'a K = &(*n).key;
+            if let Some(prev) = prev {
+                assert!(*prev < key, "The keys are not in ascending order.");
+                assert_eq!(
+                    label_cmp(&prev.as_label(), &key.as_label()),
+                    Ordering::Less,
+                    "The labels are not in ascending order."
+                );
+            }
+            *prev = Some(key);
+            *count += 1;
+
+            let right = go((*n).right, prev, count);
+            assert_eq!(left, right, "The black heights of the children differ.");
+
+            assert_eq!(
+                (*n).subtree_hash,
+                Node::subtree_hash(n),
+                "The subtree hash is outdated."
+            );
+            assert_eq!(
+                (*n).max_generation,
+                Node::max_generation(n),
+                "The maximum generation is outdated."
+            );
+
+            left + if is_red(n) { 0 } else { 1 }
+        }
+
+        assert!(unsafe { !is_red(self.root) }, "The root is red.");
+
+        let mut prev = None;
+        let mut count = 0;
+        unsafe { go(self.root, &mut prev, &mut count) };
+        assert_eq!(count, self.len, "The cached length is wrong.");
+
+        if !self.root.is_null() {
+            assert!(
+                unsafe { (*self.root).max_generation } <= self.generation,
+                "A node is newer than the tree."
+            );
+        }
+    }
+
     #[inline]
     pub fn is_empty(&self) -> bool {
         self.root.is_null()
@@ -315,6 +622,84 @@ impl<K: 'static + Label, V: AsHashTree + 'static> RbTree<K, V> {
         }
     }
 
+    /// Returns an iterator over the key-values of the tree from the largest key to the
+    /// smallest one.
+    #[inline]
+    pub fn iter_rev(&self) -> iterator::RbTreeRevIterator<'_, K, V> {
+        iterator::RbTreeRevIterator::new(self)
+    }
+
+    /// Returns `true` if the tree contains the given key, without reading its value.
+    #[inline]
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        unsafe {
+            let mut root = self.root;
+            while !root.is_null() {
+                match key.cmp((*root).key.borrow()) {
+                    Equal => return true,
+                    Less => root = (*root).left,
+                    Greater => root = (*root).right,
+                }
+            }
+            false
+        }
+    }
+
+    /// Returns the largest key that is smaller than the given key, the value of the given
+    /// key if it is in the tree, and the smallest key that is larger than the given key,
+    /// all in one descent of the tree.
+    pub fn get_with_neighbors<Q>(&self, key: &Q) -> (Option<&K>, Option<&V>, Option<&K>)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        unsafe {
+            let mut prev = Node::null();
+            let mut next = Node::null();
+            let mut root = self.root;
+
+            while !root.is_null() {
+                match key.cmp((*root).key.borrow()) {
+                    Equal => {
+                        if !(*root).left.is_null() {
+                            prev = (*root).left;
+                            while !(*prev).right.is_null() {
+                                prev = (*prev).right;
+                            }
+                        }
+
+                        if !(*root).right.is_null() {
+                            next = (*root).right;
+                            while !(*next).left.is_null() {
+                                next = (*next).left;
+                            }
+                        }
+
+                        break;
+                    }
+                    Less => {
+                        next = root;
+                        root = (*root).left;
+                    }
+                    Greater => {
+                        prev = root;
+                        root = (*root).right;
+                    }
+                }
+            }
+
+            (
+                prev.as_ref().map(|n| &n.key),
+                root.as_ref().map(|n| &n.value),
+                next.as_ref().map(|n| &n.key),
+            )
+        }
+    }
+
     #[inline]
     pub fn get_with(&self, cmp: impl Fn(&K) -> Ordering) -> Option<&V> {
         unsafe {
@@ -330,6 +715,51 @@ impl<K: 'static + Label, V: AsHashTree + 'static> RbTree<K, V> {
         }
     }
 
+    /// Like [`RbTree::modify`] but the node is found using the given comparator, which
+    /// returns the order of the key we are looking for compared to the given key.
+    pub fn modify_with<'a, T>(
+        &mut self,
+        cmp: impl Fn(&K) -> Ordering,
+        f: impl FnOnce(&'a mut V) -> T,
+    ) -> Option<T> {
+        unsafe fn go<'a, K, V, T, C, F>(
+            h: *mut Node<K, V>,
+            cmp: &C,
+            generation: u64,
+            f: F,
+        ) -> Option<T>
+        where
+            K: 'static + Label,
+            V: AsHashTree + 'static,
+            C: Fn(&K) -> Ordering,
+            F: FnOnce(&'a mut V) -> T,
+        {
+            if h.is_null() {
+                return None;
+            }
+
+            let res = match cmp(&(*h).key) {
+                Equal => {
+                    let res = f(&mut (*h).value);
+                    (*h).generation = generation;
+                    res
+                }
+                Less => go((*h).left, cmp, generation, f)?,
+                Greater => go((*h).right, cmp, generation, f)?,
+            };
+
+            Node::update(h);
+            Some(res)
+        }
+
+        let generation = self.generation + 1;
+        let res = unsafe { go(self.root, &cmp, generation, f) };
+        if res.is_some() {
+            self.generation = generation;
+        }
+        res
+    }
+
     #[inline]
     unsafe fn get_node(&self, key: &K) -> *mut Node<K, V> {
         let mut root = self.root;
@@ -353,6 +783,7 @@ impl<K: 'static + Label, V: AsHashTree + 'static> RbTree<K, V> {
         unsafe fn go<'a, K: 'static + Label, V: AsHashTree + 'static, T, Q: ?Sized>(
             mut h: *mut Node<K, V>,
             k: &Q,
+            generation: u64,
             f: impl FnOnce(&'a mut V) -> T,
         ) -> Option<T>
         where
@@ -366,22 +797,53 @@ impl<K: 'static + Label, V: AsHashTree + 'static> RbTree<K, V> {
             match k.cmp((*h).key.borrow()) {
                 Equal => {
                     let res = f(&mut (*h).value);
-                    (*h).subtree_hash = Node::subtree_hash(h);
+                    (*h).generation = generation;
+                    Node::update(h);
                     Some(res)
                 }
                 Less => {
-                    let res = go((*h).left, k, f);
-                    (*h).subtree_hash = Node::subtree_hash(h);
+                    let res = go((*h).left, k, generation, f);
+                    Node::update(h);
                     res
                 }
                 Greater => {
-                    let res = go((*h).right, k, f);
-                    (*h).subtree_hash = Node::subtree_hash(h);
+                    let res = go((*h).right, k, generation, f);
+                    Node::update(h);
                     res
                 }
             }
         }
-        unsafe { go(self.root, key, f) }
+
+        let generation = self.generation + 1;
+        let res = unsafe { go(self.root, key, generation, f) };
+        if res.is_some() {
+            self.generation = generation;
+        }
+        res
+    }
+
+    /// Returns a mutable reference to the value of the given key, the hashes of the tree
+    /// are updated once the returned guard is dropped.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<ValueGuard<'_, K, V>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+    {
+        let mut path = Vec::with_capacity(16);
+
+        unsafe {
+            let mut node = self.root;
+            while !node.is_null() {
+                path.push(node);
+                match key.cmp((*node).key.borrow()) {
+                    Equal => return Some(ValueGuard { tree: self, path }),
+                    Less => node = (*node).left,
+                    Greater => node = (*node).right,
+                }
+            }
+        }
+
+        None
     }
 
     /// Modify the maximum node with the given prefix.
@@ -404,6 +866,7 @@ impl<K: 'static + Label, V: AsHashTree + 'static> RbTree<K, V> {
         >(
             mut h: *mut Node<K, V>,
             prefix: &P,
+            generation: u64,
             f: F,
         ) -> (Option<T>, Option<F>)
         where
@@ -418,25 +881,117 @@ impl<K: 'static + Label, V: AsHashTree + 'static> RbTree<K, V> {
             let key_prefix = node_key.borrow();
 
             let res = match key_prefix.cmp(prefix) {
-                Greater | Equal if node_key.is_prefix(prefix) => match go((*h).right, prefix, f) {
-                    (None, Some(f)) => {
-                        let ret = f(node_key, &mut (*h).value);
-                        (Some(ret), None)
+                Greater | Equal if node_key.is_prefix(prefix) => {
+                    match go((*h).right, prefix, generation, f) {
+                        (None, Some(f)) => {
+                            let ret = f(node_key, &mut (*h).value);
+                            (*h).generation = generation;
+                            (Some(ret), None)
+                        }
+                        ret => ret,
                     }
-                    ret => ret,
-                },
-                Greater => go((*h).left, prefix, f),
-                Less | Equal => go((*h).right, prefix, f),
+                }
+                Greater => go((*h).left, prefix, generation, f),
+                Less | Equal => go((*h).right, prefix, generation, f),
+            };
+
+            if res.0.is_some() {
+                Node::update(h);
+            }
+
+            res
+        }
+
+        let generation = self.generation + 1;
+        let res = unsafe { go(self.root, prefix, generation, f).0 };
+        if res.is_some() {
+            self.generation = generation;
+        }
+        res
+    }
+
+    /// Modify the minimum node with the given prefix.
+    pub fn modify_min_with_prefix<'a, P, T>(
+        &mut self,
+        prefix: &P,
+        f: impl FnOnce(&'a K, &'a mut V) -> T,
+    ) -> Option<T>
+    where
+        K: Prefix<P>,
+        P: ?Sized + Ord,
+    {
+        unsafe fn go<'a, K, V: AsHashTree + 'static, P, T, F: FnOnce(&'a K, &'a mut V) -> T>(
+            h: *mut Node<K, V>,
+            prefix: &P,
+            generation: u64,
+            f: F,
+        ) -> (Option<T>, Option<F>)
+        where
+            K: Label + 'static + Prefix<P>,
+            P: ?Sized + Ord,
+        {
+            if h.is_null() {
+                return (None, Some(f));
+            }
+
+            let node_key = &(*h).key;
+            let key_prefix = node_key.borrow();
+
+            let res = match key_prefix.cmp(prefix) {
+                Greater | Equal if node_key.is_prefix(prefix) => {
+                    match go((*h).left, prefix, generation, f) {
+                        (None, Some(f)) => {
+                            let ret = f(node_key, &mut (*h).value);
+                            (*h).generation = generation;
+                            (Some(ret), None)
+                        }
+                        ret => ret,
+                    }
+                }
+                Less => go((*h).right, prefix, generation, f),
+                Greater | Equal => go((*h).left, prefix, generation, f),
             };
 
             if res.0.is_some() {
-                (*h).subtree_hash = Node::subtree_hash(h);
+                Node::update(h);
             }
 
             res
         }
 
-        unsafe { go(self.root, prefix, f).0 }
+        let generation = self.generation + 1;
+        let res = unsafe { go(self.root, prefix, generation, f).0 };
+        if res.is_some() {
+            self.generation = generation;
+        }
+        res
+    }
+
+    pub fn min_entry_with_prefix<P>(&self, prefix: &P) -> Option<(&K, &V)>
+    where
+        K: Prefix<P>,
+        P: ?Sized + Ord,
+    {
+        unsafe fn go<'a, K, V, P>(n: *mut Node<K, V>, prefix: &P) -> Option<(&'a K, &'a V)>
+        where
+            K: 'static + Label + Prefix<P>,
+            P: ?Sized + Ord,
+        {
+            if n.is_null() {
+                return None;
+            }
+
+            let node_key = &(*n).key;
+            let key_prefix = node_key.borrow();
+            match key_prefix.cmp(prefix) {
+                Greater | Equal if node_key.is_prefix(prefix) => {
+                    go((*n).left, prefix).or(Some((node_key, &(*n).value)))
+                }
+                Less => go((*n).right, prefix),
+                Greater | Equal => go((*n).left, prefix),
+            }
+        }
+        unsafe { go(self.root, prefix) }
     }
 
     pub fn max_entry_with_prefix<P: ?Sized>(&self, prefix: &P) -> Option<(&K, &V)>
@@ -528,6 +1083,33 @@ impl<K: 'static + Label, V: AsHashTree + 'static> RbTree<K, V> {
         unsafe { Node::full_witness_tree(self.root, Node::witness_tree) }
     }
 
+    /// Returns a description of the shape of the tree, including the color and the subtree
+    /// hash of every node. This is only meant for debugging and visualization.
+    #[cfg(feature = "debug")]
+    pub fn structure(&self) -> Option<TreeDump> {
+        unsafe fn go<K: 'static + Label, V>(n: *mut Node<K, V>) -> Option<Box<TreeDump>> {
+            if n.is_null() {
+                return None;
+            }
+
+            Some(Box::new(TreeDump {
+                label: (*n).key.as_label().into_owned(),
+                red: is_red(n),
+                subtree_hash: (*n).subtree_hash,
+                left: go((*n).left),
+                right: go((*n).right),
+            }))
+        }
+
+        unsafe { go(self.root).map(|dump| *dump) }
+    }
+
+    /// Like [`RbTree::keys`], but the labels are copied so the resulting tree does not
+    /// borrow from the map.
+    pub fn owned_keys(&self) -> HashTree<'static> {
+        unsafe { Node::full_witness_tree(self.root, Node::owned_witness_tree) }
+    }
+
     /// Returns a witness for the keys in the specified range.  The
     /// resulting tree doesn't include values, they are replaced with
     /// "Pruned" nodes.
@@ -576,6 +1158,70 @@ impl<K: 'static + Label, V: AsHashTree + 'static> RbTree<K, V> {
         )
     }
 
+    /// Returns a witness for the `n` entries with the smallest keys, `n` is clamped to the
+    /// length of the tree. Every entry before the last revealed entry is in the witness, so
+    /// the witness also proves that there are no smaller keys.
+    pub fn first_n(&self, n: usize) -> HashTree<'_> {
+        match self.boundary_of(n, false) {
+            Some(key) => self.witness_range_below(KeyBound::Exact(key), Node::data_tree),
+            None => self.all_or_nothing_witness(n),
+        }
+    }
+
+    /// Returns a witness for the `n` entries with the largest keys, `n` is clamped to the
+    /// length of the tree. Every entry after the first revealed entry is in the witness, so
+    /// the witness also proves that there are no larger keys.
+    pub fn last_n(&self, n: usize) -> HashTree<'_> {
+        match self.boundary_of(n, true) {
+            Some(key) => self.witness_range_above(KeyBound::Exact(key), Node::data_tree),
+            None => self.all_or_nothing_witness(n),
+        }
+    }
+
+    /// Returns the `n`-th key from the start or from the end of the tree, or [`None`] if
+    /// `n` is zero or the tree does not have more than `n` entries.
+    fn boundary_of(&self, n: usize, rev: bool) -> Option<&K> {
+        unsafe fn go<'a, K, V>(n: *mut Node<K, V>, rev: bool, count: &mut usize) -> Option<&'a K> {
+            if n.is_null() {
+                return None;
+            }
+
+            let (first, second) = if rev {
+                ((*n).right, (*n).left)
+            } else {
+                ((*n).left, (*n).right)
+            };
+
+            if let Some(key) = go(first, rev, count) {
+                return Some(key);
+            }
+
+            *count -= 1;
+            if *count == 0 {
+                return Some(&(*n).key);
+            }
+
+            go(second, rev, count)
+        }
+
+        if n == 0 || n >= self.len {
+            return None;
+        }
+
+        let mut count = n;
+        unsafe { go(self.root, rev, &mut count) }
+    }
+
+    /// The witness of [`RbTree::first_n`] and [`RbTree::last_n`] when no entry is pruned
+    /// or when every entry is pruned.
+    fn all_or_nothing_witness(&self, n: usize) -> HashTree<'_> {
+        if n == 0 && !self.root.is_null() {
+            Pruned(self.root_hash())
+        } else {
+            unsafe { Node::full_witness_tree(self.root, Node::data_tree) }
+        }
+    }
+
     /// Enumerates all the key-value pairs in the tree.
     #[inline]
     pub fn for_each<'a, F>(&'a self, mut f: F)
@@ -876,55 +1522,75 @@ impl<K: 'static + Label, V: AsHashTree + 'static> RbTree<K, V> {
     /// Inserts a key-value entry into the map.
     #[inline]
     pub fn insert(&mut self, key: K, value: V) -> (Option<V>, &mut V) {
-        struct GoResult<'a, K, V> {
+        let (old_value, node) = self.insert_node(key, value);
+        (old_value, unsafe { &mut (*node).value })
+    }
+
+    /// Inserts a key-value entry into the map, and returns a witness for the new entry in
+    /// the tree after the insertion along with the previous value of the key.
+    pub fn insert_and_witness(&mut self, key: K, value: V) -> (Option<V>, HashTree<'_>) {
+        let (old_value, node) = self.insert_node(key, value);
+        // The nodes are boxed, so the key stays at the same address while the tree is
+        // borrowed by the witness.
+        let key = unsafe { &(*node).key };
+        (old_value, self.witness(key))
+    }
+
+    /// Inserts a key-value entry into the map, and returns the previous value of the key
+    /// along with the node of the new entry.
+    fn insert_node(&mut self, key: K, value: V) -> (Option<V>, *mut Node<K, V>) {
+        struct GoResult<K, V> {
             node: *mut Node<K, V>,
             old_value: Option<V>,
-            new_value_ref: &'a mut V,
+            new_node: *mut Node<K, V>,
         }
 
         unsafe fn go<K: 'static + Label, V: AsHashTree + 'static>(
             mut h: *mut Node<K, V>,
             k: K,
             mut v: V,
-        ) -> GoResult<'static, K, V> {
+            generation: u64,
+        ) -> GoResult<K, V> {
             if h.is_null() {
-                let node = Node::new(k, v);
+                let node = Node::new(k, v, generation);
                 return GoResult {
                     node,
                     old_value: None,
-                    new_value_ref: &mut (*node).value,
+                    new_node: node,
                 };
             }
 
-            let (old_value, new_value_ref) = match k.cmp(&(*h).key) {
+            let (old_value, new_node) = match k.cmp(&(*h).key) {
                 Equal => {
                     std::mem::swap(&mut (*h).value, &mut v);
-                    (*h).subtree_hash = Node::subtree_hash(h);
-                    (Some(v), &mut (*h).value)
+                    (*h).generation = generation;
+                    Node::update(h);
+                    (Some(v), h)
                 }
                 Less => {
-                    let res = go((*h).left, k, v);
+                    let res = go((*h).left, k, v, generation);
                     (*h).left = res.node;
-                    (*h).subtree_hash = Node::subtree_hash(h);
-                    (res.old_value, res.new_value_ref)
+                    Node::update(h);
+                    (res.old_value, res.new_node)
                 }
                 Greater => {
-                    let res = go((*h).right, k, v);
+                    let res = go((*h).right, k, v, generation);
                     (*h).right = res.node;
-                    (*h).subtree_hash = Node::subtree_hash(h);
-                    (res.old_value, res.new_value_ref)
+                    Node::update(h);
+                    (res.old_value, res.new_node)
                 }
             };
 
             GoResult {
                 node: balance(h),
                 old_value,
-                new_value_ref,
+                new_node,
             }
         }
 
         unsafe {
-            let mut result = go(self.root, key, value);
+            self.generation += 1;
+            let mut result = go(self.root, key, value, self.generation);
             (*result.node).color = Color::Black;
 
             #[cfg(test)]
@@ -935,13 +1601,19 @@ impl<K: 'static + Label, V: AsHashTree + 'static> RbTree<K, V> {
             );
             #[cfg(test)]
             debug_assert!(!has_dangling_pointers(result.node));
+            #[cfg(test)]
+            debug_assert!(
+                is_label_ordered(result.node),
+                "the labels are not in ascending order:\n{:?}",
+                DebugView(result.node)
+            );
 
             if result.old_value.is_none() {
                 self.len += 1;
             }
 
             self.root = result.node;
-            (result.old_value, result.new_value_ref)
+            (result.old_value, result.new_node)
         }
     }
 
@@ -951,6 +1623,23 @@ impl<K: 'static + Label, V: AsHashTree + 'static> RbTree<K, V> {
     where
         K: Borrow<Q>,
         Q: Ord,
+    {
+        self.get(key)?;
+        let result = unsafe { self.delete_by(|k| key.cmp(k.borrow())) };
+        debug_assert!(self.get(key).is_none());
+        Some(result)
+    }
+
+    /// Remove the entry for which `cmp` returns [`Equal`] and return it, `cmp` returns the
+    /// order of the entry to remove relative to the given key.
+    ///
+    /// # Safety
+    ///
+    /// The entry must be in the tree. `cmp` is only called on the way down to the entry,
+    /// before the entry is moved, so it can refer to the key of the entry itself.
+    unsafe fn delete_by<F>(&mut self, mut cmp: F) -> (K, V)
+    where
+        F: FnMut(&K) -> Ordering,
     {
         unsafe fn move_red_left<K: 'static + Label, V: AsHashTree + 'static>(
             mut h: *mut Node<K, V>,
@@ -998,29 +1687,28 @@ impl<K: 'static + Label, V: AsHashTree + 'static> RbTree<K, V> {
                 h = move_red_left(h);
             }
             (*h).left = delete_min((*h).left, result);
-            (*h).subtree_hash = Node::subtree_hash(h);
+            Node::update(h);
             balance(h)
         }
 
-        unsafe fn go<K: 'static + Label, V: AsHashTree + 'static, Q: ?Sized>(
+        unsafe fn go<K: 'static + Label, V: AsHashTree + 'static, F>(
             mut h: *mut Node<K, V>,
             result: &mut Option<(K, V)>,
-            key: &Q,
+            cmp: &mut F,
         ) -> *mut Node<K, V>
         where
-            K: Borrow<Q>,
-            Q: Ord,
+            F: FnMut(&K) -> Ordering,
         {
-            if key < (*h).key.borrow() {
+            if cmp(&(*h).key) == Less {
                 if !is_red((*h).left) && !is_red((*(*h).left).left) {
                     h = move_red_left(h);
                 }
-                (*h).left = go((*h).left, result, key);
+                (*h).left = go((*h).left, result, cmp);
             } else {
                 if is_red((*h).left) {
                     h = rotate_right(h);
                 }
-                if key == (*h).key.borrow() && (*h).right.is_null() {
+                if (*h).right.is_null() && cmp(&(*h).key) == Equal {
                     debug_assert!((*h).left.is_null());
                     *result = Some(Node::delete(h).unwrap());
                     return Node::null();
@@ -1030,44 +1718,499 @@ impl<K: 'static + Label, V: AsHashTree + 'static> RbTree<K, V> {
                     h = move_red_right(h);
                 }
 
-                if key == (*h).key.borrow() {
+                if cmp(&(*h).key) == Equal {
                     let m = min((*h).right);
                     std::mem::swap(&mut (*h).key, &mut (*m).key);
                     std::mem::swap(&mut (*h).value, &mut (*m).value);
+                    std::mem::swap(&mut (*h).generation, &mut (*m).generation);
                     (*h).right = delete_min((*h).right, result);
                 } else {
-                    (*h).right = go((*h).right, result, key);
+                    (*h).right = go((*h).right, result, cmp);
                 }
             }
-            (*h).subtree_hash = Node::subtree_hash(h);
+            Node::update(h);
             balance(h)
         }
 
-        unsafe {
-            self.get(key)?;
-            if !is_red((*self.root).left) && !is_red((*self.root).right) {
-                (*self.root).color = Color::Red;
+        if !is_red((*self.root).left) && !is_red((*self.root).right) {
+            (*self.root).color = Color::Red;
+        }
+
+        let mut result = None;
+        self.root = go(self.root, &mut result, &mut cmp);
+        if !self.root.is_null() {
+            (*self.root).color = Color::Black;
+        }
+
+        #[cfg(test)]
+        debug_assert!(
+            is_balanced(self.root),
+            "unbalanced map: {:?}",
+            DebugView(self.root)
+        );
+
+        self.len -= 1;
+        self.generation += 1;
+        self.removed_at = self.generation;
+        result.expect("The entry is not in the tree.")
+    }
+
+    /// Retains only the entries for which the predicate returns `true`, and returns the
+    /// removed entries in the ascending order of their keys.
+    ///
+    /// If any entry is removed the tree is rebuilt from the remaining entries in one pass,
+    /// so the hash of every node is computed only once.
+    pub fn retain<F>(&mut self, mut f: F) -> Vec<(K, V)>
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let mut keep = Vec::with_capacity(self.len);
+        self.for_each(|k, v| keep.push(f(k, v)));
+
+        if keep.iter().all(|k| *k) {
+            return Vec::new();
+        }
+
+        let mut removed = Vec::new();
+        let mut retained = Vec::with_capacity(self.len);
+
+        for ((k, v, g), keep) in self.take_entries().into_iter().zip(keep) {
+            if keep {
+                retained.push((k, v, g));
+            } else {
+                removed.push((k, v));
             }
+        }
+
+        let generation = self.generation + 1;
+        *self = Self::from_sorted_entries(retained.len(), retained.into_iter());
+        self.generation = generation;
+        self.removed_at = generation;
+        removed
+    }
+
+    /// Insert all of the entries of `other` into this tree, `resolve` is called with the
+    /// key, the current value and the value from `other` for every key that is in both
+    /// trees, and its result is used as the new value.
+    ///
+    /// Both trees are walked once in order and the result is built in one pass, which is
+    /// O(n + m) instead of the O(m log(n + m)) of inserting the entries one by one. If
+    /// `other` is small compared to this tree its entries are inserted one by one instead.
+    ///
+    /// If `resolve` panics this tree keeps all of its entries, except for the one whose
+    /// value was being resolved, and the entries of `other` that were not merged yet are
+    /// dropped.
+    pub fn merge_with<F>(&mut self, mut other: RbTree<K, V>, mut resolve: F)
+    where
+        F: FnMut(&K, V, V) -> V,
+    {
+        if other.len == 0 {
+            return;
+        }
+
+        if self.is_small_batch(other.len) {
+            for (key, value, _) in other.take_entries() {
+                let removed_at = self.removed_at;
+                let value = match self.delete(&key) {
+                    Some((_, current)) => resolve(&key, current, value),
+                    None => value,
+                };
+                self.insert_node(key, value);
+                // The entry is only removed while its value is resolved.
+                self.removed_at = removed_at;
+            }
+            return;
+        }
 
-            let mut result = None;
-            self.root = go(self.root, &mut result, key);
-            if !self.root.is_null() {
-                (*self.root).color = Color::Black;
+        let entries = other.take_entries().into_iter().map(|(k, v, _)| (k, v));
+        self.merge_sorted(entries, resolve);
+    }
+
+    /// Merge the given entries, which must be sorted by their keys in the strictly
+    /// ascending order, into this tree in one ordered walk and rebuild the tree once, see
+    /// [`RbTree::merge_with`].
+    fn merge_sorted<I, F>(&mut self, entries: I, mut resolve: F)
+    where
+        I: ExactSizeIterator<Item = (K, V)>,
+        F: FnMut(&K, V, V) -> V,
+    {
+        /// Rebuilds the tree from the merged entries and the rest of its own entries when
+        /// it is dropped, so the tree is restored if `resolve` panics.
+        struct Guard<'a, K: 'static + Label, V: AsHashTree + 'static> {
+            tree: &'a mut RbTree<K, V>,
+            merged: Vec<(K, V, u64)>,
+            rest: std::iter::Peekable<std::vec::IntoIter<(K, V, u64)>>,
+            generation: u64,
+            removed_at: u64,
+            done: bool,
+        }
+
+        impl<K: 'static + Label, V: AsHashTree + 'static> Drop for Guard<'_, K, V> {
+            fn drop(&mut self) {
+                let mut entries = std::mem::take(&mut self.merged);
+                entries.extend(self.rest.by_ref());
+                *self.tree = RbTree::from_sorted_entries(entries.len(), entries.into_iter());
+                self.tree.generation = self.generation;
+                // An entry is lost if the merge did not finish.
+                self.tree.removed_at = if self.done {
+                    self.removed_at
+                } else {
+                    self.generation
+                };
             }
+        }
+
+        let generation = self.generation + 1;
+        let removed_at = self.removed_at;
+        let a = self.take_entries();
+        let mut b = entries.peekable();
+        let mut guard = Guard {
+            merged: Vec::with_capacity(a.len() + b.len()),
+            rest: a.into_iter().peekable(),
+            tree: self,
+            generation,
+            removed_at,
+            done: false,
+        };
+        let a = &mut guard.rest;
+
+        loop {
+            let entry = match (a.peek(), b.peek()) {
+                (None, None) => break,
+                (Some(_), None) => a.next().unwrap(),
+                (None, Some(_)) => {
+                    let (k, v) = b.next().unwrap();
+                    (k, v, generation)
+                }
+                (Some((x, _, _)), Some((y, _))) => match x.cmp(y) {
+                    Less => a.next().unwrap(),
+                    Greater => {
+                        let (k, v) = b.next().unwrap();
+                        (k, v, generation)
+                    }
+                    Equal => {
+                        let (k, x, _) = a.next().unwrap();
+                        let (_, y) = b.next().unwrap();
+                        let v = resolve(&k, x, y);
+                        (k, v, generation)
+                    }
+                },
+            };
+
+            guard.merged.push(entry);
+        }
+
+        guard.done = true;
+    }
+
+    /// Returns `true` if changing `count` entries one by one, which is O(count log n), is
+    /// cheaper than rebuilding the whole tree in O(n).
+    #[inline]
+    fn is_small_batch(&self, count: usize) -> bool {
+        let depth = (usize::BITS - self.len.leading_zeros()) as usize;
+        count.saturating_mul(depth) < self.len
+    }
+
+    /// Build a balanced tree from the given entries, which must be sorted by their keys in
+    /// the strictly ascending order. The entries are collected first and the hash of every
+    /// node is computed once while the tree is built bottom-up.
+    ///
+    /// The ordering of the entries is only checked in debug builds.
+    pub fn from_sorted_iter<I>(entries: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let entries = entries.into_iter().collect::<Vec<_>>();
+
+        // Loading the entries counts as a single mutation.
+        let generation = if entries.is_empty() { 0 } else { 1 };
+        let mut tree = Self::from_sorted_entries(
+            entries.len(),
+            entries.into_iter().map(|(k, v)| (k, v, generation)),
+        );
+
+        tree.generation = generation;
+        tree
+    }
 
+    /// Insert the given entries, which should be sorted by their keys in the strictly
+    /// ascending order. The entries are merged with the tree in a single pass and the tree
+    /// is rebuilt once, like [`RbTree::merge_with`], and a new value replaces the previous
+    /// value of its key.
+    ///
+    /// If the entries are not sorted, or if there are only a few of them compared to the
+    /// size of the tree, they are inserted one by one instead.
+    pub fn extend_sorted<I>(&mut self, entries: I)
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let entries = entries.into_iter().collect::<Vec<_>>();
+
+        if entries.is_empty() {
+            return;
+        }
+
+        if self.is_small_batch(entries.len()) || !entries.windows(2).all(|w| w[0].0 < w[1].0) {
+            for (key, value) in entries {
+                self.insert(key, value);
+            }
+            return;
+        }
+
+        self.merge_sorted(entries.into_iter(), |_, _, value| value);
+    }
+
+    /// Rebuild the tree from its entries so that it has the minimal depth for its length,
+    /// which makes the witnesses of the entries as small as possible. The generation of the
+    /// tree and of every entry is preserved, since no entry is changed.
+    ///
+    /// The root hash depends on the shape of the tree, so it may be different after this.
+    pub fn rebalance(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+
+        let generation = self.generation;
+        let removed_at = self.removed_at;
+        let entries = self.take_entries();
+        *self = Self::from_sorted_entries(entries.len(), entries.into_iter());
+        self.generation = generation;
+        self.removed_at = removed_at;
+    }
+
+    /// Remove all of the entries with a key in the given range and return the number of
+    /// removed entries.
+    ///
+    /// The entries are removed in order starting from the first key in the range, which is
+    /// O(k log n) for k removed entries. If the range is a large share of the tree it is
+    /// cheaper to rebuild the tree once like [`RbTree::retain`], which is O(n) and is used
+    /// instead.
+    pub fn remove_range<Q, R>(&mut self, range: R) -> usize
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+        R: RangeBounds<Q>,
+    {
+        let bounds = (range.start_bound(), range.end_bound());
+        let count = iterator::RbTreeRangeIterator::new(self, bounds).count();
+
+        if !self.is_small_batch(count) {
+            return self.retain(|k, _| !range.contains(k.borrow())).len();
+        }
+
+        for _ in 0..count {
+            let (first, _) = iterator::RbTreeRangeIterator::new(self, bounds)
+                .next()
+                .unwrap();
+            let first: *const K = first;
+            unsafe { self.delete_by(|k| (*first).cmp(k)) };
+        }
+
+        count
+    }
+
+    /// Remove all of the entries from the tree. This counts as a mutation, so the generation
+    /// of the tree is incremented and the removal is recorded for [`RbTree::removed_since`].
+    pub fn clear(&mut self) {
+        unsafe { Node::delete(std::mem::replace(&mut self.root, Node::null())) };
+        self.len = 0;
+        self.generation += 1;
+        self.removed_at = self.generation;
+    }
+
+    /// Returns `true` if any entry was removed from the tree after the given generation.
+    #[inline]
+    pub fn removed_since(&self, generation: u64) -> bool {
+        self.removed_at > generation
+    }
+
+    /// Returns the keys of the entries that were inserted or modified after the given
+    /// generation in the ascending order. The subtrees without any such entry are skipped,
+    /// so this is O(k log n) for k changed entries.
+    pub fn changed_since(&self, generation: u64) -> Vec<&K> {
+        unsafe fn go<K, V>(n: *mut Node<K, V>, generation: u64, keys: &mut Vec<&K>) {
+            if n.is_null() || (*n).max_generation <= generation {
+                return;
+            }
+
+            go((*n).left, generation, keys);
+            if (*n).generation > generation {
+                keys.push(&(*n).key);
+            }
+            go((*n).right, generation, keys);
+        }
+
+        let mut keys = Vec::new();
+        unsafe { go(self.root, generation, &mut keys) };
+        keys
+    }
+
+    /// Create a witness that reveals every entry that was inserted or modified after the
+    /// given generation, the rest of the tree is pruned.
+    pub fn witness_changed_since(&self, generation: u64) -> HashTree<'_> {
+        unsafe fn go<'a, K: 'static + Label, V: AsHashTree + 'static>(
+            n: *mut Node<K, V>,
+            generation: u64,
+        ) -> HashTree<'a> {
+            if n.is_null() {
+                return Empty;
+            }
+
+            if (*n).max_generation <= generation {
+                return Pruned((*n).subtree_hash);
+            }
+
+            let data = if (*n).generation > generation {
+                Node::data_tree(n)
+            } else {
+                Pruned(Node::data_hash(n))
+            };
+
+            three_way_fork(go((*n).left, generation), data, go((*n).right, generation))
+        }
+
+        unsafe { go(self.root, generation) }
+    }
+
+    /// Remove all of the entries from the tree and return them along with their generation
+    /// in the ascending order of their keys.
+    fn take_entries(&mut self) -> Vec<(K, V, u64)> {
+        unsafe fn go<K: 'static + Label, V: AsHashTree + 'static>(
+            n: *mut Node<K, V>,
+            entries: &mut Vec<(K, V, u64)>,
+        ) {
+            if n.is_null() {
+                return;
+            }
+
+            let (left, right) = ((*n).left, (*n).right);
+            go(left, entries);
+
+            let node = Box::from_raw(n);
             #[cfg(test)]
+            debug_alloc::mark_pointer_deleted(n);
+            entries.push((node.key, node.value, node.generation));
+
+            go(right, entries);
+        }
+
+        let mut entries = Vec::with_capacity(self.len);
+        let root = std::mem::replace(&mut self.root, Node::null());
+        self.len = 0;
+        unsafe { go(root, &mut entries) };
+        entries
+    }
+
+    /// Build a balanced tree from `len` entries and their generations that are sorted by
+    /// their keys in the ascending order. The entries are consumed in order, and the hash
+    /// of every node is computed once, right after both of its children are built.
+    ///
+    /// The tree is built as a 2-3 tree with the largest possible black height, a 3-node
+    /// is only used when a subtree has too many entries for a 2-node.
+    fn from_sorted_entries<I>(len: usize, mut entries: I) -> Self
+    where
+        I: Iterator<Item = (K, V, u64)>,
+    {
+        /// The maximum number of entries in a 2-node with the given black height.
+        fn max_two_node(black_height: u32) -> usize {
+            3usize
+                .checked_pow(black_height - 1)
+                .and_then(|n| (n - 1).checked_mul(2))
+                .map(|n| n + 1)
+                .unwrap_or(usize::MAX)
+        }
+
+        unsafe fn node<K: 'static + Label, V: AsHashTree + 'static, I>(
+            entries: &mut I,
+            prev: &mut *mut Node<K, V>,
+            left: *mut Node<K, V>,
+        ) -> *mut Node<K, V>
+        where
+            I: Iterator<Item = (K, V, u64)>,
+        {
+            let (key, value, generation) = entries.next().expect("Not enough entries.");
+            let n = Node::new(key, value, generation);
+
             debug_assert!(
-                is_balanced(self.root),
-                "unbalanced map: {:?}",
-                DebugView(self.root)
+                prev.is_null() || (**prev).key < (*n).key,
+                "The entries are not sorted."
             );
+            *prev = n;
 
-            #[cfg(test)]
-            debug_assert!(result.is_some());
-            self.len -= 1;
+            (*n).left = left;
+            n
+        }
 
-            debug_assert!(self.get(key).is_none());
-            result
+        unsafe fn finish<K: 'static + Label, V: AsHashTree + 'static>(
+            n: *mut Node<K, V>,
+            right: *mut Node<K, V>,
+            color: Color,
+        ) -> *mut Node<K, V> {
+            (*n).right = right;
+            (*n).color = color;
+            // The subtree hash of a new node is the hash of its data.
+            (*n).subtree_hash = Node::subtree_hash_with(n, (*n).subtree_hash);
+            (*n).max_generation = Node::max_generation(n);
+            n
+        }
+
+        unsafe fn go<K: 'static + Label, V: AsHashTree + 'static, I>(
+            entries: &mut I,
+            prev: &mut *mut Node<K, V>,
+            len: usize,
+            black_height: u32,
+        ) -> *mut Node<K, V>
+        where
+            I: Iterator<Item = (K, V, u64)>,
+        {
+            if black_height == 0 {
+                debug_assert_eq!(len, 0);
+                return Node::null();
+            }
+
+            if len <= max_two_node(black_height) {
+                let left_len = (len - 1) / 2;
+                let left = go(entries, prev, left_len, black_height - 1);
+                let n = node(entries, prev, left);
+                let right = go(entries, prev, len - 1 - left_len, black_height - 1);
+                finish(n, right, Color::Black)
+            } else {
+                let a_len = (len - 2) / 3;
+                let b_len = (len - 2 - a_len) / 2;
+                let c_len = len - 2 - a_len - b_len;
+                let a = go(entries, prev, a_len, black_height - 1);
+                let red = node(entries, prev, a);
+                let b = go(entries, prev, b_len, black_height - 1);
+                let red = finish(red, b, Color::Red);
+                let black = node(entries, prev, red);
+                let c = go(entries, prev, c_len, black_height - 1);
+                finish(black, c, Color::Black)
+            }
+        }
+
+        if len == 0 {
+            return Self::new();
+        }
+
+        // The largest black height that a tree with `len` nodes can have.
+        let black_height = usize::BITS - 1 - (len + 1).leading_zeros();
+        let mut prev = Node::null();
+        let root = unsafe { go(&mut entries, &mut prev, len, black_height) };
+
+        #[cfg(test)]
+        unsafe {
+            debug_assert!(
+                is_balanced(root),
+                "the tree is not balanced:\n{:?}",
+                DebugView(root)
+            );
+        }
+
+        Self {
+            len,
+            root,
+            generation: 0,
+            removed_at: 0,
         }
     }
 }
@@ -1124,8 +2267,8 @@ unsafe fn rotate_right<K: 'static + Label, V: AsHashTree + 'static>(
     (*x).color = (*(*x).right).color;
     (*(*x).right).color = Color::Red;
 
-    (*h).subtree_hash = Node::subtree_hash(h);
-    (*x).subtree_hash = Node::subtree_hash(x);
+    Node::update(h);
+    Node::update(x);
 
     x
 }
@@ -1142,8 +2285,8 @@ unsafe fn rotate_left<K: 'static + Label, V: AsHashTree + 'static>(
     (*x).color = (*(*x).left).color;
     (*(*x).left).color = Color::Red;
 
-    (*h).subtree_hash = Node::subtree_hash(h);
-    (*x).subtree_hash = Node::subtree_hash(x);
+    Node::update(h);
+    Node::update(x);
 
     x
 }
@@ -1181,6 +2324,26 @@ unsafe fn is_balanced<K, V>(root: *mut Node<K, V>) -> bool {
     go(root, num_black)
 }
 
+/// Returns true if the in-order traversal of the tree visits the labels in the ascending
+/// order as defined by [`label_cmp`], which is the order a client expects.
+#[cfg(test)]
+unsafe fn is_label_ordered<K: Label, V>(root: *mut Node<K, V>) -> bool {
+    unsafe fn go<K: Label, V>(node: *mut Node<K, V>, labels: &mut Vec<Vec<u8>>) {
+        if node.is_null() {
+            return;
+        }
+        go((*node).left, labels);
+        labels.push((*node).key.as_label().into_owned());
+        go((*node).right, labels);
+    }
+
+    let mut labels = Vec::new();
+    go(root, &mut labels);
+    labels
+        .windows(2)
+        .all(|w| label_cmp(&w[0], &w[1]) == Ordering::Less)
+}
+
 #[cfg(test)]
 unsafe fn has_dangling_pointers<K, V>(root: *mut Node<K, V>) -> bool {
     if root.is_null() {
@@ -1192,6 +2355,38 @@ unsafe fn has_dangling_pointers<K, V>(root: *mut Node<K, V>) -> bool {
         || has_dangling_pointers((*root).right)
 }
 
+/// The description of a node and its subtree, see [`RbTree::structure`].
+#[cfg(feature = "debug")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeDump {
+    /// The label of the key of the node.
+    pub label: Vec<u8>,
+    /// Whether the node is red, otherwise it is black.
+    pub red: bool,
+    /// The hash of the subtree rooted at this node.
+    pub subtree_hash: Hash,
+    pub left: Option<Box<TreeDump>>,
+    pub right: Option<Box<TreeDump>>,
+}
+
+#[cfg(feature = "debug")]
+impl TreeDump {
+    /// Render the tree as a Graphviz graph, each node shows its label and the first bytes
+    /// of its subtree hash.
+    pub fn to_dot(&self) -> String {
+        crate::hashtree::to_dot("RbTree", self, |dump| {
+            let attributes = format!(
+                "label=\"{}\\n{}\", color={}",
+                String::from_utf8_lossy(&dump.label).escape_default(),
+                hex::encode(&dump.subtree_hash[..4]),
+                if dump.red { "red" } else { "black" }
+            );
+            let children = dump.left.iter().chain(dump.right.iter());
+            (attributes, children.map(AsRef::as_ref).collect())
+        })
+    }
+}
+
 struct DebugView<K, V>(*const Node<K, V>);
 
 impl<K: Label, V> fmt::Debug for DebugView<K, V> {
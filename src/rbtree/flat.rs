@@ -0,0 +1,301 @@
+//! A flat encoding of the structure of a [`RbTree`], which makes it possible to reload a
+//! tree from the stable memory in O(n) without any rebalancing.
+//!
+//! The encoding starts with a version byte and the number of nodes as a big endian `u64`,
+//! followed by the nodes in pre-order. Each node is encoded as:
+//!
+//! 1. The color of the node, `0` for red and `1` for black.
+//! 2. A byte with the flags of the node, the first bit is set if the node has a left child
+//!    and the second bit is set if it has a right child. The position of the children is
+//!    implied by the pre-order.
+//! 3. The length of the CBOR encoded key as a big endian `u32` followed by its bytes.
+//! 4. The length of the CBOR encoded value as a big endian `u32` followed by its bytes.
+//!
+//! The generations of the entries are not encoded, a decoded tree is at the generation
+//! `1` and all of its entries are considered to be changed in that generation.
+
+#[cfg(test)]
+use super::debug_alloc;
+use super::{is_red, Color, Node, RbTree};
+use crate::label::Label;
+use crate::AsHashTree;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+
+/// The version of the flat encoding.
+const VERSION: u8 = 1;
+const HAS_LEFT: u8 = 1;
+const HAS_RIGHT: u8 = 2;
+
+/// The error type for encoding and decoding a [`RbTree`] as flat bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlatError {
+    /// A key or a value could not be encoded or decoded.
+    Codec(String),
+    /// The input ended before the entire tree was decoded.
+    UnexpectedEnd,
+    /// The input is not a valid encoding of a tree.
+    Malformed(&'static str),
+    /// The decoded tree does not satisfy the invariants of a red-black tree.
+    InvalidTree(&'static str),
+    /// The encoding of a key or a value is longer than the 4GiB that a chunk can hold.
+    ChunkTooLarge { len: usize },
+}
+
+impl Display for FlatError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FlatError::Codec(e) => write!(f, "Could not encode or decode an entry: {}", e),
+            FlatError::UnexpectedEnd => write!(f, "Unexpected end of the input"),
+            FlatError::Malformed(e) => write!(f, "Malformed input: {}", e),
+            FlatError::InvalidTree(e) => write!(f, "Invalid tree: {}", e),
+            FlatError::ChunkTooLarge { len } => {
+                write!(f, "Entry of {} bytes does not fit in a chunk", len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FlatError {}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> RbTree<K, V>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    /// Encode the tree along with its structure, see the [module](self) level docs for the
+    /// format.
+    pub fn to_flat_bytes(&self) -> Result<Vec<u8>, FlatError> {
+        unsafe fn write<K, V>(n: *mut Node<K, V>, out: &mut Vec<u8>) -> Result<(), FlatError>
+        where
+            K: Serialize,
+            V: Serialize,
+        {
+            let mut flags = 0;
+            if !(*n).left.is_null() {
+                flags |= HAS_LEFT;
+            }
+            if !(*n).right.is_null() {
+                flags |= HAS_RIGHT;
+            }
+
+            out.push(if is_red(n) { 0 } else { 1 });
+            out.push(flags);
+            write_chunk(out, &(*n).key)?;
+            write_chunk(out, &(*n).value)?;
+
+            if !(*n).left.is_null() {
+                write((*n).left, out)?;
+            }
+            if !(*n).right.is_null() {
+                write((*n).right, out)?;
+            }
+
+            Ok(())
+        }
+
+        let mut out = Vec::with_capacity(9 + self.len * 16);
+        out.push(VERSION);
+        out.extend_from_slice(&(self.len as u64).to_be_bytes());
+
+        if !self.root.is_null() {
+            unsafe { write(self.root, &mut out)? };
+        }
+
+        Ok(out)
+    }
+
+    /// Decode a tree that was encoded using [`RbTree::to_flat_bytes`]. The nodes are wired
+    /// together as they were encoded, and the hash of each node is computed only once.
+    ///
+    /// The red-black invariants and the order of the keys are validated before the tree
+    /// is returned.
+    pub fn from_flat_bytes(bytes: &[u8]) -> Result<Self, FlatError> {
+        let mut reader = Reader { bytes };
+
+        if reader.take(1)?[0] != VERSION {
+            return Err(FlatError::Malformed("unsupported version"));
+        }
+
+        let mut len = [0; 8];
+        len.copy_from_slice(reader.take(8)?);
+        let len = u64::from_be_bytes(len) as usize;
+
+        let mut tree = Self::new();
+        tree.generation = 1;
+
+        if len > 0 {
+            // The height of a red-black tree with n nodes is at most 2 * log2(n + 1), we
+            // use it to reject deep inputs without overflowing the stack.
+            let max_depth = 2 * (usize::BITS - len.leading_zeros()) as usize;
+            let mut count = 0;
+            tree.root = unsafe { read_node(&mut reader, 1, max_depth, &mut count)? };
+            tree.len = count;
+        }
+
+        if !reader.bytes.is_empty() {
+            return Err(FlatError::Malformed("trailing bytes after the tree"));
+        }
+
+        if tree.len != len {
+            return Err(FlatError::Malformed("the number of nodes does not match"));
+        }
+
+        unsafe {
+            if is_red(tree.root) {
+                return Err(FlatError::InvalidTree("the root is red"));
+            }
+
+            if black_height(tree.root).is_none() {
+                return Err(FlatError::InvalidTree(
+                    "the colors of the nodes are invalid",
+                ));
+            }
+        }
+
+        let mut ordered = true;
+        let mut prev: Option<&K> = None;
+        tree.for_each(|k, _| {
+            if let Some(prev) = prev {
+                ordered &= prev < k;
+            }
+            prev = Some(k);
+        });
+
+        if !ordered {
+            return Err(FlatError::InvalidTree(
+                "the keys are not in ascending order",
+            ));
+        }
+
+        Ok(tree)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    #[inline]
+    fn take(&mut self, n: usize) -> Result<&'a [u8], FlatError> {
+        if self.bytes.len() < n {
+            return Err(FlatError::UnexpectedEnd);
+        }
+
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    #[inline]
+    fn chunk<T: DeserializeOwned>(&mut self) -> Result<T, FlatError> {
+        let mut len = [0; 4];
+        len.copy_from_slice(self.take(4)?);
+        let data = self.take(u32::from_be_bytes(len) as usize)?;
+        serde_cbor::from_slice(data).map_err(|e| FlatError::Codec(e.to_string()))
+    }
+}
+
+fn write_chunk<T: Serialize>(out: &mut Vec<u8>, value: &T) -> Result<(), FlatError> {
+    let data = serde_cbor::to_vec(value).map_err(|e| FlatError::Codec(e.to_string()))?;
+    let len =
+        u32::try_from(data.len()).map_err(|_| FlatError::ChunkTooLarge { len: data.len() })?;
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(&data);
+    Ok(())
+}
+
+/// Read the subtree that starts at the current position of the reader, on error every
+/// node that was read is freed.
+unsafe fn read_node<K, V>(
+    reader: &mut Reader,
+    depth: usize,
+    max_depth: usize,
+    count: &mut usize,
+) -> Result<*mut Node<K, V>, FlatError>
+where
+    K: 'static + Label + DeserializeOwned,
+    V: AsHashTree + 'static + DeserializeOwned,
+{
+    if depth > max_depth {
+        return Err(FlatError::InvalidTree("the tree is too deep"));
+    }
+
+    let color = match reader.take(1)?[0] {
+        0 => Color::Red,
+        1 => Color::Black,
+        _ => return Err(FlatError::Malformed("invalid color")),
+    };
+
+    let flags = reader.take(1)?[0];
+    if flags & !(HAS_LEFT | HAS_RIGHT) != 0 {
+        return Err(FlatError::Malformed("invalid flags"));
+    }
+
+    let key = reader.chunk::<K>()?;
+    let value = reader.chunk::<V>()?;
+
+    let node = Box::into_raw(Box::new(Node {
+        key,
+        value,
+        left: Node::null(),
+        right: Node::null(),
+        color,
+        subtree_hash: [0; 32],
+        generation: 1,
+        max_generation: 1,
+    }));
+
+    #[cfg(test)]
+    debug_alloc::mark_pointer_allocated(node);
+
+    *count += 1;
+
+    if flags & HAS_LEFT != 0 {
+        match read_node(reader, depth + 1, max_depth, count) {
+            Ok(left) => (*node).left = left,
+            Err(e) => {
+                Node::delete(node);
+                return Err(e);
+            }
+        }
+    }
+
+    if flags & HAS_RIGHT != 0 {
+        match read_node(reader, depth + 1, max_depth, count) {
+            Ok(right) => (*node).right = right,
+            Err(e) => {
+                Node::delete(node);
+                return Err(e);
+            }
+        }
+    }
+
+    (*node).subtree_hash = Node::subtree_hash_with(node, Node::data_hash(node));
+
+    Ok(node)
+}
+
+/// Returns the number of black nodes on every path from the given node to a leaf, or
+/// [`None`] if the subtree is not a left-leaning red-black tree.
+unsafe fn black_height<K, V>(n: *mut Node<K, V>) -> Option<usize> {
+    if n.is_null() {
+        return Some(0);
+    }
+
+    if is_red((*n).right) || (is_red(n) && is_red((*n).left)) {
+        return None;
+    }
+
+    let left = black_height((*n).left)?;
+    let right = black_height((*n).right)?;
+
+    if left != right {
+        return None;
+    }
+
+    Some(left + if is_red(n) { 0 } else { 1 })
+}
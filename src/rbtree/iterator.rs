@@ -1,23 +1,93 @@
 use super::{Node, RbTree};
+use crate::hashtree::label_cmp;
 use crate::label::Label;
 use crate::AsHashTree;
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::iter::FusedIterator;
 use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
 
-/// An iterator over key-values in a RbTree.
-pub struct RbTreeIterator<'tree, K: 'static + Label, V: AsHashTree + 'static> {
+/// An in-order walk over the nodes of a RbTree, this is the shared state of the iterators.
+struct Walk<'tree, K: 'static + Label, V: AsHashTree + 'static> {
     visit: *mut Node<K, V>,
     stack: Vec<*mut Node<K, V>>,
-    remaining_elements: usize,
+    /// Walk from the largest key to the smallest one instead.
+    reverse: bool,
     lifetime: PhantomData<&'tree RbTree<K, V>>,
 }
 
+impl<'tree, K: 'static + Label, V: AsHashTree + 'static> Walk<'tree, K, V> {
+    #[inline]
+    fn new(visit: *mut Node<K, V>, stack: Vec<*mut Node<K, V>>, reverse: bool) -> Self {
+        Self {
+            visit,
+            stack,
+            reverse,
+            lifetime: PhantomData,
+        }
+    }
+
+    #[inline]
+    fn next(&mut self) -> Option<(&'tree K, &'tree V)> {
+        unsafe {
+            while !self.visit.is_null() {
+                self.stack.push(self.visit);
+                self.visit = if self.reverse {
+                    (*self.visit).right
+                } else {
+                    (*self.visit).left
+                };
+            }
+
+            if let Some(node) = self.stack.pop() {
+                self.visit = if self.reverse {
+                    (*node).left
+                } else {
+                    (*node).right
+                };
+                return Some((&(*node).key, &(*node).value));
+            }
+
+            None
+        }
+    }
+}
+
+/// An iterator over key-values in a RbTree.
+pub struct RbTreeIterator<'tree, K: 'static + Label, V: AsHashTree + 'static> {
+    walk: Walk<'tree, K, V>,
+    /// The number of remaining elements.
+    remaining_elements: usize,
+}
+
 impl<'tree, K: 'static + Label, V: AsHashTree + 'static> RbTreeIterator<'tree, K, V> {
     pub fn new(tree: &'tree RbTree<K, V>) -> Self {
         Self {
-            visit: tree.root,
-            stack: Vec::with_capacity(8),
+            walk: Walk::new(tree.root, Vec::with_capacity(8), false),
             remaining_elements: tree.len(),
-            lifetime: PhantomData::default(),
+        }
+    }
+
+    /// Create an iterator over the key-values whose label is larger than the given label.
+    pub(crate) fn after_label(tree: &'tree RbTree<K, V>, label: &[u8]) -> AfterLabel<'tree, K, V> {
+        let mut stack = Vec::with_capacity(8);
+        let mut node = tree.root;
+
+        unsafe {
+            while !node.is_null() {
+                if label_cmp(&(*node).key.as_label(), label) == Ordering::Greater {
+                    stack.push(node);
+                    node = (*node).left;
+                } else {
+                    node = (*node).right;
+                }
+            }
+        }
+
+        AfterLabel {
+            walk: Walk::new(std::ptr::null_mut(), stack, false),
+            max_elements: tree.len(),
         }
     }
 }
@@ -27,28 +97,198 @@ impl<'tree, K: 'static + Label, V: AsHashTree + 'static> Iterator for RbTreeIter
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
+        let item = self.walk.next()?;
+        self.remaining_elements -= 1;
+        Some(item)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining_elements, Some(self.remaining_elements))
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> ExactSizeIterator for RbTreeIterator<'_, K, V> {}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> FusedIterator for RbTreeIterator<'_, K, V> {}
+
+/// An iterator over the key-values in a RbTree from the largest key to the smallest one.
+pub struct RbTreeRevIterator<'tree, K: 'static + Label, V: AsHashTree + 'static> {
+    walk: Walk<'tree, K, V>,
+    /// The number of remaining elements.
+    remaining_elements: usize,
+}
+
+impl<'tree, K: 'static + Label, V: AsHashTree + 'static> RbTreeRevIterator<'tree, K, V> {
+    pub fn new(tree: &'tree RbTree<K, V>) -> Self {
+        Self {
+            walk: Walk::new(tree.root, Vec::with_capacity(8), true),
+            remaining_elements: tree.len(),
+        }
+    }
+}
+
+impl<'tree, K: 'static + Label, V: AsHashTree + 'static> Iterator
+    for RbTreeRevIterator<'tree, K, V>
+{
+    type Item = (&'tree K, &'tree V);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.walk.next()?;
+        self.remaining_elements -= 1;
+        Some(item)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining_elements, Some(self.remaining_elements))
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> ExactSizeIterator
+    for RbTreeRevIterator<'_, K, V>
+{
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> FusedIterator for RbTreeRevIterator<'_, K, V> {}
+
+/// An iterator over the key-values of a RbTree whose label is larger than a given label.
+///
+/// Unlike [`RbTreeIterator`] the number of the remaining elements is not known, since the
+/// nodes do not store the size of their subtrees and counting the skipped nodes would
+/// take a walk over them.
+pub struct AfterLabel<'tree, K: 'static + Label, V: AsHashTree + 'static> {
+    walk: Walk<'tree, K, V>,
+    /// An upper bound of the number of remaining elements.
+    max_elements: usize,
+}
+
+impl<'tree, K: 'static + Label, V: AsHashTree + 'static> Iterator for AfterLabel<'tree, K, V> {
+    type Item = (&'tree K, &'tree V);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.walk.next()?;
+        self.max_elements -= 1;
+        Some(item)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // Every node on the stack is yet to be visited.
+        (self.walk.stack.len(), Some(self.max_elements))
+    }
+}
+
+impl<K: 'static + Label, V: AsHashTree + 'static> FusedIterator for AfterLabel<'_, K, V> {}
+
+/// An iterator over the key-values of a RbTree with a key in a given range.
+///
+/// Only the part of the tree that is in the range is visited: the first node is found in
+/// one descent of the tree and so is the last one, and the iterator stops once it reaches
+/// the last node.
+pub struct RbTreeRangeIterator<'tree, K: 'static + Label, V: AsHashTree + 'static> {
+    walk: Walk<'tree, K, V>,
+    /// The last node in the range, or null if the iterator is exhausted.
+    last: *mut Node<K, V>,
+    /// An upper bound of the number of remaining elements.
+    max_elements: usize,
+}
+
+impl<'tree, K: 'static + Label, V: AsHashTree + 'static> RbTreeRangeIterator<'tree, K, V> {
+    /// Create an iterator over the key-values whose key is in the given range.
+    pub fn new<Q, R>(tree: &'tree RbTree<K, V>, range: R) -> Self
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Ord,
+        R: RangeBounds<Q>,
+    {
+        let after_start = |key: &Q| match range.start_bound() {
+            Bound::Included(start) => key >= start,
+            Bound::Excluded(start) => key > start,
+            Bound::Unbounded => true,
+        };
+        let before_end = |key: &Q| match range.end_bound() {
+            Bound::Included(end) => key <= end,
+            Bound::Excluded(end) => key < end,
+            Bound::Unbounded => true,
+        };
+
+        let mut stack = Vec::with_capacity(8);
+        let mut last = std::ptr::null_mut();
+
         unsafe {
-            while !self.visit.is_null() {
-                self.stack.push(self.visit);
-                self.visit = (*self.visit).left;
+            let mut node = tree.root;
+            while !node.is_null() {
+                if after_start((*node).key.borrow()) {
+                    stack.push(node);
+                    node = (*node).left;
+                } else {
+                    node = (*node).right;
+                }
             }
 
-            if let Some(node) = self.stack.pop() {
-                self.visit = (*node).right;
-                self.remaining_elements -= 1;
-                return Some((&(*node).key, &(*node).value));
+            let mut node = tree.root;
+            while !node.is_null() {
+                if before_end((*node).key.borrow()) {
+                    last = node;
+                    node = (*node).right;
+                } else {
+                    node = (*node).left;
+                }
             }
 
-            None
+            // The range is empty if its first node is after its last node.
+            match stack.last() {
+                Some(first) if !last.is_null() && (**first).key <= (*last).key => {}
+                _ => {
+                    stack.clear();
+                    last = std::ptr::null_mut();
+                }
+            }
+        }
+
+        Self {
+            walk: Walk::new(std::ptr::null_mut(), stack, false),
+            last,
+            max_elements: tree.len(),
+        }
+    }
+}
+
+impl<'tree, K: 'static + Label, V: AsHashTree + 'static> Iterator
+    for RbTreeRangeIterator<'tree, K, V>
+{
+    type Item = (&'tree K, &'tree V);
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.last.is_null() {
+            return None;
         }
+
+        let (key, value) = self.walk.next()?;
+        self.max_elements -= 1;
+        if std::ptr::eq(key, unsafe { &(*self.last).key }) {
+            self.last = std::ptr::null_mut();
+        }
+
+        Some((key, value))
     }
 
     #[inline]
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.remaining_elements, Some(self.remaining_elements))
+        if self.last.is_null() {
+            (0, Some(0))
+        } else {
+            (1, Some(self.max_elements))
+        }
     }
 }
 
+impl<K: 'static + Label, V: AsHashTree + 'static> FusedIterator for RbTreeRangeIterator<'_, K, V> {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -61,15 +301,109 @@ mod tests {
             tree.insert([i], i);
         }
 
-        let iter = RbTreeIterator::new(&tree);
+        let mut iter = RbTreeIterator::new(&tree);
+        assert_eq!(iter.len(), 250);
 
         let mut expected_v = 0u8;
 
-        for (_, v) in iter {
+        while let Some((_, v)) = iter.next() {
             assert_eq!(v, &expected_v);
             expected_v += 1;
+            assert_eq!(iter.len(), 250 - expected_v as usize);
         }
 
         assert_eq!(expected_v, 250);
     }
+
+    #[test]
+    fn should_visit_all_in_reverse() {
+        let mut tree = RbTree::<[u8; 1], u8>::new();
+
+        for i in 0..250u8 {
+            tree.insert([i], i);
+        }
+
+        let iter = tree.iter_rev();
+        assert_eq!(iter.len(), 250);
+        assert_eq!(
+            iter.map(|(_, v)| *v).collect::<Vec<_>>(),
+            (0..250u8).rev().collect::<Vec<_>>()
+        );
+        assert_eq!(RbTree::<u8, u8>::new().iter_rev().next(), None);
+    }
+
+    #[test]
+    fn should_start_after_label() {
+        let mut tree = RbTree::<[u8; 1], u8>::new();
+
+        for i in (0..250u8).step_by(2) {
+            tree.insert([i], i);
+        }
+
+        for i in 0..=255u8 {
+            let expected = (0..250u8).step_by(2).filter(|v| *v > i).collect::<Vec<_>>();
+            let iter = RbTreeIterator::after_label(&tree, &[i]);
+            let (lower, upper) = iter.size_hint();
+            assert!(lower <= expected.len() && expected.len() <= upper.unwrap());
+            assert_eq!(iter.map(|(_, v)| *v).collect::<Vec<_>>(), expected);
+        }
+
+        let iter = RbTreeIterator::after_label(&tree, &[]);
+        assert_eq!(iter.count(), tree.len());
+    }
+
+    #[test]
+    fn should_visit_range() {
+        let mut tree = RbTree::<u32, u32>::new();
+
+        for i in (0..100u32).step_by(2) {
+            tree.insert(i, i);
+        }
+
+        let collect =
+            |iter: RbTreeRangeIterator<u32, u32>| iter.map(|(k, _)| *k).collect::<Vec<_>>();
+        let expected = |lo: u32, hi: u32| {
+            (lo..hi)
+                .filter(|i| i & 1 == 0 && *i < 100)
+                .collect::<Vec<_>>()
+        };
+
+        for lo in 0..105 {
+            for hi in lo..105 {
+                assert_eq!(
+                    collect(RbTreeRangeIterator::new(&tree, lo..hi)),
+                    expected(lo, hi)
+                );
+                assert_eq!(
+                    collect(RbTreeRangeIterator::new(&tree, lo..=hi)),
+                    expected(lo, hi + 1)
+                );
+            }
+            assert_eq!(
+                collect(RbTreeRangeIterator::new(&tree, lo..)),
+                expected(lo, 100)
+            );
+            assert_eq!(
+                collect(RbTreeRangeIterator::new(&tree, ..lo)),
+                expected(0, lo)
+            );
+        }
+
+        let range = (Bound::Excluded(10), Bound::Excluded(20));
+        assert_eq!(
+            collect(RbTreeRangeIterator::new(&tree, range)),
+            vec![12, 14, 16, 18]
+        );
+        assert_eq!(
+            collect(RbTreeRangeIterator::new(
+                &tree,
+                (Bound::Included(20), Bound::Excluded(10))
+            )),
+            Vec::<u32>::new()
+        );
+        assert_eq!(
+            RbTreeRangeIterator::new(&tree, ..).count(),
+            RbTreeIterator::new(&tree).count()
+        );
+    }
 }
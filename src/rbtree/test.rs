@@ -1,3 +1,4 @@
+use super::flat::FlatError;
 use super::{KeyBound, RbTree};
 use crate::{AsHashTree, HashTree};
 use std::convert::AsRef;
@@ -129,6 +130,121 @@ fn test_key_bounds() {
     assert_eq!(t.upper_bound(&[4u8][..]), None);
 }
 
+#[test]
+fn test_get_with_neighbors() {
+    let mut t = RbTree::<u32, u32>::new();
+    assert_eq!(t.get_with_neighbors(&0), (None, None, None));
+
+    for i in 0..100u32 {
+        t.insert(i * 2, i);
+    }
+
+    for k in 0..=200u32 {
+        let prev = (0..100u32).map(|i| i * 2).filter(|i| *i < k).max();
+        let next = (0..100u32).map(|i| i * 2).find(|i| *i > k);
+        let (p, v, n) = t.get_with_neighbors(&k);
+        assert_eq!(p.copied(), prev);
+        assert_eq!(v, t.get(&k));
+        assert_eq!(n.copied(), next);
+    }
+}
+
+#[test]
+fn test_first_n_last_n() {
+    use crate::hashtree::LookupResult;
+
+    for len in 0..40u32 {
+        let mut t = RbTree::<u32, u32>::new();
+        for i in 0..len {
+            t.insert(i * 2, i);
+        }
+
+        for n in 0..len as usize + 3 {
+            let count = n.min(len as usize);
+            let keys = (0..len).map(|i| i * 2).collect::<Vec<_>>();
+
+            for (witness, expected, last) in [
+                (t.first_n(n), &keys[..count], false),
+                (t.last_n(n), &keys[keys.len() - count..], true),
+            ] {
+                assert_eq!(witness.reconstruct(), t.root_hash());
+
+                let labels = expected.iter().map(|k| k.to_be_bytes()).collect::<Vec<_>>();
+                assert_eq!(
+                    witness.get_labels(),
+                    labels.iter().map(|l| l as &[u8]).collect::<Vec<_>>()
+                );
+
+                for k in expected {
+                    assert_eq!(
+                        witness.lookup_path(&[&k.to_be_bytes()]),
+                        LookupResult::Found(&(k / 2).to_be_bytes())
+                    );
+                }
+
+                // There are no hidden keys between the revealed entries, or before them
+                // for the first entries, or after them for the last entries.
+                if let (Some(first), Some(end)) = (expected.first(), expected.last()) {
+                    let range = if last { *first..end + 2 } else { 0..*end };
+                    for k in range.filter(|k| k & 1 == 1) {
+                        assert_eq!(
+                            witness.lookup_path(&[&k.to_be_bytes()]),
+                            LookupResult::Absent
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_rebalance() {
+    unsafe fn depth<K, V>(n: *mut super::Node<K, V>) -> usize {
+        if n.is_null() {
+            return 0;
+        }
+        1 + depth((*n).left).max(depth((*n).right))
+    }
+
+    let mut t = RbTree::<u32, u32>::new();
+    t.rebalance();
+    assert!(t.is_empty());
+
+    for i in 0..2000u32 {
+        t.insert(i, i);
+    }
+    for i in 0..2000u32 {
+        if i % 7 > 0 {
+            t.delete(&i);
+        }
+    }
+
+    let generation = t.generation();
+    let changed = t
+        .changed_since(1000)
+        .into_iter()
+        .copied()
+        .collect::<Vec<_>>();
+    let entries = |t: &RbTree<u32, u32>| {
+        let mut entries = Vec::new();
+        t.for_each(|k, v| entries.push((*k, *v)));
+        entries
+    };
+    let before = entries(&t);
+
+    t.rebalance();
+    t.assert_invariants();
+
+    // The minimal depth of a tree with this many entries.
+    let min_depth = (usize::BITS - before.len().leading_zeros()) as usize;
+    assert_eq!(unsafe { depth(t.root) }, min_depth);
+    assert_eq!(entries(&t), before);
+    assert_eq!(t.generation(), generation);
+    assert!(t.removed_since(generation - 1));
+    assert_eq!(t.changed_since(1000), changed.iter().collect::<Vec<_>>());
+}
+
 #[test]
 fn test_prefix_neighbor() {
     let mut t = RbTree::<String, Vec<u8>>::new();
@@ -195,6 +311,7 @@ fn map_model_test() {
         for k in hm.keys() {
             assert_eq!(hm.get(k), rb.get(k));
         }
+        assert!(rb.verify_len());
     }
     let keys: Vec<_> = hm.keys().cloned().collect();
 
@@ -208,6 +325,7 @@ fn map_model_test() {
         for k in hm.keys() {
             assert_eq!(hm.get(k), rb.get(k));
         }
+        assert!(rb.verify_len());
     }
     assert_eq!(super::debug_alloc::count_allocated_pointers(), 0);
 }
@@ -293,3 +411,342 @@ fn test_witness_value_range() {
         vec![b"x", b"y", b"z"]
     );
 }
+
+#[test]
+fn test_retain() {
+    for n in 0..130u32 {
+        let mut t = RbTree::<[u8; 4], u32>::new();
+        for i in 0..n {
+            t.insert(i.to_be_bytes(), i);
+        }
+
+        let removed = t.retain(|_, v| v % 2 == 0);
+        assert_eq!(removed.len(), (n / 2) as usize);
+        assert!(removed.iter().all(|(_, v)| v % 2 == 1));
+        assert_eq!(t.len(), (n - n / 2) as usize);
+        assert!(t.verify_len());
+
+        unsafe {
+            assert!(super::is_balanced(t.root));
+            assert!(super::is_label_ordered(t.root));
+        }
+
+        for i in 0..n {
+            let key = i.to_be_bytes();
+            assert_eq!(t.get(&key), Some(&i).filter(|i| *i % 2 == 0));
+            assert_eq!(t.witness(&key).reconstruct(), t.root_hash());
+        }
+        assert_eq!(t.as_hash_tree().reconstruct(), t.root_hash());
+
+        // The tree must still be usable after it is rebuilt.
+        for i in n..n + 10 {
+            t.insert(i.to_be_bytes(), i);
+        }
+        for i in 0..n + 10 {
+            t.delete(&i.to_be_bytes());
+        }
+        assert!(t.is_empty());
+    }
+    assert_eq!(super::debug_alloc::count_allocated_pointers(), 0);
+}
+
+#[test]
+fn test_flat_bytes() {
+    for n in 0..100u32 {
+        let mut t = RbTree::<u32, String>::new();
+        for i in 0..n {
+            t.insert(i * 7 % 101, i.to_string());
+        }
+        for i in (0..n).step_by(3) {
+            t.delete(&(i * 7 % 101));
+        }
+
+        let bytes = t.to_flat_bytes().unwrap();
+        let mut loaded = RbTree::<u32, String>::from_flat_bytes(&bytes).unwrap();
+        assert_eq!(loaded.len(), t.len());
+        assert_eq!(loaded.root_hash(), t.root_hash());
+        assert_eq!(loaded.to_flat_bytes().unwrap(), bytes);
+
+        for i in 0..n {
+            let key = i * 7 % 101;
+            assert_eq!(loaded.get(&key), t.get(&key));
+            assert_eq!(loaded.witness(&key).reconstruct(), loaded.root_hash());
+        }
+
+        // The tree must still be usable after it is loaded.
+        for i in 0..n {
+            loaded.insert(i * 7 % 101 + 1000, i.to_string());
+        }
+        for i in 0..n {
+            loaded.delete(&(i * 7 % 101));
+            loaded.delete(&(i * 7 % 101 + 1000));
+        }
+        assert!(loaded.is_empty());
+    }
+    assert_eq!(super::debug_alloc::count_allocated_pointers(), 0);
+}
+
+#[test]
+fn test_flat_bytes_invalid() {
+    let mut t = RbTree::<u32, u32>::new();
+    for i in 0..10 {
+        t.insert(i, i);
+    }
+    let bytes = t.to_flat_bytes().unwrap();
+
+    for len in 0..bytes.len() {
+        assert!(RbTree::<u32, u32>::from_flat_bytes(&bytes[..len]).is_err());
+    }
+
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert!(RbTree::<u32, u32>::from_flat_bytes(&trailing).is_err());
+
+    // A black root with a red left child that has a larger key.
+    let mut unordered = vec![1];
+    unordered.extend_from_slice(&2u64.to_be_bytes());
+    for (color, flags, key) in [(1, 1, 1), (0, 0, 2)] {
+        unordered.extend_from_slice(&[color, flags, 0, 0, 0, 1, key, 0, 0, 0, 1, key]);
+    }
+    assert_eq!(
+        RbTree::<u32, u32>::from_flat_bytes(&unordered).err(),
+        Some(FlatError::InvalidTree(
+            "the keys are not in ascending order"
+        ))
+    );
+
+    // The same tree with its keys swapped, but with a red right child.
+    let mut right_leaning = vec![1];
+    right_leaning.extend_from_slice(&2u64.to_be_bytes());
+    for (color, flags, key) in [(1, 2, 1), (0, 0, 2)] {
+        right_leaning.extend_from_slice(&[color, flags, 0, 0, 0, 1, key, 0, 0, 0, 1, key]);
+    }
+    assert_eq!(
+        RbTree::<u32, u32>::from_flat_bytes(&right_leaning).err(),
+        Some(FlatError::InvalidTree(
+            "the colors of the nodes are invalid"
+        ))
+    );
+
+    drop(t);
+    assert_eq!(super::debug_alloc::count_allocated_pointers(), 0);
+}
+
+#[test]
+fn test_remove_range() {
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+    use std::ops::RangeBounds;
+
+    let build = || {
+        let mut t = RbTree::<u32, u32>::new();
+        for i in 0..50 {
+            t.insert(i, i);
+        }
+        t
+    };
+
+    let ranges = [
+        (Included(10), Excluded(20)),
+        (Included(10), Included(20)),
+        (Excluded(10), Included(20)),
+        (Unbounded, Excluded(25)),
+        (Excluded(25), Unbounded),
+        (Unbounded, Unbounded),
+        (Included(60), Unbounded),
+        // The small ranges are removed one entry at a time.
+        (Included(10), Included(12)),
+        (Excluded(10), Excluded(12)),
+        (Included(0), Included(0)),
+        (Excluded(47), Unbounded),
+        (Included(30), Excluded(30)),
+    ];
+
+    for range in ranges {
+        let mut t = build();
+        let expected = (0..50u32).filter(|i| range.contains(i)).count();
+        assert_eq!(t.remove_range(range), expected);
+        assert_eq!(t.len(), 50 - expected);
+        assert!(t.verify_len());
+        t.assert_invariants();
+
+        for i in 0..50 {
+            assert_eq!(t.get(&i).is_some(), !range.contains(&i));
+            assert_eq!(t.witness(&i).reconstruct(), t.root_hash());
+        }
+    }
+
+    let mut t = build();
+    assert_eq!(t.remove_range(..10), 10);
+    assert_eq!(t.remove_range(40..), 10);
+    assert_eq!(t.remove_range(20..=29), 10);
+    assert_eq!(t.len(), 20);
+    drop(t);
+    assert_eq!(super::debug_alloc::count_allocated_pointers(), 0);
+}
+
+#[test]
+fn test_merge_with() {
+    for n in 0..40u32 {
+        let mut a = RbTree::<[u8; 4], u32>::new();
+        let mut b = RbTree::<[u8; 4], u32>::new();
+        let mut expected = RbTree::<[u8; 4], u32>::new();
+        for i in 0..n {
+            if i % 2 == 0 {
+                a.insert(i.to_be_bytes(), i);
+            }
+            if i % 3 == 0 {
+                b.insert(i.to_be_bytes(), 1000 + i);
+            }
+
+            let value = match (i % 2, i % 3) {
+                (0, 0) => 1000 + 2 * i,
+                (0, _) => i,
+                (_, 0) => 1000 + i,
+                _ => continue,
+            };
+            expected.insert(i.to_be_bytes(), value);
+        }
+
+        let synced = a.generation();
+        a.merge_with(b, |k, x, y| {
+            assert_eq!(*k, x.to_be_bytes());
+            x + y
+        });
+        assert!(a.verify_len());
+        assert_eq!(a.len(), expected.len());
+        for i in 0..n {
+            let key = i.to_be_bytes();
+            assert_eq!(a.get(&key), expected.get(&key));
+            assert_eq!(a.witness(&key).reconstruct(), a.root_hash());
+        }
+        assert_eq!(a.as_hash_tree().reconstruct(), a.root_hash());
+
+        unsafe {
+            assert!(super::is_balanced(a.root));
+            assert!(super::is_label_ordered(a.root));
+        }
+
+        // Only the entries that came from the other tree are changed.
+        let changed = (0..n)
+            .filter(|i| i % 3 == 0)
+            .map(|i| i.to_be_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(a.changed_since(synced), changed.iter().collect::<Vec<_>>());
+        assert!(!a.removed_since(synced));
+    }
+    assert_eq!(super::debug_alloc::count_allocated_pointers(), 0);
+}
+
+#[test]
+fn test_changed_since() {
+    let mut t = RbTree::<u32, u32>::new();
+    for i in 0..100 {
+        t.insert(i, i);
+    }
+    let synced = t.generation();
+    assert_eq!(synced, 100);
+    assert!(t.changed_since(synced).is_empty());
+    assert_eq!(t.witness_changed_since(synced).reconstruct(), t.root_hash());
+
+    t.insert(10, 0);
+    t.modify(&20, |v| *v = 0);
+    t.insert(200, 200);
+    assert!(t.modify(&300, |v| *v = 0).is_none());
+    assert_eq!(t.changed_since(synced), vec![&10, &20, &200]);
+    assert!(!t.removed_since(synced));
+
+    // Deleting a node with two children moves the entry of its successor.
+    let before_delete = t.generation();
+    t.delete(&31);
+    assert!(t.removed_since(before_delete));
+    assert_eq!(t.changed_since(synced), vec![&10, &20, &200]);
+
+    // The generations survive a rebuild of the tree.
+    t.retain(|k, _| k % 2 == 0);
+    assert_eq!(t.changed_since(synced), vec![&10, &20, &200]);
+    assert_eq!(t.changed_since(0).len(), t.len());
+
+    let witness = t.witness_changed_since(synced);
+    assert_eq!(witness.reconstruct(), t.root_hash());
+    let labels = [10u32, 20, 200].map(|k| k.to_be_bytes());
+    assert_eq!(
+        witness.get_labels(),
+        labels.iter().map(|l| &l[..]).collect::<Vec<_>>()
+    );
+
+    drop(t);
+    assert_eq!(super::debug_alloc::count_allocated_pointers(), 0);
+}
+
+#[test]
+#[cfg(feature = "debug")]
+fn test_structure() {
+    let mut t = RbTree::<u32, u32>::new();
+    assert_eq!(t.structure(), None);
+
+    for i in 0..3 {
+        t.insert(i, i);
+    }
+
+    let dump = t.structure().unwrap();
+    assert_eq!(dump.label, 1u32.to_be_bytes());
+    assert!(!dump.red);
+    assert_eq!(dump.subtree_hash, t.root_hash());
+    assert_eq!(dump.left.as_ref().unwrap().label, 0u32.to_be_bytes());
+    assert_eq!(dump.right.as_ref().unwrap().label, 2u32.to_be_bytes());
+
+    let dot = dump.to_dot();
+    assert!(dot.starts_with("digraph RbTree {"));
+    assert!(dot.contains("n0 -> n1;"));
+    assert!(dot.contains("n0 -> n2;"));
+
+    drop(t);
+    assert_eq!(super::debug_alloc::count_allocated_pointers(), 0);
+}
+
+#[test]
+fn test_verify_hashes() {
+    let mut t = RbTree::<u32, u32>::new();
+    assert_eq!(t.verify_hashes(), Ok(()));
+
+    for i in 0..16 {
+        t.insert(i, i);
+    }
+    assert_eq!(t.verify_hashes(), Ok(()));
+
+    unsafe {
+        let root = t.root;
+        let left = (*root).left;
+        let stored = (*left).subtree_hash;
+        (*left).subtree_hash = [0; 32];
+
+        // The parent is consistent with the corrupted hash, so only the child is reported.
+        (*root).subtree_hash = super::Node::subtree_hash(root);
+        let err = t.verify_hashes().unwrap_err();
+        assert_eq!(
+            err.path,
+            vec![
+                (*root).key.to_be_bytes().to_vec(),
+                (*left).key.to_be_bytes().to_vec()
+            ]
+        );
+        assert_eq!(err.stored, [0; 32]);
+        assert_eq!(err.computed, stored);
+
+        (*left).subtree_hash = stored;
+        assert!(t.verify_hashes().is_err());
+        (*root).subtree_hash = super::Node::subtree_hash(root);
+        assert_eq!(t.verify_hashes(), Ok(()));
+
+        // A stale hash of a leaf.
+        let mut n = root;
+        while !(*n).right.is_null() {
+            n = (*n).right;
+        }
+        (*n).value = 100;
+        let err = t.verify_hashes().unwrap_err();
+        assert_eq!(err.path.last().unwrap(), &15u32.to_be_bytes());
+        assert_eq!(err.path[0], (*root).key.to_be_bytes());
+        assert_eq!(err.path.len(), 4);
+    }
+}
@@ -0,0 +1,168 @@
+//! A token ledger that certifies the balance of every account, the total supply and the
+//! log of all of the transactions in one group.
+
+use candid::{CandidType, Principal};
+use certified_vars::as_hash_tree::CandidLeaf;
+use certified_vars::hashtree::{leaf_hash, LookupResult};
+use certified_vars::label::Label;
+use certified_vars::{AsHashTree, Group, GroupBuilder, Hash, HashTree, Map, Seq};
+
+type Balances = Map<Principal, u64>;
+type Transactions = Seq<CandidLeaf<Transaction>>;
+
+/// The total supply is derived from the balances, and it is kept in its own leaf so a
+/// client can read it without fetching the balances.
+struct TotalSupply(u64);
+
+impl AsHashTree for TotalSupply {
+    fn root_hash(&self) -> Hash {
+        self.0.root_hash()
+    }
+
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        self.0.as_hash_tree()
+    }
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq)]
+enum Transaction {
+    Mint {
+        to: Principal,
+        amount: u64,
+    },
+    Transfer {
+        from: Principal,
+        to: Principal,
+        amount: u64,
+    },
+}
+
+/// The ledger, with the following hash tree:
+///
+/// ```text
+/// ─┬╴"balances" ──╴Map<Principal, u64>
+///  ├╴"supply" ──╴u64
+///  └╴"transactions" ──╴Seq<Transaction>
+/// ```
+struct Ledger {
+    group: Group,
+}
+
+impl Ledger {
+    fn new() -> Self {
+        let group = GroupBuilder::new()
+            .insert(["balances"], Balances::new())
+            .insert(["supply"], TotalSupply(0))
+            .insert(["transactions"], Transactions::new())
+            .build();
+
+        Self { group }
+    }
+
+    fn balance(&self, account: &Principal) -> u64 {
+        self.group
+            .get::<Balances>()
+            .get(account)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn mint(&mut self, to: Principal, amount: u64) {
+        self.group
+            .get_mut::<Balances>()
+            .modify_or_default(to, |balance| *balance += amount);
+        self.group.get_mut::<TotalSupply>().0 += amount;
+        self.log(Transaction::Mint { to, amount });
+    }
+
+    /// Transfer the amount and return the new balance of the sender.
+    fn transfer(&mut self, from: Principal, to: Principal, amount: u64) -> Result<u64, String> {
+        let balances = self.group.get_mut::<Balances>();
+        let balance = balances
+            .modify(&from, |balance| {
+                *balance = balance.checked_sub(amount)?;
+                Some(*balance)
+            })
+            .flatten()
+            .ok_or_else(|| "Insufficient balance.".to_string())?;
+
+        balances.modify_or_default(to, |balance| *balance += amount);
+        self.log(Transaction::Transfer { from, to, amount });
+
+        Ok(balance)
+    }
+
+    fn log(&mut self, transaction: Transaction) {
+        self.group
+            .get_mut::<Transactions>()
+            .append(transaction.into());
+    }
+
+    /// A witness for the balance of the account along with the total supply.
+    fn witness_balance(&self, account: &Principal) -> HashTree<'_> {
+        self.group
+            .witness()
+            .partial::<Balances, _>(|balances| balances.witness(account))
+            .full::<TotalSupply>()
+            .build()
+    }
+
+    /// A witness for the last transaction along with the number of transactions.
+    fn witness_last_transaction(&self) -> HashTree<'_> {
+        self.group
+            .witness()
+            .partial::<Transactions, _>(|log| log.witness_with_len(&[log.len() - 1]))
+            .build()
+    }
+}
+
+fn account(id: u8) -> Principal {
+    Principal::from_slice(&[id])
+}
+
+fn lookup<'a>(tree: &'a HashTree, path: &[&[u8]]) -> &'a [u8] {
+    match tree.lookup_path(path) {
+        LookupResult::Found(value) => value,
+        result => panic!("Path {:?} not found: {:?}", path, result),
+    }
+}
+
+#[test]
+fn ledger() {
+    let (alice, bob) = (account(1), account(2));
+    let mut ledger = Ledger::new();
+
+    ledger.mint(alice, 100);
+    ledger.mint(bob, 20);
+    assert_eq!(ledger.transfer(alice, bob, 30), Ok(70));
+    assert!(ledger.transfer(bob, alice, 1000).is_err());
+
+    assert_eq!(ledger.balance(&alice), 70);
+    assert_eq!(ledger.balance(&bob), 50);
+    assert_eq!(ledger.group.get::<TotalSupply>().0, 120);
+    assert_eq!(ledger.group.get::<Transactions>().len(), 3);
+
+    let tree = ledger.witness_balance(&bob);
+    assert_eq!(tree.reconstruct(), ledger.group.root_hash());
+    assert_eq!(
+        lookup(&tree, &[b"balances", &bob.as_label()]),
+        50u64.to_be_bytes()
+    );
+    assert_eq!(lookup(&tree, &[b"supply"]), 120u64.to_be_bytes());
+
+    // The failed transfer is not in the log.
+    let tree = ledger.witness_last_transaction();
+    assert_eq!(tree.reconstruct(), ledger.group.root_hash());
+    assert_eq!(
+        lookup(&tree, &[b"transactions", b"len"]),
+        3u64.to_be_bytes()
+    );
+
+    let last = CandidLeaf(Transaction::Transfer {
+        from: alice,
+        to: bob,
+        amount: 30,
+    });
+    let leaf = lookup(&tree, &[b"transactions", b"items", &2u64.to_be_bytes()]);
+    assert_eq!(leaf_hash(leaf), last.root_hash());
+}
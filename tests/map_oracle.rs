@@ -0,0 +1,112 @@
+//! Apply random sequences of operations to both a `Map` and a `BTreeMap`, and check that
+//! they always agree and that the map stays valid after every operation.
+
+use certified_vars::hashtree::LookupResult;
+use certified_vars::{AsHashTree, Map};
+use std::collections::BTreeMap;
+
+/// A small xorshift generator, so the runs are reproducible from their seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Check that the map agrees with the oracle on `key`, and that the witness of the key
+/// proves the same thing.
+fn check(map: &Map<u16, u32>, oracle: &BTreeMap<u16, u32>, key: u16) {
+    assert_eq!(map.get(&key), oracle.get(&key));
+    assert_eq!(map.len(), oracle.len());
+    assert_eq!(map.is_empty(), oracle.is_empty());
+
+    let root_hash = map.root_hash();
+    let witness = map.witness(&key);
+    assert_eq!(witness.reconstruct(), root_hash);
+    let label = key.to_be_bytes();
+    match oracle.get(&key) {
+        Some(value) => {
+            assert_eq!(
+                witness.lookup_path(&[&label]),
+                LookupResult::Found(&value.to_be_bytes())
+            );
+            map.assert_certifies(&key, value);
+        }
+        None => assert_eq!(witness.lookup_path(&[&label]), LookupResult::Absent),
+    }
+}
+
+/// Check that the map has the same entries as the oracle, that the tree is valid, and that
+/// the cached hashes of the map are the ones a fresh rehash of the entire tree computes.
+///
+/// The root hash also depends on the shape of the tree, which depends on the order of the
+/// operations, so it is not compared with the hash of a map that is rebuilt from the
+/// entries of the oracle.
+fn check_all(map: &Map<u16, u32>, oracle: &BTreeMap<u16, u32>) {
+    assert!(map.iter().map(|(k, v)| (*k, *v)).eq(oracle.clone()));
+    map.assert_invariants();
+    assert_eq!(map.as_hash_tree().reconstruct(), map.root_hash());
+}
+
+fn run(seed: u64, steps: usize, keys: u64) {
+    let mut rng = Rng(seed);
+    let mut map = Map::<u16, u32>::new();
+    let mut oracle = BTreeMap::<u16, u32>::new();
+
+    for step in 0..steps {
+        let key = rng.below(keys) as u16;
+
+        match rng.below(3) {
+            0 => {
+                let value = rng.next() as u32;
+                assert_eq!(map.insert(key, value), oracle.insert(key, value));
+            }
+            1 => assert_eq!(map.remove(&key), oracle.remove(&key)),
+            _ => {}
+        }
+
+        check(&map, &oracle, key);
+
+        if step % 16 == 0 {
+            check_all(&map, &oracle);
+        }
+    }
+
+    check_all(&map, &oracle);
+
+    // Drain the map in a random order.
+    while let Some(key) = oracle
+        .keys()
+        .nth(rng.below(oracle.len().max(1) as u64) as usize)
+    {
+        let key = *key;
+        assert_eq!(map.remove(&key), oracle.remove(&key));
+        check(&map, &oracle, key);
+        check_all(&map, &oracle);
+    }
+
+    assert!(map.is_empty());
+    assert_eq!(map.root_hash(), Map::<u16, u32>::new().root_hash());
+}
+
+#[test]
+fn small_key_space() {
+    for seed in 1..=8 {
+        run(seed, 2000, 16);
+    }
+}
+
+#[test]
+fn large_key_space() {
+    for seed in 1..=4u64 {
+        run(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15), 2000, 256);
+    }
+}